@@ -11,8 +11,14 @@ extern crate alloc;
 
 use {
     crate::{processor::start_hypervisor_on_all_processors, relocation::zap_relocations},
+    alloc::vec,
     hypervisor::{
-        intel::{ept::paging::Ept, vm::box_zeroed},
+        intel::{
+            ept::paging::Ept,
+            guest::{GuestEptConfig, GuestId},
+            postmortem::{dump_exit_trace, dump_last_exit_context},
+            vm::try_box_zeroed,
+        },
         logger::{self, SerialPort},
     },
     log::*,
@@ -25,6 +31,9 @@ pub mod virtualize;
 
 /// Custom panic handler for the UEFI application.
 ///
+/// Logs the panic location and message, followed by the last VM exit and the exit trace recorded on
+/// this processor, so a panic in a VM-exit handler shows where the guest was and how it got there.
+///
 /// # Arguments
 ///
 /// * `info` - Information about the panic, including the location and optional message.
@@ -45,6 +54,11 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
         }
     }
 
+    // Dump the guest state of the last VM exit and the exits leading up to it, if the panic happened
+    // after launching the guest.
+    dump_last_exit_context();
+    dump_exit_trace();
+
     // Enter an infinite loop as the panic handler should not return.
     loop {}
 }
@@ -61,14 +75,23 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
 /// # Returns
 ///
 /// The status of the application execution. Returns `Status::SUCCESS` on successful execution,
-/// or `Status::ABORTED` if the hypervisor fails to install.
+/// `Status::OUT_OF_RESOURCES` if the EPTs cannot be allocated, or `Status::ABORTED` if the
+/// hypervisor fails to install.
 #[entry]
 fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     // Initialize logging with the COM2 port and set the level filter to Trace.
+    #[cfg(not(feature = "framebuffer-logger"))]
     logger::init(SerialPort::COM2, LevelFilter::Trace);
 
+    // Initialize logging with the GOP framebuffer instead, falling back to the COM2 port without one.
+    #[cfg(feature = "framebuffer-logger")]
+    match framebuffer_sink(system_table.boot_services()) {
+        Ok(sink) => logger::init(sink, LevelFilter::Trace),
+        Err(_) => logger::init(SerialPort::COM2, LevelFilter::Trace),
+    }
+
     // Initialize UEFI services.
-    uefi_services::init(&mut system_table).unwrap();
+    let exit_boot_services_event = uefi_services::init(&mut system_table).unwrap();
     // allocator::init(&system_table);
 
     info!("The Matrix is an illusion");
@@ -78,33 +101,123 @@ fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     // Attempt to zap relocations in the UEFI environment.
     debug!("Zapping relocations");
     if let Err(e) = zap_relocations(boot_services) {
-        error!("Failed to zap relocations: {:?}", e);
+        error!("Failed to zap relocations: {}", e);
         return Status::ABORTED;
     }
 
     debug!("Allocating primary and secondary EPTs");
-    let mut primary_ept = unsafe { box_zeroed::<Ept>() };
-    let mut secondary_ept = unsafe { box_zeroed::<Ept>() };
+    let (mut primary_ept, mut secondary_ept) =
+        match unsafe { (try_box_zeroed::<Ept>(), try_box_zeroed::<Ept>()) } {
+            (Ok(primary_ept), Ok(secondary_ept)) => (primary_ept, secondary_ept),
+            _ => {
+                error!("Insufficient memory to allocate EPT");
+                return Status::OUT_OF_RESOURCES;
+            }
+        };
+
+    debug!("Identity mapping primary EPT");
+    if let Err(e) = primary_ept.build_identity_1gb() {
+        error!("Failed to identity map primary EPT: {}", e);
+        return Status::ABORTED;
+    }
+
+    // Catch structural EPT errors before they corrupt guest memory.
+    #[cfg(feature = "ept-validation")]
+    if let Err(e) = primary_ept.validate() {
+        error!("Primary EPT validation failed: {}", e);
+        return Status::ABORTED;
+    }
 
-    debug!("Identity mapping primary and secondary EPTs");
+    // Verify the EPT operations on the secondary EPT, which is re-cloned afterwards.
+    debug!("Running EPT self-test");
+    if let Err(e) = secondary_ept.clone_from(&primary_ept) {
+        error!("Failed to clone primary EPT: {}", e);
+        return Status::ABORTED;
+    }
+    if let Err(e) = secondary_ept.self_test() {
+        error!("EPT self-test failed: {}", e);
+        return Status::ABORTED;
+    }
 
-    if let Err(e) = primary_ept.build_identity() {
-        error!("Failed to identity map primary EPT: {:?}", e);
+    // Measure the EPT hot paths on scratch EPTs to catch performance regressions.
+    #[cfg(feature = "ept-benchmark")]
+    if let Err(e) = hypervisor::intel::ept::benchmark::run_ept_benchmarks() {
+        error!("EPT benchmark failed: {}", e);
         return Status::ABORTED;
     }
 
-    if let Err(e) = secondary_ept.build_identity() {
-        error!("Failed to identity map secondary EPT: {:?}", e);
+    debug!("Cloning primary EPT into secondary EPT");
+    if let Err(e) = secondary_ept.clone_from(&primary_ept) {
+        error!("Failed to clone primary EPT: {}", e);
         return Status::ABORTED;
     }
 
     // Attempt to start the hypervisor on all processors.
     debug!("Starting hypervisor on all processors");
-    if let Err(e) = start_hypervisor_on_all_processors(boot_services, primary_ept, secondary_ept) {
-        error!("Failed to start hypervisor on all processors: {:?}", e);
-        return Status::ABORTED;
+    let guest_configs = vec![GuestEptConfig::new(
+        GuestId::DEFAULT,
+        primary_ept,
+        secondary_ept,
+    )];
+    let shared_data = match start_hypervisor_on_all_processors(boot_services, guest_configs, None) {
+        Ok(shared_data) => shared_data,
+        Err(e) => {
+            error!("Failed to start hypervisor on all processors: {:?}", e);
+            return Status::ABORTED;
+        }
+    };
+
+    // The image is hidden from the guest once this function returns, so the firmware must not call
+    // back into it at exit boot services.
+    if let Some(event) = exit_boot_services_event {
+        if let Err(e) = boot_services.close_event(event) {
+            warn!("Failed to close the exit boot services event: {:?}", e);
+        }
     }
+    shared_data.finish_loading();
 
     // Return success status to UEFI environment.
     Status::SUCCESS
 }
+
+/// Creates a log sink that renders to the framebuffer of the UEFI Graphics Output Protocol.
+///
+/// The framebuffer stays mapped after the driver returns, so the sink keeps working in VM-exit
+/// handlers until the guest operating system takes over the display.
+///
+/// # Arguments
+///
+/// * `boot_services` - A reference to the UEFI boot services table.
+///
+/// # Returns
+///
+/// The framebuffer sink, or an error if there is no GOP or its pixel format is not 32-bit RGB or BGR.
+#[cfg(feature = "framebuffer-logger")]
+fn framebuffer_sink(
+    boot_services: &BootServices,
+) -> uefi::Result<hypervisor::logger::framebuffer::FramebufferSink> {
+    use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+
+    let handle = boot_services.get_handle_for_protocol::<GraphicsOutput>()?;
+    let mut gop = boot_services.open_protocol_exclusive::<GraphicsOutput>(handle)?;
+
+    let mode_info = gop.current_mode_info();
+    if !matches!(
+        mode_info.pixel_format(),
+        PixelFormat::Rgb | PixelFormat::Bgr
+    ) {
+        return Err(Status::UNSUPPORTED.into());
+    }
+
+    let (width, height) = mode_info.resolution();
+    let base = gop.frame_buffer().as_mut_ptr();
+
+    Ok(unsafe {
+        hypervisor::logger::framebuffer::FramebufferSink::new(
+            base,
+            width,
+            height,
+            mode_info.stride(),
+        )
+    })
+}
@@ -3,37 +3,58 @@
 
 use {
     crate::virtualize::virtualize_system,
-    alloc::boxed::Box,
+    alloc::{boxed::Box, vec::Vec},
     core::ffi::c_void,
     hypervisor::intel::{
         capture::{capture_registers, GuestRegisters},
-        ept::paging::Ept,
-        shared::SharedData,
+        guest::GuestEptConfig,
+        shared::{SharedData, MAX_PROCESSORS},
+        state::InitialGuestState,
     },
     log::*,
-    uefi::{prelude::*, proto::pi::mp::MpServices},
+    uefi::{
+        prelude::*,
+        proto::{loaded_image::LoadedImage, pi::mp::MpServices},
+    },
 };
 
 /// Starts the hypervisor on all processors.
 ///
+/// Every processor starts out running the default guest, i.e. the current system, unless an initial
+/// guest state is given. The guest then starts in that state instead and never returns here, so
+/// only the current processor is virtualized.
+///
 /// # Arguments
 ///
 /// * `boot_services` - A reference to the UEFI Boot Services.
-/// * `primary_ept` - The primary Extended Page Table (EPT) instance.
-/// * `secondary_ept` - The secondary Extended Page Table (EPT) instance.
+/// * `guest_configs` - The primary and secondary Extended Page Tables (EPT) of each guest, including the default guest.
+/// * `initial_guest_state` - The state to launch the guest with, e.g. a test payload, or `None` to continue the current system.
 ///
 /// # Returns
 ///
-/// A result indicating the success or failure of starting the hypervisor.
+/// The shared data of the hypervisor, whose `SharedData::finish_loading` must be called right before
+/// the loader returns, or the error of starting the hypervisor. `Status::ABORTED` is returned if the
+/// VM entry failed on any processor, which then keeps running without the hypervisor.
 pub fn start_hypervisor_on_all_processors(
     boot_services: &BootServices,
-    primary_ept: Box<Ept>,
-    secondary_ept: Box<Ept>,
-) -> uefi::Result<()> {
+    guest_configs: Vec<GuestEptConfig>,
+    initial_guest_state: Option<InitialGuestState>,
+) -> uefi::Result<&'static SharedData> {
     debug!("Creating Shared Data");
-    let shared_data =
-        SharedData::new(primary_ept, secondary_ept).expect("Failed to create shared data");
+    let shared_data = SharedData::new(guest_configs).expect("Failed to create shared data");
     let shared_data = Box::leak(shared_data);
+    shared_data.initial_guest_state = initial_guest_state;
+
+    // The image is hidden from the guest together with the rest of the hypervisor memory.
+    let loaded_image =
+        boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
+    let (image_base, image_size) = loaded_image.info();
+    let image_base = image_base as u64;
+    if let Err(e) = shared_data.set_loader_image(image_base..image_base + image_size) {
+        error!("Failed to reserve the loader image: {:?}", e);
+        return Err(Status::OUT_OF_RESOURCES.into());
+    }
+    drop(loaded_image);
 
     let handle = boot_services.get_handle_for_protocol::<MpServices>()?;
     let mp_services = boot_services.open_protocol_exclusive::<MpServices>(handle)?;
@@ -61,9 +82,21 @@ pub fn start_hypervisor_on_all_processors(
         )?;
     }
 
+    // Processors whose first VM entry failed are back here without the hypervisor.
+    let mut launch_failed = false;
+    for apic_id in 0..MAX_PROCESSORS as u32 {
+        if let Some(e) = shared_data.take_launch_failure(apic_id) {
+            error!("Failed to virtualize processor {}: {}", apic_id, e);
+            launch_failed = true;
+        }
+    }
+    if launch_failed {
+        return Err(Status::ABORTED.into());
+    }
+
     info!("The hypervisor has been installed successfully!");
 
-    Ok(())
+    Ok(shared_data)
 }
 
 /// Hypervisor initialization procedure for Application Processors (APs).
@@ -4,27 +4,52 @@
 //! Credits Satoshi Tanda: https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/switch_stack.rs
 
 use {
+    core::ops::Range,
+    hypervisor::error::HypervisorError,
     log::debug,
     uefi::{prelude::BootServices, proto::loaded_image::LoadedImage},
 };
 
+/// Offset of `e_lfanew` (the file offset of the NT headers) in the DOS header.
+const DOS_E_LFANEW_OFFSET: usize = 0x3c;
+
+/// The `PE\0\0` signature at the start of the NT headers.
+const NT_SIGNATURE: u32 = 0x0000_4550;
+
+/// The optional header magic of a PE32+ image.
+const OPTIONAL_HEADER_PE32_PLUS_MAGIC: u16 = 0x20b;
+
+/// Offset of the optional header from the start of the NT headers (signature + file header).
+const OPTIONAL_HEADER_OFFSET: usize = 0x18;
+
+/// Offset of the base relocation data directory in the PE32+ optional header
+/// (`IMAGE_DIRECTORY_ENTRY_BASERELOC` is the 6th data directory, starting at offset 0x70).
+const BASE_RELOCATION_DIRECTORY_OFFSET: usize = 0x70 + 5 * 8;
+
 /// Nullifies the relocation table of the loaded UEFI image to prevent relocation.
 ///
 /// This function manipulates the loaded image's PE header to zero out the relocation table,
 /// preventing UEFI from applying patches to the hypervisor code during the transition
 /// from physical-mode to virtual-mode addressing by the operating system.
 ///
+/// Partial-failure contract: every fallible step (opening the loaded image and validating the PE
+/// headers) happens before the first write, and the writes themselves cannot fail. On error, the
+/// image is left unmodified and the returned error tells which step failed; on success, both the
+/// relocation table offset and size are zeroed. The image is never left half-zapped.
+///
 /// # Arguments
 ///
-/// * `system_table` - Reference to the UEFI System Table.
+/// * `boot_service` - Reference to the UEFI Boot Services.
 ///
 /// # Returns
 ///
-/// The result of the operation. Returns `uefi::Result::SUCCESS` on success, or an error
-pub fn zap_relocations(boot_service: &BootServices) -> uefi::Result<()> {
+/// `Ok(())` on success, `Err(HypervisorError::LoadedImageUnavailable)` if the loaded image protocol
+/// could not be opened, or `Err(HypervisorError::InvalidPeHeader)` if the PE headers are not as expected.
+pub fn zap_relocations(boot_service: &BootServices) -> Result<(), HypervisorError> {
     // Obtain the current loaded image protocol.
-    let loaded_image =
-        boot_service.open_protocol_exclusive::<LoadedImage>(boot_service.image_handle())?;
+    let loaded_image = boot_service
+        .open_protocol_exclusive::<LoadedImage>(boot_service.image_handle())
+        .map_err(|_| HypervisorError::LoadedImageUnavailable)?;
 
     // Extract the image base address and size.
     let (image_base, image_size) = loaded_image.info();
@@ -34,13 +59,52 @@ pub fn zap_relocations(boot_service: &BootServices) -> uefi::Result<()> {
     // Log the image base address range for debugging purposes.
     debug!("Image base: {:#x?}", image_range);
 
+    // Locate the base relocation data directory before modifying anything.
+    let directory =
+        base_relocation_directory(&image_range).ok_or(HypervisorError::InvalidPeHeader)?;
+    debug!("Base relocation directory: {:#x}", directory);
+
     // Unsafe block to directly modify the PE header of the loaded image.
     // This operation nullifies the relocation table to prevent UEFI from
     // applying relocations to the hypervisor code.
     unsafe {
-        *((image_base + 0x128) as *mut u32) = 0; // Zero out the relocation table offset.
-        *((image_base + 0x12c) as *mut u32) = 0; // Zero out the relocation table size.
+        *(directory as *mut u32) = 0; // Zero out the relocation table offset.
+        *((directory + 4) as *mut u32) = 0; // Zero out the relocation table size.
     }
 
     Ok(())
 }
+
+/// Finds the address of the base relocation data directory of a loaded PE32+ image.
+///
+/// # Arguments
+///
+/// * `image_range` - The address range of the loaded image.
+///
+/// # Returns
+///
+/// The address of the data directory, or `None` if the headers are invalid or out of bounds.
+fn base_relocation_directory(image_range: &Range<usize>) -> Option<usize> {
+    let read_u32 = |address: usize| {
+        (image_range.contains(&address) && image_range.contains(&(address + 3)))
+            .then(|| unsafe { (address as *const u32).read_unaligned() })
+    };
+
+    let nt_headers =
+        image_range.start + read_u32(image_range.start + DOS_E_LFANEW_OFFSET)? as usize;
+    if read_u32(nt_headers)? != NT_SIGNATURE {
+        return None;
+    }
+
+    let optional_header = nt_headers + OPTIONAL_HEADER_OFFSET;
+    if read_u32(optional_header)? as u16 != OPTIONAL_HEADER_PE32_PLUS_MAGIC {
+        return None;
+    }
+
+    // Both the offset and the size of the directory have to be within the image.
+    let directory = optional_header + BASE_RELOCATION_DIRECTORY_OFFSET;
+    read_u32(directory)?;
+    read_u32(directory + 4)?;
+
+    Some(directory)
+}
@@ -4,10 +4,9 @@
 //! Credits to Satoshi Tanda: https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/switch_stack.rs
 
 use {
-    alloc::alloc::{alloc_zeroed, handle_alloc_error},
-    core::{alloc::Layout, arch::global_asm},
+    core::arch::global_asm,
     hypervisor::{
-        intel::{capture::GuestRegisters, page::Page, shared::SharedData},
+        intel::{capture::GuestRegisters, shared::SharedData, stack::HostStack},
         vmm::start_hypervisor,
     },
     log::debug,
@@ -20,42 +19,40 @@ use {
 /// * `guest_registers` - The guest registers to use for the hypervisor.
 /// * `shared_data` - The shared data to use for the hypervisor.
 pub fn virtualize_system(guest_registers: &GuestRegisters, shared_data: &mut SharedData) -> ! {
-    // Allocate separate stack space. This is never freed.
-    let layout = Layout::array::<Page>(0x10).unwrap();
-    let stack = unsafe { alloc_zeroed(layout) };
-    if stack.is_null() {
-        handle_alloc_error(layout);
-    }
-    let stack_base = stack as u64 + layout.size() as u64 - 0x10;
-    debug!("Stack range: {:#x?}", stack as u64..stack_base);
+    // Allocate separate stack space with a guard page below it. This is never freed.
+    let host_stack = HostStack::allocate();
+    debug!("Stack range: {:#x?}", host_stack.range());
 
     unsafe {
         switch_stack(
             guest_registers,
             shared_data as *mut _ as *mut u8,
+            &host_stack,
             start_hypervisor as usize,
-            stack_base,
         )
     };
 }
 
 extern "efiapi" {
     /// Jumps to the landing code with the new stack pointer.
+    ///
+    /// The first three arguments are passed through to the landing code unchanged.
     fn switch_stack(
         guest_registers: &GuestRegisters,
         shared_data: *mut u8,
+        host_stack: &HostStack,
         landing_code: usize,
-        stack_base: u64,
     ) -> !;
 }
 
 global_asm!(
     r#"
 // The module containing the `switch_stack` function. Jumps to the landing code with the new stack pointer.
+// The new stack pointer is `HostStack::top`, the first field of the `HostStack` in r8.
 .global switch_stack
 switch_stack:
     xchg    bx, bx
-    mov     rsp, r9
-    jmp     r8
+    mov     rsp, [r8]
+    jmp     r9
 "#
 );
@@ -1,4 +1,8 @@
-use {alloc::ffi::NulError, thiserror_no_std::Error};
+use {
+    crate::intel::{consistency::GuestStateViolation, vmerror::VmEntryFailure},
+    alloc::ffi::NulError,
+    thiserror_no_std::Error,
+};
 
 #[derive(Error, Debug)]
 pub enum HypervisorError {
@@ -68,8 +72,8 @@ pub enum HypervisorError {
     #[error("Invalid EPT PML4 base address")]
     InvalidEptPml4BaseAddress,
 
-    #[error("Failed to resolve memory type for given physical address range")]
-    MemoryTypeResolutionError,
+    #[error("Failed to resolve memory type for the physical address range at {pa:#x}")]
+    MemoryTypeResolutionError { pa: u64 },
 
     #[error("Invalid CR3 base address")]
     InvalidCr3BaseAddress,
@@ -119,8 +123,8 @@ pub enum HypervisorError {
     #[error("Invalid Permission Character")]
     InvalidPermissionCharacter,
 
-    #[error("Unaligned address error")]
-    UnalignedAddressError,
+    #[error("Address {0:#x} is not page aligned")]
+    UnalignedAddressError(u64),
 
     #[error("Already split error")]
     AlreadySplitError,
@@ -164,6 +168,159 @@ pub enum HypervisorError {
     #[error("Large page remap error")]
     LargePageRemapError,
 
-    #[error("Invalid PT index")]
-    InvalidPtIndex,
+    #[error("Invalid PT index {0}")]
+    InvalidPtIndex(usize),
+
+    #[error("PT index {0} is already in use by another split")]
+    PtIndexAlreadyInUse(usize),
+
+    #[error("No free PT index")]
+    NoFreePtIndex,
+
+    #[error("No PT index is left for hiding memory, the remaining ones are kept for hooks")]
+    HidingPtBudgetExhausted,
+
+    #[error("Page is not split")]
+    PageNotSplit,
+
+    #[error("Page is presplit and cannot be merged")]
+    PageSplitPinned,
+
+    #[error("EPT self-test failed")]
+    EptSelfTestFailed,
+
+    #[error("EPT validation failed")]
+    EptValidationFailed,
+
+    #[error("Failed to open the loaded image protocol, relocations were not modified")]
+    LoadedImageUnavailable,
+
+    #[error("Loaded image has an unexpected PE header, relocations were not modified")]
+    InvalidPeHeader,
+
+    #[error("Invalid host stack guard page address")]
+    InvalidGuardPageAddress,
+
+    #[error("VM entry failed: {0}")]
+    VmEntryFailed(VmEntryFailure),
+
+    #[error("Invalid guest state: {0}")]
+    InvalidGuestState(GuestStateViolation),
+
+    #[error("Write tracker is full")]
+    WriteTrackerFull,
+
+    #[error("Pseudo-instruction is empty, longer than 15 bytes, or overlaps a registered one")]
+    InvalidPseudoInstruction,
+
+    #[error("Pseudo-instruction registry is full")]
+    PseudoInstructionsFull,
+
+    #[error("MSR is not covered by the MSR bitmap")]
+    MsrNotInBitmap,
+
+    #[error("The maximum number of audited MSRs has been reached")]
+    MsrAuditSetFull,
+
+    #[error("Remapping onto memory reserved by the hypervisor is not allowed")]
+    RemapIntoReservedRegion,
+
+    #[error("Guest and host ranges of a remap differ in length")]
+    RemapLengthMismatch,
+
+    #[error("Reserved region list is full")]
+    ReservedRegionsFull,
+
+    #[error("VMX-preemption timer is not supported")]
+    PreemptionTimerUnsupported,
+
+    #[error("VMX-preemption timer callback is already registered")]
+    PreemptionTimerAlreadyRegistered,
+
+    #[error("EPTP is not valid")]
+    InvalidEptp,
+
+    #[error("EPTP slot is out of range")]
+    InvalidEptpSlot,
+
+    #[error("EPTP slot is empty")]
+    EptpSlotEmpty,
+
+    #[error("Temporary access grant list is full")]
+    TemporaryAccessFull,
+
+    #[error("Hooked pages cannot be write-protected for copy-on-write")]
+    CowPageHooked,
+
+    #[error("Monitor trap flag is not supported")]
+    MonitorTrapFlagUnsupported,
+
+    #[error("Vectors 0 to 31 are reserved for exceptions and cannot be injected as interrupts")]
+    InvalidInterruptVector,
+
+    #[error("TSC scaling is not supported")]
+    TscScalingUnsupported,
+
+    #[error("TSC scaling ratio must be non-zero and below 65536")]
+    InvalidTscScale,
+
+    #[error("Agent EPT view has not been created")]
+    AgentViewMissing,
+
+    #[error("Agent EPT view has already been created")]
+    AgentViewAlreadyCreated,
+
+    #[error("Guest is already registered")]
+    GuestAlreadyRegistered,
+
+    #[error("Guest registry is full")]
+    GuestRegistryFull,
+
+    #[error("Guest is not registered")]
+    GuestNotFound,
+
+    #[error("No EPTs were configured for the default guest")]
+    DefaultGuestMissing,
+
+    #[error("Guest physical address is not mapped")]
+    GuestPhysicalAddressNotMapped,
+
+    #[error("Guest virtual address is not mapped")]
+    GuestVirtualAddressNotMapped,
+
+    #[error("Host virtual address is not mapped")]
+    HostVirtualAddressNotMapped,
+
+    #[error("Host physical address is not mapped in the host address space")]
+    HostPhysicalAddressNotMapped,
+
+    #[error("Guest PE image is malformed")]
+    InvalidGuestPeImage,
+
+    #[error("Export not found")]
+    ExportNotFound,
+
+    #[error("Export is forwarded to another module")]
+    ExportForwardedToOtherModule,
+
+    #[error("Hook manager is full")]
+    HookManagerFull,
+
+    #[error("Page is already hooked")]
+    HookAlreadyInstalled,
+
+    #[error("Page is not hooked")]
+    HookNotFound,
+
+    #[error("Operation is not supported for 2MB hooks")]
+    LargeHookUnsupported,
+
+    #[error("No shared page is registered by the running guest")]
+    SharedPageNotRegistered,
+
+    #[error("Shared page was remapped and has been unregistered")]
+    SharedPageRemapped,
+
+    #[error("Access is outside the shared page")]
+    SharedPageOutOfBounds,
 }
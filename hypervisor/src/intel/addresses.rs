@@ -5,10 +5,26 @@
 //! as well as methods for extracting page frame numbers (PFNs) and other address-related information.
 
 use {
-    core::ops::{Deref, DerefMut},
-    x86::bits64::paging::{PAddr, BASE_PAGE_SHIFT},
+    crate::{
+        error::HypervisorError,
+        intel::support::{cr3, cr4},
+    },
+    core::{
+        ops::{Deref, DerefMut},
+        sync::atomic::{AtomicBool, Ordering},
+    },
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE},
+        controlregs::Cr4,
+    },
 };
 
+/// Whether host virtual addresses are known to equal host physical addresses.
+///
+/// The firmware and the host page tables built by `PageTables::build_identity` both identity map
+/// memory, so translation is skipped until this is cleared with `PhysicalAddress::set_host_identity_mapped`.
+static HOST_IDENTITY_MAPPED: AtomicBool = AtomicBool::new(true);
+
 /// A representation of physical addresses.
 ///
 /// Provides utility methods to work with physical addresses,
@@ -36,8 +52,274 @@ impl PhysicalAddress {
     pub fn pa(&self) -> u64 {
         self.0.as_u64()
     }
+
+    /// Translates a host virtual address to the host physical address it is mapped to.
+    ///
+    /// While host paging is known to be identity mapped, the address is returned as is. Otherwise
+    /// the page tables referenced by the current CR3 are walked, honoring large pages. The paging
+    /// structures themselves are read directly, so they must still be identity mapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `va` - The host virtual address to translate.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address, or `Err(HypervisorError::HostVirtualAddressNotMapped)` if the address
+    /// is not mapped.
+    pub fn from_host_va(va: u64) -> Result<u64, HypervisorError> {
+        if HOST_IDENTITY_MAPPED.load(Ordering::Relaxed) {
+            return Ok(va);
+        }
+
+        let levels = match cr4().contains(Cr4::CR4_ENABLE_LA57) {
+            true => 5,
+            false => 4,
+        };
+
+        // The host paging structures are allocated from identity-mapped memory, so they are read at
+        // their physical addresses.
+        Self::walk_64bit(cr3() & ADDRESS_MASK, va, levels, &Some)
+            .map(|pa| pa.pa())
+            .ok_or(HypervisorError::HostVirtualAddressNotMapped)
+    }
+
+    /// Returns the host virtual address through which the hypervisor accesses a host physical address,
+    /// e.g. guest memory translated through the EPT.
+    ///
+    /// Only the identity map gives the hypervisor access to arbitrary host physical memory, so this fails
+    /// once host paging is no longer known to be identity mapped, instead of dereferencing the physical
+    /// address as a pointer into unrelated memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `pa` - The host physical address to access.
+    ///
+    /// # Returns
+    ///
+    /// The host virtual address, or `Err(HypervisorError::HostPhysicalAddressNotMapped)` if the hypervisor
+    /// has no mapping of it.
+    pub fn to_host_va(pa: u64) -> Result<u64, HypervisorError> {
+        match HOST_IDENTITY_MAPPED.load(Ordering::Relaxed) {
+            true => Ok(pa),
+            false => Err(HypervisorError::HostPhysicalAddressNotMapped),
+        }
+    }
+
+    /// Sets whether host virtual addresses are known to equal host physical addresses.
+    ///
+    /// Must be cleared before switching to host page tables that do not identity map memory, so
+    /// `from_host_va` walks the page tables instead of returning addresses as is.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity_mapped` - Whether host paging is identity mapped.
+    pub fn set_host_identity_mapped(identity_mapped: bool) {
+        HOST_IDENTITY_MAPPED.store(identity_mapped, Ordering::Relaxed);
+    }
+
+    /// Translates a guest virtual address to a guest physical address.
+    ///
+    /// Walks the guest's paging structures starting at `guest_cr3` in the given paging mode, honoring
+    /// large pages. The paging structures live in guest physical memory, so each of them is read
+    /// through the host virtual address `table_va` returns for its guest physical address, e.g. after
+    /// translating it through the guest's EPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `paging_mode` - The guest's paging mode, e.g. from `Vm::guest_paging_mode`.
+    /// * `guest_cr3` - The guest's CR3 value.
+    /// * `guest_va` - The guest virtual address to translate.
+    /// * `table_va` - Returns the host virtual address of the page containing a guest paging structure,
+    ///   given its page-aligned guest physical address, or `None` if it is not mapped.
+    ///
+    /// # Returns
+    ///
+    /// Returns the guest physical address, or `None` if the address or one of the paging structures
+    /// is not mapped or, with 4-level or 5-level paging, the address is not canonical.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.3 32-BIT PAGING, 4.4 PAE PAGING, 4.5 4-LEVEL PAGING AND 5-LEVEL PAGING
+    pub fn from_guest_va(
+        paging_mode: PagingMode,
+        guest_cr3: u64,
+        guest_va: u64,
+        table_va: impl Fn(u64) -> Option<u64>,
+    ) -> Option<Self> {
+        match paging_mode {
+            PagingMode::Disabled => Some(Self::from_pa(guest_va)),
+            PagingMode::Bits32 { pse } => Self::walk_32bit(guest_cr3, guest_va, pse, &table_va),
+            PagingMode::Pae => Self::walk_pae(guest_cr3, guest_va, &table_va),
+            PagingMode::Level4 | PagingMode::Level5 => {
+                let levels = match paging_mode {
+                    PagingMode::Level5 => 5,
+                    _ => 4,
+                };
+
+                if !is_canonical(guest_va, levels) {
+                    return None;
+                }
+
+                Self::walk_64bit(guest_cr3 & ADDRESS_MASK, guest_va, levels, &table_va)
+            }
+        }
+    }
+
+    /// Walks 64-bit paging structures with 8-byte entries and 9 address bits per level.
+    ///
+    /// # Arguments
+    ///
+    /// * `table_pa` - The physical address of the top-level table.
+    /// * `guest_va` - The guest virtual address to translate.
+    /// * `levels` - The number of levels to walk: 4 for 4-level paging, 5 for 5-level paging,
+    ///   2 for the page directory and page table of PAE paging.
+    /// * `table_va` - Returns the host virtual address of a paging structure, as in `from_guest_va`.
+    fn walk_64bit(
+        mut table_pa: u64,
+        guest_va: u64,
+        levels: u64,
+        table_va: &impl Fn(u64) -> Option<u64>,
+    ) -> Option<Self> {
+        // Level 0 is the PT, level 1 the PD, and level 2 the PDPT.
+        for level in (0..levels).rev() {
+            let shift = BASE_PAGE_SHIFT as u64 + 9 * level;
+            let index = (guest_va >> shift) & 0x1ff;
+            let entry = read_entry::<u64>(table_pa, index, table_va)?;
+
+            if entry & PRESENT == 0 {
+                return None;
+            }
+
+            // A PDPTE or PDE with the page size bit set maps a 1GB or 2MB page.
+            if level == 0 || ((level == 1 || level == 2) && entry & LARGE != 0) {
+                let page_mask = (1u64 << shift) - 1;
+                return Some(Self::from_pa(
+                    (entry & ADDRESS_MASK & !page_mask) | (guest_va & page_mask),
+                ));
+            }
+
+            table_pa = entry & ADDRESS_MASK;
+        }
+
+        None
+    }
+
+    /// Walks PAE paging structures: 4 PDPTEs referenced by CR3, followed by a 2-level walk.
+    fn walk_pae(
+        guest_cr3: u64,
+        guest_va: u64,
+        table_va: &impl Fn(u64) -> Option<u64>,
+    ) -> Option<Self> {
+        const PDPT_ADDRESS_MASK: u64 = 0xffff_ffe0;
+
+        let pdpt_pa = guest_cr3 & PDPT_ADDRESS_MASK;
+        let index = (guest_va >> 30) & 0b11;
+        let pdpte = read_entry::<u64>(pdpt_pa, index, table_va)?;
+
+        if pdpte & PRESENT == 0 {
+            return None;
+        }
+
+        Self::walk_64bit(pdpte & ADDRESS_MASK, guest_va & 0x3fff_ffff, 2, table_va)
+    }
+
+    /// Walks 32-bit paging structures with 4-byte entries and 10 address bits per level.
+    fn walk_32bit(
+        guest_cr3: u64,
+        guest_va: u64,
+        pse: bool,
+        table_va: &impl Fn(u64) -> Option<u64>,
+    ) -> Option<Self> {
+        const TABLE_ADDRESS_MASK: u64 = 0xffff_f000;
+
+        let guest_va = guest_va & 0xffff_ffff;
+        let pd_pa = guest_cr3 & TABLE_ADDRESS_MASK;
+        let pde = read_entry::<u32>(pd_pa, guest_va >> 22, table_va)? as u64;
+
+        if pde & PRESENT == 0 {
+            return None;
+        }
+
+        // With CR4.PSE, a PDE with the page size bit set maps a 4MB page, whose address bits 39:32 are in PDE bits 20:13.
+        if pse && pde & LARGE != 0 {
+            let page_pa = (pde & 0xffc0_0000) | (((pde >> 13) & 0xff) << 32);
+            return Some(Self::from_pa(page_pa | (guest_va & 0x3f_ffff)));
+        }
+
+        let pt_pa = pde & TABLE_ADDRESS_MASK;
+        let pte = read_entry::<u32>(pt_pa, (guest_va >> 12) & 0x3ff, table_va)? as u64;
+
+        if pte & PRESENT == 0 {
+            return None;
+        }
+
+        Some(Self::from_pa(
+            (pte & TABLE_ADDRESS_MASK) | (guest_va & 0xfff),
+        ))
+    }
+}
+
+/// The paging mode of the guest, which determines how guest virtual addresses are translated.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.1.1 Four Paging Modes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// CR0.PG is clear: linear addresses are physical addresses.
+    Disabled,
+
+    /// 32-bit paging (CR4.PAE clear), optionally with 4MB pages (CR4.PSE).
+    Bits32 {
+        /// Whether 4MB pages are enabled.
+        pse: bool,
+    },
+
+    /// PAE paging (CR4.PAE set, IA32_EFER.LMA clear).
+    Pae,
+
+    /// 4-level paging (IA32_EFER.LMA set, CR4.LA57 clear).
+    Level4,
+
+    /// 5-level paging (IA32_EFER.LMA and CR4.LA57 set).
+    Level5,
+}
+
+/// Checks whether a virtual address is canonical for 4-level or 5-level paging, i.e. whether the
+/// bits above the highest translated bit (47 or 56) are copies of it.
+fn is_canonical(va: u64, levels: u64) -> bool {
+    let unused_bits = 64 - (BASE_PAGE_SHIFT as u64 + 9 * levels);
+    (((va << unused_bits) as i64) >> unused_bits) as u64 == va
 }
 
+/// Reads an entry of a guest paging structure.
+///
+/// # Arguments
+///
+/// * `table_pa` - The guest physical address of the paging structure. Paging structures never cross a page.
+/// * `index` - The index of the entry.
+/// * `table_va` - Returns the host virtual address of a paging structure, as in `PhysicalAddress::from_guest_va`.
+///
+/// # Returns
+///
+/// The entry, or `None` if the paging structure is not mapped.
+fn read_entry<T: Copy>(
+    table_pa: u64,
+    index: u64,
+    table_va: &impl Fn(u64) -> Option<u64>,
+) -> Option<T> {
+    let page_offset = table_pa & (BASE_PAGE_SIZE as u64 - 1);
+    let table = table_va(table_pa - page_offset)? + page_offset;
+
+    Some(unsafe { (table as *const T).add(index as usize).read_volatile() })
+}
+
+/// The present bit of a paging-structure entry.
+const PRESENT: u64 = 1 << 0;
+
+/// The page size bit of a paging-structure entry.
+const LARGE: u64 = 1 << 7;
+
+/// The physical address bits of a 64-bit paging-structure entry.
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
 impl const Deref for PhysicalAddress {
     type Target = PAddr;
 
@@ -53,3 +335,165 @@ impl const DerefMut for PhysicalAddress {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::intel::{page::Page, vm::box_zeroed},
+        alloc::boxed::Box,
+    };
+
+    /// The guest physical address the 4KB test page maps to. It is never accessed.
+    const PAGE_PA: u64 = 0x1234_5000;
+
+    /// The guest physical address the 1GB test page maps to. It is never accessed.
+    const HUGE_PAGE_PA: u64 = 0x4000_0000;
+
+    // PML5 index 0x1f5 sets bit 56, so bits 63:57 must be set for the address to be canonical,
+    // and bits 55:48 are not all set, so the address is not canonical with 4-level paging.
+    const PAGE_VA: u64 = 0xfff5_51d7_05db_7123;
+    const HUGE_PAGE_VA: u64 = 0xfff5_51d7_4abc_d123;
+
+    /// The guest physical address of the first paging structure. The structures are placed at
+    /// consecutive pages from here, so they can only be read through `GuestTables::table_va`.
+    const TABLES_GPA: u64 = 0x10_0000;
+
+    /// Synthetic 5-level guest paging structures, mapping `PAGE_VA` to `PAGE_PA` with a 4KB page and
+    /// `HUGE_PAGE_VA` to `HUGE_PAGE_PA` with a 1GB page.
+    struct GuestTables {
+        /// The PML5, PML4, PDPT, PD, and PT, in this order.
+        tables: [Box<Page>; 5],
+    }
+
+    impl GuestTables {
+        fn new() -> Self {
+            let guest_tables = Self {
+                tables: [(); 5].map(|_| unsafe { box_zeroed::<Page>() }),
+            };
+            let [pml5, pml4, pdpt, pd, pt] = [0, 1, 2, 3, 4].map(Self::gpa);
+
+            guest_tables.set(pml5, index(PAGE_VA, 4), pml4 | PRESENT);
+            guest_tables.set(pml4, index(PAGE_VA, 3), pdpt | PRESENT);
+            guest_tables.set(pdpt, index(PAGE_VA, 2), pd | PRESENT);
+            guest_tables.set(pd, index(PAGE_VA, 1), pt | PRESENT);
+            guest_tables.set(pt, index(PAGE_VA, 0), PAGE_PA | PRESENT);
+            guest_tables.set(pdpt, index(HUGE_PAGE_VA, 2), HUGE_PAGE_PA | LARGE | PRESENT);
+
+            guest_tables
+        }
+
+        /// Returns the guest physical address of the paging structure at `level`, counted from the PML5.
+        fn gpa(level: usize) -> u64 {
+            TABLES_GPA + (level * BASE_PAGE_SIZE) as u64
+        }
+
+        /// Returns the host virtual address of the paging structure at a guest physical address.
+        fn table_va(&self, gpa: u64) -> Option<u64> {
+            let level = (gpa.checked_sub(TABLES_GPA)? / BASE_PAGE_SIZE as u64) as usize;
+            let table = self.tables.get(level)?;
+            Some(&**table as *const Page as u64)
+        }
+
+        fn set(&self, table_gpa: u64, index: usize, entry: u64) {
+            let table = self.table_va(table_gpa).unwrap();
+            unsafe { (table as *mut u64).add(index).write_volatile(entry) };
+        }
+
+        /// Translates a guest virtual address with the PML5 or, with 4-level paging, the PML4 as root.
+        fn walk(&self, mode: PagingMode, va: u64) -> Option<u64> {
+            let cr3 = match mode {
+                PagingMode::Level5 => Self::gpa(0),
+                _ => Self::gpa(1),
+            };
+            PhysicalAddress::from_guest_va(mode, cr3, va, |gpa| self.table_va(gpa))
+                .map(|pa| pa.pa())
+        }
+    }
+
+    /// Returns the index of the entry translating `va` in the paging structure at `level`, with 0 for the PT.
+    fn index(va: u64, level: u64) -> usize {
+        ((va >> (BASE_PAGE_SHIFT as u64 + 9 * level)) & 0x1ff) as usize
+    }
+
+    #[test]
+    fn level5_4kb_page() {
+        let tables = GuestTables::new();
+        assert_eq!(
+            tables.walk(PagingMode::Level5, PAGE_VA),
+            Some(PAGE_PA | 0x123)
+        );
+    }
+
+    #[test]
+    fn level5_1gb_page() {
+        let tables = GuestTables::new();
+        assert_eq!(
+            tables.walk(PagingMode::Level5, HUGE_PAGE_VA),
+            Some(HUGE_PAGE_PA | 0x0abc_d123)
+        );
+    }
+
+    #[test]
+    fn level5_rejects_unmapped_address() {
+        let tables = GuestTables::new();
+        assert_eq!(
+            tables.walk(PagingMode::Level5, PAGE_VA + BASE_PAGE_SIZE as u64),
+            None
+        );
+    }
+
+    #[test]
+    fn level4_walks_lower_bits() {
+        let tables = GuestTables::new();
+        assert_eq!(
+            tables.walk(PagingMode::Level4, PAGE_VA & 0x0000_7fff_ffff_ffff),
+            Some(PAGE_PA | 0x123)
+        );
+    }
+
+    #[test]
+    fn rejects_non_canonical_addresses() {
+        let tables = GuestTables::new();
+
+        // Bit 56 is set, but bit 63 is not.
+        assert_eq!(tables.walk(PagingMode::Level5, PAGE_VA & !(1 << 63)), None);
+
+        // Canonical with 5-level paging, but bits 63:48 are not copies of bit 47.
+        assert_eq!(tables.walk(PagingMode::Level4, PAGE_VA), None);
+
+        // The first address above the lower half of each paging mode.
+        assert_eq!(tables.walk(PagingMode::Level4, 1 << 47), None);
+        assert_eq!(tables.walk(PagingMode::Level5, 1 << 56), None);
+    }
+
+    #[test]
+    fn canonical_addresses() {
+        assert!(is_canonical(0x0000_7fff_ffff_ffff, 4));
+        assert!(is_canonical(0xffff_8000_0000_0000, 4));
+        assert!(!is_canonical(0x0000_8000_0000_0000, 4));
+        assert!(!is_canonical(0xfff5_51d7_05db_7123, 4));
+
+        assert!(is_canonical(0x00ff_ffff_ffff_ffff, 5));
+        assert!(is_canonical(0xff00_0000_0000_0000, 5));
+        assert!(!is_canonical(0x0100_0000_0000_0000, 5));
+        assert!(!is_canonical(0x7ff5_51d7_05db_7123, 5));
+    }
+
+    #[test]
+    fn unmapped_paging_structure() {
+        let tables = GuestTables::new();
+        let walk = PhysicalAddress::from_guest_va(
+            PagingMode::Level5,
+            GuestTables::gpa(0),
+            PAGE_VA,
+            |gpa| {
+                // The PD is not mapped in guest physical memory.
+                (gpa != GuestTables::gpa(3))
+                    .then(|| tables.table_va(gpa))
+                    .flatten()
+            },
+        );
+        assert!(walk.is_none());
+    }
+}
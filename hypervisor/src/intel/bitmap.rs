@@ -0,0 +1,198 @@
+//! Manages the MSR bitmap, which controls which `RDMSR` and `WRMSR` instructions cause VM exits, and
+//! the I/O bitmaps, which do the same for `IN`, `OUT`, `INS`, and `OUTS`.
+//!
+//! A clear bit lets the guest access the MSR directly, a set bit causes a VM exit. MSRs outside the
+//! two ranges covered by the bitmap always cause a VM exit.
+//!
+//! The I/O bitmaps cover all 65536 ports, so every port can be passed through or trapped. An access
+//! of several bytes causes a VM exit if the bit of any port it touches is set, and an access that
+//! wraps around past port 0xFFFF always causes a VM exit.
+//!
+//! The local APIC is not virtualized (no virtual-APIC page, APIC-register virtualization, or
+//! virtual-interrupt delivery), so the guest owns the physical local APIC. The x2APIC MSRs are
+//! explicitly passed through so that interrupt-heavy guests do not exit on every APIC access. In
+//! xAPIC mode, the APIC MMIO page is identity mapped with full permissions in the EPT, so it does not
+//! cause EPT violations either.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.9 MSR-Bitmap Address
+
+use {
+    crate::{error::HypervisorError, intel::vmexit::msr::MsrAccessType},
+    core::ops::RangeInclusive,
+    x86::msr::{IA32_FEATURE_CONTROL, IA32_MISC_ENABLE},
+};
+
+/// The MSRs of the local APIC in x2APIC mode.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.12.1.2 x2APIC Register Address Space
+pub const X2APIC_MSR_RANGE: RangeInclusive<u32> = 0x800..=0x8ff;
+
+/// The low MSR range covered by the bitmap.
+const LOW_MSR_RANGE: RangeInclusive<u32> = 0x0000_0000..=0x0000_1fff;
+
+/// The high MSR range covered by the bitmap.
+const HIGH_MSR_RANGE: RangeInclusive<u32> = 0xc000_0000..=0xc000_1fff;
+
+/// The number of ports covered by each of the two I/O bitmaps.
+const IO_BITMAP_PORTS: usize = 0x8000;
+
+/// The 4KB MSR bitmap referenced by the MSR-bitmap address VMCS field.
+#[repr(C, align(4096))]
+pub struct MsrBitmap {
+    /// Read bitmap for MSRs 0x00000000 to 0x00001FFF.
+    read_low_msrs: [u8; 0x400],
+    /// Read bitmap for MSRs 0xC0000000 to 0xC0001FFF.
+    read_high_msrs: [u8; 0x400],
+    /// Write bitmap for MSRs 0x00000000 to 0x00001FFF.
+    write_low_msrs: [u8; 0x400],
+    /// Write bitmap for MSRs 0xC0000000 to 0xC0001FFF.
+    write_high_msrs: [u8; 0x400],
+}
+
+impl MsrBitmap {
+    /// Enables or disables VM exits on accesses to an MSR.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR address.
+    /// * `access` - Whether to change the read or the write intercept.
+    /// * `intercept` - `true` to cause VM exits on the access, `false` to pass it through.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::MsrNotInBitmap)` if the MSR is not covered by the
+    /// bitmap. Accesses to such MSRs always cause VM exits.
+    pub fn set_intercept(
+        &mut self,
+        msr: u32,
+        access: MsrAccessType,
+        intercept: bool,
+    ) -> Result<(), HypervisorError> {
+        let (bitmap, offset) = match (access, msr) {
+            (MsrAccessType::Read, msr) if LOW_MSR_RANGE.contains(&msr) => {
+                (&mut self.read_low_msrs, msr - LOW_MSR_RANGE.start())
+            }
+            (MsrAccessType::Read, msr) if HIGH_MSR_RANGE.contains(&msr) => {
+                (&mut self.read_high_msrs, msr - HIGH_MSR_RANGE.start())
+            }
+            (MsrAccessType::Write, msr) if LOW_MSR_RANGE.contains(&msr) => {
+                (&mut self.write_low_msrs, msr - LOW_MSR_RANGE.start())
+            }
+            (MsrAccessType::Write, msr) if HIGH_MSR_RANGE.contains(&msr) => {
+                (&mut self.write_high_msrs, msr - HIGH_MSR_RANGE.start())
+            }
+            _ => return Err(HypervisorError::MsrNotInBitmap),
+        };
+
+        let byte = &mut bitmap[offset as usize / 8];
+        let mask = 1 << (offset % 8);
+
+        if intercept {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+
+        Ok(())
+    }
+
+    /// Causes VM exits on reads and writes of IA32_FEATURE_CONTROL, so the guest sees its shadow value.
+    pub fn intercept_feature_control(&mut self) {
+        // IA32_FEATURE_CONTROL is in the low range, so this cannot fail.
+        let _ = self.set_intercept(IA32_FEATURE_CONTROL, MsrAccessType::Read, true);
+        let _ = self.set_intercept(IA32_FEATURE_CONTROL, MsrAccessType::Write, true);
+    }
+
+    /// Causes VM exits on writes to IA32_MISC_ENABLE, so the `CpuidCache` can be invalidated when the
+    /// guest limits the maximum basic `CPUID` leaf.
+    pub fn intercept_misc_enable(&mut self) {
+        // IA32_MISC_ENABLE is in the low range, so this cannot fail.
+        let _ = self.set_intercept(IA32_MISC_ENABLE, MsrAccessType::Write, true);
+    }
+
+    /// Causes VM exits on writes to the MSRs, e.g. the MSRs of the `MsrAudit`.
+    ///
+    /// MSRs outside of the bitmap are skipped, since writes to them always cause VM exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `msrs` - The MSR addresses.
+    pub fn intercept_writes(&mut self, msrs: &[u32]) {
+        for &msr in msrs {
+            let _ = self.set_intercept(msr, MsrAccessType::Write, true);
+        }
+    }
+
+    /// Lets the guest read and write the x2APIC MSRs without causing VM exits.
+    pub fn passthrough_x2apic_msrs(&mut self) {
+        for msr in X2APIC_MSR_RANGE {
+            // The x2APIC MSRs are in the low range, so this cannot fail.
+            let _ = self.set_intercept(msr, MsrAccessType::Read, false);
+            let _ = self.set_intercept(msr, MsrAccessType::Write, false);
+        }
+    }
+}
+
+/// The I/O bitmaps A and B referenced by the I/O-bitmap address VMCS fields.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.4 I/O-Bitmap Addresses
+#[repr(C, align(4096))]
+pub struct IoBitmap {
+    /// Bitmap A for ports 0x0000 to 0x7FFF.
+    a: [u8; IO_BITMAP_PORTS / 8],
+    /// Bitmap B for ports 0x8000 to 0xFFFF.
+    b: [u8; IO_BITMAP_PORTS / 8],
+}
+
+impl IoBitmap {
+    /// Enables or disables VM exits on accesses to an I/O port.
+    ///
+    /// Each port has its own bit, so trapping a port also traps 16-bit and 32-bit accesses that start
+    /// at a lower port and include it, e.g. a 32-bit `IN` from port 0x3FE traps port 0x3FF.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port.
+    /// * `intercept` - `true` to cause VM exits on accesses to the port, `false` to pass them through.
+    pub fn set_intercept(&mut self, port: u16, intercept: bool) {
+        let port = usize::from(port);
+        let (bitmap, offset) = match port < IO_BITMAP_PORTS {
+            true => (&mut self.a, port),
+            false => (&mut self.b, port - IO_BITMAP_PORTS),
+        };
+
+        let byte = &mut bitmap[offset / 8];
+        let mask = 1 << (offset % 8);
+
+        if intercept {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+
+    /// Returns whether accesses to an I/O port cause VM exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port.
+    pub fn is_intercepted(&self, port: u16) -> bool {
+        let port = usize::from(port);
+        let (bitmap, offset) = match port < IO_BITMAP_PORTS {
+            true => (&self.a, port),
+            false => (&self.b, port - IO_BITMAP_PORTS),
+        };
+
+        bitmap[offset / 8] & (1 << (offset % 8)) != 0
+    }
+
+    /// Returns the address of I/O bitmap A, written to the VMCS.
+    pub fn a_address(&self) -> u64 {
+        self.a.as_ptr() as u64
+    }
+
+    /// Returns the address of I/O bitmap B, written to the VMCS.
+    pub fn b_address(&self) -> u64 {
+        self.b.as_ptr() as u64
+    }
+}
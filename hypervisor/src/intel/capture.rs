@@ -27,6 +27,21 @@ extern "efiapi" {
     /// This function involves inline assembly and direct manipulation of register values, requiring
     /// careful consideration of calling context to ensure system stability.
     pub fn capture_registers(registers: &mut GuestRegisters) -> bool;
+
+    /// Loads the general purpose registers, RFLAGS, and RSP, and jumps to RIP.
+    ///
+    /// The counterpart of `capture_registers`: restoring the registers it captured returns from it
+    /// again, with the return value taken from `registers.rax`.
+    ///
+    /// # Arguments
+    ///
+    /// - `registers`: The register state to continue with.
+    ///
+    /// # Safety
+    ///
+    /// The stack at `registers.rsp` must be writable below the stack pointer, since RIP and RFLAGS are
+    /// passed through it, and the code at `registers.rip` must expect the restored state.
+    pub fn restore_registers(registers: &GuestRegisters) -> !;
 }
 
 /// Represents the state of guest general-purpose registers along with RFLAGS, RSP, and RIP.
@@ -63,6 +78,128 @@ pub struct GuestRegisters {
     pub rip: u64,
 }
 
+/// Identifies a single guest register that can be accessed through `GuestRegisters` or the VMCS.
+///
+/// The general-purpose registers are declared in their x86 encoding order (RAX = 0 ... R15 = 15),
+/// followed by RIP and RFLAGS, which have no general-purpose encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    Rip,
+    Rflags,
+}
+
+impl Register {
+    /// The general-purpose registers in encoding order.
+    const GENERAL_PURPOSE: [Self; 16] = [
+        Self::Rax,
+        Self::Rcx,
+        Self::Rdx,
+        Self::Rbx,
+        Self::Rsp,
+        Self::Rbp,
+        Self::Rsi,
+        Self::Rdi,
+        Self::R8,
+        Self::R9,
+        Self::R10,
+        Self::R11,
+        Self::R12,
+        Self::R13,
+        Self::R14,
+        Self::R15,
+    ];
+
+    /// Returns the general-purpose register with the given encoding, as reported in exit qualifications.
+    ///
+    /// # Arguments
+    ///
+    /// - `index`: The register encoding (0 = RAX ... 15 = R15).
+    pub fn from_gpr_index(index: u64) -> Option<Self> {
+        Self::GENERAL_PURPOSE.get(index as usize).copied()
+    }
+}
+
+impl GuestRegisters {
+    /// Returns the saved value of the given register.
+    ///
+    /// For RSP, RIP, and RFLAGS this is the copy taken from the VMCS on the last VM-exit.
+    ///
+    /// # Arguments
+    ///
+    /// - `register`: The register to read.
+    pub fn get(&self, register: Register) -> u64 {
+        match register {
+            Register::Rax => self.rax,
+            Register::Rcx => self.rcx,
+            Register::Rdx => self.rdx,
+            Register::Rbx => self.rbx,
+            Register::Rsp => self.rsp,
+            Register::Rbp => self.rbp,
+            Register::Rsi => self.rsi,
+            Register::Rdi => self.rdi,
+            Register::R8 => self.r8,
+            Register::R9 => self.r9,
+            Register::R10 => self.r10,
+            Register::R11 => self.r11,
+            Register::R12 => self.r12,
+            Register::R13 => self.r13,
+            Register::R14 => self.r14,
+            Register::R15 => self.r15,
+            Register::Rip => self.rip,
+            Register::Rflags => self.rflags,
+        }
+    }
+
+    /// Updates the saved value of the given register.
+    ///
+    /// This only changes the save area. RSP, RIP, and RFLAGS are loaded from the VMCS on VM-entry,
+    /// so those must also be written to the VMCS to take effect (see `Vm::set_guest_reg`).
+    ///
+    /// # Arguments
+    ///
+    /// - `register`: The register to write.
+    /// - `value`: The new value of the register.
+    pub fn set(&mut self, register: Register, value: u64) {
+        let slot = match register {
+            Register::Rax => &mut self.rax,
+            Register::Rcx => &mut self.rcx,
+            Register::Rdx => &mut self.rdx,
+            Register::Rbx => &mut self.rbx,
+            Register::Rsp => &mut self.rsp,
+            Register::Rbp => &mut self.rbp,
+            Register::Rsi => &mut self.rsi,
+            Register::Rdi => &mut self.rdi,
+            Register::R8 => &mut self.r8,
+            Register::R9 => &mut self.r9,
+            Register::R10 => &mut self.r10,
+            Register::R11 => &mut self.r11,
+            Register::R12 => &mut self.r12,
+            Register::R13 => &mut self.r13,
+            Register::R14 => &mut self.r14,
+            Register::R15 => &mut self.r15,
+            Register::Rip => &mut self.rip,
+            Register::Rflags => &mut self.rflags,
+        };
+        *slot = value;
+    }
+}
+
 global_asm!(
     r#"
 // The module containing the `capture_registers` function.
@@ -124,5 +261,36 @@ capture_registers:
     xor rax, rax
 
     ret
+
+// Loads general purpose registers, RFLAGS, and RSP, and jumps to RIP.
+//
+// extern "efiapi" fn restore_registers(registers: &GuestRegisters) -> !
+.global restore_registers
+restore_registers:
+    // Switch to the restored stack and pass RIP and RFLAGS through it, since they are loaded last.
+    mov     rsp, [rcx + registers_rsp]
+    push    qword ptr [rcx + registers_rip]
+    push    qword ptr [rcx + registers_rflags]
+
+    // Restore general purpose registers, RCX last since it points to `registers`.
+    mov     rax, [rcx + registers_rax]
+    mov     rbx, [rcx + registers_rbx]
+    mov     rdx, [rcx + registers_rdx]
+    mov     rsi, [rcx + registers_rsi]
+    mov     rdi, [rcx + registers_rdi]
+    mov     rbp, [rcx + registers_rbp]
+    mov     r8,  [rcx + registers_r8]
+    mov     r9,  [rcx + registers_r9]
+    mov     r10, [rcx + registers_r10]
+    mov     r11, [rcx + registers_r11]
+    mov     r12, [rcx + registers_r12]
+    mov     r13, [rcx + registers_r13]
+    mov     r14, [rcx + registers_r14]
+    mov     r15, [rcx + registers_r15]
+    mov     rcx, [rcx + registers_rcx]
+
+    // Restore RFLAGS and RIP.
+    popfq
+    ret
 "#
 );
@@ -0,0 +1,428 @@
+//! Checks the guest-state area of the current VMCS against the VM-entry consistency rules.
+//!
+//! A VM entry that violates one of these rules fails with the opaque basic exit reason "VM-entry
+//! failure due to invalid guest state", without saying which rule was violated. `check_guest_state`
+//! evaluates the rules that are most commonly broken during bring-up in software, and reports the
+//! first violated one. Passing the checks does not guarantee that the VM entry succeeds, since not
+//! every documented rule is covered.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1 Checks on the Guest State Area
+
+use {
+    crate::intel::{
+        segmentation::Segment,
+        state::{
+            GuestActivityState, BLOCKING_BY_MOV_SS, BLOCKING_BY_STI, DEBUGCTL_BTF,
+            PENDING_DEBUG_RESERVED, PENDING_DEBUG_SINGLE_STEP,
+        },
+        support::{rdmsr, vmread},
+        vm::Vm,
+    },
+    x86::{
+        controlregs::{Cr0, Cr4},
+        msr::{IA32_VMX_CR0_FIXED0, IA32_VMX_CR0_FIXED1, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1},
+        vmx::vmcs::{
+            self,
+            control::{EntryControls, SecondaryControls},
+        },
+    },
+};
+
+/// A violated VM-entry guest-state consistency rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestStateViolation {
+    /// CR0 sets a bit that is fixed to 0 or clears a bit that is fixed to 1 in VMX operation.
+    Cr0FixedBits(u64),
+
+    /// CR0.PG is set while CR0.PE is clear.
+    Cr0PagingWithoutProtection(u64),
+
+    /// CR4 sets a bit that is fixed to 0 or clears a bit that is fixed to 1 in VMX operation.
+    Cr4FixedBits(u64),
+
+    /// The "IA-32e mode guest" VM-entry control is set, but CR0.PG or CR4.PAE is clear.
+    Ia32eModeWithoutPaging,
+
+    /// CR4.PCIDE is set outside of IA-32e mode.
+    PcidOutsideIa32eMode,
+
+    /// IA32_EFER sets reserved bits.
+    EferReservedBits(u64),
+
+    /// IA32_EFER.LMA does not match the "IA-32e mode guest" VM-entry control.
+    EferLmaMismatch(u64),
+
+    /// IA32_EFER.LME does not match IA32_EFER.LMA while CR0.PG is set.
+    EferLmeMismatch(u64),
+
+    /// RFLAGS sets a reserved bit or clears bit 1.
+    RflagsReservedBits(u64),
+
+    /// RFLAGS.VM is set in IA-32e mode or while CR0.PE is clear.
+    InvalidVirtual8086Mode,
+
+    /// The state of a segment register violates a rule, described by the message.
+    InvalidSegment(Segment, &'static str),
+
+    /// The activity state is not one of the defined states.
+    InvalidActivityState(u32),
+
+    /// The activity state is not supported by the processor, according to IA32_VMX_MISC.
+    UnsupportedActivityState(u32),
+
+    /// The activity state is HLT, but the DPL of SS is not 0.
+    HaltOutsideRing0,
+
+    /// Blocking by STI or MOV SS is set outside of the active state.
+    BlockingWhileInactive(u32),
+
+    /// The pending debug exceptions set reserved bits.
+    PendingDebugReservedBits(u64),
+
+    /// The BS bit of the pending debug exceptions does not match RFLAGS.TF and IA32_DEBUGCTL.BTF,
+    /// while the guest is halted or in an STI or MOV SS shadow.
+    PendingSingleStepMismatch(u64),
+
+    /// The interruptibility state sets reserved bits.
+    InterruptibilityReservedBits(u32),
+
+    /// The VMCS link pointer is not all ones, although VMCS shadowing is not used.
+    InvalidVmcsLinkPointer(u64),
+}
+
+impl core::fmt::Display for GuestStateViolation {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Cr0FixedBits(cr0) => write!(f, "CR0 {:#x} violates IA32_VMX_CR0_FIXED0/1", cr0),
+            Self::Cr0PagingWithoutProtection(cr0) => write!(f, "CR0 {:#x} sets PG without PE", cr0),
+            Self::Cr4FixedBits(cr4) => write!(f, "CR4 {:#x} violates IA32_VMX_CR4_FIXED0/1", cr4),
+            Self::Ia32eModeWithoutPaging => write!(f, "IA-32e mode guest requires CR0.PG and CR4.PAE"),
+            Self::PcidOutsideIa32eMode => write!(f, "CR4.PCIDE requires IA-32e mode guest"),
+            Self::EferReservedBits(efer) => write!(f, "IA32_EFER {:#x} sets reserved bits", efer),
+            Self::EferLmaMismatch(efer) => write!(f, "IA32_EFER.LMA of {:#x} does not match the IA-32e mode guest control", efer),
+            Self::EferLmeMismatch(efer) => write!(f, "IA32_EFER.LME of {:#x} does not match LMA while CR0.PG is set", efer),
+            Self::RflagsReservedBits(rflags) => write!(f, "RFLAGS {:#x} sets reserved bits or clears bit 1", rflags),
+            Self::InvalidVirtual8086Mode => write!(f, "RFLAGS.VM requires CR0.PE and no IA-32e mode guest"),
+            Self::InvalidSegment(segment, rule) => write!(f, "{:?}: {}", segment, rule),
+            Self::InvalidActivityState(state) => write!(f, "activity state {} is not defined", state),
+            Self::UnsupportedActivityState(state) => write!(f, "activity state {} is not supported by the processor", state),
+            Self::HaltOutsideRing0 => write!(f, "HLT activity state requires SS.DPL 0"),
+            Self::BlockingWhileInactive(state) => write!(f, "blocking by STI or MOV SS in interruptibility state {:#x} requires the active state", state),
+            Self::PendingDebugReservedBits(pending) => write!(f, "pending debug exceptions {:#x} set reserved bits", pending),
+            Self::PendingSingleStepMismatch(pending) => write!(f, "BS in pending debug exceptions {:#x} must equal RFLAGS.TF && !IA32_DEBUGCTL.BTF", pending),
+            Self::InterruptibilityReservedBits(state) => write!(f, "interruptibility state {:#x} sets reserved bits", state),
+            Self::InvalidVmcsLinkPointer(pointer) => write!(f, "VMCS link pointer {:#x} is not all ones", pointer),
+        }
+    }
+}
+
+/// Checks the guest-state area of the current VMCS against the VM-entry consistency rules.
+///
+/// Covers the control registers, IA32_EFER (if loaded on VM entry), RFLAGS, the access rights of the
+/// segment registers, the activity and interruptibility states, the pending debug exceptions, and
+/// the VMCS link pointer.
+///
+/// # Arguments
+///
+/// * `vm` - The VM whose VMCS is current.
+///
+/// # Returns
+///
+/// `Ok(())` if no checked rule is violated, or the first violated rule.
+pub fn check_guest_state(vm: &Vm) -> Result<(), GuestStateViolation> {
+    let entry_controls =
+        EntryControls::from_bits_truncate(vmread(vmcs::control::VMENTRY_CONTROLS) as u32);
+    let secondary_controls = SecondaryControls::from_bits_truncate(vmread(
+        vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
+    ) as u32);
+    let ia32e_mode = entry_controls.contains(EntryControls::IA32E_MODE_GUEST);
+    let unrestricted_guest = secondary_controls.contains(SecondaryControls::UNRESTRICTED_GUEST);
+
+    let cr0 = vmread(vmcs::guest::CR0);
+    let cr4 = vmread(vmcs::guest::CR4);
+    let rflags = vmread(vmcs::guest::RFLAGS);
+
+    check_control_registers(cr0, cr4, ia32e_mode, unrestricted_guest)?;
+
+    if entry_controls.contains(EntryControls::LOAD_IA32_EFER) {
+        check_efer(vmread(vmcs::guest::IA32_EFER_FULL), cr0, ia32e_mode)?;
+    }
+
+    check_rflags(rflags, cr0, ia32e_mode)?;
+
+    // Virtual-8086 mode has its own segment rules, which the hypervisor never needs.
+    const RFLAGS_VM: u64 = 1 << 17;
+    if rflags & RFLAGS_VM == 0 {
+        check_segments(vm, ia32e_mode, unrestricted_guest)?;
+    }
+
+    check_non_register_state(vm, rflags)
+}
+
+/// Checks CR0 and CR4 against the fixed bits of VMX operation and the IA-32e mode guest control.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+fn check_control_registers(
+    cr0: u64,
+    cr4: u64,
+    ia32e_mode: bool,
+    unrestricted_guest: bool,
+) -> Result<(), GuestStateViolation> {
+    let mut cr0_fixed0 = rdmsr(IA32_VMX_CR0_FIXED0);
+    let cr0_fixed1 = rdmsr(IA32_VMX_CR0_FIXED1);
+
+    // With unrestricted guest, CR0.PE and CR0.PG may be cleared regardless of IA32_VMX_CR0_FIXED0.
+    if unrestricted_guest {
+        cr0_fixed0 &= !((Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING).bits() as u64);
+    }
+
+    if cr0 & cr0_fixed0 != cr0_fixed0 || cr0 & !cr0_fixed1 != 0 {
+        return Err(GuestStateViolation::Cr0FixedBits(cr0));
+    }
+
+    let cr0 = Cr0::from_bits_truncate(cr0 as usize);
+    if cr0.contains(Cr0::CR0_ENABLE_PAGING) && !cr0.contains(Cr0::CR0_PROTECTED_MODE) {
+        return Err(GuestStateViolation::Cr0PagingWithoutProtection(
+            cr0.bits() as u64
+        ));
+    }
+
+    let cr4_fixed0 = rdmsr(IA32_VMX_CR4_FIXED0);
+    let cr4_fixed1 = rdmsr(IA32_VMX_CR4_FIXED1);
+    if cr4 & cr4_fixed0 != cr4_fixed0 || cr4 & !cr4_fixed1 != 0 {
+        return Err(GuestStateViolation::Cr4FixedBits(cr4));
+    }
+
+    let cr4 = Cr4::from_bits_truncate(cr4 as usize);
+    if ia32e_mode && (!cr0.contains(Cr0::CR0_ENABLE_PAGING) || !cr4.contains(Cr4::CR4_ENABLE_PAE)) {
+        return Err(GuestStateViolation::Ia32eModeWithoutPaging);
+    }
+
+    if !ia32e_mode && cr4.contains(Cr4::CR4_ENABLE_PCID) {
+        return Err(GuestStateViolation::PcidOutsideIa32eMode);
+    }
+
+    Ok(())
+}
+
+/// Checks the guest IA32_EFER, which is only loaded on VM entry with the "load IA32_EFER" control.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+fn check_efer(efer: u64, cr0: u64, ia32e_mode: bool) -> Result<(), GuestStateViolation> {
+    const SCE: u64 = 1 << 0;
+    const LME: u64 = 1 << 8;
+    const LMA: u64 = 1 << 10;
+    const NXE: u64 = 1 << 11;
+
+    if efer & !(SCE | LME | LMA | NXE) != 0 {
+        return Err(GuestStateViolation::EferReservedBits(efer));
+    }
+
+    if (efer & LMA != 0) != ia32e_mode {
+        return Err(GuestStateViolation::EferLmaMismatch(efer));
+    }
+
+    let paging = Cr0::from_bits_truncate(cr0 as usize).contains(Cr0::CR0_ENABLE_PAGING);
+    if paging && (efer & LME != 0) != (efer & LMA != 0) {
+        return Err(GuestStateViolation::EferLmeMismatch(efer));
+    }
+
+    Ok(())
+}
+
+/// Checks the reserved bits of RFLAGS and the use of virtual-8086 mode.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.4 Checks on Guest RIP, RFLAGS, and SSP
+fn check_rflags(rflags: u64, cr0: u64, ia32e_mode: bool) -> Result<(), GuestStateViolation> {
+    const RESERVED_ZERO: u64 = !((1 << 22) - 1) | 1 << 15 | 1 << 5 | 1 << 3;
+    const RESERVED_ONE: u64 = 1 << 1;
+    const VM: u64 = 1 << 17;
+
+    if rflags & RESERVED_ZERO != 0 || rflags & RESERVED_ONE == 0 {
+        return Err(GuestStateViolation::RflagsReservedBits(rflags));
+    }
+
+    let protected_mode = Cr0::from_bits_truncate(cr0 as usize).contains(Cr0::CR0_PROTECTED_MODE);
+    if rflags & VM != 0 && (ia32e_mode || !protected_mode) {
+        return Err(GuestStateViolation::InvalidVirtual8086Mode);
+    }
+
+    Ok(())
+}
+
+/// Checks the access rights and limits of the segment registers outside of virtual-8086 mode.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.2 Checks on Guest Segment Registers
+#[rustfmt::skip]
+fn check_segments(vm: &Vm, ia32e_mode: bool, unrestricted_guest: bool) -> Result<(), GuestStateViolation> {
+    /// Access-rights bits 11:8 and 31:17, which must be zero.
+    const RESERVED_ACCESS_RIGHTS: u32 = 0xfffe_0f00;
+
+    let segments = [Segment::Cs, Segment::Ss, Segment::Ds, Segment::Es, Segment::Fs, Segment::Gs, Segment::Ldtr, Segment::Tr];
+
+    for segment in segments {
+        let descriptor = vm.guest_segment(segment);
+        let access_rights = descriptor.access_rights;
+        let segment_type = access_rights.segment_type();
+        let fail = |rule| Err(GuestStateViolation::InvalidSegment(segment, rule));
+
+        if access_rights.unusable() {
+            match segment {
+                Segment::Cs => return fail("CS must be usable"),
+                Segment::Tr => return fail("TR must be usable"),
+                _ => continue,
+            }
+        }
+
+        if access_rights.0 & RESERVED_ACCESS_RIGHTS != 0 {
+            return fail("reserved access-rights bits are set");
+        }
+        if !access_rights.present() {
+            return fail("a usable segment must be present");
+        }
+        if descriptor.limit & 0xfff != 0xfff && access_rights.granularity() {
+            return fail("G is set, but limit bits 11:0 are not all ones");
+        }
+        if descriptor.limit > 0xf_ffff && !access_rights.granularity() {
+            return fail("G is clear, but limit bits 31:20 are not all zeros");
+        }
+
+        match segment {
+            Segment::Tr | Segment::Ldtr => {
+                if access_rights.descriptor_type() {
+                    return fail("must be a system segment (S = 0)");
+                }
+            }
+            _ => {
+                if !access_rights.descriptor_type() {
+                    return fail("must be a code or data segment (S = 1)");
+                }
+            }
+        }
+
+        match segment {
+            Segment::Cs => {
+                if !matches!(segment_type, 9 | 11 | 13 | 15) && !(unrestricted_guest && segment_type == 3) {
+                    return fail("type must be an accessed code segment, or 3 with unrestricted guest");
+                }
+                if ia32e_mode && access_rights.long_mode() && access_rights.default_big() {
+                    return fail("D must be clear for a 64-bit code segment");
+                }
+
+                // A non-conforming code segment runs at the privilege level of the stack.
+                let ss_access_rights = vm.guest_segment(Segment::Ss).access_rights;
+                if matches!(segment_type, 9 | 11) && access_rights.descriptor_privilege_level() != ss_access_rights.descriptor_privilege_level() {
+                    return fail("DPL of a non-conforming code segment must equal the DPL of SS");
+                }
+            }
+            Segment::Ss => {
+                if !matches!(segment_type, 3 | 7) {
+                    return fail("type must be a read/write data segment");
+                }
+            }
+            Segment::Ds | Segment::Es | Segment::Fs | Segment::Gs => {
+                const ACCESSED: u32 = 1 << 0;
+                const READABLE: u32 = 1 << 1;
+                const CODE: u32 = 1 << 3;
+
+                if segment_type & ACCESSED == 0 {
+                    return fail("type must be accessed");
+                }
+                if segment_type & CODE != 0 && segment_type & READABLE == 0 {
+                    return fail("a code segment must be readable");
+                }
+            }
+            Segment::Ldtr => {
+                if segment_type != 2 {
+                    return fail("type must be LDT (2)");
+                }
+            }
+            Segment::Tr => {
+                if segment_type != 11 && (ia32e_mode || segment_type != 3) {
+                    return fail("type must be a busy TSS (11, or 3 outside of IA-32e mode)");
+                }
+                if descriptor.selector.bits() & (1 << 2) != 0 {
+                    return fail("selector must reference the GDT (TI = 0)");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the activity state, the interruptibility state, the pending debug exceptions, and the
+/// VMCS link pointer.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.5 Checks on Guest Non-Register State
+fn check_non_register_state(vm: &Vm, rflags: u64) -> Result<(), GuestStateViolation> {
+    const RESERVED_INTERRUPTIBILITY: u32 = !0b1_1111;
+    const RFLAGS_TF: u64 = 1 << 8;
+
+    let activity_state = vmread(vmcs::guest::ACTIVITY_STATE) as u32;
+    let state = match activity_state {
+        0 => GuestActivityState::Active,
+        1 => GuestActivityState::Hlt,
+        2 => GuestActivityState::Shutdown,
+        3 => GuestActivityState::WaitForSipi,
+        _ => return Err(GuestStateViolation::InvalidActivityState(activity_state)),
+    };
+
+    if !state.is_supported() {
+        return Err(GuestStateViolation::UnsupportedActivityState(
+            activity_state,
+        ));
+    }
+
+    if state == GuestActivityState::Hlt
+        && vm
+            .guest_segment(Segment::Ss)
+            .access_rights
+            .descriptor_privilege_level()
+            != 0
+    {
+        return Err(GuestStateViolation::HaltOutsideRing0);
+    }
+
+    let interruptibility_state = vmread(vmcs::guest::INTERRUPTIBILITY_STATE) as u32;
+    if interruptibility_state & RESERVED_INTERRUPTIBILITY != 0 {
+        return Err(GuestStateViolation::InterruptibilityReservedBits(
+            interruptibility_state,
+        ));
+    }
+
+    let blocking = interruptibility_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) != 0;
+    if blocking && state != GuestActivityState::Active {
+        return Err(GuestStateViolation::BlockingWhileInactive(
+            interruptibility_state,
+        ));
+    }
+
+    let pending_debug_exceptions = vmread(vmcs::guest::PENDING_DBG_EXCEPTIONS);
+    if pending_debug_exceptions & PENDING_DEBUG_RESERVED != 0 {
+        return Err(GuestStateViolation::PendingDebugReservedBits(
+            pending_debug_exceptions,
+        ));
+    }
+
+    // A single-step trap must be pending exactly when the guest is single-stepping instructions,
+    // since the trap of the halting or shadowing instruction was deferred.
+    if blocking || state == GuestActivityState::Hlt {
+        let single_stepping =
+            rflags & RFLAGS_TF != 0 && vmread(vmcs::guest::IA32_DEBUGCTL_FULL) & DEBUGCTL_BTF == 0;
+        let single_step_pending = pending_debug_exceptions & PENDING_DEBUG_SINGLE_STEP != 0;
+        if single_stepping != single_step_pending {
+            return Err(GuestStateViolation::PendingSingleStepMismatch(
+                pending_debug_exceptions,
+            ));
+        }
+    }
+
+    let link_pointer = vmread(vmcs::guest::LINK_PTR_FULL);
+    if link_pointer != u64::MAX {
+        return Err(GuestStateViolation::InvalidVmcsLinkPointer(link_pointer));
+    }
+
+    Ok(())
+}
@@ -26,6 +26,44 @@ pub enum VmxControl {
 ///
 /// Returns the adjusted control value based on system capabilities and the requested value.
 pub fn adjust_vmx_controls(control: VmxControl, requested_value: u64) -> u64 {
+    let (allowed0, allowed1) = read_vmx_capabilities(control);
+    let mut effective_value = u32::try_from(requested_value).unwrap();
+    effective_value |= allowed0;
+    effective_value &= allowed1;
+    u64::from(effective_value)
+}
+
+/// Checks whether all of the given control bits may be set to 1 on this processor.
+///
+/// `adjust_vmx_controls` silently clears unsupported bits, so features that change guest
+/// behavior (e.g. unrestricted guest) should be checked with this first.
+///
+/// # Arguments
+///
+/// * `control` - The type of VMX control the bits belong to.
+/// * `bits` - The control bits to check.
+///
+/// # Returns
+///
+/// Returns `true` if every bit in `bits` is allowed to be 1, `false` otherwise.
+pub fn is_vmx_control_supported(control: VmxControl, bits: u64) -> bool {
+    let (_, allowed1) = read_vmx_capabilities(control);
+    let bits = u32::try_from(bits).unwrap();
+    bits & allowed1 == bits
+}
+
+/// Reads the allowed 0-settings and allowed 1-settings of a VMX control from its capability MSR.
+///
+/// # Arguments
+///
+/// * `control` - The type of VMX control whose capabilities to read.
+///
+/// # Returns
+///
+/// Returns a tuple of `(allowed0, allowed1)`.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.3 VM-EXECUTION CONTROLS
+fn read_vmx_capabilities(control: VmxControl) -> (u32, u32) {
     const IA32_VMX_BASIC_VMX_CONTROLS_FLAG: u64 = 1 << 55;
 
     let vmx_basic = unsafe { msr::rdmsr(msr::IA32_VMX_BASIC) };
@@ -45,10 +83,5 @@ pub fn adjust_vmx_controls(control: VmxControl, requested_value: u64) -> u64 {
     };
 
     let capabilities = unsafe { msr::rdmsr(cap_msr) };
-    let allowed0 = capabilities as u32;
-    let allowed1 = (capabilities >> 32) as u32;
-    let mut effective_value = u32::try_from(requested_value).unwrap();
-    effective_value |= allowed0;
-    effective_value &= allowed1;
-    u64::from(effective_value)
+    (capabilities as u32, (capabilities >> 32) as u32)
 }
@@ -0,0 +1,404 @@
+//! Decodes the guest instruction that caused a VM exit.
+//!
+//! Used to recover information the VMCS does not provide, such as the value a guest instruction
+//! stores to memory when it triggers an EPT violation. Exit handlers that need the operands of the
+//! faulting instruction can use `Vm::decode_current_instruction` instead of hand-decoding exit
+//! qualifications.
+
+use {
+    crate::intel::{capture::Register, vm::Vm, vmerror::EptViolationExitQualification, vmfield},
+    iced_x86::{Decoder, DecoderOptions, Instruction, Mnemonic, OpKind},
+    x86::{
+        bits64::{paging::BASE_PAGE_SIZE, rflags::RFlags},
+        controlregs::Cr0,
+    },
+};
+
+/// The maximum length of an x86 instruction.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// A memory store performed by a guest instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct StoreOperand {
+    /// The size of the store in bytes.
+    pub size: usize,
+
+    /// The value being stored, or `None` if it cannot be determined from the instruction alone
+    /// (e.g. read-modify-write instructions or stores wider than 8 bytes).
+    pub value: Option<u64>,
+}
+
+/// The guest memory accessed by the instruction that caused an EPT violation.
+#[derive(Debug, Clone, Copy)]
+pub struct EptViolationAccess {
+    /// The guest physical address of the first byte of the memory operand.
+    ///
+    /// The guest physical address reported in the VMCS is the first byte accessed on the faulting
+    /// page, which is not the start of the operand if the operand crosses into the page. The start
+    /// is then derived assuming the operand is physically contiguous, which only holds for the bytes
+    /// on the faulting page.
+    pub guest_pa: u64,
+
+    /// The size of the access in bytes. For string instructions, the size of a single element.
+    pub size: usize,
+}
+
+impl EptViolationAccess {
+    /// Returns the offset of the access into its 4KB page.
+    pub fn page_offset(&self) -> usize {
+        self.guest_pa as usize & (BASE_PAGE_SIZE - 1)
+    }
+}
+
+/// Decodes the guest memory accessed by the instruction that caused the current EPT violation.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `qualification` - The exit qualification of the EPT violation.
+///
+/// # Returns
+///
+/// Returns the decoded `EptViolationAccess`, or `None` if the instruction could not be read or
+/// decoded, or the violation was not caused by a data access to a memory operand.
+pub fn decode_ept_violation_access(
+    vm: &Vm,
+    qualification: &EptViolationExitQualification,
+) -> Option<EptViolationAccess> {
+    let instruction = decode_current_instruction(vm)?;
+    ept_violation_access(vm, &instruction, qualification)
+}
+
+/// Determines the guest memory accessed by a decoded instruction that caused an EPT violation.
+///
+/// The accessed operand is the first memory operand for writes and the last one for reads, which
+/// selects the destination and the source of string instructions like `MOVS`. If the guest linear
+/// address of the access is reported, the offset of the faulting byte into the operand is derived
+/// from it, otherwise the access is assumed to start at the reported guest physical address.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `instruction` - The instruction at the current guest RIP.
+/// * `qualification` - The exit qualification of the EPT violation.
+///
+/// # Returns
+///
+/// Returns the `EptViolationAccess`, or `None` if the violation was not caused by a data access to
+/// a memory operand of the instruction.
+pub fn ept_violation_access(
+    vm: &Vm,
+    instruction: &Instruction,
+    qualification: &EptViolationExitQualification,
+) -> Option<EptViolationAccess> {
+    if qualification.instruction_fetch || !(qualification.data_read || qualification.data_write) {
+        return None;
+    }
+
+    // Accesses to guest paging structures during translation report the GPA of the entry instead.
+    if qualification.guest_linear_address_valid && !qualification.guest_physical_access {
+        return None;
+    }
+
+    let mut memory_operands = (0..instruction.op_count()).filter(|&operand| {
+        matches!(
+            instruction.op_kind(operand),
+            OpKind::Memory
+                | OpKind::MemorySegSI
+                | OpKind::MemorySegESI
+                | OpKind::MemorySegRSI
+                | OpKind::MemorySegDI
+                | OpKind::MemorySegEDI
+                | OpKind::MemorySegRDI
+                | OpKind::MemoryESDI
+                | OpKind::MemoryESEDI
+                | OpKind::MemoryESRDI
+        )
+    });
+
+    let operand = match qualification.data_write {
+        true => memory_operands.next()?,
+        false => memory_operands.next_back()?,
+    };
+
+    let size = instruction.memory_size().size();
+    let faulting_pa = vmfield::ro::GUEST_PHYSICAL_ADDR_FULL.read();
+
+    let offset = match qualification.guest_linear_address_valid {
+        true => {
+            let faulting_la = vmfield::ro::GUEST_LINEAR_ADDR.read();
+            let operand_la = instruction.virtual_address(operand, 0, |register, _, _| {
+                address_register_value(vm, register)
+            })?;
+
+            match faulting_la.wrapping_sub(operand_la) {
+                offset if offset < size as u64 => offset,
+                offset => {
+                    log::trace!(
+                        "Faulting address {:#x} is {:#x} bytes past the operand at {:#x}",
+                        faulting_la,
+                        offset,
+                        operand_la
+                    );
+                    0
+                }
+            }
+        }
+        false => 0,
+    };
+
+    Some(EptViolationAccess {
+        guest_pa: faulting_pa.wrapping_sub(offset),
+        size,
+    })
+}
+
+/// Decodes the store performed by the guest instruction at the current guest RIP.
+///
+/// Only instructions whose stored value is one of their source operands (`MOV`, `MOVNTI`, `XCHG`,
+/// and `STOS`) report a value; other stores only report their size.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// Returns the decoded `StoreOperand`, or `None` if the instruction could not be read or decoded,
+/// or does not write to memory.
+pub fn decode_store_operand(vm: &Vm) -> Option<StoreOperand> {
+    let instruction = decode_current_instruction(vm)?;
+    store_operand(vm, &instruction)
+}
+
+/// Determines the store performed by a decoded guest instruction.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `instruction` - The instruction at the current guest RIP.
+///
+/// # Returns
+///
+/// Returns the `StoreOperand`, or `None` if the instruction does not write to memory.
+pub fn store_operand(vm: &Vm, instruction: &Instruction) -> Option<StoreOperand> {
+    if !matches!(
+        instruction.op0_kind(),
+        OpKind::Memory | OpKind::MemoryESRDI | OpKind::MemoryESEDI
+    ) {
+        return None;
+    }
+
+    let size = instruction.memory_size().size();
+
+    let value = match instruction.mnemonic() {
+        Mnemonic::Mov
+        | Mnemonic::Movnti
+        | Mnemonic::Xchg
+        | Mnemonic::Stosb
+        | Mnemonic::Stosw
+        | Mnemonic::Stosd
+        | Mnemonic::Stosq
+            if size <= 8 =>
+        {
+            source_operand_value(vm, instruction).map(|value| truncate(value, size))
+        }
+        _ => None,
+    };
+
+    Some(StoreOperand { size, value })
+}
+
+/// Reads and decodes the guest instruction at the current guest RIP.
+///
+/// The instruction is read through the guest's paging structures and the primary EPT, and decoded
+/// with the bitness of the guest's current code segment. An instruction crossing into an unmapped
+/// page is decoded from the bytes of the first page, which succeeds if it fits there.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// Returns the decoded `Instruction`, or `None` if the instruction could not be read or is invalid.
+pub fn decode_current_instruction(vm: &Vm) -> Option<Instruction> {
+    let bitness = guest_code_bitness();
+    let rip = vm.guest_reg(Register::Rip);
+
+    let mut bytes = [0u8; MAX_INSTRUCTION_LENGTH];
+    let length = read_current_instruction_bytes(vm, &mut bytes)?;
+
+    let mut decoder = Decoder::with_ip(bitness, &bytes[..length], rip, DecoderOptions::NONE);
+    let instruction = decoder.decode();
+
+    if instruction.is_invalid() {
+        log::trace!("Failed to decode guest instruction at {:#x}", rip);
+        return None;
+    }
+
+    Some(instruction)
+}
+
+/// Reads the bytes at the current guest RIP, without decoding them.
+///
+/// The bytes are read through the guest's paging structures and the primary EPT. If the bytes cross
+/// into an unmapped page, only the bytes of the first page are read.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `bytes` - The buffer to read the bytes into.
+///
+/// # Returns
+///
+/// Returns the number of bytes read, or `None` if the first page is not mapped.
+pub fn read_current_instruction_bytes(
+    vm: &Vm,
+    bytes: &mut [u8; MAX_INSTRUCTION_LENGTH],
+) -> Option<usize> {
+    let rip = vm.guest_reg(Register::Rip);
+
+    // Outside of 64-bit mode, RIP is an offset into the code segment.
+    let linear_address = match guest_code_bitness() {
+        64 => rip,
+        _ => vmfield::guest::CS_BASE.read().wrapping_add(rip),
+    };
+
+    if vm.read_guest_virt(linear_address, bytes).is_ok() {
+        return Some(bytes.len());
+    }
+
+    // The instruction may still fit in the first page.
+    let length = BASE_PAGE_SIZE - (linear_address as usize & (BASE_PAGE_SIZE - 1));
+    vm.read_guest_virt(linear_address, &mut bytes[..length])
+        .ok()?;
+
+    Some(length)
+}
+
+/// Determines the default operand bitness of the guest's current code segment.
+///
+/// The "IA-32e mode guest" VM-entry control reflects IA32_EFER.LMA on VM exit. In IA-32e mode,
+/// CS.L selects 64-bit mode, otherwise CS.D selects between 32-bit and 16-bit code. Real mode
+/// and virtual-8086 mode always use 16-bit code.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.3.1.1 Saving Control Registers, Debug Registers, and MSRs
+fn guest_code_bitness() -> u32 {
+    const IA32E_MODE_GUEST: u32 = 1 << 9;
+    const CS_LONG_MODE: u32 = 1 << 13;
+    const CS_DEFAULT_SIZE: u32 = 1 << 14;
+
+    let cs_access_rights = vmfield::guest::CS_ACCESS_RIGHTS.read();
+    let guest_cr0 = Cr0::from_bits_truncate(vmfield::guest::CR0.read() as usize);
+    let guest_rflags = RFlags::from_bits_truncate(vmfield::guest::RFLAGS.read());
+
+    if vmfield::control::VMENTRY_CONTROLS.read() & IA32E_MODE_GUEST != 0
+        && cs_access_rights & CS_LONG_MODE != 0
+    {
+        64
+    } else if !guest_cr0.contains(Cr0::CR0_PROTECTED_MODE)
+        || guest_rflags.contains(RFlags::FLAGS_VM)
+    {
+        16
+    } else if cs_access_rights & CS_DEFAULT_SIZE != 0 {
+        32
+    } else {
+        16
+    }
+}
+
+/// Returns the value of the first register or immediate operand following the memory destination.
+fn source_operand_value(vm: &Vm, instruction: &Instruction) -> Option<u64> {
+    for operand in 1..instruction.op_count() {
+        match instruction.op_kind(operand) {
+            OpKind::Register => return register_value(vm, instruction.op_register(operand)),
+            OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => return Some(instruction.immediate(operand)),
+            _ => continue,
+        }
+    }
+
+    None
+}
+
+/// Reads the value of a decoded general-purpose register operand from the guest.
+fn register_value(vm: &Vm, register: iced_x86::Register) -> Option<u64> {
+    use iced_x86::Register as R;
+
+    let value = vm.guest_reg(guest_register(register)?);
+
+    // AH, CH, DH, and BH refer to bits 15:8 of their register.
+    match register {
+        R::AH | R::CH | R::DH | R::BH => Some(value >> 8),
+        _ => Some(value),
+    }
+}
+
+/// Reads the value of a register used in address calculation, including segment bases.
+///
+/// In 64-bit mode, the bases of ES, CS, SS, and DS are treated as 0 regardless of their value.
+fn address_register_value(vm: &Vm, register: iced_x86::Register) -> Option<u64> {
+    use iced_x86::Register as R;
+
+    match register {
+        R::ES | R::CS | R::SS | R::DS if guest_code_bitness() == 64 => Some(0),
+        R::ES => Some(vmfield::guest::ES_BASE.read()),
+        R::CS => Some(vmfield::guest::CS_BASE.read()),
+        R::SS => Some(vmfield::guest::SS_BASE.read()),
+        R::DS => Some(vmfield::guest::DS_BASE.read()),
+        R::FS => Some(vmfield::guest::FS_BASE.read()),
+        R::GS => Some(vmfield::guest::GS_BASE.read()),
+        _ => register_value(vm, register),
+    }
+}
+
+/// Maps a decoded general-purpose register operand to the guest register containing it.
+///
+/// # Arguments
+///
+/// * `register` - The decoded register, of any size (e.g. `EAX` maps to `Register::Rax`).
+///
+/// # Returns
+///
+/// The guest register, or `None` if the operand is not a general-purpose register.
+pub fn guest_register(register: iced_x86::Register) -> Option<Register> {
+    use iced_x86::Register as R;
+
+    let guest_register = match register.full_register() {
+        R::RAX => Register::Rax,
+        R::RCX => Register::Rcx,
+        R::RDX => Register::Rdx,
+        R::RBX => Register::Rbx,
+        R::RSP => Register::Rsp,
+        R::RBP => Register::Rbp,
+        R::RSI => Register::Rsi,
+        R::RDI => Register::Rdi,
+        R::R8 => Register::R8,
+        R::R9 => Register::R9,
+        R::R10 => Register::R10,
+        R::R11 => Register::R11,
+        R::R12 => Register::R12,
+        R::R13 => Register::R13,
+        R::R14 => Register::R14,
+        R::R15 => Register::R15,
+        _ => return None,
+    };
+
+    Some(guest_register)
+}
+
+/// Truncates a value to the given size in bytes.
+fn truncate(value: u64, size: usize) -> u64 {
+    if size >= 8 {
+        value
+    } else {
+        value & ((1u64 << (size * 8)) - 1)
+    }
+}
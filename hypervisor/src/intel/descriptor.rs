@@ -195,7 +195,6 @@ pub struct TaskStateSegment {
     pub ar: u32,
 
     /// The actual TSS data.
-    #[allow(dead_code)]
     #[derivative(Debug = "ignore")]
     segment: Box<TaskStateSegmentRaw>,
 }
@@ -219,6 +218,23 @@ impl Default for TaskStateSegment {
     }
 }
 
+impl TaskStateSegment {
+    /// Sets an entry of the interrupt stack table (IST).
+    ///
+    /// # Arguments
+    ///
+    /// - `index`: The IST index from 1 to 7, as referenced by interrupt gates.
+    /// - `stack_top`: The initial stack pointer loaded when an interrupt gate with this index is used.
+    ///
+    /// See: Figure 8-11. 64-Bit TSS Format
+    pub fn set_interrupt_stack(&mut self, index: u8, stack_top: u64) {
+        assert!((1..=7).contains(&index));
+
+        let offset = 0x24 + (usize::from(index) - 1) * core::mem::size_of::<u64>();
+        self.segment.0[offset..offset + 8].copy_from_slice(&stack_top.to_le_bytes());
+    }
+}
+
 /// Low-level representation of the 64-bit Task State Segment (TSS).
 ///
 /// Encapsulates the raw structure of the TSS as defined in the x86_64 architecture.
@@ -0,0 +1,233 @@
+//! Measures the EPT hot paths with the timestamp counter.
+//!
+//! Building the identity map, splitting and merging 2MB pages, changing page permissions, and a full
+//! hook install/remove cycle are run on scratch EPTs, and the average number of cycles per operation
+//! is logged. This is meant to run as a diagnostic pass before the hypervisor is started, to catch
+//! regressions and to compare optimizations such as 1GB pages.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::{
+                hooks::{EptHookManager, HookConfig, HookReadPolicy, HookStrategy},
+                paging::{AccessType, Ept},
+            },
+            page::Page,
+            reserved::ReservedRegions,
+            support::rdtsc,
+            vm::box_zeroed,
+        },
+    },
+    log::*,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The number of times the identity map is built. Each build resolves the memory type of all 512GB.
+const BUILD_ITERATIONS: u64 = 4;
+
+/// The number of times each page-level operation is repeated.
+const PAGE_ITERATIONS: u64 = 64;
+
+/// The 2MB page operated on. Lies outside the first 2MB, which always uses `pt[0]`.
+const BENCHMARK_GPA: u64 = 0x40_0000;
+
+/// The page table used to split `BENCHMARK_GPA`.
+const BENCHMARK_PT_INDEX: usize = 1;
+
+/// Runs all EPT benchmarks and logs the average cycles per operation at the info level.
+///
+/// Trace and debug logging are suppressed while the benchmarks run, since logging every EPT operation
+/// over serial would dominate the measurements. Two scratch EPTs are allocated and freed again, so the
+/// EPTs used by the hypervisor are not affected.
+///
+/// The timestamp counter is read without serializing instructions, so single measurements can be off by
+/// a few dozen cycles. Each operation is repeated and averaged to compensate.
+///
+/// # Returns
+///
+/// `Ok(())` if all benchmarks ran, or the error of the first failed EPT operation.
+pub fn run_ept_benchmarks() -> Result<(), HypervisorError> {
+    let max_level = log::max_level();
+    log::set_max_level(LevelFilter::Info);
+
+    let result = run_all();
+
+    log::set_max_level(max_level);
+
+    result
+}
+
+/// Runs each benchmark in turn.
+fn run_all() -> Result<(), HypervisorError> {
+    benchmark_build_identity("build_identity", Ept::build_identity)?;
+    benchmark_build_identity("build_identity_1gb", Ept::build_identity_1gb)?;
+
+    let mut primary_ept = unsafe { box_zeroed::<Ept>() };
+    let mut secondary_ept = unsafe { box_zeroed::<Ept>() };
+    primary_ept.build_identity()?;
+
+    benchmark_split_and_merge(&mut primary_ept)?;
+    benchmark_modify_page_permissions(&mut primary_ept)?;
+
+    secondary_ept.clone_from(&primary_ept)?;
+    benchmark_hook_cycle(&mut primary_ept, &mut secondary_ept)
+}
+
+/// Measures building the identity map into a freshly zeroed EPT.
+///
+/// # Arguments
+///
+/// * `name` - The name the result is logged under.
+/// * `build` - The function building the identity map.
+fn benchmark_build_identity(
+    name: &str,
+    build: fn(&mut Ept) -> Result<(), HypervisorError>,
+) -> Result<(), HypervisorError> {
+    let mut cycles = 0;
+
+    for _ in 0..BUILD_ITERATIONS {
+        // A rebuilt table would keep the page tables of its previous splits allocated.
+        let mut ept = unsafe { box_zeroed::<Ept>() };
+
+        let (result, elapsed) = measure(|| build(&mut ept));
+        result?;
+        cycles += elapsed;
+    }
+
+    report(name, cycles, BUILD_ITERATIONS);
+
+    Ok(())
+}
+
+/// Measures splitting `BENCHMARK_GPA` into 4KB pages and merging it back.
+///
+/// # Arguments
+///
+/// * `ept` - An identity-mapped EPT in which `BENCHMARK_GPA` is not split.
+fn benchmark_split_and_merge(ept: &mut Ept) -> Result<(), HypervisorError> {
+    let mut split_cycles = 0;
+    let mut merge_cycles = 0;
+
+    for _ in 0..PAGE_ITERATIONS {
+        let (result, elapsed) =
+            measure(|| ept.split_2mb_to_4kb(BENCHMARK_GPA, BENCHMARK_PT_INDEX, false));
+        result?;
+        split_cycles += elapsed;
+
+        let (result, elapsed) = measure(|| ept.merge_4kb_to_2mb(BENCHMARK_GPA));
+        result?;
+        merge_cycles += elapsed;
+    }
+
+    report("split_2mb_to_4kb", split_cycles, PAGE_ITERATIONS);
+    report("merge_4kb_to_2mb", merge_cycles, PAGE_ITERATIONS);
+
+    Ok(())
+}
+
+/// Measures changing the permissions of the 4KB pages of `BENCHMARK_GPA`, alternating between
+/// read-write and execute-only like a hook does.
+///
+/// # Arguments
+///
+/// * `ept` - An identity-mapped EPT in which `BENCHMARK_GPA` is not split. It is merged back afterwards.
+fn benchmark_modify_page_permissions(ept: &mut Ept) -> Result<(), HypervisorError> {
+    ept.split_2mb_to_4kb(BENCHMARK_GPA, BENCHMARK_PT_INDEX, false)?;
+
+    let mut cycles = 0;
+
+    for i in 0..PAGE_ITERATIONS {
+        let guest_pa = BENCHMARK_GPA + i * BASE_PAGE_SIZE as u64;
+        let access_type = match i % 2 {
+            0 => AccessType::READ_WRITE,
+            _ => AccessType::EXECUTE_ALL_MODES,
+        };
+
+        let (result, elapsed) =
+            measure(|| ept.modify_page_permissions(guest_pa, access_type, BENCHMARK_PT_INDEX));
+        result?;
+        cycles += elapsed;
+    }
+
+    report("modify_page_permissions", cycles, PAGE_ITERATIONS);
+
+    ept.merge_4kb_to_2mb(BENCHMARK_GPA)
+}
+
+/// Measures installing a hook on `BENCHMARK_GPA`, disabling it, and merging the split pages back
+/// in both EPTs, which leaves the EPTs as they were.
+///
+/// # Arguments
+///
+/// * `primary_ept` - An identity-mapped EPT in which `BENCHMARK_GPA` is not split.
+/// * `secondary_ept` - A clone of `primary_ept`.
+fn benchmark_hook_cycle(
+    primary_ept: &mut Ept,
+    secondary_ept: &mut Ept,
+) -> Result<(), HypervisorError> {
+    // No regions are reserved, so the shadow page can be mapped anywhere.
+    let no_reserved_regions = ReservedRegions::new();
+
+    let shadow_page = unsafe { box_zeroed::<Page>() };
+    let shadow_page_pa = &*shadow_page as *const Page as u64;
+
+    let mut cycles = 0;
+
+    for _ in 0..PAGE_ITERATIONS {
+        // Removing a hook leaks a restored copy of its shadow page, so each cycle disables the hook
+        // and starts with an empty registry instead.
+        let mut hook_manager = EptHookManager::new(Ept::is_execute_only_supported());
+
+        let (result, elapsed) = measure(|| {
+            hook_manager.install(
+                primary_ept,
+                secondary_ept,
+                HookConfig {
+                    guest_pa: BENCHMARK_GPA,
+                    shadow_page_pa,
+                    strategy: HookStrategy::EptSwap,
+                    read_policy: HookReadPolicy::Original,
+                },
+                &no_reserved_regions,
+            )?;
+            hook_manager.set_enabled(
+                primary_ept,
+                secondary_ept,
+                BENCHMARK_GPA,
+                false,
+                &no_reserved_regions,
+            )?;
+            secondary_ept.merge_4kb_to_2mb(BENCHMARK_GPA)?;
+            primary_ept.merge_4kb_to_2mb(BENCHMARK_GPA)
+        });
+        result?;
+        cycles += elapsed;
+    }
+
+    report("hook install/remove", cycles, PAGE_ITERATIONS);
+
+    Ok(())
+}
+
+/// Runs an operation and returns its result along with the number of elapsed TSC cycles.
+fn measure<T>(operation: impl FnOnce() -> T) -> (T, u64) {
+    let start = rdtsc();
+    let result = operation();
+
+    (result, rdtsc().wrapping_sub(start))
+}
+
+/// Logs the average number of cycles per operation of a benchmark.
+///
+/// # Arguments
+///
+/// * `name` - The name of the benchmarked operation.
+/// * `cycles` - The total number of cycles of all iterations.
+/// * `iterations` - The number of iterations.
+fn report(name: &str, cycles: u64, iterations: u64) {
+    info!(
+        "EPT benchmark: {name}: {} cycles/op ({iterations} iterations)",
+        cycles / iterations
+    );
+}
@@ -0,0 +1,224 @@
+//! Write-protects guest memory to take copy-on-write snapshots.
+//!
+//! `CowTracker::protect` clears the writable bit of every 4KB page in a range in both EPTs. The first
+//! guest write to such a page causes an EPT violation, in which `CowTracker::copy_on_write` copies
+//! the original contents of the page into its snapshot buffer and gives back the write access it
+//! removed in each EPT. The guest then re-executes the write. Pages the guest never writes to are
+//! never copied, so a snapshot only costs a VM exit per modified page. The snapshot of the range
+//! consists of the copied pages, plus the pages that were never written, whose current contents are
+//! still the original ones.
+//!
+//! The snapshot buffers are allocated when the range is protected, since memory cannot be allocated
+//! from a VM-exit handler.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::paging::{AccessType, Ept},
+            page::Page,
+            vm::box_zeroed,
+        },
+    },
+    alloc::{boxed::Box, vec::Vec},
+    spin::Mutex,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// A single write-protected 4KB page.
+#[derive(Debug)]
+struct CowPage {
+    /// The page-aligned guest physical address of the page.
+    guest_page_pa: u64,
+
+    /// The buffer the original contents of the page are copied to on the first write.
+    snapshot: Box<Page>,
+
+    /// Whether the page has been written to, i.e. whether `snapshot` holds its original contents.
+    copied: bool,
+
+    /// The permissions `protect` removed from the page in the primary and the secondary EPT, which
+    /// are given back on the first write. Empty in an EPT in which the page was not writable, e.g.
+    /// the execute-only shadow mapping of a hook.
+    removed_access: [AccessType; 2],
+}
+
+/// Registry of the guest pages write-protected for copy-on-write.
+#[derive(Debug)]
+pub struct CowTracker {
+    /// The write-protected pages.
+    pages: Mutex<Vec<CowPage>>,
+}
+
+impl Default for CowTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CowTracker {
+    /// Creates an empty `CowTracker`.
+    pub const fn new() -> Self {
+        Self {
+            pages: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Write-protects every 4KB page touched by a guest physical address range in both EPTs.
+    ///
+    /// Each 2MB page in the range is split in each EPT with a page table from that EPT's own allocator,
+    /// unless it is split already, and a snapshot buffer is allocated for each page. Pages that are already protected are skipped.
+    /// The caller is responsible for invalidating the EPT caches (`invept_all_contexts`) if the EPTs
+    /// are in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT.
+    /// * `secondary_ept` - The secondary EPT.
+    /// * `guest_pa` - The guest physical address of the start of the range.
+    /// * `len` - The length of the range in bytes.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or the error of the failed EPT operation. Pages protected before the
+    /// failure stay protected.
+    pub fn protect(
+        &self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+        len: usize,
+    ) -> Result<(), HypervisorError> {
+        let mut pages = self.pages.lock();
+
+        for (guest_page_pa, pt_table_index) in primary_ept.prepare_hook_region(guest_pa, len)? {
+            if pages.iter().any(|page| page.guest_page_pa == guest_page_pa) {
+                continue;
+            }
+
+            // The secondary EPT may split the 2MB page with a different page table than the primary.
+            let secondary_pt_index = match secondary_ept.split_pt_index(guest_page_pa) {
+                Some(secondary_pt_index) => secondary_pt_index,
+                None => secondary_ept.split_2mb_to_4kb_alloc(guest_page_pa)?,
+            };
+
+            let mut removed_access = [AccessType::empty(); 2];
+
+            for ((ept, pt_table_index), removed_access) in [
+                (&mut *primary_ept, pt_table_index),
+                (&mut *secondary_ept, secondary_pt_index),
+            ]
+            .into_iter()
+            .zip(&mut removed_access)
+            {
+                let access_type = ept
+                    .page_permissions(guest_page_pa)
+                    .ok_or(HypervisorError::GuestPhysicalAddressNotMapped)?;
+                ept.modify_page_permissions(
+                    guest_page_pa,
+                    access_type.difference(AccessType::WRITE),
+                    pt_table_index,
+                )?;
+                *removed_access = access_type.intersection(AccessType::WRITE);
+            }
+
+            pages.push(CowPage {
+                guest_page_pa,
+                snapshot: unsafe { box_zeroed::<Page>() },
+                copied: false,
+                removed_access,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Copies a write-protected page into its snapshot buffer and gives back the write access `protect`
+    /// removed in each EPT.
+    ///
+    /// Called for EPT violations caused by writes. If another processor already copied the page, only
+    /// its permissions were stale, so nothing is copied. A page that was not writable in one of the
+    /// EPTs before it was protected, e.g. a hooked page, stays non-writable there, and the violation
+    /// is left to the usual handling, e.g. swapping back to the primary EPT. From a VM-exit handler,
+    /// the EPTs must be taken from `SharedData::lock_epts`. The caller is responsible for invalidating
+    /// the EPT caches (`invept_all_contexts`).
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT.
+    /// * `secondary_ept` - The secondary EPT.
+    /// * `guest_pa` - Any guest physical address within the page being written to.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(true)` if the page is write-protected for copy-on-write and the write can be re-executed
+    /// in either EPT, `Ok(false)` if it is not protected or stays non-writable in one of the EPTs, or
+    /// the error of the failed EPT operation.
+    pub fn copy_on_write(
+        &self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+    ) -> Result<bool, HypervisorError> {
+        let guest_page_pa = page_align(guest_pa);
+        let mut pages = self.pages.lock();
+
+        let Some(page) = pages
+            .iter_mut()
+            .find(|page| page.guest_page_pa == guest_page_pa)
+        else {
+            return Ok(false);
+        };
+
+        let writable_in_both = page
+            .removed_access
+            .iter()
+            .all(|removed_access| removed_access.contains(AccessType::WRITE));
+
+        if page.copied {
+            return Ok(writable_in_both);
+        }
+
+        // The original contents are read through the primary EPT, like `Vm::read_guest_phys` does.
+        let host_pa = primary_ept
+            .gpa_to_hpa(guest_page_pa)
+            .ok_or(HypervisorError::GuestPhysicalAddressNotMapped)?;
+        unsafe { core::ptr::copy_nonoverlapping(host_pa as *const Page, &mut *page.snapshot, 1) };
+        page.copied = true;
+
+        for (ept, removed_access) in [primary_ept, secondary_ept]
+            .into_iter()
+            .zip(page.removed_access)
+        {
+            let pt_table_index = ept
+                .split_pt_index(guest_page_pa)
+                .ok_or(HypervisorError::PageNotSplit)?;
+            let access_type = ept
+                .page_permissions(guest_page_pa)
+                .ok_or(HypervisorError::GuestPhysicalAddressNotMapped)?;
+            ept.modify_page_permissions(
+                guest_page_pa,
+                access_type.union(removed_access),
+                pt_table_index,
+            )?;
+        }
+
+        Ok(writable_in_both)
+    }
+
+    /// Calls a function with the snapshot of every page that has been written to since it was protected.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The function to call with the page-aligned guest physical address and the original contents of each page.
+    pub fn for_each_snapshot(&self, mut f: impl FnMut(u64, &Page)) {
+        for page in self.pages.lock().iter().filter(|page| page.copied) {
+            f(page.guest_page_pa, &page.snapshot);
+        }
+    }
+}
+
+/// Aligns a guest physical address down to its 4KB page.
+fn page_align(guest_pa: u64) -> u64 {
+    guest_pa & !(BASE_PAGE_SIZE as u64 - 1)
+}
@@ -0,0 +1,1283 @@
+//! Manages EPT hooks on 4KB guest pages, and on whole 2MB guest pages.
+//!
+//! A hook uses the EPT-swap model handled in `vmexit::ept`: in the primary EPT the hooked page keeps
+//! its original contents but is not executable, and in the secondary EPT it is execute-only and
+//! mapped to a shadow page with the hooked code. Executing the page while the primary EPT is active
+//! swaps to the secondary EPT, and reading or writing it while the secondary EPT is active swaps back.
+//!
+//! Processors without execute-only EPT translations map the shadow page read-execute in the secondary
+//! EPT instead. Writes still swap back to the primary EPT, but reads while the secondary EPT is
+//! active see the shadow page, so the hooked bytes are visible to the guest.
+//!
+//! Only the hooked 4KB page is swapped, since its neighbors keep their read-write-execute mappings in
+//! both EPTs. Data on the hooked page itself, however, is read from the original page, so code that
+//! reads it (e.g. jump tables or constants next to a hooked function) swaps back to the primary EPT
+//! on every read, and to the secondary EPT on the next execute. For such pages, an EPT-swap hook can
+//! be installed with `HookReadPolicy::Shadow`, which maps the shadow page read-execute in the
+//! secondary EPT. The shadow page is a copy of the original page, so reads return the original data
+//! without a swap, apart from the hooked bytes, which become visible. Only writes still swap back.
+//!
+//! Alternatively, a hook can use the MTF strategy (`HookStrategy::Mtf`), which only needs one EPT:
+//! the hooked page is mapped read-write to the original page in both EPTs, and executing it maps
+//! the shadow page read-execute for a single instruction, stepped with the monitor trap flag.
+//!
+//! A whole 2MB region, e.g. a driver's code section, can be hooked with `EptHookManager::install_large`
+//! instead. The large PDE is made execute-only in the secondary EPT and non-executable in the primary
+//! EPT directly, so the region is neither split nor uses one of the 63 page tables. The tradeoff is
+//! granularity: every data access to any byte of the 2MB region while the secondary EPT is active, and
+//! every execution while the primary EPT is active, swaps EPTPs, so regions mixing code with hot data
+//! swap far more often than a 4KB hook would. 2MB hooks always use the EPT-swap strategy, and their
+//! shadow is a 2MB region, e.g. from `create_large_shadow_region`, or the original region itself to
+//! only monitor execution.
+//!
+//! Hooks can be disabled and re-enabled at runtime. The 2MB page containing a hook stays split into
+//! 4KB pages across toggles, so toggling never needs a new page table.
+//!
+//! Hooked code is patched with `EptHookManager::patch`, which never modifies a page another processor
+//! may be executing. The patch is written to a fresh copy of the shadow page, and the secondary EPT
+//! is switched to the copy with a single 8-byte store to the PTE. A processor executing the page sees
+//! either the old or the new contents, never a partially written instruction, without having to
+//! stop all processors first. The original page is never modified.
+//!
+//! A hook is removed with `EptHookManager::remove_hook`, which leaves no trace of it. The original bytes
+//! under the patched range of the shadow page are saved when the hook is installed and extended by
+//! each patch. On removal, they are written back into a fresh copy of the shadow page, which is swapped
+//! in like a patch, before the page is mapped back to the original page. The guest therefore sees the
+//! original bytes from the first store on, and the remaining EPT updates no longer change what it
+//! executes. If a hook split the 2MB page, the last hook removed from it merges it back, unless
+//! something else has changed it since.
+//!
+//! Like the write tracker, the hook registry has a fixed capacity, since memory cannot be allocated
+//! from a VM-exit handler.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::PhysicalAddress,
+            ept::paging::{AccessType, Ept},
+            page::Page,
+            reserved::ReservedRegions,
+            support::flush_cache_range,
+            vm::{box_zeroed, try_box_zeroed},
+        },
+    },
+    alloc::{boxed::Box, vec},
+    x86::bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE},
+};
+
+/// The maximum number of hooks that can be installed at the same time.
+pub const MAX_HOOKS: usize = 64;
+
+/// `jmp qword ptr [rip+0]`, followed by the 8-byte absolute jump target.
+const INLINE_JUMP: [u8; 6] = [0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
+
+/// The size of the inline jump including its target.
+const INLINE_HOOK_SIZE: usize = INLINE_JUMP.len() + core::mem::size_of::<u64>();
+
+/// The maximum length of the patched range whose original bytes are saved with a hook.
+///
+/// Covers an inline jump with some room for further patches. Hooks whose shadow page differs from the
+/// original page over a longer range are restored from the whole original page instead.
+pub const MAX_ORIGINAL_BYTES: usize = 64;
+
+/// How a hook makes the guest execute the shadow page while reading and writing the original page.
+///
+/// The strategy is chosen per hook when it is installed, defaulting to `SharedData::hook_strategy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStrategy {
+    /// Swap between the primary and the secondary EPT on execute and data accesses.
+    ///
+    /// Executing hooked code is free once the secondary EPT is active, so this is the fastest strategy
+    /// for hot functions. Without execute-only EPT translations, however, the shadow page must be
+    /// readable in the secondary EPT, so the guest can read the hooked bytes. Every access to other
+    /// pages in between the two EPTs (e.g. data on the same page as a hooked function) costs a swap.
+    /// With `HookReadPolicy::Shadow`, reads of the hooked page itself no longer do.
+    EptSwap,
+
+    /// Single-step every instruction executed from the hooked page with the monitor trap flag.
+    ///
+    /// The page is only mapped to the shadow page for the duration of a single instruction, so the
+    /// hooked bytes are hidden from reads on processors without execute-only EPT translations, apart
+    /// from reads by other processors during that instruction. Every instruction executed from the
+    /// page costs two VM exits, so this is only suitable for hooks on rarely executed code.
+    ///
+    /// The EPT caches are only invalidated on the stepping processor, since there is no way to make
+    /// other processors invalidate theirs. Another processor that accessed the page during the step
+    /// may keep using the cached shadow mapping after the step, until its own next INVEPT.
+    Mtf,
+}
+
+impl HookStrategy {
+    /// Selects the strategy that hides hooks best on the current processor.
+    ///
+    /// EPT swapping is preferred if execute-only EPT translations are supported, since it hides the
+    /// shadow page and is faster. Otherwise MTF single-stepping is used if the monitor trap flag is
+    /// supported.
+    ///
+    /// # Arguments
+    ///
+    /// * `execute_only_supported` - Whether the processor supports execute-only EPT translations.
+    /// * `mtf_supported` - Whether the processor supports the monitor trap flag.
+    pub const fn preferred(execute_only_supported: bool, mtf_supported: bool) -> Self {
+        match (execute_only_supported, mtf_supported) {
+            (false, true) => Self::Mtf,
+            _ => Self::EptSwap,
+        }
+    }
+}
+
+/// How data reads of an enabled EPT-swap hook's page are served while the secondary EPT is active.
+///
+/// The read policy is chosen per hook when it is installed, defaulting to `SharedData::hook_read_policy`.
+/// It has no effect on MTF hooks, and on processors without execute-only EPT translations, on which
+/// shadow pages are always readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookReadPolicy {
+    /// Reads swap back to the primary EPT and see the original page, hiding the hooked bytes.
+    ///
+    /// This is the default. Every read after executing the page costs two EPTP swaps, which
+    /// thrashes on pages mixing hooked code with frequently read data.
+    Original,
+
+    /// Reads are served from the shadow page in the secondary EPT, which is mapped read-execute.
+    ///
+    /// The shadow page is a copy of the original page, so reads see the original data without an
+    /// EPTP swap, but the hooked bytes are visible to the guest.
+    Shadow,
+}
+
+/// The original bytes under the range of a shadow page that differs from the original page.
+#[derive(Debug, Clone, Copy)]
+struct OriginalBytes {
+    /// The offset of the saved range within the page.
+    offset: usize,
+
+    /// The length of the saved range, 0 if the shadow page does not differ from the original page.
+    len: usize,
+
+    /// The original bytes of the range, of which the first `len` are used.
+    bytes: [u8; MAX_ORIGINAL_BYTES],
+
+    /// Whether the range fits into `bytes`. If not, the whole original page is restored instead.
+    complete: bool,
+}
+
+impl OriginalBytes {
+    /// Creates an empty range, for a shadow page that does not differ from the original page.
+    const fn empty() -> Self {
+        Self {
+            offset: 0,
+            len: 0,
+            bytes: [0; MAX_ORIGINAL_BYTES],
+            complete: true,
+        }
+    }
+
+    /// Saves the original bytes under the range in which a shadow page differs from the original page.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_page_pa` - The host physical address of the original page.
+    /// * `shadow_page_pa` - The host physical address of the shadow page.
+    ///
+    /// # Returns
+    ///
+    /// The saved bytes, or `Err(HypervisorError::HostPhysicalAddressNotMapped)` if either page cannot
+    /// be accessed.
+    fn capture(original_page_pa: u64, shadow_page_pa: u64) -> Result<Self, HypervisorError> {
+        let original = unsafe { page_bytes(original_page_pa)? };
+        let shadow = unsafe { page_bytes(shadow_page_pa)? };
+
+        let mut saved = Self::empty();
+
+        if let Some(first) = (0..BASE_PAGE_SIZE).find(|&i| original[i] != shadow[i]) {
+            let last = (first..BASE_PAGE_SIZE)
+                .rev()
+                .find(|&i| original[i] != shadow[i])
+                .unwrap_or(first);
+            saved.extend(original_page_pa, first, last + 1 - first)?;
+        }
+
+        Ok(saved)
+    }
+
+    /// Extends the saved range to cover a range that is about to be patched.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_page_pa` - The host physical address of the original page.
+    /// * `offset` - The offset of the patched range within the page.
+    /// * `len` - The length of the patched range.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::HostPhysicalAddressNotMapped)` if the original page
+    /// cannot be accessed, in which case the saved range is unchanged.
+    fn extend(
+        &mut self,
+        original_page_pa: u64,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), HypervisorError> {
+        if len == 0 || !self.complete {
+            return Ok(());
+        }
+
+        let (start, end) = match self.len {
+            0 => (offset, offset + len),
+            _ => (
+                self.offset.min(offset),
+                (self.offset + self.len).max(offset + len),
+            ),
+        };
+
+        if end - start > MAX_ORIGINAL_BYTES {
+            self.complete = false;
+            return Ok(());
+        }
+
+        let original = unsafe { page_bytes(original_page_pa)? };
+        self.bytes[..end - start].copy_from_slice(&original[start..end]);
+        self.offset = start;
+        self.len = end - start;
+
+        Ok(())
+    }
+
+    /// Writes the original bytes into a copy of the shadow page.
+    ///
+    /// # Arguments
+    ///
+    /// * `page` - The copy of the shadow page to restore.
+    /// * `original_page_pa` - The host physical address of the original page, copied as a whole if the
+    ///   saved range is incomplete.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::HostPhysicalAddressNotMapped)` if the original page
+    /// is needed but cannot be accessed.
+    fn restore(&self, page: &mut [u8], original_page_pa: u64) -> Result<(), HypervisorError> {
+        match self.complete {
+            true => {
+                page[self.offset..self.offset + self.len].copy_from_slice(&self.bytes[..self.len])
+            }
+            false => page.copy_from_slice(unsafe { page_bytes(original_page_pa)? }),
+        }
+
+        Ok(())
+    }
+}
+
+/// A 2MB region copied into a shadow region for a 2MB hook.
+#[repr(C, align(0x200000))]
+struct LargePage([u8; LARGE_PAGE_SIZE]);
+
+/// A single hooked 4KB page, or a hooked 2MB page.
+#[derive(Debug, Clone, Copy)]
+struct EptHook {
+    /// The page-aligned guest physical address of the hooked page.
+    guest_page_pa: u64,
+
+    /// The page-aligned host physical address the hooked page was mapped to in the primary EPT when
+    /// the hook was installed, which may differ from `guest_page_pa` if the page was remapped. The
+    /// page is mapped back to it while the hook does not map the shadow page.
+    original_page_pa: u64,
+
+    /// The page-aligned host physical address of the shadow page executed instead.
+    shadow_page_pa: u64,
+
+    /// The page table splitting the 2MB page containing the hook in the primary and the secondary EPT,
+    /// which are allocated independently. Unused by 2MB hooks.
+    pt_table_indices: EptPair<usize>,
+
+    /// Whether the hook covers a whole 2MB page mapped by a large PDE, instead of a 4KB page.
+    large: bool,
+
+    /// Whether the hook owns the split of the 2MB page in the primary and the secondary EPT, so removing
+    /// it may merge the page back. Set by the hook whose installation split the page, and handed over
+    /// to another hook on the same 2MB page if that hook is removed first.
+    owns_split: EptPair<bool>,
+
+    /// The original bytes under the patched range of the shadow page. Not saved for 2MB hooks.
+    original_bytes: OriginalBytes,
+
+    /// Whether the hook is currently active in the EPTs.
+    enabled: bool,
+
+    /// How the shadow page is executed.
+    strategy: HookStrategy,
+
+    /// How data reads are served while the secondary EPT is active.
+    read_policy: HookReadPolicy,
+
+    /// The number of processors single-stepping the shadow page of an MTF hook, which stays mapped
+    /// until the last of them finishes its step.
+    steps: u32,
+}
+
+/// A value for each of the primary and the secondary EPT.
+#[derive(Debug, Clone, Copy, Default)]
+struct EptPair<T> {
+    primary: T,
+    secondary: T,
+}
+
+/// The page and the behavior of a 4KB hook, for `EptHookManager::install`.
+#[derive(Debug, Clone, Copy)]
+pub struct HookConfig {
+    /// Any guest physical address within the page to hook.
+    pub guest_pa: u64,
+
+    /// The page-aligned host physical address of the shadow page.
+    pub shadow_page_pa: u64,
+
+    /// How the shadow page is executed.
+    pub strategy: HookStrategy,
+
+    /// How data reads are served while the secondary EPT is active.
+    pub read_policy: HookReadPolicy,
+}
+
+/// Registry of EPT hooks installed in the primary and secondary EPTs.
+#[derive(Debug)]
+pub struct EptHookManager {
+    /// The installed hooks, with `MAX_HOOKS` slots allocated on the heap. `None` entries are free slots.
+    hooks: Box<[Option<EptHook>]>,
+
+    /// The permissions of enabled hooks in the secondary EPT.
+    shadow_access: AccessType,
+}
+
+impl EptHookManager {
+    /// Creates an empty `EptHookManager`.
+    ///
+    /// # Arguments
+    ///
+    /// * `execute_only_supported` - Whether the processor supports execute-only EPT translations
+    ///   (`Ept::is_execute_only_supported`). If not, shadow pages are mapped read-execute.
+    pub fn new(execute_only_supported: bool) -> Self {
+        Self {
+            hooks: vec![None; MAX_HOOKS].into_boxed_slice(),
+            shadow_access: match execute_only_supported {
+                true => AccessType::EXECUTE_ALL_MODES,
+                false => AccessType::READ_EXECUTE,
+            },
+        }
+    }
+
+    /// Registers the memory of the hook slots as owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::ReservedRegionsFull)` if no free slot is left.
+    pub fn reserve_storage(
+        &self,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        reserved_regions.reserve_object(&*self.hooks)
+    }
+
+    /// Installs an enabled hook on the page containing the given guest physical address.
+    ///
+    /// Splits the 2MB page containing the hook in each EPT that does not have it split already, with a
+    /// page table from that EPT's own allocator, since the EPTs may use different page tables for the
+    /// same 2MB page. The original page is the host page the hooked page is mapped to in the primary
+    /// EPT, which is not necessarily the identity mapping, e.g. after a remap. The original bytes under
+    /// the range in which the shadow page differs from the original page are saved, to be restored by
+    /// `remove_hook`.
+    ///
+    /// If the hook cannot be installed, both EPTs are left as they were: the hooked page is mapped back
+    /// to the original page and the 2MB pages split here are merged, which releases their page tables.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT, in which the original page stays readable and writable.
+    /// * `secondary_ept` - The secondary EPT, in which the page is mapped execute-only to the shadow page.
+    /// * `config` - The page to hook, its shadow page, and the behavior of the hook.
+    /// * `reserved_regions` - The host memory owned by the hypervisor, which the shadow page must not overlap.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, `Err(HypervisorError::HookAlreadyInstalled)` if the page is
+    /// already hooked, `Err(HypervisorError::HookManagerFull)` if no free slot is left,
+    /// `Err(HypervisorError::HookError)` if the page is not mapped in the primary EPT, or the error
+    /// of the failed EPT operation.
+    pub fn install(
+        &mut self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        config: HookConfig,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let guest_page_pa = page_align(config.guest_pa);
+
+        if self.find_mut(guest_page_pa).is_some() {
+            return Err(HypervisorError::HookAlreadyInstalled);
+        }
+
+        let slot_index = self
+            .hooks
+            .iter()
+            .position(|hook| hook.is_none())
+            .ok_or(HypervisorError::HookManagerFull)?;
+
+        let original_page_pa = primary_ept
+            .gpa_to_hpa(guest_page_pa)
+            .ok_or(HypervisorError::HookError)?;
+        let original_bytes = OriginalBytes::capture(original_page_pa, config.shadow_page_pa)?;
+
+        let (primary_pt_index, primary_owns_split) = split_for_hook(primary_ept, guest_page_pa)?;
+        let (secondary_pt_index, secondary_owns_split) =
+            match split_for_hook(secondary_ept, guest_page_pa) {
+                Ok(split) => split,
+                Err(e) => {
+                    if primary_owns_split {
+                        primary_ept.merge_4kb_to_2mb(guest_page_pa)?;
+                    }
+                    return Err(e);
+                }
+            };
+
+        let hook = EptHook {
+            guest_page_pa,
+            original_page_pa,
+            shadow_page_pa: config.shadow_page_pa,
+            pt_table_indices: EptPair {
+                primary: primary_pt_index,
+                secondary: secondary_pt_index,
+            },
+            large: false,
+            owns_split: EptPair {
+                primary: primary_owns_split,
+                secondary: secondary_owns_split,
+            },
+            original_bytes,
+            enabled: true,
+            strategy: config.strategy,
+            read_policy: config.read_policy,
+            steps: 0,
+        };
+
+        if let Err(e) = hook.apply(
+            primary_ept,
+            secondary_ept,
+            self.shadow_access,
+            reserved_regions,
+        ) {
+            // Map the page back to the original page in both EPTs before merging or keeping the splits.
+            let disabled = EptHook {
+                enabled: false,
+                ..hook
+            };
+            disabled.apply(
+                primary_ept,
+                secondary_ept,
+                self.shadow_access,
+                reserved_regions,
+            )?;
+            disabled.merge_owned_splits(primary_ept, secondary_ept)?;
+            return Err(e);
+        }
+
+        self.hooks[slot_index] = Some(hook);
+
+        Ok(())
+    }
+
+    /// Installs an enabled EPT-swap hook on the whole 2MB page containing the given guest physical address.
+    ///
+    /// The 2MB page must still be mapped by a large PDE in both EPTs, and is not split: its PDE is made
+    /// non-executable in the primary EPT, and execute-only in the secondary EPT with the shadow region.
+    /// Executing any byte of the region swaps to the secondary EPT, and accessing any byte of it as
+    /// data swaps back, see the module documentation for the tradeoff.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT, in which the original region stays readable and writable.
+    /// * `secondary_ept` - The secondary EPT, in which the region is mapped execute-only to the shadow region.
+    /// * `guest_pa` - Any guest physical address within the 2MB page to hook.
+    /// * `shadow_region_pa` - The 2MB-aligned host physical address of the shadow region, or the address
+    ///   of the original region to only monitor execution.
+    /// * `read_policy` - How data reads are served while the secondary EPT is active.
+    /// * `reserved_regions` - The host memory owned by the hypervisor, which the shadow region must not overlap.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, `Err(HypervisorError::HookAlreadyInstalled)` if a page of the region
+    /// is already hooked, `Err(HypervisorError::PageAlreadySplit)` if the region is mapped with 4KB
+    /// pages in either EPT, `Err(HypervisorError::HookManagerFull)` if no free slot is left,
+    /// `Err(HypervisorError::HookError)` if the region is not mapped in the primary EPT, or the
+    /// error of the failed EPT operation.
+    pub fn install_large(
+        &mut self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+        shadow_region_pa: u64,
+        read_policy: HookReadPolicy,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let guest_page_pa = large_page_align(guest_pa);
+
+        if self
+            .hooks
+            .iter()
+            .flatten()
+            .any(|hook| large_page_align(hook.guest_page_pa) == guest_page_pa)
+        {
+            return Err(HypervisorError::HookAlreadyInstalled);
+        }
+
+        if primary_ept.split_pt_index(guest_page_pa).is_some()
+            || secondary_ept.split_pt_index(guest_page_pa).is_some()
+        {
+            log::error!(
+                "Cannot install a 2MB hook on a split page: {:#x}",
+                guest_page_pa
+            );
+            return Err(HypervisorError::PageAlreadySplit);
+        }
+
+        let slot_index = self
+            .hooks
+            .iter()
+            .position(|hook| hook.is_none())
+            .ok_or(HypervisorError::HookManagerFull)?;
+
+        let original_region_pa = primary_ept
+            .gpa_to_hpa(guest_page_pa)
+            .ok_or(HypervisorError::HookError)?;
+
+        let hook = EptHook {
+            guest_page_pa,
+            original_page_pa: original_region_pa,
+            shadow_page_pa: shadow_region_pa,
+            pt_table_indices: EptPair::default(),
+            large: true,
+            owns_split: EptPair::default(),
+            original_bytes: OriginalBytes::empty(),
+            enabled: true,
+            strategy: HookStrategy::EptSwap,
+            read_policy,
+            steps: 0,
+        };
+        hook.apply(
+            primary_ept,
+            secondary_ept,
+            self.shadow_access,
+            reserved_regions,
+        )?;
+
+        self.hooks[slot_index] = Some(hook);
+
+        Ok(())
+    }
+
+    /// Enables or disables the hook on the page containing the given guest physical address.
+    ///
+    /// Disabling maps the page in the secondary EPT back to the original page with the same
+    /// permissions as in the primary EPT, which are restored to read-write-execute, so the page no
+    /// longer causes EPT violations. Enabling restores the execute-only shadow mapping. The 2MB page
+    /// stays split either way.
+    ///
+    /// The caller is responsible for invalidating the EPT caches (`invept_all_contexts`) if the
+    /// EPTs are in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT.
+    /// * `secondary_ept` - The secondary EPT.
+    /// * `guest_pa` - Any guest physical address within the hooked page.
+    /// * `enabled` - Whether the hook should be active.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, `Err(HypervisorError::HookNotFound)` if the page is not hooked,
+    /// or the error of the failed EPT operation, in which case the hook and its mappings keep their
+    /// previous state.
+    pub fn set_enabled(
+        &mut self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+        enabled: bool,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let shadow_access = self.shadow_access;
+        let hook = self
+            .find_mut(page_align(guest_pa))
+            .ok_or(HypervisorError::HookNotFound)?;
+
+        if hook.enabled == enabled {
+            return Ok(());
+        }
+
+        // Processors still stepping the old mapping find no stepped hook when they finish.
+        hook.enabled = enabled;
+        hook.steps = 0;
+
+        if let Err(e) = hook.apply(primary_ept, secondary_ept, shadow_access, reserved_regions) {
+            // Write the previous mappings back, so the hook state matches the EPTs.
+            hook.enabled = !enabled;
+            hook.apply(primary_ept, secondary_ept, shadow_access, reserved_regions)?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Patches the shadow page of a hook without racing processors executing it.
+    ///
+    /// The shadow page is copied, the bytes are written to the copy, and the secondary EPT is
+    /// switched to the copy with a single store if an EPT-swap hook is enabled. An MTF hook maps the
+    /// copy on its next step instead. The original bytes under the
+    /// patched range are saved, to be restored by `remove_hook`. The previous shadow page is
+    /// never freed, since processors may keep executing it until they invalidate their EPT caches.
+    ///
+    /// The copy is allocated here, so this must not be called from a VM-exit handler. The caller is
+    /// responsible for invalidating the EPT caches (`invept_all_contexts`) if the EPTs are in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `secondary_ept` - The secondary EPT, in which the hooked page is mapped to the shadow page.
+    /// * `guest_pa` - The guest physical address of the first byte to patch.
+    /// * `bytes` - The bytes to write. They must not cross the end of the page.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address of the new shadow page, `Err(HypervisorError::HookNotFound)` if the
+    /// page is not hooked, `Err(HypervisorError::NotEnoughBytes)` if the bytes would cross the end of
+    /// the page, `Err(HypervisorError::LargeHookUnsupported)` for a 2MB hook, or the error of the
+    /// failed EPT operation.
+    pub fn patch(
+        &mut self,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+        bytes: &[u8],
+        reserved_regions: &ReservedRegions,
+    ) -> Result<u64, HypervisorError> {
+        let offset = (guest_pa - page_align(guest_pa)) as usize;
+        if offset + bytes.len() > BASE_PAGE_SIZE {
+            return Err(HypervisorError::NotEnoughBytes);
+        }
+
+        let hook = self
+            .find_mut(page_align(guest_pa))
+            .ok_or(HypervisorError::HookNotFound)?;
+
+        // Copying a whole 2MB shadow region per patch is not worth it; patch the region directly.
+        if hook.large {
+            return Err(HypervisorError::LargeHookUnsupported);
+        }
+
+        hook.original_bytes
+            .extend(hook.original_page_pa, offset, bytes.len())?;
+
+        hook.swap_shadow_page(secondary_ept, reserved_regions, |page| {
+            page[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        })
+    }
+
+    /// Removes the hook on the page containing the given guest physical address, leaving no trace of it.
+    ///
+    /// The original bytes are first written back into a fresh copy of the shadow page, which is
+    /// swapped in atomically like a patch if the hook is enabled. Only then is the page mapped back to
+    /// the original page in both EPTs, so a processor executing the page never sees a partially restored
+    /// instruction. If installing a hook split the 2MB page, no other hook is left on it, and it is
+    /// unchanged otherwise in both EPTs, the page is merged back into a large page. While other hooks
+    /// are left, one of them takes over the split instead. 2MB hooks are mapped back to their original
+    /// region, which was never split.
+    ///
+    /// The fresh copy is allocated here, so this must not be called from a VM-exit handler. The caller
+    /// is responsible for invalidating the EPT caches (`invept_all_contexts`) if the EPTs are in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT.
+    /// * `secondary_ept` - The secondary EPT.
+    /// * `guest_pa` - Any guest physical address within the hooked page.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, `Err(HypervisorError::HookNotFound)` if the page is not hooked,
+    /// or the error of the failed EPT operation, in which case the hook stays installed.
+    pub fn remove_hook(
+        &mut self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let shadow_access = self.shadow_access;
+        let slot_index = self
+            .hooks
+            .iter()
+            .position(|hook| hook.is_some_and(|hook| hook.contains(page_align(guest_pa))))
+            .ok_or(HypervisorError::HookNotFound)?;
+        let Some(hook) = self.hooks[slot_index].as_mut() else {
+            unreachable!("The slot was found occupied");
+        };
+
+        // A disabled or MTF hook does not map its shadow page, so no processor can be executing it.
+        if hook.enabled && !hook.large && hook.strategy == HookStrategy::EptSwap {
+            let original_bytes = hook.original_bytes;
+            let original_page_pa = hook.original_page_pa;
+            hook.swap_shadow_page(secondary_ept, reserved_regions, |page| {
+                original_bytes.restore(page, original_page_pa)
+            })?;
+        }
+
+        hook.enabled = false;
+        hook.apply(primary_ept, secondary_ept, shadow_access, reserved_regions)?;
+
+        let removed = *hook;
+        self.hooks[slot_index] = None;
+
+        let remaining_hook = self.hooks.iter_mut().flatten().find(|hook| {
+            large_page_align(hook.guest_page_pa) == large_page_align(removed.guest_page_pa)
+        });
+
+        match remaining_hook {
+            // The page stays split for the remaining hook, which merges it once it is removed itself.
+            Some(hook) => {
+                hook.owns_split.primary |= removed.owns_split.primary;
+                hook.owns_split.secondary |= removed.owns_split.secondary;
+            }
+            None => removed.merge_owned_splits(primary_ept, secondary_ept)?,
+        }
+
+        Ok(())
+    }
+
+    /// Maps the shadow page of an enabled MTF hook for execution, to single-step one instruction.
+    ///
+    /// The shadow page is mapped read-execute in both EPTs, since the guest may run on either. The
+    /// EPTs are shared by all processors, so the step is counted: processors that step the same page at
+    /// the same time keep the shadow page mapped until the last of them calls `end_step`. Meanwhile,
+    /// other processors reading the page see the shadow page too, which is the tradeoff of the MTF
+    /// strategy on processors without execute-only translations. From a VM-exit handler, the manager
+    /// and the EPTs must be taken from `SharedData::lock_epts`.
+    ///
+    /// The caller sets the monitor trap flag and calls `end_step` on the following MTF VM exit, and is
+    /// responsible for invalidating the EPT caches (`invept_all_contexts`).
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT.
+    /// * `secondary_ept` - The secondary EPT.
+    /// * `guest_pa` - Any guest physical address within the hooked page.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, `Err(HypervisorError::HookNotFound)` if the page has no enabled
+    /// MTF hook, or the error of the failed EPT operation.
+    pub fn begin_step(
+        &mut self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let hook = self
+            .find_stepped(page_align(guest_pa))
+            .ok_or(HypervisorError::HookNotFound)?;
+
+        // Another processor is stepping the page, so the shadow page is already mapped.
+        if hook.steps > 0 {
+            hook.steps += 1;
+            return Ok(());
+        }
+
+        for (ept, pt_table_index) in [
+            (primary_ept, hook.pt_table_indices.primary),
+            (secondary_ept, hook.pt_table_indices.secondary),
+        ] {
+            ept.modify_page_permissions(
+                hook.guest_page_pa,
+                AccessType::READ_EXECUTE,
+                pt_table_index,
+            )?;
+            ept.remap_gpa_to_hpa(
+                hook.guest_page_pa,
+                hook.shadow_page_pa,
+                pt_table_index,
+                reserved_regions,
+            )?;
+        }
+
+        hook.steps = 1;
+
+        Ok(())
+    }
+
+    /// Maps the original page of an MTF hook back after a single-stepped instruction, once no other
+    /// processor is stepping it, see `begin_step`.
+    ///
+    /// The caller is responsible for invalidating the EPT caches (`invept_all_contexts`).
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT.
+    /// * `secondary_ept` - The secondary EPT.
+    /// * `guest_pa` - Any guest physical address within the hooked page.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, `Err(HypervisorError::HookNotFound)` if the page has no enabled
+    /// MTF hook, or the error of the failed EPT operation.
+    pub fn end_step(
+        &mut self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_pa: u64,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let shadow_access = self.shadow_access;
+        let hook = self
+            .find_stepped(page_align(guest_pa))
+            .ok_or(HypervisorError::HookNotFound)?;
+
+        hook.steps = hook.steps.saturating_sub(1);
+        if hook.steps > 0 {
+            return Ok(());
+        }
+
+        hook.apply(primary_ept, secondary_ept, shadow_access, reserved_regions)
+    }
+
+    /// Returns the strategy of the enabled hook on the page containing the given guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Any guest physical address within the page.
+    ///
+    /// # Returns
+    ///
+    /// The `HookStrategy` of the hook, or `None` if the page has no enabled hook.
+    pub fn strategy(&self, guest_pa: u64) -> Option<HookStrategy> {
+        self.hooks
+            .iter()
+            .flatten()
+            .find(|hook| hook.contains(guest_pa) && hook.enabled)
+            .map(|hook| hook.strategy)
+    }
+
+    /// Returns whether the page containing the given guest physical address has an enabled hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Any guest physical address within the page.
+    pub fn is_enabled(&self, guest_pa: u64) -> bool {
+        self.hooks
+            .iter()
+            .flatten()
+            .any(|hook| hook.contains(guest_pa) && hook.enabled)
+    }
+
+    /// Finds the enabled MTF hook for a page-aligned guest physical address.
+    fn find_stepped(&mut self, guest_page_pa: u64) -> Option<&mut EptHook> {
+        self.hooks.iter_mut().flatten().find(|hook| {
+            hook.contains(guest_page_pa) && hook.enabled && hook.strategy == HookStrategy::Mtf
+        })
+    }
+
+    /// Finds the hook covering a page-aligned guest physical address.
+    fn find_mut(&mut self, guest_page_pa: u64) -> Option<&mut EptHook> {
+        self.hooks
+            .iter_mut()
+            .flatten()
+            .find(|hook| hook.contains(guest_page_pa))
+    }
+}
+
+impl EptHook {
+    /// Replaces the shadow page of a 4KB hook with a modified copy, without racing processors executing it.
+    ///
+    /// The copy is flushed from the caches and, if an EPT-swap hook is enabled, mapped in the secondary
+    /// EPT with a single store to the PTE. An enabled MTF hook maps the original page outside of its
+    /// steps, so the copy is mapped by the next `EptHookManager::begin_step`. The previous shadow page
+    /// is never freed.
+    ///
+    /// # Arguments
+    ///
+    /// * `secondary_ept` - The secondary EPT, in which the hooked page is mapped to the shadow page.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    /// * `modify` - Modifies the copy of the shadow page.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address of the new shadow page, the error of `modify`, or the error of the
+    /// failed address translation or EPT operation.
+    fn swap_shadow_page(
+        &mut self,
+        secondary_ept: &mut Ept,
+        reserved_regions: &ReservedRegions,
+        modify: impl FnOnce(&mut [u8]) -> Result<(), HypervisorError>,
+    ) -> Result<u64, HypervisorError> {
+        let shadow_page = Box::leak(unsafe { box_zeroed::<Page>() }) as *mut Page as *mut u8;
+        let page = unsafe { core::slice::from_raw_parts_mut(shadow_page, BASE_PAGE_SIZE) };
+
+        page.copy_from_slice(unsafe { page_bytes(self.shadow_page_pa)? });
+        modify(page)?;
+
+        // The whole copy must reach memory before the EPT maps it for execution.
+        flush_cache_range(shadow_page as u64, BASE_PAGE_SIZE);
+
+        let shadow_page_pa = PhysicalAddress::from_host_va(shadow_page as u64)?;
+
+        if self.enabled && self.strategy == HookStrategy::EptSwap {
+            secondary_ept.remap_gpa_to_hpa(
+                self.guest_page_pa,
+                shadow_page_pa,
+                self.pt_table_indices.secondary,
+                reserved_regions,
+            )?;
+        }
+
+        self.shadow_page_pa = shadow_page_pa;
+
+        Ok(shadow_page_pa)
+    }
+
+    /// Returns whether the hooked 4KB or 2MB page contains a guest physical address.
+    fn contains(&self, guest_pa: u64) -> bool {
+        match self.large {
+            true => large_page_align(guest_pa) == self.guest_page_pa,
+            false => page_align(guest_pa) == self.guest_page_pa,
+        }
+    }
+
+    /// Writes the mappings for the current state of the hook into both EPTs.
+    ///
+    /// `shadow_access` is the permissions of the shadow page in the secondary EPT while an EPT-swap
+    /// hook is enabled, unless its read policy makes it readable. An enabled MTF hook maps the original page read-write in both EPTs, so that
+    /// executing it causes an EPT violation whichever EPT is active.
+    fn apply(
+        &self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        shadow_access: AccessType,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let (primary_access, secondary_access, secondary_hpa) =
+            if self.enabled && self.strategy == HookStrategy::Mtf {
+                (
+                    AccessType::READ_WRITE,
+                    AccessType::READ_WRITE,
+                    self.original_page_pa,
+                )
+            } else if self.enabled {
+                let shadow_access = match self.read_policy {
+                    HookReadPolicy::Original => shadow_access,
+                    HookReadPolicy::Shadow => AccessType::READ_EXECUTE,
+                };
+                (AccessType::READ_WRITE, shadow_access, self.shadow_page_pa)
+            } else {
+                (
+                    AccessType::READ_WRITE_EXECUTE,
+                    AccessType::READ_WRITE_EXECUTE,
+                    self.original_page_pa,
+                )
+            };
+
+        if self.large {
+            primary_ept.modify_large_page_permissions(self.guest_page_pa, primary_access)?;
+            primary_ept.remap_gpa_to_hpa_2mb(
+                self.guest_page_pa,
+                self.original_page_pa,
+                reserved_regions,
+            )?;
+            secondary_ept.modify_large_page_permissions(self.guest_page_pa, secondary_access)?;
+            return secondary_ept.remap_gpa_to_hpa_2mb(
+                self.guest_page_pa,
+                secondary_hpa,
+                reserved_regions,
+            );
+        }
+
+        primary_ept.modify_page_permissions(
+            self.guest_page_pa,
+            primary_access,
+            self.pt_table_indices.primary,
+        )?;
+        primary_ept.remap_gpa_to_hpa(
+            self.guest_page_pa,
+            self.original_page_pa,
+            self.pt_table_indices.primary,
+            reserved_regions,
+        )?;
+        secondary_ept.modify_page_permissions(
+            self.guest_page_pa,
+            secondary_access,
+            self.pt_table_indices.secondary,
+        )?;
+        secondary_ept.remap_gpa_to_hpa(
+            self.guest_page_pa,
+            secondary_hpa,
+            self.pt_table_indices.secondary,
+            reserved_regions,
+        )
+    }
+
+    /// Merges the 2MB page containing a 4KB hook back in each EPT in which the hook owns the split, if
+    /// it is unchanged otherwise. Merging releases the page table of the split.
+    ///
+    /// Must only be called once no other hook is left on the 2MB page.
+    fn merge_owned_splits(
+        &self,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+    ) -> Result<(), HypervisorError> {
+        for (ept, owns_split) in [
+            (secondary_ept, self.owns_split.secondary),
+            (primary_ept, self.owns_split.primary),
+        ] {
+            if owns_split && ept.is_identity_split(self.guest_page_pa) {
+                ept.merge_4kb_to_2mb(self.guest_page_pa)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a shadow copy of the page containing `guest_pa` with an absolute jump to `handler_va`
+/// written at `guest_pa`.
+///
+/// The shadow page is allocated here and never freed, so this must not be called from a VM-exit
+/// handler. The first bytes of the original function are overwritten, so the handler cannot call
+/// the original function through `guest_pa`.
+///
+/// The patched bytes are flushed from the caches before returning. Callers must keep this order:
+/// patch and flush the shadow page, then map it into the EPT (`EptHookManager::install`), then
+/// invalidate the EPT caches with INVEPT if the EPT is in use. INVEPT only drops cached
+/// translations, so the shadow page contents must already be visible when the new translation is
+/// first used by the guest.
+///
+/// # Arguments
+///
+/// * `ept` - The EPT through which the original page is read.
+/// * `guest_pa` - The guest physical address of the function to hook.
+/// * `handler_va` - The guest virtual address the function jumps to. Equals the physical address
+///   while the guest runs on the firmware's identity map.
+///
+/// # Returns
+///
+/// The host physical address of the shadow page, `Err(HypervisorError::NotEnoughBytes)` if the jump
+/// would cross the end of the page, or `Err(HypervisorError::HookError)` if the page is not mapped.
+pub fn create_inline_hook_shadow_page(
+    ept: &Ept,
+    guest_pa: u64,
+    handler_va: u64,
+) -> Result<u64, HypervisorError> {
+    let offset = (guest_pa - page_align(guest_pa)) as usize;
+    if offset + INLINE_HOOK_SIZE > BASE_PAGE_SIZE {
+        return Err(HypervisorError::NotEnoughBytes);
+    }
+
+    let original_page_pa = ept
+        .gpa_to_hpa(page_align(guest_pa))
+        .ok_or(HypervisorError::HookError)?;
+
+    let shadow_page = Box::leak(unsafe { box_zeroed::<Page>() }) as *mut Page as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(original_page_pa as *const u8, shadow_page, BASE_PAGE_SIZE);
+
+        let hook = shadow_page.add(offset);
+        core::ptr::copy_nonoverlapping(INLINE_JUMP.as_ptr(), hook, INLINE_JUMP.len());
+        (hook.add(INLINE_JUMP.len()) as *mut u64).write_unaligned(handler_va);
+    }
+
+    // The patched bytes must reach memory before the EPT maps the shadow page for execution.
+    flush_cache_range(shadow_page as u64 + offset as u64, INLINE_HOOK_SIZE);
+
+    Ok(shadow_page as u64)
+}
+
+/// Creates a shadow copy of the 2MB region containing `guest_pa`, for `EptHookManager::install_large`.
+///
+/// The region is allocated 2MB aligned here and never freed, so this must not be called from a VM-exit
+/// handler. Patch the copy before installing the hook, and flush the patched bytes from the caches
+/// like `create_inline_hook_shadow_page` does.
+///
+/// # Arguments
+///
+/// * `ept` - The EPT through which the original region is read.
+/// * `guest_pa` - Any guest physical address within the region.
+///
+/// # Returns
+///
+/// The 2MB-aligned host physical address of the shadow region, `Err(HypervisorError::HookError)` if
+/// the region is not mapped, or `Err(HypervisorError::OutOfMemory)` if it cannot be allocated.
+pub fn create_large_shadow_region(ept: &Ept, guest_pa: u64) -> Result<u64, HypervisorError> {
+    let original_region_pa = ept
+        .gpa_to_hpa(large_page_align(guest_pa))
+        .ok_or(HypervisorError::HookError)?;
+
+    let shadow_region =
+        Box::leak(unsafe { try_box_zeroed::<LargePage>()? }) as *mut LargePage as *mut u8;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            original_region_pa as *const u8,
+            shadow_region,
+            LARGE_PAGE_SIZE,
+        )
+    };
+
+    flush_cache_range(shadow_region as u64, LARGE_PAGE_SIZE);
+
+    Ok(shadow_region as u64)
+}
+
+/// Splits the 2MB page containing a page to hook in an EPT, unless it is split already.
+///
+/// # Arguments
+///
+/// * `ept` - The EPT to split the page in, with a page table from its own allocator.
+/// * `guest_page_pa` - The page-aligned guest physical address of the page to hook.
+///
+/// # Returns
+///
+/// The index of the page table mapping the page and whether the 2MB page was split here, or an
+/// `Err(HypervisorError)` if it could not be split, in which case no page table is allocated.
+fn split_for_hook(ept: &mut Ept, guest_page_pa: u64) -> Result<(usize, bool), HypervisorError> {
+    match ept.split_pt_index(guest_page_pa) {
+        Some(pt_table_index) => Ok((pt_table_index, false)),
+        None => Ok((ept.split_2mb_to_4kb_alloc(guest_page_pa)?, true)),
+    }
+}
+
+/// Returns the bytes of a 4KB page in host physical memory, accessed through
+/// `PhysicalAddress::to_host_va`.
+///
+/// # Safety
+///
+/// The page must not be written through another reference while the slice lives.
+///
+/// # Returns
+///
+/// The bytes of the page, or `Err(HypervisorError::HostPhysicalAddressNotMapped)` if the hypervisor has
+/// no mapping of it.
+unsafe fn page_bytes<'a>(page_pa: u64) -> Result<&'a [u8], HypervisorError> {
+    let page_va = PhysicalAddress::to_host_va(page_pa)?;
+    Ok(unsafe { core::slice::from_raw_parts(page_va as *const u8, BASE_PAGE_SIZE) })
+}
+
+/// Aligns a guest physical address down to its 4KB page.
+fn page_align(guest_pa: u64) -> u64 {
+    guest_pa & !(BASE_PAGE_SIZE as u64 - 1)
+}
+
+/// Aligns a guest physical address down to its 2MB page.
+fn large_page_align(guest_pa: u64) -> u64 {
+    guest_pa & !(LARGE_PAGE_SIZE as u64 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::intel::{ept::mtrr::Mtrr, vm::box_zeroed},
+        alloc::vec,
+    };
+
+    /// A 2MB page outside the first 2MB, which are always mapped by `pt[0]`.
+    const LARGE_PAGE_GPA: u64 = 0x40_0000;
+
+    /// Builds an identity-mapped EPT in memory, with write-back memory everywhere.
+    fn build_ept() -> Box<Ept> {
+        let mut ept = unsafe { box_zeroed::<Ept>() };
+        ept.build_identity_with_page_sizes(Mtrr::from_descriptors(vec![]), false, &[])
+            .unwrap();
+        ept
+    }
+
+    /// Registers a disabled hook on a page of a 2MB page that is split in both EPTs, without touching
+    /// the contents of the page.
+    fn add_disabled_hook(
+        hook_manager: &mut EptHookManager,
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        guest_page_pa: u64,
+    ) {
+        let (primary, primary_owns_split) = split_for_hook(primary_ept, guest_page_pa).unwrap();
+        let (secondary, secondary_owns_split) =
+            split_for_hook(secondary_ept, guest_page_pa).unwrap();
+
+        let slot = hook_manager
+            .hooks
+            .iter_mut()
+            .find(|hook| hook.is_none())
+            .unwrap();
+        *slot = Some(EptHook {
+            guest_page_pa,
+            original_page_pa: guest_page_pa,
+            shadow_page_pa: guest_page_pa,
+            pt_table_indices: EptPair { primary, secondary },
+            large: false,
+            owns_split: EptPair {
+                primary: primary_owns_split,
+                secondary: secondary_owns_split,
+            },
+            original_bytes: OriginalBytes::empty(),
+            enabled: false,
+            strategy: HookStrategy::EptSwap,
+            read_policy: HookReadPolicy::Original,
+            steps: 0,
+        });
+    }
+
+    #[test]
+    fn removing_hooks_out_of_order_merges_split() {
+        let mut primary_ept = build_ept();
+        let mut secondary_ept = build_ept();
+        let mut hook_manager = EptHookManager::new(true);
+        let reserved_regions = ReservedRegions::new();
+        let first = LARGE_PAGE_GPA + 0x1000;
+        let second = LARGE_PAGE_GPA + 0x2000;
+
+        for guest_page_pa in [first, second] {
+            add_disabled_hook(
+                &mut hook_manager,
+                &mut primary_ept,
+                &mut secondary_ept,
+                guest_page_pa,
+            );
+        }
+
+        hook_manager
+            .remove_hook(
+                &mut primary_ept,
+                &mut secondary_ept,
+                first,
+                &reserved_regions,
+            )
+            .unwrap();
+        assert!(primary_ept.split_pt_index(LARGE_PAGE_GPA).is_some());
+        assert!(secondary_ept.split_pt_index(LARGE_PAGE_GPA).is_some());
+
+        hook_manager
+            .remove_hook(
+                &mut primary_ept,
+                &mut secondary_ept,
+                second,
+                &reserved_regions,
+            )
+            .unwrap();
+        assert_eq!(primary_ept.split_pt_index(LARGE_PAGE_GPA), None);
+        assert_eq!(secondary_ept.split_pt_index(LARGE_PAGE_GPA), None);
+    }
+}
@@ -1,3 +1,9 @@
-// pub mod hooks;
+pub mod benchmark;
+pub mod cow;
+pub mod hooks;
 pub mod mtrr;
 pub mod paging;
+pub mod snapshot;
+pub mod temporary;
+pub mod throttle;
+pub mod tracking;
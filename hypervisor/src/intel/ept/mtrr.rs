@@ -59,6 +59,15 @@ impl Mtrr {
         Self { descriptors }
     }
 
+    /// Builds a map from known memory ranges instead of the MTRRs of the processor, e.g. to build an
+    /// EPT in a unit test, where the MTRR MSRs cannot be read.
+    ///
+    /// # Arguments
+    /// * `descriptors` - The memory ranges that are not write-back.
+    pub fn from_descriptors(descriptors: Vec<MtrrRangeDescriptor>) -> Self {
+        Self { descriptors }
+    }
+
     /// Finds the memory type for a given physical address range based on the MTRR map.
     ///
     /// This method examines the MTRR map to find the appropriate memory type for the
@@ -99,6 +108,32 @@ impl Mtrr {
         memory_type.or(Some(MemoryType::WriteBack))
     }
 
+    /// Finds the memory type for a physical address range that must have a single memory type.
+    ///
+    /// Unlike `find`, this method fails if any MTRR range only partially overlaps the given range,
+    /// which means the range has mixed memory types and cannot be mapped with a single large page.
+    ///
+    /// # Arguments
+    /// * `range` - The physical address range for which to find the memory type.
+    ///
+    /// # Returns
+    /// The memory type for the whole range, or `None` if the range has mixed memory types.
+    pub fn find_uniform(&mut self, range: core::ops::Range<u64>) -> Option<MemoryType> {
+        let is_mixed = self.descriptors.iter().any(|descriptor| {
+            let overlaps =
+                descriptor.base_address < range.end && descriptor.end_address >= range.start;
+            let contains =
+                descriptor.base_address <= range.start && descriptor.end_address >= range.end - 1;
+            overlaps && !contains
+        });
+
+        if is_mixed {
+            return None;
+        }
+
+        self.find(range)
+    }
+
     /// Calculates the end address of an MTRR memory range.
     ///
     /// # Arguments
@@ -8,16 +8,50 @@
 use {
     crate::{
         error::HypervisorError,
-        intel::ept::mtrr::{MemoryType, Mtrr},
+        intel::{
+            addresses::PhysicalAddress,
+            controls::{is_vmx_control_supported, VmxControl},
+            ept::{
+                mtrr::{MemoryType, Mtrr},
+                temporary::TemporaryAccess,
+            },
+            reserved::ReservedRegions,
+            support::rdmsr,
+        },
     },
+    alloc::vec::Vec,
+    bit_field::BitField,
     bitfield::bitfield,
-    core::ptr::addr_of,
+    core::{mem::size_of, ops::Range, ptr::addr_of},
     log::*,
     x86::bits64::paging::{
-        pd_index, pdpt_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE,
+        pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE,
+        HUGE_PAGE_SIZE, LARGE_PAGE_SIZE,
     },
 };
 
+/// Physical address ranges that `build_identity` always maps with 4KB pages, each with its own memory type.
+///
+/// Add known MMIO holes above the first 2MB here so they do not force the memory type of a whole
+/// 2MB page. Every 2MB page overlapping a range uses one of the 63 page tables available for splits.
+/// The default covers the legacy ISA memory hole, see `ISA_MEMORY_HOLE`.
+pub const FORCE_4KB_RANGES: &[Range<u64>] = &[ISA_MEMORY_HOLE];
+
+/// The legacy ISA memory hole at 15MB-16MB, which chipsets can map to the ISA bus.
+const ISA_MEMORY_HOLE: Range<u64> = 0xf0_0000..0x100_0000;
+
+/// Guest physical addresses whose 2MB pages `build_identity` splits up front and keeps split, see `Ept::presplit`.
+///
+/// Add regions that are hooked often, such as guest page tables or frequently patched code, so hooks can be
+/// toggled there without splitting on demand and the INVEPT that follows. Addresses in the same 2MB page share
+/// a page table, and addresses in the first 2MB, which `pt[0]` always maps, need none.
+///
+/// Each listed 2MB page permanently takes one of the 63 page tables available for splits, which are shared
+/// with `FORCE_4KB_RANGES`, `set_memory_type_range`, and hooks installed at runtime. Hooks on pages that are
+/// not presplit fail with `HypervisorError::NoFreePtIndex` once the pool is exhausted, so keep the list short.
+/// Presplitting the 2MB page at 4MB also makes `Ept::self_test` fail, since it splits that page itself.
+pub const PRESPLIT_GPAS: &[u64] = &[];
+
 /// Represents the entire Extended Page Table structure.
 ///
 /// EPT is a set of nested page tables similar to the standard x86-64 paging mechanism.
@@ -36,23 +70,100 @@ pub struct Ept {
     /// We reserve 1-63 PTs for splitting large 2MB pages into 512 smaller 4KB pages for a given guest physical address (`split_2mb_to_4kb`)
     /// Pt[0] is used for the first 2MB of the physical address space, when calling `build_identity`
     pt: [Pt; 64],
+    /// Bitmap of the indices in `pt` that are in use by a split. Bit 0 is never allocated, as `pt[0]` is reserved.
+    used_pt_indices: u64,
+    /// Bitmap of the indices in `pt` used by splits made with `presplit`, which `merge_4kb_to_2mb` keeps in place.
+    presplit_pt_indices: u64,
+    /// How permission changes that make a page both writable and executable are treated.
+    wx_policy: WxPolicy,
+}
+
+/// How an EPT treats permission changes that would make a page both writable and executable (W^X).
+///
+/// The policy applies to `modify_page_permissions` and `modify_page_permissions_with_verification`.
+/// The identity map built by `build_identity` stays read-write-execute, since the hypervisor does
+/// not know which guest pages hold code.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WxPolicy {
+    /// Writable and executable pages are allowed. This is the default.
+    Disabled = 0,
+
+    /// Writable and executable pages are allowed, but logged.
+    Log = 1,
+
+    /// The execute permissions are stripped from writable pages, and the change is logged.
+    Strip = 2,
+}
+
+/// Redirects a guest physical range to a different host physical range, applied by `Ept::apply_remaps`.
+///
+/// Used for experiments such as backing an MMIO window with a fake device. Both ranges must be 4KB
+/// aligned and have the same length.
+#[derive(Debug, Clone)]
+pub struct Remap {
+    /// The guest physical range to redirect.
+    pub guest_range: Range<u64>,
+
+    /// The host physical range backing `guest_range`.
+    pub host_range: Range<u64>,
+
+    /// The memory type of the remapped pages, e.g. `MemoryType::Uncacheable` for MMIO.
+    pub memory_type: MemoryType,
 }
 
 impl Ept {
     /// Builds an identity-mapped Extended Page Table (EPT) structure with considerations for Memory Type Range Registers (MTRR).
     /// This function initializes the EPT with a 1:1 physical-to-virtual memory mapping,
     /// setting up the required PML4, PDPT, and PD entries for the initial memory range.
+    /// The paging-verification bits of all leaf entries are left clear.
     ///
     /// # Returns
     /// A result indicating the success or failure of the operation. In case of failure,
     /// a `HypervisorError` is returned, detailing the nature of the error.
     ///
     /// # Errors
-    /// This function returns an `Err(HypervisorError::MemoryTypeResolutionError)` if it fails
+    /// This function returns an `Err(HypervisorError::MemoryTypeResolutionError { .. })` if it fails
     /// to resolve memory types based on MTRR settings for any page.
     pub fn build_identity(&mut self) -> Result<(), HypervisorError> {
-        // Initialize a new MTRR instance for memory type resolution.
-        let mut mtrr = Mtrr::new();
+        self.build_identity_with_page_sizes(Mtrr::new(), false, FORCE_4KB_RANGES)
+    }
+
+    /// Builds an identity-mapped EPT like `build_identity`, but maps each gigabyte with a single 1GB page
+    /// where the MTRRs report a uniform memory type for it.
+    ///
+    /// Ranges with mixed memory types (such as the low MMIO region) and the first gigabyte, whose first 2MB
+    /// always need 4KB granularity, fall back to 2MB and 4KB pages. If the processor does not support
+    /// 1GB EPT pages, this is equivalent to `build_identity`.
+    ///
+    /// # Returns
+    /// A result indicating the success or failure of the operation.
+    ///
+    /// # Errors
+    /// This function returns an `Err(HypervisorError::MemoryTypeResolutionError { .. })` if it fails
+    /// to resolve memory types based on MTRR settings for any page.
+    pub fn build_identity_1gb(&mut self) -> Result<(), HypervisorError> {
+        let use_1gb_pages = Self::is_1gb_page_supported();
+
+        if !use_1gb_pages {
+            debug!("1GB EPT pages are not supported, falling back to 2MB pages");
+        }
+
+        self.build_identity_with_page_sizes(Mtrr::new(), use_1gb_pages, FORCE_4KB_RANGES)
+    }
+
+    /// Builds the identity map, optionally using 1GB pages for gigabytes with a uniform memory type.
+    ///
+    /// # Arguments
+    /// * `mtrr` - The MTRRs used to resolve the memory type of each page.
+    /// * `use_1gb_pages` - Whether 1GB PDPTE pages may be used.
+    /// * `force_4kb_ranges` - Physical address ranges that are mapped with 4KB pages with per-page memory types.
+    pub(crate) fn build_identity_with_page_sizes(
+        &mut self,
+        mut mtrr: Mtrr,
+        use_1gb_pages: bool,
+        force_4kb_ranges: &[Range<u64>],
+    ) -> Result<(), HypervisorError> {
         trace!("{mtrr:#x?}");
         trace!("Initializing EPTs");
 
@@ -60,36 +171,46 @@ impl Ept {
         let mut pa = 0u64;
 
         // Configure the first PML4 entry to point to the PDPT. This sets up the root of our page table.
-        self.pml4.0.entries[0].set_readable(true);
-        self.pml4.0.entries[0].set_writable(true);
-        self.pml4.0.entries[0].set_executable(true);
-        self.pml4.0.entries[0].set_pfn(addr_of!(self.pdpt) as u64 >> BASE_PAGE_SHIFT);
+        self.pml4.0.entries[0].set_access_type(AccessType::READ_WRITE_EXECUTE);
+        self.pml4.0.entries[0].set_pfn(table_pfn(addr_of!(self.pdpt))?);
 
         // Iterate through each PDPT entry to configure PDs.
         for (i, pdpte) in self.pdpt.0.entries.iter_mut().enumerate() {
-            pdpte.set_readable(true);
-            pdpte.set_writable(true);
-            pdpte.set_executable(true);
-            pdpte.set_pfn(addr_of!(self.pd[i]) as u64 >> BASE_PAGE_SHIFT);
+            pdpte.set_access_type(AccessType::READ_WRITE_EXECUTE);
+
+            // Map the whole gigabyte with a 1GB page if it has a single memory type. The first gigabyte
+            // is excluded because its first 2MB are mapped with 4KB pages.
+            if use_1gb_pages && pa != 0 {
+                if let Some(memory_type) = mtrr.find_uniform(pa..pa + HUGE_PAGE_SIZE as u64) {
+                    pdpte.set_memory_type(memory_type as u64);
+                    pdpte.set_large(true);
+                    pdpte.set_paging_verification(PagingVerification::empty());
+                    pdpte.set_pfn(pa >> BASE_PAGE_SHIFT);
+                    pa += HUGE_PAGE_SIZE as u64;
+                    continue;
+                }
+            }
+
+            pdpte.set_pfn(table_pfn(addr_of!(self.pd[i]))?);
 
             // Configure each PDE within a PD. The first PD manages the first 2MB with 4KB granularity.
             for pde in &mut self.pd[i].0.entries {
                 if pa == 0 {
                     // Handle the special case for the first 2MB to ensure MTRR types are correctly applied.
-                    pde.set_readable(true);
-                    pde.set_writable(true);
-                    pde.set_executable(true);
-                    pde.set_pfn(addr_of!(self.pt[0]) as u64 >> BASE_PAGE_SHIFT); // Use Pt[0] for the first 2MB
+                    // This range contains the memory real-mode code expects (IVT, BDA, EBDA, VGA, and option/BIOS ROMs
+                    // below 1MB), which the fixed-range MTRRs describe with 4KB granularity, so unrestricted guests
+                    // running in real or unpaged mode see the same memory types as on bare metal.
+                    pde.set_access_type(AccessType::READ_WRITE_EXECUTE);
+                    pde.set_pfn(table_pfn(addr_of!(self.pt[0]))?); // Use Pt[0] for the first 2MB
 
                     // Configure PT entries for the first 2MB, respecting MTRR settings, using Pt[0].
                     for pte in &mut self.pt[0].0.entries {
                         let memory_type = mtrr
                             .find(pa..pa + BASE_PAGE_SIZE as u64)
-                            .ok_or(HypervisorError::MemoryTypeResolutionError)?;
-                        pte.set_readable(true);
-                        pte.set_writable(true);
-                        pte.set_executable(true);
+                            .ok_or(HypervisorError::MemoryTypeResolutionError { pa })?;
+                        pte.set_access_type(AccessType::READ_WRITE_EXECUTE);
                         pte.set_memory_type(memory_type as u64);
+                        pte.set_paging_verification(PagingVerification::empty());
                         pte.set_pfn(pa >> BASE_PAGE_SHIFT);
                         pa += BASE_PAGE_SIZE as u64;
                     }
@@ -97,19 +218,108 @@ impl Ept {
                     // For the rest of the physical address space, configure PD entries for large pages (2MB).
                     let memory_type = mtrr
                         .find(pa..pa + LARGE_PAGE_SIZE as u64)
-                        .ok_or(HypervisorError::MemoryTypeResolutionError)?;
+                        .ok_or(HypervisorError::MemoryTypeResolutionError { pa })?;
 
-                    pde.set_readable(true);
-                    pde.set_writable(true);
-                    pde.set_executable(true);
+                    pde.set_access_type(AccessType::READ_WRITE_EXECUTE);
                     pde.set_memory_type(memory_type as u64);
                     pde.set_large(true);
+                    pde.set_paging_verification(PagingVerification::empty());
                     pde.set_pfn(pa >> BASE_PAGE_SHIFT);
                     pa += LARGE_PAGE_SIZE as u64;
                 }
             }
         }
 
+        for range in force_4kb_ranges {
+            self.map_with_4kb_memory_types(&mut mtrr, range.clone())?;
+        }
+
+        self.presplit(PRESPLIT_GPAS)
+    }
+
+    /// Maps every 2MB page overlapping a physical address range with 4KB pages, each with its own memory type.
+    ///
+    /// A large page only has a single memory type, which `Mtrr::find` resolves to the strictest type in
+    /// its range. MMIO holes that only cover part of a large page therefore need 4KB granularity to keep
+    /// the surrounding memory cacheable. Each split uses a page table from the allocator. The first 2MB
+    /// are always mapped with 4KB pages and are skipped.
+    ///
+    /// # Arguments
+    /// * `mtrr` - The MTRRs used to resolve the memory type of each 4KB page.
+    /// * `range` - The physical address range to map with 4KB pages.
+    ///
+    /// # Returns
+    /// A result indicating the success or failure of the operation.
+    fn map_with_4kb_memory_types(
+        &mut self,
+        mtrr: &mut Mtrr,
+        range: Range<u64>,
+    ) -> Result<(), HypervisorError> {
+        let mut large_page_pa = range.start & !(LARGE_PAGE_SIZE as u64 - 1);
+
+        while large_page_pa < range.end {
+            if large_page_pa != 0 {
+                trace!("Mapping {:#x} with 4kb pages", large_page_pa);
+
+                let pt_table_index = self.split_2mb_to_4kb_alloc(large_page_pa)?;
+                for (i, pte) in self.pt[pt_table_index].0.entries.iter_mut().enumerate() {
+                    let pa = large_page_pa + (i * BASE_PAGE_SIZE) as u64;
+                    let memory_type = mtrr
+                        .find(pa..pa + BASE_PAGE_SIZE as u64)
+                        .ok_or(HypervisorError::MemoryTypeResolutionError { pa })?;
+                    pte.set_memory_type(memory_type as u64);
+                }
+            }
+
+            large_page_pa += LARGE_PAGE_SIZE as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Makes this EPT an identical copy of another EPT.
+    ///
+    /// All table entries are copied from `other`, after which the entries that reference the
+    /// paging structures themselves (PML4E -> PDPT, PDPTE -> PD, and PDE -> PT) are rewritten to
+    /// point at this EPT's own tables. This keeps the secondary EPT in sync with the primary EPT
+    /// for non-hooked memory without resolving the MTRRs a second time.
+    ///
+    /// # Arguments
+    ///
+    /// * `other`: The EPT to copy, typically the identity-mapped primary EPT.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the EPT was copied, or an error if a paging structure has no host physical address.
+    pub fn clone_from(&mut self, other: &Ept) -> Result<(), HypervisorError> {
+        trace!("Cloning EPT");
+
+        // Copy the tables in place; `Ept` is too large to be moved through the stack.
+        unsafe { core::ptr::copy_nonoverlapping(other as *const Ept, self as *mut Ept, 1) };
+
+        // Point the PML4 entry at our own PDPT.
+        self.pml4.0.entries[0].set_pfn(table_pfn(addr_of!(self.pdpt))?);
+
+        // Point each PDPT entry that does not map a 1GB page at our own PD.
+        for (i, pdpte) in self.pdpt.0.entries.iter_mut().enumerate() {
+            if !pdpte.large() {
+                pdpte.set_pfn(table_pfn(addr_of!(self.pd[i]))?);
+            }
+        }
+
+        // Point each PDE that references one of the other EPT's page tables at our own copy.
+        for pd in &mut self.pd {
+            for pde in &mut pd.0.entries {
+                if pde.large() || !pde.readable() {
+                    continue;
+                }
+
+                if let Some(index) = other.find_pt_index(pde.pfn()) {
+                    pde.set_pfn(table_pfn(addr_of!(self.pt[index]))?);
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -122,28 +332,38 @@ impl Ept {
     ///
     /// * `guest_pa`: The guest physical address within the 2MB page that needs to be split.
     /// * `pt_table_index`: The index within the `pt` array of Page Tables to be used for this operation.
-    /// Must be in the range [1, 63] as `pt[0]` is reserved for the first 2MB of physical address space.
+    ///   Must be in the range [1, 63] as `pt[0]` is reserved for the first 2MB of physical address space.
+    /// * `reuse`: Whether `pt_table_index` may already be in use, e.g. because the caller has just
+    ///   allocated it with `alloc_pt_index`. Reusing a page table that backs another split silently
+    ///   remaps that region, so this should only be `true` for an index the caller owns.
     ///
     /// # Returns
     ///
-    /// A `Result<(), HypervisorError>` indicating if the operation was successful.
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful. Fails with
+    /// `HypervisorError::PageAlreadySplit` if the page is already split, or with
+    /// `HypervisorError::PtIndexAlreadyInUse` if the page table is in use and `reuse` is `false`.
     pub fn split_2mb_to_4kb(
         &mut self,
         guest_pa: u64,
         pt_table_index: usize,
+        reuse: bool,
     ) -> Result<(), HypervisorError> {
         trace!("Splitting 2mb page into 4kb pages: {:x}", guest_pa);
 
         // Ensure the PT index is valid.
         if pt_table_index == 0 || pt_table_index >= self.pt.len() {
             error!("Invalid PT index: {}", pt_table_index);
-            return Err(HypervisorError::InvalidPtIndex);
+            return Err(HypervisorError::InvalidPtIndex(pt_table_index));
         }
 
         let guest_pa = VAddr::from(guest_pa);
 
         let pdpt_index = pdpt_index(guest_pa);
         let pd_index = pd_index(guest_pa);
+
+        // A 1GB page has to be split into 2MB pages first.
+        self.split_1gb_to_2mb(pdpt_index)?;
+
         let pde = &mut self.pd[pdpt_index].0.entries[pd_index];
 
         // We can only split large pages and not page directories.
@@ -154,225 +374,1841 @@ impl Ept {
             return Err(HypervisorError::PageAlreadySplit);
         }
 
+        // Overwriting a page table that backs another split would silently remap that region.
+        if !reuse && self.used_pt_indices.get_bit(pt_table_index) {
+            error!("PT index {} is already in use", pt_table_index);
+            return Err(HypervisorError::PtIndexAlreadyInUse(pt_table_index));
+        }
+
         // Get the memory type of the large page, before we unmap (reset) it.
         let memory_type = pde.memory_type();
 
         // Unmap the 2MB page by resetting the page directory entry.
         Self::unmap_2mb(pde);
 
-        // Map the unmapped physical memory to 4KB pages.
+        // Map the unmapped physical memory to 4KB pages, starting at the base of the 2MB page. Page
+        // tables are reused after a merge, so each entry is cleared of the bits of its previous user.
+        let large_page_base = guest_pa.align_down_to_large_page();
         for (i, pte) in &mut self.pt[pt_table_index].0.entries.iter_mut().enumerate() {
-            let pa = (guest_pa.as_usize() + i * BASE_PAGE_SIZE) as u64;
-            pte.set_readable(true);
-            pte.set_writable(true);
-            pte.set_executable(true);
+            let pa = (large_page_base.as_usize() + i * BASE_PAGE_SIZE) as u64;
+            *pte = Entry(0);
+            pte.set_access_type(AccessType::READ_WRITE_EXECUTE);
             pte.set_memory_type(memory_type);
             pte.set_pfn(pa >> BASE_PAGE_SHIFT);
         }
 
         // Update the PDE to point to the new page table.
-        pde.set_readable(true);
-        pde.set_writable(true);
-        pde.set_executable(true);
-        pde.set_memory_type(memory_type);
+        pde.set_access_type(AccessType::READ_WRITE_EXECUTE);
+        pde.set_memory_type(0); // Bits 7:3 are reserved in a PDE that references a page table.
         pde.set_large(false); // This is no longer a large page.
-        pde.set_pfn(addr_of!(self.pt[pt_table_index]) as u64 >> BASE_PAGE_SHIFT);
+        pde.set_pfn(table_pfn(addr_of!(self.pt[pt_table_index]))?);
+
+        // Keep the allocator from handing out this page table while the split is in place.
+        self.used_pt_indices.set_bit(pt_table_index, true);
 
         Ok(())
     }
 
-    /// Modifies the access permissions for a page within the extended page table (EPT).
+    /// Splits a large 2MB page into 512 smaller 4KB pages, using a page table from the allocator.
     ///
-    /// This function adjusts the permissions of either a 2MB or a 4KB page based on its alignment.
-    /// It is the responsibility of the caller to ensure that the `guest_pa` is aligned to the size
-    /// of the page they intend to modify.
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The guest physical address within the 2MB page that needs to be split.
+    ///
+    /// # Returns
+    ///
+    /// The index of the page table used for the split, or an `Err(HypervisorError)` if no page table
+    /// is free or the split failed. The index is released again if the split failed.
+    pub fn split_2mb_to_4kb_alloc(&mut self, guest_pa: u64) -> Result<usize, HypervisorError> {
+        let pt_table_index = self.alloc_pt_index()?;
+
+        if let Err(e) = self.split_2mb_to_4kb(guest_pa, pt_table_index, true) {
+            self.free_pt_index(pt_table_index);
+            return Err(e);
+        }
+
+        Ok(pt_table_index)
+    }
+
+    /// Splits every 2MB page touched by a guest physical address range, e.g. a hooked function that
+    /// straddles a 2MB boundary.
+    ///
+    /// Large pages are split with page tables from the allocator, and 2MB pages that are already split
+    /// keep their page table. The operation is atomic: if any split fails, the pages split by this call
+    /// are merged back before the error is returned.
     ///
     /// # Arguments
     ///
-    /// * `guest_pa` - Guest physical address of the page whose permissions are to be changed.
-    /// * `access_type` - The new access permissions to set for the page.
-    /// * `pt_table_index`: The index within the `pt` array of Page Tables to be used for this operation.
-    /// Must be in the range [1, 63] as `pt[0]` is reserved for the first 2MB of physical address space.
+    /// * `guest_pa`: The guest physical address of the start of the range.
+    /// * `len`: The length of the range in bytes.
     ///
     /// # Returns
     ///
-    /// A `Result<(), HypervisorError>` indicating if the operation was successful.
-    pub fn modify_page_permissions(
+    /// The page-aligned guest physical address of every 4KB page in the range, paired with the index of
+    /// the page table that maps it, ready to be passed to `modify_page_permissions` or `remap_gpa_to_hpa`.
+    /// Returns `Err(HypervisorError::InvalidPtIndex(0))` if the range touches the first 2MB, which are
+    /// mapped by the reserved `pt[0]`.
+    pub fn prepare_hook_region(
         &mut self,
         guest_pa: u64,
-        access_type: AccessType,
-        pt_table_index: usize,
-    ) -> Result<(), HypervisorError> {
-        trace!("Modifying permissions for GPA {:x}", guest_pa);
+        len: usize,
+    ) -> Result<Vec<(u64, usize)>, HypervisorError> {
+        trace!("Preparing hook region {:#x} ({:#x} bytes)", guest_pa, len);
 
-        // Ensure the PT index is valid.
-        if pt_table_index == 0 || pt_table_index >= self.pt.len() {
-            error!("Invalid PT index: {}", pt_table_index);
-            return Err(HypervisorError::InvalidPtIndex);
+        let mut pages = Vec::new();
+        if len == 0 {
+            return Ok(pages);
         }
 
-        let guest_pa = VAddr::from(guest_pa);
+        let first_page = guest_pa & !(BASE_PAGE_SIZE as u64 - 1);
+        let end = guest_pa + len as u64;
 
-        // Ensure the guest physical address is aligned to a page boundary.
-        if !guest_pa.is_large_page_aligned() && !guest_pa.is_base_page_aligned() {
-            error!("Page is not aligned: {:#x}", guest_pa);
-            return Err(HypervisorError::UnalignedAddressError);
-        }
+        // The 2MB pages split by this call, so they can be merged back on failure.
+        let mut split_large_pages = Vec::new();
+        let mut large_page_pa = first_page & !(LARGE_PAGE_SIZE as u64 - 1);
 
-        let pdpt_index = pdpt_index(guest_pa);
-        let pd_index = pd_index(guest_pa);
-        let pt_index = pt_index(guest_pa);
+        while large_page_pa < end {
+            let result = match self.split_pt_index(large_page_pa) {
+                Some(0) => Err(HypervisorError::InvalidPtIndex(0)),
+                Some(pt_table_index) => Ok(pt_table_index),
+                None => self.split_2mb_to_4kb_alloc(large_page_pa).inspect(|_| {
+                    split_large_pages.push(large_page_pa);
+                }),
+            };
 
-        let pde = &mut self.pd[pdpt_index].0.entries[pd_index];
+            let pt_table_index = match result {
+                Ok(pt_table_index) => pt_table_index,
+                Err(e) => {
+                    for &pa in &split_large_pages {
+                        // Merging a page that was just split cannot fail.
+                        let _ = self.merge_4kb_to_2mb(pa);
+                    }
+                    return Err(e);
+                }
+            };
 
-        if pde.large() {
-            trace!("Changing the permissions of a 2mb page");
-            pde.set_readable(access_type.contains(AccessType::READ));
-            pde.set_writable(access_type.contains(AccessType::WRITE));
-            pde.set_executable(access_type.contains(AccessType::EXECUTE));
-        } else {
-            trace!("Changing the permissions of a 4kb page");
-            let pte = &mut self.pt[pt_table_index].0.entries[pt_index];
-            pte.set_readable(access_type.contains(AccessType::READ));
-            pte.set_writable(access_type.contains(AccessType::WRITE));
-            pte.set_executable(access_type.contains(AccessType::EXECUTE));
+            let region_start = first_page.max(large_page_pa);
+            let region_end = end.min(large_page_pa + LARGE_PAGE_SIZE as u64);
+            for page in (region_start..region_end).step_by(BASE_PAGE_SIZE) {
+                pages.push((page, pt_table_index));
+            }
+
+            large_page_pa += LARGE_PAGE_SIZE as u64;
         }
 
-        Ok(())
+        Ok(pages)
     }
 
-    /// Remaps a guest physical address to a new host physical address within the EPT.
+    /// Splits the 2MB pages containing a list of guest physical addresses and keeps them split.
     ///
-    /// This function updates the EPT entry corresponding to the provided guest physical address (GPA)
-    /// to map to the specified host physical address (HPA). It is designed to remap 4KB pages.
+    /// Hooks on these pages then only change their 4KB entries, without splitting a large page, allocating
+    /// a page table, or invalidating the translations of the whole 2MB page first. Pages that are already
+    /// split keep their page table. `merge_4kb_to_2mb` refuses to merge a presplit page, so the split
+    /// outlives the hooks placed in it.
+    ///
+    /// Every presplit 2MB page permanently uses one of the page tables available for splits, see
+    /// `PRESPLIT_GPAS`. The operation is atomic: if a split fails, the pages split by this call are merged
+    /// back before the error is returned.
     ///
     /// # Arguments
     ///
-    /// * `guest_pa` - The guest physical address that needs to be remapped.
-    /// * `host_pa` - The new host physical address to map the guest physical address to.
-    /// * `pt_table_index`: The index within the `pt` array of Page Tables to be used for this operation.
-    /// Must be in the range [1, 63] as `pt[0]` is reserved for the first 2MB of physical address space.
+    /// * `guest_pas`: The guest physical addresses to keep mapped with 4KB pages. Addresses in the first
+    ///   2MB, which are always mapped with 4KB pages by `pt[0]`, are skipped.
     ///
     /// # Returns
     ///
-    /// A `Result<(), HypervisorError>` indicating if the operation was successful. In case of failure,
-    /// a `HypervisorError` is returned, detailing the nature of the error.
-    pub fn remap_gpa_to_hpa(
-        &mut self,
-        guest_pa: u64,
-        host_pa: u64,
-        pt_table_index: usize,
-    ) -> Result<(), HypervisorError> {
-        trace!("Remapping GPA {:x} to HPA {:x}", guest_pa, host_pa);
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful. Fails with
+    /// `HypervisorError::NoFreePtIndex` if the page tables run out.
+    pub fn presplit(&mut self, guest_pas: &[u64]) -> Result<(), HypervisorError> {
+        let presplit_pt_indices = self.presplit_pt_indices;
 
-        // Ensure the PT index is valid.
-        if pt_table_index == 0 || pt_table_index >= self.pt.len() {
-            error!("Invalid PT index: {}", pt_table_index);
-            return Err(HypervisorError::InvalidPtIndex);
-        }
+        // The 2MB pages split by this call, so they can be merged back on failure.
+        let mut split_large_pages = Vec::new();
 
-        let guest_pa = VAddr::from(guest_pa);
-        let host_pa = VAddr::from(host_pa);
+        for &guest_pa in guest_pas {
+            let large_page_pa = guest_pa & !(LARGE_PAGE_SIZE as u64 - 1);
 
-        // Ensure both addresses are page aligned
-        if !guest_pa.is_base_page_aligned() || !host_pa.is_base_page_aligned() {
-            error!(
-                "Addresses are not aligned: GPA {:#x}, HPA {:#x}",
-                guest_pa, host_pa
-            );
-            return Err(HypervisorError::UnalignedAddressError);
+            let result = match self.split_pt_index(large_page_pa) {
+                Some(0) => continue,
+                Some(pt_table_index) => Ok(pt_table_index),
+                None => self.split_2mb_to_4kb_alloc(large_page_pa).inspect(|_| {
+                    split_large_pages.push(large_page_pa);
+                }),
+            };
+
+            match result {
+                Ok(pt_table_index) => {
+                    trace!("Presplit {:#x} using pt[{}]", large_page_pa, pt_table_index);
+                    self.presplit_pt_indices.set_bit(pt_table_index, true);
+                }
+                Err(e) => {
+                    error!("Failed to presplit {:#x}: {}", large_page_pa, e);
+                    self.presplit_pt_indices = presplit_pt_indices;
+                    for &pa in &split_large_pages {
+                        // Merging a page that was just split cannot fail.
+                        let _ = self.merge_4kb_to_2mb(pa);
+                    }
+                    return Err(e);
+                }
+            }
         }
 
-        // Calculate indexes for accessing the EPT hierarchy
-        let pdpt_index = pdpt_index(guest_pa);
-        let pd_index = pd_index(guest_pa);
-        let pt_index = pt_index(guest_pa);
+        Ok(())
+    }
 
-        let pde = &self.pd[pdpt_index].0.entries[pd_index];
+    /// Allocates an unused page table index for `split_2mb_to_4kb`.
+    ///
+    /// # Returns
+    ///
+    /// An index in the range [1, 63], or `Err(HypervisorError::NoFreePtIndex)` if all page tables are in use.
+    pub fn alloc_pt_index(&mut self) -> Result<usize, HypervisorError> {
+        let pt_table_index = (1..self.pt.len())
+            .find(|&index| !self.used_pt_indices.get_bit(index))
+            .ok_or(HypervisorError::NoFreePtIndex)?;
 
-        // Verify that we're not dealing with a large page mapping
-        if pde.large() {
-            error!("Cannot remap a large page: GPA {:#x}", guest_pa);
-            return Err(HypervisorError::LargePageRemapError);
-        }
+        self.used_pt_indices.set_bit(pt_table_index, true);
 
-        // Access the corresponding PT entry
-        let pte = &mut self.pt[pt_table_index].0.entries[pt_index];
+        Ok(pt_table_index)
+    }
 
-        // Update the PTE to point to the new HPA
-        pte.set_pfn(host_pa >> BASE_PAGE_SHIFT);
-        trace!(
-            "Updated PTE for GPA {:x} to point to HPA {:x}",
-            guest_pa,
-            host_pa
-        );
+    /// Releases a page table index, making it available to `alloc_pt_index` again.
+    ///
+    /// The page table must no longer be referenced, i.e. the split using it must have been merged.
+    /// Indices outside the range [1, 63] are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `pt_table_index`: The index to release.
+    pub fn free_pt_index(&mut self, pt_table_index: usize) {
+        if (1..self.pt.len()).contains(&pt_table_index) {
+            self.used_pt_indices.set_bit(pt_table_index, false);
+        }
+    }
 
-        Ok(())
+    /// Returns the number of page tables `alloc_pt_index` can still hand out.
+    pub fn free_pt_count(&self) -> usize {
+        (1..self.pt.len())
+            .filter(|&index| !self.used_pt_indices.get_bit(index))
+            .count()
     }
 
-    /// Unmaps a 2MB page by clearing the corresponding page directory entry.
-    ///
-    /// This function clears the entry, effectively removing any mapping for the 2MB page.
-    /// It's used when transitioning a region of memory from a single large page to multiple smaller pages or simply freeing the page.
+    /// Returns the index of the page table that maps the split 2MB page containing a guest physical address.
     ///
     /// # Arguments
     ///
-    /// * `entry`: Mutable reference to the page directory entry to unmap.
-    pub fn unmap_2mb(entry: &mut Entry) {
-        if !entry.readable() {
-            // The page is already not present; no action needed.
-            return;
+    /// * `guest_pa`: The guest physical address within the 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// The index within the `pt` array, or `None` if the 2MB page is not split.
+    pub fn split_pt_index(&self, guest_pa: u64) -> Option<usize> {
+        let guest_pa = VAddr::from(guest_pa);
+
+        if self.pdpt.0.entries[pdpt_index(guest_pa)].large() {
+            return None;
         }
 
-        // Unmap the large page and clear the flags
-        entry.set_readable(false);
-        entry.set_writable(false);
-        entry.set_executable(false);
-        entry.set_memory_type(0);
-        entry.set_large(false);
-        entry.set_pfn(0); // Reset the Page Frame Number
+        let pde = &self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+        if pde.large() || !pde.is_present() {
+            return None;
+        }
+
+        self.find_pt_index(pde.pfn())
     }
 
-    /// Unmaps a 4KB page, typically involved in deconstructing finer-grained page tables.
+    /// Returns whether a split 2MB page maps its 4KB pages exactly like the large page did.
     ///
-    /// This function wraps the unmap_2mb function, as the actual unmap logic is similar.
-    /// It's used for unmap operations specifically targeting 4KB pages.
+    /// This holds if every PTE identity-maps its page read-write-execute with the same memory type and
+    /// no other bits set, so `merge_4kb_to_2mb` would not revert any remap or permission change.
     ///
     /// # Arguments
     ///
-    /// * `entry`: Mutable reference to the page directory entry of the 4KB page to unmap.
-    #[allow(dead_code)]
-    fn unmap_4kb(entry: &mut Entry) {
-        // Delegate to the unmap_2mb function as the unmap logic is the same.
-        Self::unmap_2mb(entry);
+    /// * `guest_pa`: The guest physical address within the 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the page is split with a page table other than the reserved `pt[0]` and unchanged
+    /// since, `false` otherwise.
+    pub fn is_identity_split(&self, guest_pa: u64) -> bool {
+        let Some(pt_table_index) = self.split_pt_index(guest_pa).filter(|&index| index != 0) else {
+            return false;
+        };
+
+        let entries = &self.pt[pt_table_index].0.entries;
+        let first = entries[0];
+        let large_page_base = guest_pa & !(LARGE_PAGE_SIZE as u64 - 1);
+
+        first.pfn() == large_page_base >> BASE_PAGE_SHIFT
+            && first.access_type() == AccessType::READ_WRITE_EXECUTE
+            && entries
+                .iter()
+                .enumerate()
+                .all(|(i, entry)| entry.bits() == first.bits() + ((i as u64) << BASE_PAGE_SHIFT))
     }
 
-    /// Creates an Extended Page Table Pointer (EPTP) with a Write-Back memory type and a 4-level page walk.
+    /// Merges the 512 4KB pages of a split 2MB page back into a single identity-mapped 2MB page.
     ///
-    /// This function is used in the setup of Intel VT-x virtualization, specifically for configuring the EPT.
-    /// It encodes the provided physical base address of the EPT PML4 table into the EPTP format, setting
-    /// the memory type to Write-Back and indicating a 4-level page walk.
+    /// This reverts `split_2mb_to_4kb`, including any remapping and permission changes made to the
+    /// 4KB pages. The page table that was used for the split is left untouched and its index is
+    /// released, so it can be reused.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The guest physical address within the 2MB page that needs to be merged.
     ///
     /// # Returns
-    /// A `Result<u64, HypervisorError>` containing the configured EPTP value. Returns an error if
-    /// the base address is not properly aligned.
     ///
-    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.6 EPT Paging-Structure Entries
-    pub fn create_eptp_with_wb_and_4lvl_walk(&self) -> Result<u64, HypervisorError> {
-        // Get the virtual address of the PML4 table for EPT.
-        let addr = addr_of!(self.pml4) as u64;
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful. Fails with
+    /// `HypervisorError::PageSplitPinned` if the page was split by `presplit`.
+    pub fn merge_4kb_to_2mb(&mut self, guest_pa: u64) -> Result<(), HypervisorError> {
+        trace!("Merging 4kb pages into a 2mb page: {:x}", guest_pa);
 
-        // Get the physical address of the PML4 table for EPT.
-        let ept_pml4_base_addr = addr;
+        let guest_pa = VAddr::from(guest_pa);
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
 
-        // Represents the EPT page walk length for Intel VT-x, specifically for a 4-level page walk.
-        // The value is 3 (encoded as '3 << 3' in EPTP) because the EPTP encoding requires "number of levels minus one".
-        const EPT_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
+        if self.pdpt.0.entries[pdpt_index].large()
+            || self.pd[pdpt_index].0.entries[pd_index].large()
+        {
+            error!("Page is not split: {:#x}", guest_pa);
+            return Err(HypervisorError::PageNotSplit);
+        }
 
-        // Represents the memory type setting for Write-Back (WB) in the EPTP.
-        const EPT_MEMORY_TYPE_WB: u64 = MemoryType::WriteBack as u64;
+        // All 4KB pages of a split large page share the memory type of the large page.
+        let pt_table_index = self
+            .find_pt_index(self.pd[pdpt_index].0.entries[pd_index].pfn())
+            .ok_or(HypervisorError::PageNotSplit)?;
 
-        // Check if the base address is 4KB aligned (the lower 12 bits should be zero).
+        if self.presplit_pt_indices.get_bit(pt_table_index) {
+            trace!("Page is presplit: {:x}.", guest_pa);
+            return Err(HypervisorError::PageSplitPinned);
+        }
+
+        let memory_type = self.pt[pt_table_index].0.entries[0].memory_type();
+
+        let pde = &mut self.pd[pdpt_index].0.entries[pd_index];
+        pde.set_access_type(AccessType::READ_WRITE_EXECUTE);
+        pde.set_memory_type(memory_type);
+        pde.set_large(true);
+        pde.set_pfn(guest_pa.align_down_to_large_page().as_u64() >> BASE_PAGE_SHIFT);
+
+        self.free_pt_index(pt_table_index);
+
+        Ok(())
+    }
+
+    /// Splits a 1GB page into 512 2MB pages, keeping its permissions and memory type.
+    ///
+    /// Every PDPT entry has a dedicated page directory in `pd`, so no page table has to be reserved.
+    /// Does nothing if the PDPT entry does not map a 1GB page.
+    ///
+    /// # Arguments
+    ///
+    /// * `pdpt_index`: The index of the PDPT entry to split.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the page is no longer mapped by a 1GB page, or an error if the page directory has no
+    /// host physical address.
+    fn split_1gb_to_2mb(&mut self, pdpt_index: usize) -> Result<(), HypervisorError> {
+        let pdpte = self.pdpt.0.entries[pdpt_index];
+
+        if !pdpte.large() {
+            return Ok(());
+        }
+
+        let pd_pfn = table_pfn(addr_of!(self.pd[pdpt_index]))?;
+
+        trace!(
+            "Splitting 1gb page into 2mb pages: {:x}",
+            pdpte.pfn() << BASE_PAGE_SHIFT
+        );
+
+        for (i, pde) in self.pd[pdpt_index].0.entries.iter_mut().enumerate() {
+            let pa = (pdpte.pfn() << BASE_PAGE_SHIFT) + (i * LARGE_PAGE_SIZE) as u64;
+            pde.set_access_type(pdpte.access_type());
+            pde.set_memory_type(pdpte.memory_type());
+            pde.set_large(true);
+            pde.set_pfn(pa >> BASE_PAGE_SHIFT);
+        }
+
+        let pdpte = &mut self.pdpt.0.entries[pdpt_index];
+        pdpte.set_access_type(AccessType::READ_WRITE_EXECUTE);
+        pdpte.set_memory_type(0);
+        pdpte.set_large(false);
+        pdpte.set_pfn(pd_pfn);
+
+        Ok(())
+    }
+
+    /// Modifies the access permissions for a page within the extended page table (EPT).
+    ///
+    /// This function adjusts the permissions of either a 2MB or a 4KB page based on its alignment.
+    /// It is the responsibility of the caller to ensure that the `guest_pa` is aligned to the size
+    /// of the page they intend to modify. The paging-verification bits of the entry are left untouched.
+    /// To change a 2MB page without a page table index, use `modify_large_page_permissions`.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Guest physical address of the page whose permissions are to be changed.
+    /// * `access_type` - The new access permissions to set for the page.
+    /// * `pt_table_index`: The index within the `pt` array of Page Tables to be used for this operation.
+    /// Must be in the range [1, 63] as `pt[0]` is reserved for the first 2MB of physical address space.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful.
+    pub fn modify_page_permissions(
+        &mut self,
+        guest_pa: u64,
+        access_type: AccessType,
+        pt_table_index: usize,
+    ) -> Result<(), HypervisorError> {
+        trace!("Modifying permissions for GPA {:x}", guest_pa);
+
+        let access_type = self.apply_wx_policy(guest_pa, access_type);
+        let entry = self.leaf_entry_mut(guest_pa, pt_table_index)?;
+        entry.set_access_type(access_type);
+
+        Ok(())
+    }
+
+    /// Modifies the access permissions of a 2MB page directly in its PDE, without splitting it.
+    ///
+    /// Used to change the permissions of a whole 2MB region at once, e.g. to make a driver's code
+    /// execute-only for a 2MB hook, without spending a page table on 512 4KB pages. A 1GB page
+    /// containing the region is split into 2MB pages first. The W^X policy applies like in
+    /// `modify_page_permissions`.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 2MB-aligned guest physical address of the page.
+    /// * `access_type` - The new access permissions to set for the page.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::UnalignedAddressError)` if `guest_pa` is not 2MB
+    /// aligned, or `Err(HypervisorError::PageAlreadySplit)` if the page is mapped with 4KB pages.
+    pub fn modify_large_page_permissions(
+        &mut self,
+        guest_pa: u64,
+        access_type: AccessType,
+    ) -> Result<(), HypervisorError> {
+        trace!("Modifying permissions for 2MB GPA {:x}", guest_pa);
+
+        let guest_pa_addr = VAddr::from(guest_pa);
+        if !guest_pa_addr.is_large_page_aligned() {
+            error!("Page is not 2MB aligned: {:#x}", guest_pa);
+            return Err(HypervisorError::UnalignedAddressError(guest_pa));
+        }
+
+        let pdpt_index = pdpt_index(guest_pa_addr);
+        let pd_index = pd_index(guest_pa_addr);
+
+        self.split_1gb_to_2mb(pdpt_index)?;
+
+        if !self.pd[pdpt_index].0.entries[pd_index].large() {
+            error!("Page is not mapped by a large PDE: {:#x}", guest_pa);
+            return Err(HypervisorError::PageAlreadySplit);
+        }
+
+        let access_type = self.apply_wx_policy(guest_pa, access_type);
+        self.pd[pdpt_index].0.entries[pd_index].set_access_type(access_type);
+
+        Ok(())
+    }
+
+    /// Modifies the access permissions and the paging-verification bits for a page within the EPT.
+    ///
+    /// Behaves like `modify_page_permissions`, but also sets the "verify guest paging" (bit 57) and
+    /// "paging-write access" (bit 58) bits of the entry according to `verification`.
+    ///
+    /// These bits only take effect if the "guest-paging verification" and "EPT paging-write control"
+    /// tertiary processor-based VM-execution controls are enabled, which requires the "activate tertiary
+    /// controls" primary control. This hypervisor does not enable the tertiary controls, so the processor
+    /// currently ignores the bits. Use `is_paging_verification_supported` to check for hardware support.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Guest physical address of the page whose permissions are to be changed.
+    /// * `access_type` - The new access permissions to set for the page.
+    /// * `verification` - The paging-verification bits to set for the page. Bits not present are cleared.
+    /// * `pt_table_index`: The index within the `pt` array of Page Tables to be used for this operation.
+    ///   Must be in the range [1, 63] as `pt[0]` is reserved for the first 2MB of physical address space.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.2 EPT Violations
+    pub fn modify_page_permissions_with_verification(
+        &mut self,
+        guest_pa: u64,
+        access_type: AccessType,
+        verification: PagingVerification,
+        pt_table_index: usize,
+    ) -> Result<(), HypervisorError> {
+        trace!(
+            "Modifying permissions for GPA {:x} with paging verification {:?}",
+            guest_pa,
+            verification
+        );
+
+        let access_type = self.apply_wx_policy(guest_pa, access_type);
+        let entry = self.leaf_entry_mut(guest_pa, pt_table_index)?;
+        entry.set_access_type(access_type);
+        entry.set_paging_verification(verification);
+
+        Ok(())
+    }
+
+    /// Temporarily grants permissions on a 4KB page and runs a closure, typically one that arms the
+    /// monitor trap flag to single-step the guest over the page.
+    ///
+    /// The current permissions are recorded in `grants` for the current processor, and are restored by
+    /// `TemporaryAccess::restore`, which the monitor trap flag handler calls on the next VM exit. The
+    /// grant stays in place if the closure does not arrange for that. The W^X policy still applies to
+    /// the granted permissions. From a VM-exit handler, the EPT must be taken from `SharedData::lock_epts`,
+    /// like in the monitor trap flag handler. The caller is responsible for invalidating the EPT caches.
+    ///
+    /// # Arguments
+    ///
+    /// * `grants` - The registry recording the permissions to restore, usually `SharedData::temporary_access`.
+    /// * `guest_pa` - Any guest physical address within the page. The 2MB page containing it must be split.
+    /// * `access_type` - The permissions to grant, e.g. `AccessType::READ_WRITE_EXECUTE`.
+    /// * `f` - The closure to run once the permissions have been granted.
+    ///
+    /// # Returns
+    ///
+    /// The result of the closure, or an `Err(HypervisorError)` if the permissions could not be granted,
+    /// in which case the closure is not run.
+    pub fn with_temporary_access<R>(
+        &mut self,
+        grants: &TemporaryAccess,
+        guest_pa: u64,
+        access_type: AccessType,
+        f: impl FnOnce() -> R,
+    ) -> Result<R, HypervisorError> {
+        trace!(
+            "Temporarily granting {:?} on GPA {:x}",
+            access_type,
+            guest_pa
+        );
+
+        grants.grant(self, guest_pa, access_type)?;
+
+        Ok(f())
+    }
+
+    /// Returns the W^X policy of this EPT.
+    pub fn wx_policy(&self) -> WxPolicy {
+        self.wx_policy
+    }
+
+    /// Sets the W^X policy applied to later permission changes. Existing mappings are not changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `wx_policy` - The new policy.
+    pub fn set_wx_policy(&mut self, wx_policy: WxPolicy) {
+        self.wx_policy = wx_policy;
+    }
+
+    /// Applies the W^X policy to a requested permission change.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Guest physical address of the page whose permissions are changed, for logging.
+    /// * `access_type` - The requested permissions.
+    ///
+    /// # Returns
+    ///
+    /// The permissions to set for the page.
+    fn apply_wx_policy(&self, guest_pa: u64, access_type: AccessType) -> AccessType {
+        if !access_type.contains(AccessType::WRITE)
+            || !access_type.intersects(AccessType::EXECUTE_ALL_MODES)
+        {
+            return access_type;
+        }
+
+        match self.wx_policy {
+            WxPolicy::Disabled => access_type,
+            WxPolicy::Log => {
+                warn!("W^X: GPA {:#x} is made writable and executable", guest_pa);
+                access_type
+            }
+            WxPolicy::Strip => {
+                warn!(
+                    "W^X: stripping execute permissions from writable GPA {:#x}",
+                    guest_pa
+                );
+                access_type - AccessType::EXECUTE_ALL_MODES
+            }
+        }
+    }
+
+    /// Checks whether the processor supports the "verify guest paging" and "paging-write access" EPT bits.
+    ///
+    /// Both require the "activate tertiary controls" primary processor-based control (bit 17) and
+    /// the "EPT paging-write control" (bit 2) and "guest-paging verification" (bit 3) tertiary controls.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.3.4 Tertiary Processor-Based VM-Execution Controls
+    pub fn is_paging_verification_supported() -> bool {
+        const ACTIVATE_TERTIARY_CONTROLS: u64 = 1 << 17;
+        const IA32_VMX_PROCBASED_CTLS3: u32 = 0x492;
+        const EPT_PAGING_WRITE_CONTROL: u64 = 1 << 2;
+        const GUEST_PAGING_VERIFICATION: u64 = 1 << 3;
+
+        if !is_vmx_control_supported(VmxControl::ProcessorBased, ACTIVATE_TERTIARY_CONTROLS) {
+            return false;
+        }
+
+        // IA32_VMX_PROCBASED_CTLS3 reports the allowed 1-settings in all 64 bits.
+        let required = EPT_PAGING_WRITE_CONTROL | GUEST_PAGING_VERIFICATION;
+        rdmsr(IA32_VMX_PROCBASED_CTLS3) & required == required
+    }
+
+    /// Checks whether the processor supports execute-only EPT translations.
+    ///
+    /// Without this capability, an EPT entry that is executable but not readable is a misconfiguration.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+    pub fn is_execute_only_supported() -> bool {
+        const EPT_EXECUTE_ONLY_SUPPORT: u64 = 1 << 0;
+        rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP) & EPT_EXECUTE_ONLY_SUPPORT != 0
+    }
+
+    /// Returns the leaf entry mapping the given page, splitting a 1GB page first if necessary.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Guest physical address of the page, aligned to 2MB or 4KB.
+    /// * `pt_table_index` - The index within the `pt` array used if the page is mapped with 4KB pages.
+    ///
+    /// # Returns
+    ///
+    /// The PDE if the page is mapped by a 2MB page, otherwise the PTE, or an `Err(HypervisorError)`
+    /// if the index or the address is invalid.
+    fn leaf_entry_mut(
+        &mut self,
+        guest_pa: u64,
+        pt_table_index: usize,
+    ) -> Result<&mut Entry, HypervisorError> {
+        // Ensure the PT index is valid.
+        if pt_table_index == 0 || pt_table_index >= self.pt.len() {
+            error!("Invalid PT index: {}", pt_table_index);
+            return Err(HypervisorError::InvalidPtIndex(pt_table_index));
+        }
+
+        let guest_pa = VAddr::from(guest_pa);
+
+        // Ensure the guest physical address is aligned to a page boundary.
+        if !guest_pa.is_large_page_aligned() && !guest_pa.is_base_page_aligned() {
+            error!("Page is not aligned: {:#x}", guest_pa);
+            return Err(HypervisorError::UnalignedAddressError(guest_pa.as_u64()));
+        }
+
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
+        let pt_index = pt_index(guest_pa);
+
+        // Never change the permissions of a whole 1GB page.
+        self.split_1gb_to_2mb(pdpt_index)?;
+
+        if self.pd[pdpt_index].0.entries[pd_index].large() {
+            trace!("Changing the permissions of a 2mb page");
+            Ok(&mut self.pd[pdpt_index].0.entries[pd_index])
+        } else {
+            trace!("Changing the permissions of a 4kb page");
+            Ok(&mut self.pt[pt_table_index].0.entries[pt_index])
+        }
+    }
+
+    /// Remaps a guest physical address to a new host physical address within the EPT.
+    ///
+    /// This function updates the EPT entry corresponding to the provided guest physical address (GPA)
+    /// to map to the specified host physical address (HPA). It is designed to remap 4KB pages.
+    ///
+    /// The target page must not overlap this EPT or any region in `reserved_regions`, since mapping
+    /// hypervisor memory into the guest would let the guest modify it.
+    ///
+    /// The new entry is written with a single 8-byte store, so a processor walking the EPT at the same
+    /// time translates the page either to the old or to the new host page.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address that needs to be remapped.
+    /// * `host_pa` - The new host physical address to map the guest physical address to.
+    /// * `pt_table_index`: The index within the `pt` array of Page Tables to be used for this operation.
+    /// Must be in the range [1, 63] as `pt[0]` is reserved for the first 2MB of physical address space.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful. In case of failure,
+    /// a `HypervisorError` is returned, detailing the nature of the error.
+    pub fn remap_gpa_to_hpa(
+        &mut self,
+        guest_pa: u64,
+        host_pa: u64,
+        pt_table_index: usize,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        trace!("Remapping GPA {:x} to HPA {:x}", guest_pa, host_pa);
+
+        // Ensure the PT index is valid.
+        if pt_table_index == 0 || pt_table_index >= self.pt.len() {
+            error!("Invalid PT index: {}", pt_table_index);
+            return Err(HypervisorError::InvalidPtIndex(pt_table_index));
+        }
+
+        let guest_pa = VAddr::from(guest_pa);
+        let host_pa = VAddr::from(host_pa);
+
+        // Ensure both addresses are page aligned
+        if !guest_pa.is_base_page_aligned() || !host_pa.is_base_page_aligned() {
+            error!(
+                "Addresses are not aligned: GPA {:#x}, HPA {:#x}",
+                guest_pa, host_pa
+            );
+            let unaligned = match guest_pa.is_base_page_aligned() {
+                true => host_pa,
+                false => guest_pa,
+            };
+            return Err(HypervisorError::UnalignedAddressError(unaligned.as_u64()));
+        }
+
+        // Refuse to map hypervisor memory, including this EPT, into the guest.
+        self.check_remap_target(
+            host_pa.as_u64()..host_pa.as_u64() + BASE_PAGE_SIZE as u64,
+            reserved_regions,
+        )?;
+
+        // Calculate indexes for accessing the EPT hierarchy
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
+        let pt_index = pt_index(guest_pa);
+
+        self.split_1gb_to_2mb(pdpt_index)?;
+
+        let pde = &self.pd[pdpt_index].0.entries[pd_index];
+
+        // Verify that we're not dealing with a large page mapping
+        if pde.large() {
+            error!("Cannot remap a large page: GPA {:#x}", guest_pa);
+            return Err(HypervisorError::LargePageRemapError);
+        }
+
+        // Access the corresponding PT entry
+        let pte = &mut self.pt[pt_table_index].0.entries[pt_index];
+
+        // Update the PTE to point to the new HPA. The entry is built on the side, since updating the
+        // bitfield in place may take several stores.
+        let mut new_pte = *pte;
+        new_pte.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+        unsafe { core::ptr::write_volatile(pte, new_pte) };
+        trace!(
+            "Updated PTE for GPA {:x} to point to HPA {:x}",
+            guest_pa,
+            host_pa
+        );
+
+        Ok(())
+    }
+
+    /// Applies a table of remaps, e.g. after `build_identity`.
+    ///
+    /// Each 2MB page touched by a guest range is split with a page table from the allocator, unless it
+    /// is already split, and each 4KB page is remapped with `remap_gpa_to_hpa` and given the memory type
+    /// of its remap. The permissions of the pages are kept.
+    ///
+    /// All remaps are validated before any page is changed. Ranges within the first 2MB are refused,
+    /// since they are mapped by the reserved `pt[0]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `remaps` - The remaps to apply, in order. Later remaps override earlier ones where they overlap.
+    /// * `reserved_regions` - The host memory owned by the hypervisor, which no host range may overlap.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::UnalignedAddressError)` if a range is not 4KB aligned,
+    /// `Err(HypervisorError::RemapLengthMismatch)` if the ranges of a remap differ in length,
+    /// `Err(HypervisorError::InvalidPtIndex(0))` if a guest range starts within the first 2MB,
+    /// `Err(HypervisorError::RemapIntoReservedRegion)` if a host range overlaps hypervisor memory,
+    /// or the error of the failed split.
+    pub fn apply_remaps(
+        &mut self,
+        remaps: &[Remap],
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        let page_mask = BASE_PAGE_SIZE as u64 - 1;
+
+        for remap in remaps {
+            let Remap {
+                guest_range,
+                host_range,
+                ..
+            } = remap;
+
+            if let Some(&unaligned) = [
+                guest_range.start,
+                guest_range.end,
+                host_range.start,
+                host_range.end,
+            ]
+            .iter()
+            .find(|&&address| address & page_mask != 0)
+            {
+                error!("Remap is not page aligned: {:#x?}", remap);
+                return Err(HypervisorError::UnalignedAddressError(unaligned));
+            }
+
+            // Reversed ranges have no length, and are refused like ranges of different lengths.
+            let guest_len = guest_range.end.checked_sub(guest_range.start);
+            if guest_len.is_none() || guest_len != host_range.end.checked_sub(host_range.start) {
+                error!("Remap ranges differ in length: {:#x?}", remap);
+                return Err(HypervisorError::RemapLengthMismatch);
+            }
+
+            if guest_range.start < LARGE_PAGE_SIZE as u64 {
+                error!("Remap touches the first 2MB: {:#x?}", remap);
+                return Err(HypervisorError::InvalidPtIndex(0));
+            }
+
+            self.check_remap_target(host_range.clone(), reserved_regions)?;
+        }
+
+        for remap in remaps {
+            trace!(
+                "Remapping GPA {:#x?} to HPA {:#x?} as {:?}",
+                remap.guest_range,
+                remap.host_range,
+                remap.memory_type
+            );
+
+            let len = remap.guest_range.end - remap.guest_range.start;
+            let pages = self.prepare_hook_region(remap.guest_range.start, len as usize)?;
+
+            for (guest_pa, pt_table_index) in pages {
+                let host_pa = remap.host_range.start + (guest_pa - remap.guest_range.start);
+                self.remap_gpa_to_hpa(guest_pa, host_pa, pt_table_index, reserved_regions)?;
+                self.leaf_entry_mut(guest_pa, pt_table_index)?
+                    .set_memory_type(remap.memory_type as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overrides the memory type of a guest physical range, e.g. to force a range to UC or WC for
+    /// cache experiments.
+    ///
+    /// The memory type is written into the `memory_type` field of every leaf entry mapping the range,
+    /// replacing the type `build_identity` derived from the MTRRs. 2MB pages fully inside the range keep
+    /// their PDE, and the 2MB pages only partially covered at either end are split with page tables
+    /// from the allocator. 1GB pages are split into 2MB pages. If a split fails, the pages split by this
+    /// call are merged back and no entry is changed. The caller is responsible for invalidating the EPT
+    /// caches.
+    ///
+    /// Every `MemoryType` is legal in a leaf entry; only the EPTP and the paging-structure accesses are
+    /// restricted to UC and WB, which this does not touch. The override is warned about if it makes
+    /// memory cacheable that the MTRRs, and so the guest, consider uncacheable or write-combining, e.g.
+    /// MMIO or a frame buffer, since with EPT the MTRRs no longer apply and cached or speculative
+    /// accesses to devices can corrupt their state.
+    ///
+    /// # Arguments
+    ///
+    /// * `start_gpa` - The page-aligned guest physical address of the start of the range.
+    /// * `len` - The length of the range in bytes, a multiple of 4KB.
+    /// * `memory_type` - The memory type to set.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::UnalignedAddressError)` if the range is not 4KB
+    /// aligned, or the error of the failed split.
+    pub fn set_memory_type_range(
+        &mut self,
+        start_gpa: u64,
+        len: usize,
+        memory_type: MemoryType,
+    ) -> Result<(), HypervisorError> {
+        trace!(
+            "Setting the memory type of GPA {:#x} ({:#x} bytes) to {:?}",
+            start_gpa,
+            len,
+            memory_type
+        );
+
+        let page_mask = BASE_PAGE_SIZE as u64 - 1;
+        let end = start_gpa + len as u64;
+
+        if let Some(unaligned) = [start_gpa, end]
+            .into_iter()
+            .find(|address| address & page_mask != 0)
+        {
+            error!(
+                "Memory type range is not page aligned: {:#x}..{:#x}",
+                start_gpa, end
+            );
+            return Err(HypervisorError::UnalignedAddressError(unaligned));
+        }
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        Self::warn_memory_type_conflicts(start_gpa..end, memory_type);
+
+        // Only the first and the last 2MB page can be partially covered, so at most two are split.
+        let first_large_page = start_gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        let last_large_page = (end - 1) & !(LARGE_PAGE_SIZE as u64 - 1);
+        let mut split_large_pages = Vec::new();
+
+        for large_page_pa in [first_large_page, last_large_page] {
+            let covered =
+                large_page_pa >= start_gpa && large_page_pa + LARGE_PAGE_SIZE as u64 <= end;
+            if covered || split_large_pages.contains(&large_page_pa) {
+                continue;
+            }
+
+            self.split_1gb_to_2mb(pdpt_index(VAddr::from(large_page_pa)))?;
+
+            if self.split_pt_index(large_page_pa).is_some() {
+                continue;
+            }
+
+            match self.split_2mb_to_4kb_alloc(large_page_pa) {
+                Ok(_) => split_large_pages.push(large_page_pa),
+                Err(e) => {
+                    for &pa in &split_large_pages {
+                        // Merging a page that was just split cannot fail.
+                        let _ = self.merge_4kb_to_2mb(pa);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut large_page_pa = first_large_page;
+        while large_page_pa < end {
+            let guest_pa = VAddr::from(large_page_pa);
+            self.split_1gb_to_2mb(pdpt_index(guest_pa))?;
+
+            match self.split_pt_index(large_page_pa) {
+                Some(pt_table_index) => {
+                    let region_start = start_gpa.max(large_page_pa);
+                    let region_end = end.min(large_page_pa + LARGE_PAGE_SIZE as u64);
+
+                    for page in (region_start..region_end).step_by(BASE_PAGE_SIZE) {
+                        self.pt[pt_table_index].0.entries[pt_index(VAddr::from(page))]
+                            .set_memory_type(memory_type as u64);
+                    }
+                }
+                None => {
+                    self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)]
+                        .set_memory_type(memory_type as u64);
+                }
+            }
+
+            large_page_pa += LARGE_PAGE_SIZE as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Warns if overriding the memory type of a range makes memory cacheable that the MTRRs mark
+    /// uncacheable or write-combining.
+    ///
+    /// The range is checked in 2MB steps, falling back to 4KB steps where the MTRRs give a 2MB page
+    /// mixed memory types. Each conflicting MTRR memory type is reported once, with the first address.
+    fn warn_memory_type_conflicts(range: Range<u64>, memory_type: MemoryType) {
+        let is_cacheable = |memory_type: MemoryType| {
+            matches!(
+                memory_type,
+                MemoryType::WriteThrough | MemoryType::WriteProtected | MemoryType::WriteBack
+            )
+        };
+
+        if !is_cacheable(memory_type) && memory_type != MemoryType::WriteCombining {
+            return;
+        }
+
+        let mut mtrr = Mtrr::new();
+        let mut reported: Vec<MemoryType> = Vec::new();
+        let mut guest_pa = range.start;
+
+        while guest_pa < range.end {
+            let large_page_end = (guest_pa | (LARGE_PAGE_SIZE as u64 - 1)) + 1;
+            let chunk_end = range.end.min(large_page_end);
+
+            let (chunk_end, mtrr_type) = match mtrr.find_uniform(guest_pa..chunk_end) {
+                Some(mtrr_type) => (chunk_end, mtrr_type),
+                None => {
+                    let page_end = guest_pa + BASE_PAGE_SIZE as u64;
+                    (
+                        page_end,
+                        mtrr.find(guest_pa..page_end)
+                            .unwrap_or(MemoryType::WriteBack),
+                    )
+                }
+            };
+
+            let conflicts = match mtrr_type {
+                MemoryType::Uncacheable => true,
+                MemoryType::WriteCombining => is_cacheable(memory_type),
+                _ => false,
+            };
+
+            if conflicts && !reported.contains(&mtrr_type) {
+                warn!(
+                    "Forcing {:?} on GPA {:#x}, which the MTRRs mark {:?}: cached or combined accesses to device memory can corrupt it",
+                    memory_type, guest_pa, mtrr_type
+                );
+                reported.push(mtrr_type);
+            }
+
+            guest_pa = chunk_end;
+        }
+    }
+
+    /// Remaps a 2MB guest physical page to a new host physical address within the EPT.
+    ///
+    /// Unlike `remap_gpa_to_hpa`, this function updates the large-page PDE directly, so a whole 2MB
+    /// region (e.g. a large MMIO window) can be redirected without splitting it into 4KB pages.
+    /// The memory type and permissions of the PDE are kept.
+    ///
+    /// The target region must not overlap this EPT or any region in `reserved_regions`, since mapping
+    /// hypervisor memory into the guest would let the guest modify it.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The 2MB-aligned guest physical address that needs to be remapped.
+    /// * `host_pa` - The 2MB-aligned host physical address to map the guest physical address to.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::UnalignedAddressError)` if either address is not
+    /// 2MB aligned, or `Err(HypervisorError::PageAlreadySplit)` if the guest page is mapped with 4KB pages.
+    pub fn remap_gpa_to_hpa_2mb(
+        &mut self,
+        guest_pa: u64,
+        host_pa: u64,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        trace!("Remapping 2MB GPA {:x} to HPA {:x}", guest_pa, host_pa);
+
+        let guest_pa = VAddr::from(guest_pa);
+        let host_pa = VAddr::from(host_pa);
+
+        // Ensure both addresses are large page aligned
+        if !guest_pa.is_large_page_aligned() || !host_pa.is_large_page_aligned() {
+            error!(
+                "Addresses are not 2MB aligned: GPA {:#x}, HPA {:#x}",
+                guest_pa, host_pa
+            );
+            let unaligned = match guest_pa.is_large_page_aligned() {
+                true => host_pa,
+                false => guest_pa,
+            };
+            return Err(HypervisorError::UnalignedAddressError(unaligned.as_u64()));
+        }
+
+        // Refuse to map hypervisor memory, including this EPT, into the guest.
+        self.check_remap_target(
+            host_pa.as_u64()..host_pa.as_u64() + LARGE_PAGE_SIZE as u64,
+            reserved_regions,
+        )?;
+
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
+
+        self.split_1gb_to_2mb(pdpt_index)?;
+
+        let pde = &mut self.pd[pdpt_index].0.entries[pd_index];
+
+        // Verify that the guest page is still mapped by the PDE itself
+        if !pde.large() {
+            error!(
+                "Cannot remap a split page as a large page: GPA {:#x}",
+                guest_pa
+            );
+            return Err(HypervisorError::PageAlreadySplit);
+        }
+
+        // Update the PDE to point to the new HPA
+        pde.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+        trace!(
+            "Updated PDE for GPA {:x} to point to HPA {:x}",
+            guest_pa,
+            host_pa
+        );
+
+        Ok(())
+    }
+
+    /// Maps a host page that is hidden from the guest's normal view into this EPT.
+    ///
+    /// Unlike `remap_gpa_to_hpa`, the host page may belong to the hypervisor, since this is meant for an
+    /// EPT the guest only reaches by switching to it with VMFUNC, such as the agent view in `SharedData`.
+    /// The page is mapped write-back with the given permissions, bypassing the W^X policy. The 2MB page
+    /// containing the guest physical address is split with a page table from the allocator if needed.
+    ///
+    /// Mapping hypervisor memory lets the guest modify it through this EPT, so the page should hold
+    /// nothing but data shared with the guest. This EPT itself can never be mapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The page-aligned guest physical address to map the host page at.
+    /// * `host_pa` - The page-aligned host physical address of the hidden page.
+    /// * `access_type` - The permissions of the mapping.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or an `Err(HypervisorError)` if an address is unaligned, the host page
+    /// overlaps this EPT, or the 2MB page could not be split.
+    pub fn map_hidden_page(
+        &mut self,
+        guest_pa: u64,
+        host_pa: u64,
+        access_type: AccessType,
+    ) -> Result<(), HypervisorError> {
+        trace!("Mapping hidden HPA {:x} at GPA {:x}", host_pa, guest_pa);
+
+        let page_mask = BASE_PAGE_SIZE as u64 - 1;
+        if guest_pa & page_mask != 0 || host_pa & page_mask != 0 {
+            let unaligned = match guest_pa & page_mask {
+                0 => host_pa,
+                _ => guest_pa,
+            };
+            return Err(HypervisorError::UnalignedAddressError(unaligned));
+        }
+
+        if self.overlaps_self(host_pa..host_pa + BASE_PAGE_SIZE as u64) {
+            error!("Cannot map the EPT into the guest: HPA {:#x}", host_pa);
+            return Err(HypervisorError::RemapIntoReservedRegion);
+        }
+
+        let pt_table_index = match self.split_pt_index(guest_pa) {
+            Some(pt_table_index) => pt_table_index,
+            None => self.split_2mb_to_4kb_alloc(guest_pa)?,
+        };
+
+        let pte = &mut self.pt[pt_table_index].0.entries[pt_index(VAddr::from(guest_pa))];
+        pte.set_access_type(access_type);
+        pte.set_memory_type(MemoryType::WriteBack as u64);
+        pte.set_pfn(host_pa >> BASE_PAGE_SHIFT);
+
+        Ok(())
+    }
+
+    /// Checks whether a host physical range overlaps this EPT.
+    fn overlaps_self(&self, host_range: Range<u64>) -> bool {
+        let ept = self as *const Self as u64..self as *const Self as u64 + size_of::<Self>() as u64;
+        host_range.start < ept.end && ept.start < host_range.end
+    }
+
+    /// Ensures that a host physical range can be mapped into the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `host_range` - The host physical range to map.
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the range is free, or `Err(HypervisorError::RemapIntoReservedRegion)` if it
+    /// overlaps this EPT or a reserved region.
+    fn check_remap_target(
+        &self,
+        host_range: Range<u64>,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        if self.overlaps_self(host_range.clone())
+            || reserved_regions.check(host_range.clone()).is_err()
+        {
+            error!(
+                "Cannot remap into a reserved region: HPA {:#x}",
+                host_range.start
+            );
+            return Err(HypervisorError::RemapIntoReservedRegion);
+        }
+
+        Ok(())
+    }
+
+    /// Translates a guest physical address to the host physical address it is mapped to.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to translate.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address, or `None` if the guest physical address is not mapped.
+    pub fn gpa_to_hpa(&self, guest_pa: u64) -> Option<u64> {
+        let (entry, page_size) = self.leaf_entry(guest_pa)?;
+        Some((entry.pfn() << BASE_PAGE_SHIFT) + (guest_pa & (page_size - 1)))
+    }
+
+    /// Returns the access permissions of the page mapping a guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to query.
+    ///
+    /// # Returns
+    ///
+    /// The `AccessType` of the mapping, or `None` if the guest physical address is not mapped.
+    pub fn page_permissions(&self, guest_pa: u64) -> Option<AccessType> {
+        let (entry, _) = self.leaf_entry(guest_pa)?;
+        Some(entry.access_type())
+    }
+
+    /// Returns the memory type of the page mapping a guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to query.
+    ///
+    /// # Returns
+    ///
+    /// The `MemoryType` of the mapping, or `None` if the guest physical address is not mapped or its
+    /// entry holds a reserved memory type.
+    pub fn page_memory_type(&self, guest_pa: u64) -> Option<MemoryType> {
+        let (entry, _) = self.leaf_entry(guest_pa)?;
+
+        match entry.has_valid_memory_type() {
+            true => Some(Mtrr::from_raw(entry.memory_type() as u8)),
+            false => None,
+        }
+    }
+
+    /// Iterates over the present mappings of the EPT, in ascending order of guest physical address.
+    ///
+    /// 4KB pages are reported one by one. Consecutive 2MB and 1GB pages that map contiguous host
+    /// memory with the same permissions are collapsed into a single mapping, so walking the identity
+    /// map yields a handful of mappings rather than hundreds of thousands. The tables are read in
+    /// place, so the EPT must not be modified while iterating.
+    ///
+    /// # Returns
+    ///
+    /// An iterator over the `MappedPage`s of the EPT.
+    pub fn iter_mapped_pages(&self) -> MappedPages<'_> {
+        MappedPages {
+            ept: self,
+            guest_pa: 0,
+            pending: None,
+        }
+    }
+
+    /// Finds the leaf entry at or after a guest physical address, skipping unmapped regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The page-aligned guest physical address to start searching at.
+    ///
+    /// # Returns
+    ///
+    /// The guest physical address of the leaf entry, the entry, and the size of the page it maps,
+    /// or `None` if no entry maps an address at or above `guest_pa`.
+    fn next_leaf_entry(&self, mut guest_pa: u64) -> Option<(u64, &Entry, u64)> {
+        // Only the first PML4 entry is in use, covering the first 512GB.
+        while guest_pa < (HUGE_PAGE_SIZE * 512) as u64 {
+            let guest_va = VAddr::from(guest_pa);
+
+            let pdpte = &self.pdpt.0.entries[pdpt_index(guest_va)];
+            if !pdpte.is_present() {
+                guest_pa = align_up(guest_pa + 1, HUGE_PAGE_SIZE as u64);
+                continue;
+            }
+            if pdpte.large() {
+                return Some((
+                    guest_pa & !(HUGE_PAGE_SIZE as u64 - 1),
+                    pdpte,
+                    HUGE_PAGE_SIZE as u64,
+                ));
+            }
+
+            let pde = &self.pd[pdpt_index(guest_va)].0.entries[pd_index(guest_va)];
+            if !pde.is_present() {
+                guest_pa = align_up(guest_pa + 1, LARGE_PAGE_SIZE as u64);
+                continue;
+            }
+            if pde.large() {
+                return Some((
+                    guest_pa & !(LARGE_PAGE_SIZE as u64 - 1),
+                    pde,
+                    LARGE_PAGE_SIZE as u64,
+                ));
+            }
+
+            let Some(pt) = self.find_pt(pde.pfn()) else {
+                guest_pa = align_up(guest_pa + 1, LARGE_PAGE_SIZE as u64);
+                continue;
+            };
+
+            let pte = &pt.0.entries[pt_index(guest_va)];
+            if pte.is_present() {
+                return Some((guest_pa, pte, BASE_PAGE_SIZE as u64));
+            }
+            guest_pa += BASE_PAGE_SIZE as u64;
+        }
+
+        None
+    }
+
+    /// Finds the entry that maps a guest physical address, walking the tables the way the processor does.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to look up.
+    ///
+    /// # Returns
+    ///
+    /// The leaf entry and the size of the page it maps, or `None` if the address is not mapped.
+    fn leaf_entry(&self, guest_pa: u64) -> Option<(&Entry, u64)> {
+        // Only the first PML4 entry is in use, covering the first 512GB.
+        if guest_pa >= (HUGE_PAGE_SIZE * 512) as u64 {
+            return None;
+        }
+
+        let guest_va = VAddr::from(guest_pa);
+
+        let pdpte = &self.pdpt.0.entries[pdpt_index(guest_va)];
+        if !pdpte.is_present() {
+            return None;
+        }
+        if pdpte.large() {
+            return Some((pdpte, HUGE_PAGE_SIZE as u64));
+        }
+
+        let pde = &self.pd[pdpt_index(guest_va)].0.entries[pd_index(guest_va)];
+        if !pde.is_present() {
+            return None;
+        }
+        if pde.large() {
+            return Some((pde, LARGE_PAGE_SIZE as u64));
+        }
+
+        let pte = &self.find_pt(pde.pfn())?.0.entries[pt_index(guest_va)];
+        if !pte.is_present() {
+            return None;
+        }
+
+        Some((pte, BASE_PAGE_SIZE as u64))
+    }
+
+    /// Finds the page table in `pt` with the given page frame number.
+    fn find_pt(&self, pfn: u64) -> Option<&Pt> {
+        self.find_pt_index(pfn).map(|index| &self.pt[index])
+    }
+
+    /// Finds the index of the page table in `pt` with the given page frame number.
+    fn find_pt_index(&self, pfn: u64) -> Option<usize> {
+        self.pt
+            .iter()
+            .position(|pt| table_pfn(addr_of!(*pt)).is_ok_and(|table| table == pfn))
+    }
+
+    /// Returns a copy of an entry of the PML4 table.
+    ///
+    /// # Arguments
+    ///
+    /// * `pml4_index` - The index of the entry, in the range [0, 511].
+    ///
+    /// # Returns
+    ///
+    /// The entry, or `None` if the index is out of range.
+    pub fn pml4_entry(&self, pml4_index: usize) -> Option<Entry> {
+        self.pml4.0.entries.get(pml4_index).copied()
+    }
+
+    /// Returns a copy of an entry of the PDPT.
+    ///
+    /// # Arguments
+    ///
+    /// * `pdpt_index` - The index of the entry, in the range [0, 511].
+    ///
+    /// # Returns
+    ///
+    /// The entry, or `None` if the index is out of range.
+    pub fn pdpt_entry(&self, pdpt_index: usize) -> Option<Entry> {
+        self.pdpt.0.entries.get(pdpt_index).copied()
+    }
+
+    /// Returns a copy of an entry of one of the page directories.
+    ///
+    /// # Arguments
+    ///
+    /// * `pdpt_index` - The index of the page directory, which is the index of the PDPT entry pointing to it.
+    /// * `pd_index` - The index of the entry within the page directory, in the range [0, 511].
+    ///
+    /// # Returns
+    ///
+    /// The entry, or `None` if an index is out of range.
+    pub fn pd_entry(&self, pdpt_index: usize, pd_index: usize) -> Option<Entry> {
+        self.pd.get(pdpt_index)?.0.entries.get(pd_index).copied()
+    }
+
+    /// Returns a copy of an entry of one of the page tables used for splits.
+    ///
+    /// # Arguments
+    ///
+    /// * `pt_table_index` - The index within the `pt` array, in the range [0, 63].
+    /// * `pt_index` - The index of the entry within the page table, in the range [0, 511].
+    ///
+    /// # Returns
+    ///
+    /// The entry, or `None` if an index is out of range.
+    pub fn pt_entry(&self, pt_table_index: usize, pt_index: usize) -> Option<Entry> {
+        self.pt
+            .get(pt_table_index)?
+            .0
+            .entries
+            .get(pt_index)
+            .copied()
+    }
+
+    /// Exercises the EPT operations on a scratch guest physical address and verifies the resulting
+    /// mappings, without requiring a guest.
+    ///
+    /// Checks that every `Entry` field maps to its documented bits, then splits, re-permissions, remaps,
+    /// and merges the 2MB page at `SELF_TEST_GPA` using the last page table, checking the result of each
+    /// step with `gpa_to_hpa` and `page_permissions`. Each step is logged as passed or failed.
+    ///
+    /// This modifies the table, so it must be run on a freshly built table that is rebuilt or re-cloned
+    /// afterwards. The scratch page is merged back on success.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if all checks passed, or `Err(HypervisorError::EptSelfTestFailed)` otherwise.
+    pub fn self_test(&mut self) -> Result<(), HypervisorError> {
+        /// The 2MB page used for testing. Lies outside the first 2MB, which always uses `pt[0]`.
+        const SELF_TEST_GPA: u64 = 0x40_0000;
+
+        // No regions are registered yet; remapping onto the table itself is refused regardless.
+        let no_reserved_regions = ReservedRegions::new();
+
+        let pt_table_index = self.pt.len() - 1;
+        let page = SELF_TEST_GPA + BASE_PAGE_SIZE as u64;
+        let neighbor = SELF_TEST_GPA + 2 * BASE_PAGE_SIZE as u64;
+        let remapped_hpa = SELF_TEST_GPA + 3 * BASE_PAGE_SIZE as u64;
+        let mut passed = true;
+
+        let mut check = |name: &str, ok: bool| {
+            if ok {
+                debug!("EPT self-test: {name}: passed");
+            } else {
+                error!("EPT self-test: {name}: failed");
+                passed = false;
+            }
+        };
+
+        // Each field of `Entry` must cover exactly its documented bits, since an off-by-one would
+        // corrupt every entry written through it.
+        let raw = |set: fn(&mut Entry)| {
+            let mut entry = Entry(0);
+            set(&mut entry);
+            entry.0
+        };
+        check(
+            "entry readable bit",
+            raw(|e| e.set_readable(true)) == 1 << 0,
+        );
+        check(
+            "entry writable bit",
+            raw(|e| e.set_writable(true)) == 1 << 1,
+        );
+        check(
+            "entry executable bit",
+            raw(|e| e.set_executable(true)) == 1 << 2,
+        );
+        check(
+            "entry memory type bits",
+            raw(|e| e.set_memory_type(7)) == 0b111 << 3,
+        );
+        check("entry large bit", raw(|e| e.set_large(true)) == 1 << 7);
+        check(
+            "entry user executable bit",
+            raw(|e| e.set_user_executable(true)) == 1 << 10,
+        );
+        check(
+            "entry max pfn",
+            raw(|e| e.set_pfn((1 << 40) - 1)) == 0x000f_ffff_ffff_f000,
+        );
+        check(
+            "entry verify guest paging bit",
+            raw(|e| e.set_verify_guest_paging(true)) == 1 << 57,
+        );
+        check(
+            "entry paging-write access bit",
+            raw(|e| e.set_paging_write_access(true)) == 1 << 58,
+        );
+        check(
+            "entry large and pfn do not alias",
+            raw(|e| {
+                e.set_pfn((1 << 40) - 1);
+                e.set_large(true);
+                e.set_large(false);
+            }) == 0x000f_ffff_ffff_f000
+                && raw(|e| {
+                    e.set_large(true);
+                    e.set_pfn(0);
+                }) == 1 << 7,
+        );
+
+        check("identity mapped", self.gpa_to_hpa(page) == Some(page));
+        check(
+            "initially RWX",
+            self.page_permissions(page) == Some(AccessType::READ_WRITE_EXECUTE),
+        );
+
+        // Split from an address that is not 2MB aligned to catch base address arithmetic errors.
+        check(
+            "split",
+            self.split_2mb_to_4kb(page, pt_table_index, false).is_ok(),
+        );
+        check(
+            "split keeps identity",
+            self.gpa_to_hpa(page) == Some(page)
+                && self.gpa_to_hpa(SELF_TEST_GPA) == Some(SELF_TEST_GPA),
+        );
+        let pt_pfn = table_pfn(addr_of!(self.pt[pt_table_index])).ok();
+        let split_pde = self.pd_entry(pdpt_index(VAddr::from(page)), pd_index(VAddr::from(page)));
+        check(
+            "split points at page table",
+            split_pde.is_some_and(|pde| !pde.large() && Some(pde.pfn()) == pt_pfn),
+        );
+        check(
+            "split entry",
+            self.pt_entry(pt_table_index, pt_index(VAddr::from(page)))
+                .is_some_and(|pte| pte.pfn() == page >> BASE_PAGE_SHIFT && !pte.large()),
+        );
+        check(
+            "split twice",
+            matches!(
+                self.split_2mb_to_4kb(page, pt_table_index, false),
+                Err(HypervisorError::PageAlreadySplit)
+            ),
+        );
+
+        // The next 2MB page must not be split into the page table that now backs `page`. It can only
+        // be tried if the page is not split already, e.g. for its MTRR memory types.
+        let next_large_page = SELF_TEST_GPA + LARGE_PAGE_SIZE as u64;
+        let next_pde = self.pd_entry(
+            pdpt_index(VAddr::from(next_large_page)),
+            pd_index(VAddr::from(next_large_page)),
+        );
+        if next_pde.is_some_and(|pde| pde.large()) {
+            check(
+                "split into used page table",
+                matches!(
+                    self.split_2mb_to_4kb(next_large_page, pt_table_index, false),
+                    Err(HypervisorError::PtIndexAlreadyInUse(_))
+                ),
+            );
+        }
+
+        check(
+            "modify permissions",
+            self.modify_page_permissions(page, AccessType::EXECUTE, pt_table_index)
+                .is_ok(),
+        );
+        check(
+            "execute-only",
+            self.page_permissions(page) == Some(AccessType::EXECUTE),
+        );
+        check(
+            "neighbor unchanged",
+            self.page_permissions(neighbor) == Some(AccessType::READ_WRITE_EXECUTE),
+        );
+
+        check(
+            "remap",
+            self.remap_gpa_to_hpa(page, remapped_hpa, pt_table_index, &no_reserved_regions)
+                .is_ok(),
+        );
+        check(
+            "remapped",
+            self.gpa_to_hpa(page + 0x10) == Some(remapped_hpa + 0x10),
+        );
+        check(
+            "neighbor identity",
+            self.gpa_to_hpa(neighbor) == Some(neighbor),
+        );
+
+        let own_page = addr_of!(self.pt[pt_table_index]) as u64;
+        check(
+            "remap onto own tables refused",
+            matches!(
+                self.remap_gpa_to_hpa(neighbor, own_page, pt_table_index, &no_reserved_regions),
+                Err(HypervisorError::RemapIntoReservedRegion)
+            ) && self.gpa_to_hpa(neighbor) == Some(neighbor),
+        );
+
+        check("merge", self.merge_4kb_to_2mb(page).is_ok());
+        check(
+            "merge restores identity",
+            self.gpa_to_hpa(page) == Some(page),
+        );
+        check(
+            "merge restores RWX",
+            self.page_permissions(page) == Some(AccessType::READ_WRITE_EXECUTE),
+        );
+
+        if passed {
+            info!("EPT self-test passed");
+            Ok(())
+        } else {
+            Err(HypervisorError::EptSelfTestFailed)
+        }
+    }
+
+    /// Walks the whole EPT hierarchy and checks it for structural errors.
+    ///
+    /// Checks that the PML4 entry points to the PDPT, each PDPTE that does not map a 1GB page points
+    /// to its own PD, each PDE that does not map a 2MB page points to a distinct page table in `pt`,
+    /// every page lies within the 512GB covered by the PML4 entry, leaf entries use a valid memory
+    /// type, and no entry sets reserved bits. Non-present entries are skipped. Each error is logged.
+    ///
+    /// Such errors otherwise go unnoticed until a guest corrupts memory or an EPT misconfiguration exit occurs.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the EPT is valid, or `Err(HypervisorError::EptValidationFailed)` otherwise.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.1 EPT Misconfigurations
+    #[rustfmt::skip]
+    pub fn validate(&self) -> Result<(), HypervisorError> {
+        /// The first page frame number past the 512GB covered by the PML4 entry.
+        const IDENTITY_END_PFN: u64 = (HUGE_PAGE_SIZE as u64 * 512) >> BASE_PAGE_SHIFT;
+
+        let address_reserved_mask = address_reserved_mask();
+
+        let mut valid = true;
+        let mut check = |ok: bool, problem: core::fmt::Arguments| {
+            if !ok {
+                error!("EPT validation: {problem}");
+                valid = false;
+            }
+        };
+
+        for (i, pml4e) in self.pml4.0.entries.iter().enumerate() {
+            if !pml4e.is_present() {
+                continue;
+            }
+
+            check(i == 0, format_args!("PML4E {i} is present, but only the first 512GB are mapped"));
+            check(table_pfn(addr_of!(self.pdpt)).ok() == Some(pml4e.pfn()), format_args!("PML4E {i} does not point to the PDPT"));
+            check(pml4e.0 & (TABLE_RESERVED_MASK | address_reserved_mask) == 0, format_args!("PML4E {i} sets reserved bits: {:#x}", pml4e.0));
+        }
+
+        // Bitmap of the page tables in `pt` referenced so far.
+        let mut referenced_pts = 0u64;
+
+        for (i, pdpte) in self.pdpt.0.entries.iter().enumerate() {
+            if !pdpte.is_present() {
+                continue;
+            }
+
+            if pdpte.large() {
+                check(pdpte.pfn() < IDENTITY_END_PFN, format_args!("PDPTE {i} maps a 1GB page outside of the identity range: {:#x}", pdpte.pfn()));
+                check(pdpte.has_valid_memory_type(), format_args!("PDPTE {i} uses a reserved memory type: {}", pdpte.memory_type()));
+                check(pdpte.0 & (HUGE_PAGE_RESERVED_MASK | address_reserved_mask) == 0, format_args!("PDPTE {i} sets reserved bits: {:#x}", pdpte.0));
+                continue;
+            }
+
+            // The walks index `pd` with the PDPT index, so no two PDPTEs can share a PD.
+            check(table_pfn(addr_of!(self.pd[i])).ok() == Some(pdpte.pfn()), format_args!("PDPTE {i} does not point to its own PD"));
+            check(pdpte.0 & (TABLE_RESERVED_MASK | address_reserved_mask) == 0, format_args!("PDPTE {i} sets reserved bits: {:#x}", pdpte.0));
+
+            for (j, pde) in self.pd[i].0.entries.iter().enumerate() {
+                if !pde.is_present() {
+                    continue;
+                }
+
+                if pde.large() {
+                    check(pde.pfn() < IDENTITY_END_PFN, format_args!("PDE {i}/{j} maps a 2MB page outside of the identity range: {:#x}", pde.pfn()));
+                    check(pde.has_valid_memory_type(), format_args!("PDE {i}/{j} uses a reserved memory type: {}", pde.memory_type()));
+                    check(pde.0 & (LARGE_PAGE_RESERVED_MASK | address_reserved_mask) == 0, format_args!("PDE {i}/{j} sets reserved bits: {:#x}", pde.0));
+                    continue;
+                }
+
+                check(pde.0 & (TABLE_RESERVED_MASK | address_reserved_mask) == 0, format_args!("PDE {i}/{j} sets reserved bits: {:#x}", pde.0));
+
+                let Some(pt_table_index) = self.find_pt_index(pde.pfn()) else {
+                    check(false, format_args!("PDE {i}/{j} does not point to a page table in `pt`: {:#x}", pde.pfn()));
+                    continue;
+                };
+
+                check(!referenced_pts.get_bit(pt_table_index), format_args!("PDE {i}/{j} shares pt[{pt_table_index}] with another PDE"));
+                check(pt_table_index == 0 || self.used_pt_indices.get_bit(pt_table_index), format_args!("PDE {i}/{j} points to pt[{pt_table_index}], which is not allocated"));
+                referenced_pts.set_bit(pt_table_index, true);
+
+                for (k, pte) in self.pt[pt_table_index].0.entries.iter().enumerate() {
+                    if !pte.is_present() {
+                        continue;
+                    }
+
+                    check(pte.pfn() < IDENTITY_END_PFN, format_args!("PTE {i}/{j}/{k} maps a 4KB page outside of the identity range: {:#x}", pte.pfn()));
+                    check(pte.has_valid_memory_type(), format_args!("PTE {i}/{j}/{k} uses a reserved memory type: {}", pte.memory_type()));
+                    check(pte.0 & address_reserved_mask == 0, format_args!("PTE {i}/{j}/{k} sets reserved bits: {:#x}", pte.0));
+                }
+            }
+        }
+
+        match valid {
+            true => Ok(()),
+            false => Err(HypervisorError::EptValidationFailed),
+        }
+    }
+
+    /// Finds the malformed entry among the EPT entries that translate a guest physical address.
+    ///
+    /// Walks the entries like the processor does and checks each present entry for the causes of an
+    /// EPT misconfiguration: write access without read access, execute-only access without processor
+    /// support, reserved bits, and, in the entry mapping the page, a reserved memory type.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address that caused the EPT misconfiguration.
+    ///
+    /// # Returns
+    ///
+    /// The first malformed entry, or `None` if the walk reaches a non-present entry or a valid leaf entry.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.1 EPT Misconfigurations
+    #[rustfmt::skip]
+    pub fn find_misconfiguration(&self, guest_pa: u64) -> Option<Misconfiguration> {
+        let execute_only_supported = Self::is_execute_only_supported();
+        let address_reserved_mask = address_reserved_mask();
+        let guest_va = VAddr::from(guest_pa);
+
+        let check = |level: EptLevel, index: usize, entry: &Entry, reserved_mask: u64, is_leaf: bool| {
+            entry
+                .misconfiguration_cause(reserved_mask | address_reserved_mask, is_leaf, execute_only_supported)
+                .map(|cause| Misconfiguration {
+                    level,
+                    index,
+                    entry: entry.0,
+                    cause,
+                })
+        };
+
+        // The PML4 entry is never a leaf, and bit 7 is reserved in it.
+        let index = pml4_index(guest_va);
+        let pml4e = &self.pml4.0.entries[index];
+        if !pml4e.is_present() {
+            return None;
+        }
+        if let Some(misconfiguration) = check(EptLevel::Pml4, index, pml4e, TABLE_RESERVED_MASK, false) {
+            return Some(misconfiguration);
+        }
+
+        let index = pdpt_index(guest_va);
+        let pdpte = &self.pdpt.0.entries[index];
+        if !pdpte.is_present() {
+            return None;
+        }
+        if pdpte.large() {
+            return check(EptLevel::Pdpt, index, pdpte, HUGE_PAGE_RESERVED_MASK, true);
+        }
+        if let Some(misconfiguration) = check(EptLevel::Pdpt, index, pdpte, TABLE_RESERVED_MASK, false) {
+            return Some(misconfiguration);
+        }
+
+        let pd = &self.pd[index];
+        let index = pd_index(guest_va);
+        let pde = &pd.0.entries[index];
+        if !pde.is_present() {
+            return None;
+        }
+        if pde.large() {
+            return check(EptLevel::Pd, index, pde, LARGE_PAGE_RESERVED_MASK, true);
+        }
+        if let Some(misconfiguration) = check(EptLevel::Pd, index, pde, TABLE_RESERVED_MASK, false) {
+            return Some(misconfiguration);
+        }
+
+        let index = pt_index(guest_va);
+        let pte = &self.find_pt(pde.pfn())?.0.entries[index];
+        if !pte.is_present() {
+            return None;
+        }
+        check(EptLevel::Pt, index, pte, 0, true)
+    }
+
+    /// Unmaps a 2MB page by clearing the corresponding page directory entry.
+    ///
+    /// This function clears the entry, effectively removing any mapping for the 2MB page.
+    /// It's used when transitioning a region of memory from a single large page to multiple smaller pages or simply freeing the page.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry`: Mutable reference to the page directory entry to unmap.
+    pub fn unmap_2mb(entry: &mut Entry) {
+        if !entry.readable() {
+            // The page is already not present; no action needed.
+            return;
+        }
+
+        // Unmap the large page and clear the flags
+        entry.set_access_type(AccessType::empty());
+        entry.set_memory_type(0);
+        entry.set_large(false);
+        entry.set_pfn(0); // Reset the Page Frame Number
+    }
+
+    /// Unmaps a 4KB page, typically involved in deconstructing finer-grained page tables.
+    ///
+    /// This function wraps the unmap_2mb function, as the actual unmap logic is similar.
+    /// It's used for unmap operations specifically targeting 4KB pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry`: Mutable reference to the page directory entry of the 4KB page to unmap.
+    #[allow(dead_code)]
+    fn unmap_4kb(entry: &mut Entry) {
+        // Delegate to the unmap_2mb function as the unmap logic is the same.
+        Self::unmap_2mb(entry);
+    }
+
+    /// Checks whether the processor supports 1GB pages in the EPT.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+    fn is_1gb_page_supported() -> bool {
+        const EPT_1GB_PAGE_SUPPORT: u64 = 1 << 17;
+        rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP) & EPT_1GB_PAGE_SUPPORT != 0
+    }
+
+    /// Creates an Extended Page Table Pointer (EPTP) with a Write-Back memory type and a 4-level page walk.
+    ///
+    /// This function is used in the setup of Intel VT-x virtualization, specifically for configuring the EPT.
+    /// It encodes the provided physical base address of the EPT PML4 table into the EPTP format, setting
+    /// the memory type to Write-Back and indicating a 4-level page walk.
+    ///
+    /// # Returns
+    /// A `Result<u64, HypervisorError>` containing the configured EPTP value. Returns an error if
+    /// the base address is not properly aligned.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.6 EPT Paging-Structure Entries
+    pub fn create_eptp_with_wb_and_4lvl_walk(&self) -> Result<u64, HypervisorError> {
+        // Get the virtual address of the PML4 table for EPT.
+        let addr = addr_of!(self.pml4) as u64;
+
+        // Get the physical address of the PML4 table for EPT.
+        let ept_pml4_base_addr = PhysicalAddress::from_host_va(addr)?;
+
+        // Represents the EPT page walk length for Intel VT-x, specifically for a 4-level page walk.
+        // The value is 3 (encoded as '3 << 3' in EPTP) because the EPTP encoding requires "number of levels minus one".
+        const EPT_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
+
+        // Represents the memory type setting for Write-Back (WB) in the EPTP.
+        const EPT_MEMORY_TYPE_WB: u64 = MemoryType::WriteBack as u64;
+
+        // Check if the base address is 4KB aligned (the lower 12 bits should be zero).
         if ept_pml4_base_addr.trailing_zeros() >= 12 {
             // Construct the EPTP with the page walk length and memory type for WB.
             Ok(ept_pml4_base_addr | EPT_PAGE_WALK_LENGTH_4 | EPT_MEMORY_TYPE_WB)
@@ -380,6 +2216,39 @@ impl Ept {
             Err(HypervisorError::InvalidEptPml4BaseAddress)
         }
     }
+
+    /// Checks that an EPTP has the format produced by `create_eptp_with_wb_and_4lvl_walk`.
+    ///
+    /// The EPTP must use the Write-Back memory type and a 4-level page walk, must not set any of
+    /// the reserved bits 11:7, and must point at a 4KB-aligned PML4 table. Bit 6 (accessed and dirty
+    /// flags) may be set.
+    ///
+    /// # Arguments
+    /// * `eptp` - The EPTP to check.
+    ///
+    /// # Returns
+    /// `Ok(())` if the EPTP is valid, or `Err(HypervisorError::InvalidEptp)` otherwise.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.11 Extended-Page-Table Pointer (EPTP)
+    pub fn validate_eptp(eptp: u64) -> Result<(), HypervisorError> {
+        const MEMORY_TYPE_MASK: u64 = 0b111;
+        const PAGE_WALK_LENGTH_MASK: u64 = 0b111 << 3;
+        const EPT_PAGE_WALK_LENGTH_4: u64 = 3 << 3;
+        const RESERVED_MASK: u64 = 0b1_1111 << 7;
+
+        let is_valid = eptp & MEMORY_TYPE_MASK == MemoryType::WriteBack as u64
+            && eptp & PAGE_WALK_LENGTH_MASK == EPT_PAGE_WALK_LENGTH_4
+            && eptp & RESERVED_MASK == 0
+            && eptp >> BASE_PAGE_SHIFT != 0;
+
+        match is_valid {
+            true => Ok(()),
+            false => {
+                error!("Invalid EPTP: {:#x}", eptp);
+                Err(HypervisorError::InvalidEptp)
+            }
+        }
+    }
 }
 
 /// Represents an EPT PML4 Entry (PML4E) that references a Page-Directory-Pointer Table.
@@ -415,6 +2284,154 @@ struct Pd(Table);
 #[derive(Debug, Clone, Copy)]
 struct Pt(Table);
 
+/// A level of the EPT paging hierarchy, ordered from the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EptLevel {
+    /// The PML4 table.
+    Pml4,
+    /// The page-directory-pointer table.
+    Pdpt,
+    /// A page directory.
+    Pd,
+    /// A page table.
+    Pt,
+}
+
+/// The reason an EPT entry causes an EPT misconfiguration.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.1 EPT Misconfigurations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MisconfigurationCause {
+    /// The entry allows write access, but not read access.
+    WriteWithoutRead,
+    /// The entry allows supervisor-mode or user-mode execute access, but not read access, and the processor does not support
+    /// execute-only translations.
+    ExecuteOnlyUnsupported,
+    /// The entry sets reserved bits. Holds the reserved bits that are set.
+    ReservedBits(u64),
+    /// The entry maps a page with a reserved memory type. Holds the memory type.
+    InvalidMemoryType(u64),
+}
+
+impl core::fmt::Display for MisconfigurationCause {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::WriteWithoutRead => write!(f, "write access without read access"),
+            Self::ExecuteOnlyUnsupported => write!(
+                f,
+                "execute-only access, which the processor does not support"
+            ),
+            Self::ReservedBits(bits) => write!(f, "reserved bits {:#x} are set", bits),
+            Self::InvalidMemoryType(memory_type) => {
+                write!(f, "reserved memory type {}", memory_type)
+            }
+        }
+    }
+}
+
+/// A malformed EPT entry found by `Ept::find_misconfiguration`.
+#[derive(Debug, Clone, Copy)]
+pub struct Misconfiguration {
+    /// The level of the paging structure containing the entry.
+    pub level: EptLevel,
+    /// The index of the entry within its paging structure.
+    pub index: usize,
+    /// The raw value of the entry.
+    pub entry: u64,
+    /// Why the entry is malformed.
+    pub cause: MisconfigurationCause,
+}
+
+/// A present mapping of guest physical memory, as reported by `Ept::iter_mapped_pages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedPage {
+    /// The guest physical address of the start of the mapping.
+    pub guest_pa: u64,
+    /// The host physical address the start of the mapping is mapped to.
+    pub host_pa: u64,
+    /// The size of the mapping in bytes. 4KB, or a multiple of 2MB for collapsed large pages.
+    pub size: u64,
+    /// The access permissions of the mapping.
+    pub access_type: AccessType,
+}
+
+/// Iterator over the present mappings of an EPT, created by `Ept::iter_mapped_pages`.
+pub struct MappedPages<'a> {
+    /// The EPT being walked.
+    ept: &'a Ept,
+    /// The guest physical address to continue the walk at.
+    guest_pa: u64,
+    /// A large page that was found while collapsing, but could not be merged into the previous mapping.
+    pending: Option<MappedPage>,
+}
+
+impl MappedPages<'_> {
+    /// Finds the next leaf entry and advances the walk past it.
+    fn next_leaf(&mut self) -> Option<MappedPage> {
+        let (guest_pa, entry, size) = self.ept.next_leaf_entry(self.guest_pa)?;
+        self.guest_pa = guest_pa + size;
+
+        Some(MappedPage {
+            guest_pa,
+            host_pa: entry.pfn() << BASE_PAGE_SHIFT,
+            size,
+            access_type: entry.access_type(),
+        })
+    }
+}
+
+impl Iterator for MappedPages<'_> {
+    type Item = MappedPage;
+
+    fn next(&mut self) -> Option<MappedPage> {
+        let mut mapping = match self.pending.take() {
+            Some(mapping) => mapping,
+            None => self.next_leaf()?,
+        };
+
+        if mapping.size == BASE_PAGE_SIZE as u64 {
+            return Some(mapping);
+        }
+
+        // Extend the large page with the following ones while they continue it.
+        while let Some(next) = self.next_leaf() {
+            let continues = next.size != BASE_PAGE_SIZE as u64
+                && next.guest_pa == mapping.guest_pa + mapping.size
+                && next.host_pa == mapping.host_pa + mapping.size
+                && next.access_type == mapping.access_type;
+
+            if !continues {
+                self.pending = Some(next);
+                break;
+            }
+
+            mapping.size += next.size;
+        }
+
+        Some(mapping)
+    }
+}
+
+/// Aligns a guest physical address up to a power-of-two boundary.
+fn align_up(guest_pa: u64, alignment: u64) -> u64 {
+    (guest_pa + alignment - 1) & !(alignment - 1)
+}
+
+/// Reserved bits 7:3 of EPT entries that reference a paging structure.
+const TABLE_RESERVED_MASK: u64 = 0b1_1111 << 3;
+
+/// Reserved bits 29:12 of EPT PDPTEs that map a 1GB page.
+const HUGE_PAGE_RESERVED_MASK: u64 = 0x3fff_f000;
+
+/// Reserved bits 20:12 of EPT PDEs that map a 2MB page.
+const LARGE_PAGE_RESERVED_MASK: u64 = 0x1f_f000;
+
+/// Returns the bits 51:MAXPHYADDR, which are reserved in every EPT entry.
+fn address_reserved_mask() -> u64 {
+    let physical_address_bits = x86::cpuid::cpuid!(0x8000_0008).eax & 0xff;
+    ((1u64 << 52) - 1) & !((1u64 << physical_address_bits) - 1)
+}
+
 /// General struct to represent a table in the EPT paging structure.
 ///
 /// This struct is used as a basis for PML4, PDPT, PD, and PT. It contains an array of entries
@@ -425,6 +2442,15 @@ struct Table {
     entries: [Entry; 512],
 }
 
+/// Returns the page frame number of a paging structure, as referenced by the entries pointing at it.
+///
+/// # Arguments
+///
+/// * `table` - A pointer to the paging structure in host virtual memory.
+fn table_pfn<T>(table: *const T) -> Result<u64, HypervisorError> {
+    Ok(PhysicalAddress::from_host_va(table as u64)? >> BASE_PAGE_SHIFT)
+}
+
 bitfield! {
     /// Represents an Extended Page Table Entry (EPT Entry).
     ///
@@ -435,9 +2461,12 @@ bitfield! {
     ///
     /// * `readable` - If set, the memory region can be read.
     /// * `writable` - If set, the memory region can be written to.
-    /// * `executable` - If set, code can be executed from the memory region.
+    /// * `executable` - If set, code can be executed from the memory region. With mode-based execute control,
+    ///   this only allows supervisor-mode execution (`supervisor_executable`).
     /// * `memory_type` - The memory type (e.g., WriteBack, Uncacheable).
     /// * `large` - If set, this entry maps a large page.
+    /// * `user_executable` - If set and mode-based execute control is enabled, user-mode code can be executed
+    ///   from the memory region. Ignored otherwise.
     /// * `pfn` - The Page Frame Number, indicating the physical address.
     /// * `verify_guest_paging` - Additional flag for guest paging verification.
     /// * `paging_write_access` - Additional flag for paging write access.
@@ -453,28 +2482,340 @@ bitfield! {
     pub executable, set_executable: 2;
     pub memory_type, set_memory_type: 5, 3;
     pub large, set_large: 7;
+    pub user_executable, set_user_executable: 10;
     pub pfn, set_pfn: 51, 12;
     pub verify_guest_paging, set_verify_guest_paging: 57;
     pub paging_write_access, set_paging_write_access: 58;
 }
 
+impl Entry {
+    /// Returns the raw value of the entry.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Checks whether the entry maps or references anything, i.e. grants any access.
+    pub fn is_present(&self) -> bool {
+        self.readable() || self.writable() || self.executable() || self.user_executable()
+    }
+
+    /// Checks whether supervisor-mode code can be executed from the memory region.
+    ///
+    /// This is the `executable` bit, which only applies to supervisor-mode accesses when mode-based
+    /// execute control is enabled.
+    pub fn supervisor_executable(&self) -> bool {
+        self.executable()
+    }
+
+    /// Sets whether supervisor-mode code can be executed from the memory region.
+    pub fn set_supervisor_executable(&mut self, executable: bool) {
+        self.set_executable(executable);
+    }
+
+    /// Returns the read, write, and execute permissions of the entry.
+    pub fn access_type(&self) -> AccessType {
+        let mut access_type = AccessType::empty();
+        access_type.set(AccessType::READ, self.readable());
+        access_type.set(AccessType::WRITE, self.writable());
+        access_type.set(AccessType::EXECUTE, self.executable());
+        access_type.set(AccessType::USER_EXECUTE, self.user_executable());
+        access_type
+    }
+
+    /// Sets the read, write, and execute permissions of the entry.
+    pub fn set_access_type(&mut self, access_type: AccessType) {
+        self.set_readable(access_type.contains(AccessType::READ));
+        self.set_writable(access_type.contains(AccessType::WRITE));
+        self.set_executable(access_type.contains(AccessType::EXECUTE));
+        self.set_user_executable(access_type.contains(AccessType::USER_EXECUTE));
+    }
+
+    /// Checks whether the entry uses one of the memory types UC, WC, WT, WP, and WB. The memory types 2, 3, and 7 are reserved.
+    pub fn has_valid_memory_type(&self) -> bool {
+        matches!(self.memory_type(), 0 | 1 | 4 | 5 | 6)
+    }
+
+    /// Determines why a present entry causes an EPT misconfiguration, if it does.
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved_mask` - The bits that are reserved at the level of the entry.
+    /// * `is_leaf` - Whether the entry maps a page, in which case its memory type is checked.
+    /// * `execute_only_supported` - Whether the processor supports execute-only translations.
+    fn misconfiguration_cause(
+        &self,
+        reserved_mask: u64,
+        is_leaf: bool,
+        execute_only_supported: bool,
+    ) -> Option<MisconfigurationCause> {
+        if self.writable() && !self.readable() {
+            Some(MisconfigurationCause::WriteWithoutRead)
+        } else if (self.executable() || self.user_executable())
+            && !self.readable()
+            && !execute_only_supported
+        {
+            Some(MisconfigurationCause::ExecuteOnlyUnsupported)
+        } else if self.0 & reserved_mask != 0 {
+            Some(MisconfigurationCause::ReservedBits(self.0 & reserved_mask))
+        } else if is_leaf && !self.has_valid_memory_type() {
+            Some(MisconfigurationCause::InvalidMemoryType(self.memory_type()))
+        } else {
+            None
+        }
+    }
+
+    /// Sets the "verify guest paging" and "paging-write access" bits of a leaf entry.
+    pub fn set_paging_verification(&mut self, verification: PagingVerification) {
+        self.set_verify_guest_paging(
+            verification.contains(PagingVerification::VERIFY_GUEST_PAGING),
+        );
+        self.set_paging_write_access(
+            verification.contains(PagingVerification::PAGING_WRITE_ACCESS),
+        );
+    }
+}
+
+impl From<&Entry> for AccessType {
+    fn from(entry: &Entry) -> Self {
+        entry.access_type()
+    }
+}
+
 bitflags::bitflags! {
     /// Represents the different access permissions for an EPT entry.
-    #[derive(Debug, Clone, Copy)]
+    ///
+    /// With mode-based execute control enabled, `EXECUTE` only allows supervisor-mode execution and
+    /// `USER_EXECUTE` allows user-mode execution. Without it, `EXECUTE` applies to all modes and
+    /// `USER_EXECUTE` is ignored. The combined execute permissions include both.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.3.2 EPT Violations
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct AccessType: u8 {
         /// The EPT entry allows read access.
-        const READ = 0b001;
+        const READ = 0b0001;
         /// The EPT entry allows write access.
-        const WRITE = 0b010;
-        /// The EPT entry allows execute access.
-        const EXECUTE = 0b100;
+        const WRITE = 0b0010;
+        /// The EPT entry allows execute access (supervisor-mode only with mode-based execute control).
+        const EXECUTE = 0b0100;
+        /// The EPT entry allows user-mode execute access with mode-based execute control.
+        const USER_EXECUTE = 0b1000;
+        /// The EPT entry allows execute access in both supervisor and user mode.
+        const EXECUTE_ALL_MODES = Self::EXECUTE.bits() | Self::USER_EXECUTE.bits();
         /// The EPT entry allows read and write access.
         const READ_WRITE = Self::READ.bits() | Self::WRITE.bits();
         /// The EPT entry allows read and execute access.
-        const READ_EXECUTE = Self::READ.bits() | Self::EXECUTE.bits();
+        const READ_EXECUTE = Self::READ.bits() | Self::EXECUTE_ALL_MODES.bits();
         /// The EPT entry allows write and execute access.
-        const WRITE_EXECUTE = Self::WRITE.bits() | Self::EXECUTE.bits();
+        const WRITE_EXECUTE = Self::WRITE.bits() | Self::EXECUTE_ALL_MODES.bits();
         /// The EPT entry allows read, write, and execute access.
-        const READ_WRITE_EXECUTE = Self::READ.bits() | Self::WRITE.bits() | Self::EXECUTE.bits();
+        const READ_WRITE_EXECUTE = Self::READ.bits() | Self::WRITE.bits() | Self::EXECUTE_ALL_MODES.bits();
+    }
+}
+
+bitflags::bitflags! {
+    /// Represents the paging-verification bits of a leaf EPT entry.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 29.3.2 EPT Translation Mechanism
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PagingVerification: u8 {
+        /// Guest paging structures used to translate the address must be mapped with "paging-write access".
+        const VERIFY_GUEST_PAGING = 0b01;
+        /// Guest paging-structure updates (A/D bits) are allowed even if the page is not writable.
+        const PAGING_WRITE_ACCESS = 0b10;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::intel::{ept::mtrr::MtrrRangeDescriptor, vm::box_zeroed},
+        alloc::{boxed::Box, vec},
+    };
+
+    /// A 2MB page outside the first 2MB, which are always mapped by `pt[0]`.
+    const LARGE_PAGE_GPA: u64 = 0x40_0000;
+
+    /// Builds an identity-mapped EPT in memory, with write-back memory outside the given ranges.
+    fn build_ept(descriptors: Vec<MtrrRangeDescriptor>) -> Box<Ept> {
+        let mut ept = unsafe { box_zeroed::<Ept>() };
+        ept.build_identity_with_page_sizes(Mtrr::from_descriptors(descriptors), false, &[])
+            .unwrap();
+        ept
+    }
+
+    #[test]
+    fn self_test_passes() {
+        assert!(build_ept(vec![]).self_test().is_ok());
+    }
+
+    #[test]
+    fn identity_map() {
+        let ept = build_ept(vec![]);
+
+        for guest_pa in [
+            0,
+            0x1000,
+            0x1f_f123,
+            LARGE_PAGE_GPA,
+            0x1_0000_0000,
+            0x7f_ffff_f000,
+        ] {
+            assert_eq!(ept.gpa_to_hpa(guest_pa), Some(guest_pa));
+            assert_eq!(
+                ept.page_permissions(guest_pa),
+                Some(AccessType::READ_WRITE_EXECUTE)
+            );
+        }
+
+        assert_eq!(ept.gpa_to_hpa(0x80_0000_0000), None);
+        assert_eq!(ept.split_pt_index(0), Some(0));
+        assert_eq!(ept.split_pt_index(LARGE_PAGE_GPA), None);
+    }
+
+    #[test]
+    fn memory_types_follow_mtrrs() {
+        let ept = build_ept(vec![MtrrRangeDescriptor {
+            base_address: 0x60_0000,
+            end_address: 0x9f_ffff,
+            memory_type: MemoryType::Uncacheable,
+        }]);
+
+        assert_eq!(
+            ept.page_memory_type(0x60_0000),
+            Some(MemoryType::Uncacheable)
+        );
+        assert_eq!(
+            ept.page_memory_type(LARGE_PAGE_GPA),
+            Some(MemoryType::WriteBack)
+        );
+    }
+
+    #[test]
+    fn split_unaligned_address_maps_from_large_page_base() {
+        let mut ept = build_ept(vec![]);
+        let guest_pa = LARGE_PAGE_GPA + 0x12_3000;
+
+        let pt_table_index = ept.split_2mb_to_4kb_alloc(guest_pa).unwrap();
+
+        assert_eq!(ept.split_pt_index(guest_pa), Some(pt_table_index));
+        assert!(ept.is_identity_split(guest_pa));
+        for page in
+            (LARGE_PAGE_GPA..LARGE_PAGE_GPA + LARGE_PAGE_SIZE as u64).step_by(BASE_PAGE_SIZE)
+        {
+            assert_eq!(ept.gpa_to_hpa(page), Some(page));
+        }
+    }
+
+    #[test]
+    fn merge_reverts_split_and_releases_page_table() {
+        let mut ept = build_ept(vec![]);
+        let guest_pa = LARGE_PAGE_GPA + 0x5000;
+
+        let pt_table_index = ept.split_2mb_to_4kb_alloc(guest_pa).unwrap();
+        ept.modify_page_permissions(guest_pa, AccessType::READ_WRITE, pt_table_index)
+            .unwrap();
+        assert_eq!(ept.page_permissions(guest_pa), Some(AccessType::READ_WRITE));
+        assert!(!ept.is_identity_split(guest_pa));
+
+        ept.merge_4kb_to_2mb(guest_pa).unwrap();
+
+        assert_eq!(ept.split_pt_index(guest_pa), None);
+        assert_eq!(ept.gpa_to_hpa(guest_pa), Some(guest_pa));
+        assert_eq!(
+            ept.page_permissions(guest_pa),
+            Some(AccessType::READ_WRITE_EXECUTE)
+        );
+        assert_eq!(ept.alloc_pt_index().unwrap(), pt_table_index);
+    }
+
+    #[test]
+    fn reused_page_table_drops_previous_bits() {
+        let mut ept = build_ept(vec![]);
+        let guest_pa = LARGE_PAGE_GPA + 0x5000;
+
+        let pt_table_index = ept.split_2mb_to_4kb_alloc(guest_pa).unwrap();
+        ept.modify_page_permissions_with_verification(
+            guest_pa,
+            AccessType::READ_WRITE_EXECUTE,
+            PagingVerification::all(),
+            pt_table_index,
+        )
+        .unwrap();
+        ept.merge_4kb_to_2mb(guest_pa).unwrap();
+
+        let next_guest_pa = guest_pa + LARGE_PAGE_SIZE as u64;
+        assert_eq!(
+            ept.split_2mb_to_4kb_alloc(next_guest_pa).unwrap(),
+            pt_table_index
+        );
+
+        let (entry, _) = ept.leaf_entry(next_guest_pa).unwrap();
+        assert!(!entry.verify_guest_paging());
+        assert!(!entry.paging_write_access());
+        assert!(ept.is_identity_split(next_guest_pa));
+    }
+
+    #[test]
+    fn page_tables_run_out() {
+        let mut ept = build_ept(vec![]);
+
+        for i in 0..63 {
+            ept.split_2mb_to_4kb_alloc(LARGE_PAGE_GPA + i * LARGE_PAGE_SIZE as u64)
+                .unwrap();
+        }
+
+        let next_large_page = LARGE_PAGE_GPA + 63 * LARGE_PAGE_SIZE as u64;
+        assert!(matches!(
+            ept.split_2mb_to_4kb_alloc(next_large_page),
+            Err(HypervisorError::NoFreePtIndex)
+        ));
+        assert_eq!(ept.split_pt_index(next_large_page), None);
+    }
+
+    #[test]
+    fn clone_uses_own_page_tables() {
+        let mut primary = build_ept(vec![]);
+        let guest_pa = LARGE_PAGE_GPA + 0x3000;
+        let pt_table_index = primary.split_2mb_to_4kb_alloc(guest_pa).unwrap();
+
+        let mut secondary = unsafe { box_zeroed::<Ept>() };
+        secondary.clone_from(&primary).unwrap();
+        secondary
+            .modify_page_permissions(guest_pa, AccessType::EXECUTE, pt_table_index)
+            .unwrap();
+
+        assert_eq!(
+            secondary.page_permissions(guest_pa),
+            Some(AccessType::EXECUTE)
+        );
+        assert_eq!(
+            primary.page_permissions(guest_pa),
+            Some(AccessType::READ_WRITE_EXECUTE)
+        );
+    }
+
+    #[test]
+    fn remaps_into_first_large_page_change_nothing() {
+        let mut ept = build_ept(vec![]);
+        let remaps = [
+            Remap {
+                guest_range: LARGE_PAGE_GPA..LARGE_PAGE_GPA + 0x1000,
+                host_range: 0x80_0000..0x80_1000,
+                memory_type: MemoryType::WriteBack,
+            },
+            Remap {
+                guest_range: 0x1000..0x2000,
+                host_range: 0x80_1000..0x80_2000,
+                memory_type: MemoryType::WriteBack,
+            },
+        ];
+
+        assert!(matches!(
+            ept.apply_remaps(&remaps, &ReservedRegions::new()),
+            Err(HypervisorError::InvalidPtIndex(0))
+        ));
+        assert_eq!(ept.split_pt_index(LARGE_PAGE_GPA), None);
+        assert_eq!(ept.gpa_to_hpa(LARGE_PAGE_GPA), Some(LARGE_PAGE_GPA));
     }
 }
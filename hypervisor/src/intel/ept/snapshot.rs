@@ -0,0 +1,263 @@
+//! Captures the entries of an EPT and diffs them against a later capture.
+//!
+//! Used to debug unexpected EPT mutations, e.g. to verify that `split_2mb_to_4kb` or
+//! `modify_page_permissions` changed only the entries they were meant to. Take a snapshot with
+//! `Ept::snapshot` before the operation and another one after it, and list the changed entries with
+//! `EptSnapshot::diff`.
+//!
+//! An identity-mapped EPT has hundreds of thousands of present entries, so a snapshot does not store
+//! them one by one. Consecutive entries of a paging structure whose values grow by the same stride,
+//! like the PDEs of an identity map with the same memory type, are stored as a single run. Entries
+//! that are zero are not stored at all.
+//!
+//! Snapshots are allocated, so they must not be taken from a VM-exit handler.
+
+use {
+    crate::intel::ept::paging::{Ept, EptLevel},
+    alloc::vec::Vec,
+};
+
+/// The number of entries in each EPT paging structure.
+const ENTRIES_PER_TABLE: usize = 512;
+
+/// The position of an entry in the EPT paging structures.
+///
+/// Positions are ordered by level, then table, then index, which is the order in which a snapshot
+/// stores its entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EntryPosition {
+    /// The level of the paging structure containing the entry.
+    pub level: EptLevel,
+
+    /// The index of the paging structure within its level: the PDPT index for page directories,
+    /// the index within the `pt` array for page tables, and 0 for the PML4 and the PDPT.
+    pub table: usize,
+
+    /// The index of the entry within its paging structure.
+    pub index: usize,
+}
+
+/// An entry that differs between two snapshots, as reported by `EptSnapshot::diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryChange {
+    /// The position of the entry.
+    pub position: EntryPosition,
+
+    /// The raw value of the entry in the older snapshot, 0 if it was not present.
+    pub before: u64,
+
+    /// The raw value of the entry in the newer snapshot, 0 if it is not present.
+    pub after: u64,
+}
+
+impl core::fmt::Display for EntryChange {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:?} {}/{}: {:#x} -> {:#x}",
+            self.position.level, self.position.table, self.position.index, self.before, self.after
+        )
+    }
+}
+
+/// Consecutive non-zero entries of a paging structure whose values form an arithmetic sequence.
+#[derive(Debug, Clone, Copy)]
+struct EntryRun {
+    /// The position of the first entry of the run.
+    start: EntryPosition,
+
+    /// The number of entries in the run.
+    count: usize,
+
+    /// The raw value of the first entry.
+    first_value: u64,
+
+    /// The difference between the values of consecutive entries.
+    stride: u64,
+}
+
+impl EntryRun {
+    /// Returns the value of an entry of the run.
+    fn value(&self, offset: usize) -> u64 {
+        self.first_value
+            .wrapping_add(self.stride.wrapping_mul(offset as u64))
+    }
+
+    /// Returns the position of an entry of the run.
+    fn position(&self, offset: usize) -> EntryPosition {
+        EntryPosition {
+            index: self.start.index + offset,
+            ..self.start
+        }
+    }
+
+    /// Returns whether an entry continues the run.
+    fn continues(&self, position: EntryPosition, value: u64) -> bool {
+        let next = self.position(self.count);
+
+        match self.count {
+            1 => next == position,
+            _ => next == position && self.value(self.count) == value,
+        }
+    }
+}
+
+/// A compact copy of the non-zero entries of an EPT, taken with `Ept::snapshot`.
+#[derive(Debug, Clone, Default)]
+pub struct EptSnapshot {
+    /// The runs of entries, ordered by the position of their first entry.
+    runs: Vec<EntryRun>,
+}
+
+impl EptSnapshot {
+    /// Returns the number of non-zero entries in the snapshot.
+    pub fn entry_count(&self) -> usize {
+        self.runs.iter().map(|run| run.count).sum()
+    }
+
+    /// Returns the number of runs the entries are stored as, which determines the size of the snapshot.
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Lists the entries that differ between this snapshot and a newer one.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The newer snapshot, of the same EPT.
+    ///
+    /// # Returns
+    ///
+    /// The changed entries, ordered by position. Entries that became present have a `before` of 0,
+    /// and entries that were cleared have an `after` of 0.
+    pub fn diff(&self, other: &EptSnapshot) -> Vec<EntryChange> {
+        let mut changes = Vec::new();
+        let mut before = self.entries().peekable();
+        let mut after = other.entries().peekable();
+
+        loop {
+            let change = match (before.peek().copied(), after.peek().copied()) {
+                (None, None) => break,
+                (Some((position, value)), None) => {
+                    before.next();
+                    (position, value, 0)
+                }
+                (None, Some((position, value))) => {
+                    after.next();
+                    (position, 0, value)
+                }
+                (Some((old_position, old_value)), Some((new_position, new_value))) => {
+                    match old_position.cmp(&new_position) {
+                        core::cmp::Ordering::Less => {
+                            before.next();
+                            (old_position, old_value, 0)
+                        }
+                        core::cmp::Ordering::Greater => {
+                            after.next();
+                            (new_position, 0, new_value)
+                        }
+                        core::cmp::Ordering::Equal => {
+                            before.next();
+                            after.next();
+                            (old_position, old_value, new_value)
+                        }
+                    }
+                }
+            };
+
+            let (position, before, after) = change;
+            if before != after {
+                changes.push(EntryChange {
+                    position,
+                    before,
+                    after,
+                });
+            }
+        }
+
+        changes
+    }
+
+    /// Returns the non-zero entries of the snapshot in position order.
+    fn entries(&self) -> impl Iterator<Item = (EntryPosition, u64)> + '_ {
+        self.runs.iter().flat_map(|run| {
+            (0..run.count).map(move |offset| (run.position(offset), run.value(offset)))
+        })
+    }
+
+    /// Appends an entry, which must follow all entries appended before. Zero entries are skipped.
+    fn push(&mut self, position: EntryPosition, value: u64) {
+        if value == 0 {
+            return;
+        }
+
+        if let Some(run) = self.runs.last_mut() {
+            if run.continues(position, value) {
+                if run.count == 1 {
+                    run.stride = value.wrapping_sub(run.first_value);
+                }
+                run.count += 1;
+                return;
+            }
+        }
+
+        self.runs.push(EntryRun {
+            start: position,
+            count: 1,
+            first_value: value,
+            stride: 0,
+        });
+    }
+}
+
+impl Ept {
+    /// Takes a snapshot of all non-zero entries of the EPT, including unused page tables.
+    ///
+    /// This allocates, so it must not be called from a VM-exit handler.
+    ///
+    /// # Returns
+    ///
+    /// The `EptSnapshot`, to be compared with a later one using `EptSnapshot::diff`.
+    pub fn snapshot(&self) -> EptSnapshot {
+        let mut snapshot = EptSnapshot::default();
+
+        let mut push_table =
+            |level: EptLevel, table: usize, entry: &dyn Fn(usize) -> Option<u64>| {
+                for index in 0..ENTRIES_PER_TABLE {
+                    if let Some(value) = entry(index) {
+                        snapshot.push(
+                            EntryPosition {
+                                level,
+                                table,
+                                index,
+                            },
+                            value,
+                        );
+                    }
+                }
+            };
+
+        push_table(EptLevel::Pml4, 0, &|index| {
+            self.pml4_entry(index).map(|entry| entry.bits())
+        });
+        push_table(EptLevel::Pdpt, 0, &|index| {
+            self.pdpt_entry(index).map(|entry| entry.bits())
+        });
+
+        for table in 0..ENTRIES_PER_TABLE {
+            push_table(EptLevel::Pd, table, &|index| {
+                self.pd_entry(table, index).map(|entry| entry.bits())
+            });
+        }
+
+        let mut table = 0;
+        while self.pt_entry(table, 0).is_some() {
+            push_table(EptLevel::Pt, table, &|index| {
+                self.pt_entry(table, index).map(|entry| entry.bits())
+            });
+            table += 1;
+        }
+
+        snapshot
+    }
+}
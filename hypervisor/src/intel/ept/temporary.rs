@@ -0,0 +1,179 @@
+//! Tracks temporary permission grants on 4KB guest pages.
+//!
+//! Stepping over a hooked instruction follows a fixed pattern: save the permissions of the page,
+//! grant full access, single-step the guest with the monitor trap flag (MTF), and restore the saved
+//! permissions in the MTF handler. `Ept::with_temporary_access` performs the first half and records
+//! the saved permissions here, and `handle_monitor_trap_flag` restores them.
+//!
+//! Grants are keyed by guest page and processor, so processors stepping over different pages do not
+//! clobber each other. If several processors grant access to the same page, the permissions from
+//! before the first grant are restored once the last grant is released.
+//!
+//! Like the hook registry, the grants have a fixed capacity, since memory cannot be allocated from a
+//! VM-exit handler.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::ept::paging::{AccessType, Ept},
+        logger::apic_id,
+    },
+    spin::Mutex,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The maximum number of temporary grants that can be active at the same time.
+pub const MAX_TEMPORARY_GRANTS: usize = 64;
+
+/// A single temporary grant.
+#[derive(Debug, Clone, Copy)]
+struct Grant {
+    /// The page-aligned guest physical address of the page.
+    guest_page_pa: u64,
+
+    /// The address of the EPT the access was granted in.
+    ept: u64,
+
+    /// The APIC ID of the processor that holds the grant.
+    apic_id: u32,
+
+    /// The permissions of the page before the first grant on it.
+    saved_access_type: AccessType,
+
+    /// The index of the page table that maps the page.
+    pt_table_index: usize,
+}
+
+/// Registry of the temporary permission grants of all processors.
+#[derive(Debug)]
+pub struct TemporaryAccess {
+    /// The active grants. `None` entries are free slots.
+    grants: Mutex<[Option<Grant>; MAX_TEMPORARY_GRANTS]>,
+}
+
+impl Default for TemporaryAccess {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TemporaryAccess {
+    /// Creates an empty `TemporaryAccess`.
+    pub const fn new() -> Self {
+        Self {
+            grants: Mutex::new([None; MAX_TEMPORARY_GRANTS]),
+        }
+    }
+
+    /// Grants temporary access to a page for the current processor, recording the permissions to restore.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept` - The EPT to change. The 2MB page containing `guest_pa` must be split.
+    /// * `guest_pa` - Any guest physical address within the page.
+    /// * `access_type` - The permissions to grant.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::PageNotSplit)` if the page is not mapped by a 4KB page,
+    /// `Err(HypervisorError::TemporaryAccessFull)` if no free slot is left, or the error of the
+    /// permission change.
+    pub fn grant(
+        &self,
+        ept: &mut Ept,
+        guest_pa: u64,
+        access_type: AccessType,
+    ) -> Result<(), HypervisorError> {
+        let guest_page_pa = page_align(guest_pa);
+        let ept_address = ept as *const Ept as u64;
+
+        let pt_table_index = ept
+            .split_pt_index(guest_page_pa)
+            .ok_or(HypervisorError::PageNotSplit)?;
+
+        let mut grants = self.grants.lock();
+
+        // A page that is already granted holds temporary permissions, so its original ones are reused.
+        let saved_access_type = match grants
+            .iter()
+            .flatten()
+            .find(|grant| grant.guest_page_pa == guest_page_pa && grant.ept == ept_address)
+        {
+            Some(grant) => grant.saved_access_type,
+            None => ept
+                .page_permissions(guest_page_pa)
+                .ok_or(HypervisorError::GuestPhysicalAddressNotMapped)?,
+        };
+
+        let slot = grants
+            .iter_mut()
+            .find(|grant| grant.is_none())
+            .ok_or(HypervisorError::TemporaryAccessFull)?;
+
+        ept.modify_page_permissions(guest_page_pa, access_type, pt_table_index)?;
+
+        *slot = Some(Grant {
+            guest_page_pa,
+            ept: ept_address,
+            apic_id: apic_id(),
+            saved_access_type,
+            pt_table_index,
+        });
+
+        Ok(())
+    }
+
+    /// Releases the grants the current processor holds in an EPT.
+    ///
+    /// The saved permissions of a page are restored once no processor holds a grant on it anymore.
+    /// The caller is responsible for invalidating the EPT caches (`invept_all_contexts`).
+    ///
+    /// # Arguments
+    ///
+    /// * `ept` - The EPT the access was granted in.
+    ///
+    /// # Returns
+    ///
+    /// The number of released grants, or the error of the first failed permission change. The grants
+    /// are released either way.
+    pub fn restore(&self, ept: &mut Ept) -> Result<usize, HypervisorError> {
+        let ept_address = ept as *const Ept as u64;
+        let apic_id = apic_id();
+        let mut grants = self.grants.lock();
+        let mut released = 0;
+        let mut result = Ok(());
+
+        for index in 0..grants.len() {
+            let Some(grant) = grants[index] else {
+                continue;
+            };
+
+            if grant.ept != ept_address || grant.apic_id != apic_id {
+                continue;
+            }
+
+            grants[index] = None;
+            released += 1;
+
+            let still_granted = grants.iter().flatten().any(|other| {
+                other.guest_page_pa == grant.guest_page_pa && other.ept == ept_address
+            });
+
+            if !still_granted {
+                let restored = ept.modify_page_permissions(
+                    grant.guest_page_pa,
+                    grant.saved_access_type,
+                    grant.pt_table_index,
+                );
+                result = result.and(restored);
+            }
+        }
+
+        result.map(|_| released)
+    }
+}
+
+/// Aligns a guest physical address down to its 4KB page.
+fn page_align(guest_pa: u64) -> u64 {
+    guest_pa & !(BASE_PAGE_SIZE as u64 - 1)
+}
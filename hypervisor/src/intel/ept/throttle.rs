@@ -0,0 +1,256 @@
+//! Throttles the logging of EPT violations.
+//!
+//! In the EPT-swap hook model, every call to a hooked function causes EPT violations, so a hot hook
+//! easily causes thousands of them per second. Logging each one floods the serial port and slows
+//! the guest to a crawl. Instead, violations are counted per guest page and a summary is logged
+//! once per period, e.g. "GPA 0x1000: 5000 EPT violations in the last 3000000000 TSC cycles".
+//!
+//! The first violation on a page is still logged in full, and every violation is logged in full
+//! while verbose logging is enabled.
+//!
+//! Like the other registries, the counters have a fixed capacity, since memory cannot be allocated
+//! from a VM-exit handler. When they are full, the counter with the oldest period is summarized and
+//! reused.
+//!
+//! Each processor also counts its consecutive violations at the same guest physical address and RIP
+//! in a `ViolationStreak`. A long streak means the guest re-executes the faulting instruction without
+//! making progress, e.g. because a misconfigured hook faults on every access.
+
+use {
+    crate::intel::support::rdtsc,
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    spin::Mutex,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The maximum number of guest pages whose violations are counted at the same time.
+pub const MAX_THROTTLED_PAGES: usize = 64;
+
+/// The default summary period in TSC cycles, roughly one second on a 3 GHz TSC.
+pub const DEFAULT_SUMMARY_PERIOD_TSC: u64 = 3_000_000_000;
+
+/// The number of consecutive violations at the same guest physical address and RIP after which a
+/// processor is considered livelocked.
+///
+/// A working EPT-swap hook faults at most twice in a row at the same address, once per EPTP swap.
+pub const LIVELOCK_THRESHOLD: u32 = 64;
+
+/// The violations counted for a single guest page in the current period.
+#[derive(Debug, Clone, Copy)]
+struct ViolationCounter {
+    /// The page-aligned guest physical address of the page.
+    guest_page_pa: u64,
+
+    /// The number of violations since `period_start`.
+    count: u64,
+
+    /// The TSC value at the start of the current period.
+    period_start: u64,
+}
+
+/// Per-page counters of EPT violations, used to coalesce their logging into periodic summaries.
+#[derive(Debug)]
+pub struct ViolationThrottle {
+    /// The counters. `None` entries are free slots.
+    counters: Mutex<[Option<ViolationCounter>; MAX_THROTTLED_PAGES]>,
+
+    /// Whether every violation is logged in full.
+    verbose: AtomicBool,
+
+    /// The summary period in TSC cycles.
+    period_tsc: AtomicU64,
+}
+
+impl Default for ViolationThrottle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ViolationThrottle {
+    /// Creates a `ViolationThrottle` with no counters, verbose logging disabled, and the default period.
+    pub const fn new() -> Self {
+        Self {
+            counters: Mutex::new([None; MAX_THROTTLED_PAGES]),
+            verbose: AtomicBool::new(false),
+            period_tsc: AtomicU64::new(DEFAULT_SUMMARY_PERIOD_TSC),
+        }
+    }
+
+    /// Enables or disables logging every violation in full.
+    ///
+    /// # Arguments
+    ///
+    /// * `verbose` - Whether every violation is logged in full instead of being summarized.
+    pub fn set_verbose(&self, verbose: bool) {
+        self.verbose.store(verbose, Ordering::Relaxed);
+    }
+
+    /// Sets the period after which the violations of a page are summarized.
+    ///
+    /// # Arguments
+    ///
+    /// * `period_tsc` - The period in TSC cycles.
+    pub fn set_period(&self, period_tsc: u64) {
+        self.period_tsc.store(period_tsc, Ordering::Relaxed);
+    }
+
+    /// Counts a violation, and logs the summary of its page if the period has elapsed.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address that caused the violation.
+    ///
+    /// # Returns
+    ///
+    /// Whether the violation should be logged in full: always while verbose logging is enabled,
+    /// otherwise only for the first violation on a page.
+    pub fn record(&self, guest_pa: u64) -> bool {
+        if self.verbose.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let guest_page_pa = guest_pa & !(BASE_PAGE_SIZE as u64 - 1);
+        let now = rdtsc();
+        let period_tsc = self.period_tsc.load(Ordering::Relaxed);
+
+        let mut summaries = [None; 2];
+        let mut first_violation = false;
+
+        {
+            let mut counters = self.counters.lock();
+
+            let existing = counters.iter().position(|counter| {
+                counter.is_some_and(|counter| counter.guest_page_pa == guest_page_pa)
+            });
+
+            let index = match existing {
+                Some(index) => index,
+                None => {
+                    // Reuse a free counter, or summarize and reuse the one with the oldest period.
+                    let index = free_or_oldest(&*counters);
+                    summaries[0] = counters[index].take().map(|counter| counter.summarize(now));
+                    counters[index] = Some(ViolationCounter {
+                        guest_page_pa,
+                        count: 0,
+                        period_start: now,
+                    });
+                    first_violation = true;
+                    index
+                }
+            };
+
+            let Some(counter) = counters[index].as_mut() else {
+                unreachable!("Counter was just found or created");
+            };
+            counter.count += 1;
+
+            if now.wrapping_sub(counter.period_start) >= period_tsc {
+                summaries[1] = Some(counter.summarize(now));
+                counter.count = 0;
+                counter.period_start = now;
+            }
+        }
+
+        // Log outside of the lock, so other processors are not held up by the serial port.
+        for (guest_page_pa, count, elapsed) in summaries.into_iter().flatten() {
+            if count > 0 {
+                log::debug!(
+                    "GPA {:#x}: {} EPT violations in the last {} TSC cycles",
+                    guest_page_pa,
+                    count,
+                    elapsed
+                );
+            }
+        }
+
+        first_violation
+    }
+}
+
+impl ViolationCounter {
+    /// Returns the guest page, the number of violations, and the elapsed TSC cycles of the current period.
+    fn summarize(&self, now: u64) -> (u64, u64, u64) {
+        (
+            self.guest_page_pa,
+            self.count,
+            now.wrapping_sub(self.period_start),
+        )
+    }
+}
+
+/// Returns the index of a free counter, or of the counter with the oldest period if none is free.
+fn free_or_oldest(counters: &[Option<ViolationCounter>]) -> usize {
+    counters
+        .iter()
+        .position(Option::is_none)
+        .or_else(|| {
+            counters
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, counter)| counter.map_or(0, |counter| counter.period_start))
+                .map(|(index, _)| index)
+        })
+        .unwrap_or(0)
+}
+
+/// The consecutive EPT violations of a processor at the same guest physical address and RIP.
+///
+/// The exact address is compared, not its page, so string instructions that fault on successive
+/// addresses of the same page are making progress.
+#[derive(Debug, Clone, Copy)]
+pub struct ViolationStreak {
+    /// The guest physical address of the last violation.
+    guest_pa: u64,
+
+    /// The guest RIP of the last violation.
+    guest_rip: u64,
+
+    /// The number of consecutive violations at `guest_pa` and `guest_rip`.
+    count: u32,
+}
+
+impl Default for ViolationStreak {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ViolationStreak {
+    /// Creates an empty streak.
+    pub const fn new() -> Self {
+        Self {
+            guest_pa: u64::MAX,
+            guest_rip: u64::MAX,
+            count: 0,
+        }
+    }
+
+    /// Counts a violation, restarting the streak if it occurred at a different address or RIP.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address that caused the violation.
+    /// * `guest_rip` - The guest RIP of the faulting instruction.
+    ///
+    /// # Returns
+    ///
+    /// Whether the streak just reached `LIVELOCK_THRESHOLD`.
+    pub fn record(&mut self, guest_pa: u64, guest_rip: u64) -> bool {
+        if self.guest_pa != guest_pa || self.guest_rip != guest_rip {
+            *self = Self {
+                guest_pa,
+                guest_rip,
+                count: 0,
+            };
+        }
+
+        self.count = self.count.saturating_add(1);
+        self.count == LIVELOCK_THRESHOLD
+    }
+
+    /// Ends the streak, e.g. after the cause of a livelock has been removed.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
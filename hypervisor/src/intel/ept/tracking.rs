@@ -0,0 +1,138 @@
+//! Tracks guest writes to hooked code pages.
+//!
+//! In the EPT-swap model, a write to a hooked page (execute-only in the secondary EPT) causes an
+//! EPT violation and swaps back to the primary EPT. The write tracker lets callers register a
+//! callback for such pages, which is invoked with the guest physical address and the bytes being
+//! written, so code-patching attempts against our own hooks can be detected.
+//!
+//! Callbacks are plain `fn` pointers and the registry has a fixed capacity, since memory cannot be
+//! allocated from a VM-exit handler.
+
+use {crate::error::HypervisorError, x86::bits64::paging::BASE_PAGE_SIZE};
+
+/// The maximum number of pages that can be tracked at the same time.
+pub const MAX_TRACKED_PAGES: usize = 64;
+
+/// Callback invoked when the guest writes to a tracked page.
+///
+/// # Arguments
+///
+/// * `guest_pa` - The guest physical address of the first byte written, decoded from the faulting
+///   instruction. This precedes the tracked page if the write crosses into it. Falls back to the
+///   reported guest physical address if the instruction cannot be decoded.
+/// * `bytes` - The bytes being written, decoded from the faulting instruction. Empty if the
+///   written value could not be determined from the instruction (e.g. `add [mem], reg` or SIMD stores).
+pub type WriteCallback = fn(guest_pa: u64, bytes: &[u8]);
+
+/// A single page registered for write tracking.
+#[derive(Debug, Clone, Copy)]
+struct TrackedPage {
+    /// The page-aligned guest physical address of the tracked page.
+    guest_page_pa: u64,
+
+    /// The callback to invoke on writes to the page.
+    callback: WriteCallback,
+}
+
+/// Registry of guest physical pages whose writes are reported to a callback.
+#[derive(Debug)]
+pub struct WriteTracker {
+    /// The tracked pages. `None` entries are free slots.
+    pages: [Option<TrackedPage>; MAX_TRACKED_PAGES],
+}
+
+impl Default for WriteTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteTracker {
+    /// Creates an empty `WriteTracker`.
+    pub const fn new() -> Self {
+        Self {
+            pages: [None; MAX_TRACKED_PAGES],
+        }
+    }
+
+    /// Starts tracking writes to the page containing the given guest physical address.
+    ///
+    /// If the page is already tracked, its callback is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Any guest physical address within the page to track.
+    /// * `callback` - The callback to invoke on writes to the page.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, or `Err(HypervisorError::WriteTrackerFull)` if no free slot is left.
+    pub fn track(&mut self, guest_pa: u64, callback: WriteCallback) -> Result<(), HypervisorError> {
+        let guest_page_pa = page_align(guest_pa);
+
+        if let Some(page) = self.find_mut(guest_page_pa) {
+            page.callback = callback;
+            return Ok(());
+        }
+
+        let slot = self
+            .pages
+            .iter_mut()
+            .find(|page| page.is_none())
+            .ok_or(HypervisorError::WriteTrackerFull)?;
+
+        *slot = Some(TrackedPage {
+            guest_page_pa,
+            callback,
+        });
+
+        Ok(())
+    }
+
+    /// Stops tracking writes to the page containing the given guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Any guest physical address within the page to stop tracking.
+    pub fn untrack(&mut self, guest_pa: u64) {
+        let guest_page_pa = page_align(guest_pa);
+
+        for page in self.pages.iter_mut() {
+            if matches!(page, Some(tracked) if tracked.guest_page_pa == guest_page_pa) {
+                *page = None;
+            }
+        }
+    }
+
+    /// Finds the callback registered for the page containing the given guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - Any guest physical address within the page.
+    ///
+    /// # Returns
+    ///
+    /// Returns the registered `WriteCallback`, or `None` if the page is not tracked.
+    pub fn find(&self, guest_pa: u64) -> Option<WriteCallback> {
+        let guest_page_pa = page_align(guest_pa);
+
+        self.pages
+            .iter()
+            .flatten()
+            .find(|page| page.guest_page_pa == guest_page_pa)
+            .map(|page| page.callback)
+    }
+
+    /// Finds the tracked page entry for a page-aligned guest physical address.
+    fn find_mut(&mut self, guest_page_pa: u64) -> Option<&mut TrackedPage> {
+        self.pages
+            .iter_mut()
+            .flatten()
+            .find(|page| page.guest_page_pa == guest_page_pa)
+    }
+}
+
+/// Aligns a guest physical address down to its 4KB page.
+fn page_align(guest_pa: u64) -> u64 {
+    guest_pa & !(BASE_PAGE_SIZE as u64 - 1)
+}
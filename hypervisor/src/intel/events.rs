@@ -5,8 +5,9 @@
 
 use {
     crate::intel::{
-        support::vmwrite,
+        support::{vmread, vmwrite},
         vmerror::{ExceptionInterrupt, InterruptionType},
+        vmfield,
     },
     bitfield::bitfield,
     x86::vmx::vmcs,
@@ -85,6 +86,28 @@ impl EventInjection {
         event.0
     }
 
+    /// Inject Non-Maskable Interrupt (NMI) to the guest (Event Injection).
+    fn non_maskable_interrupt() -> u32 {
+        let mut event = EventInjection(0);
+
+        event.set_vector(ExceptionInterrupt::NonMaskableInterrupt as u32);
+        event.set_type(InterruptionType::NonMaskableInterrupt as u32);
+        event.set_valid(VALID);
+
+        event.0
+    }
+
+    /// Inject External Interrupt to the guest (Event Injection).
+    fn external_interrupt(vector: u8) -> u32 {
+        let mut event = EventInjection(0);
+
+        event.set_vector(vector as u32);
+        event.set_type(InterruptionType::ExternalInterrupt as u32);
+        event.set_valid(VALID);
+
+        event.0
+    }
+
     /// Inject Undefined Opcode (#UD) to the guest (Event Injection).
     fn undefined_opcode() -> u32 {
         let mut event = EventInjection(0);
@@ -161,4 +184,148 @@ impl EventInjection {
             EventInjection::undefined_opcode(),
         );
     }
+
+    /// Injects a non-maskable interrupt into the guest.
+    ///
+    /// This function is used to deliver an NMI that was intercepted by the hypervisor.
+    /// With virtual NMIs enabled, the injection sets virtual-NMI blocking until the guest executes IRET.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
+    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
+    pub fn vmentry_inject_nmi() {
+        vmwrite(
+            vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
+            EventInjection::non_maskable_interrupt(),
+        );
+    }
+
+    /// Injects an external interrupt into the guest.
+    ///
+    /// This function is used to deliver an interrupt queued by the hypervisor. The guest must be able
+    /// to take it, i.e. RFLAGS.IF is set and there is no blocking by STI or MOV SS, which is the case
+    /// on an interrupt-window VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
+    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
+    pub fn vmentry_inject_interrupt(vector: u8) {
+        vmwrite(
+            vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
+            EventInjection::external_interrupt(vector),
+        );
+    }
+
+    /// Re-injects the event whose delivery caused the current VM exit, if there is one.
+    ///
+    /// A VM exit can occur while the processor delivers an event through the guest IDT, e.g. an EPT
+    /// violation on the IDT, the stack or the handler of the event. The event is then reported in the
+    /// IDT-vectoring information field instead of being delivered, and is lost unless it is injected
+    /// again on the next VM entry. Software interrupts and exceptions are injected with the length of
+    /// the instruction that raised them, so the guest resumes after that instruction once it is
+    /// delivered.
+    ///
+    /// # Returns
+    ///
+    /// Whether an event was re-injected.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.4 Information for VM Exits During Event Delivery
+    /// and 27.6.1.3 Event Injection for Vectored Events.
+    pub fn vmentry_reinject_vectoring_event() -> bool {
+        let vectoring = EventInjection(vmfield::ro::IDT_VECTORING_INFO.read());
+        if vectoring.get_valid() != VALID {
+            return false;
+        }
+
+        // The IDT-vectoring information has the same format, except that bit 12 is undefined.
+        let mut event = EventInjection(0);
+        event.set_vector(vectoring.get_vector());
+        event.set_type(vectoring.get_type());
+        event.set_deliver_error_code(vectoring.get_deliver_error_code());
+        event.set_valid(VALID);
+
+        if event.get_deliver_error_code() == 1 {
+            vmfield::control::VMENTRY_EXCEPTION_ERR_CODE
+                .write(vmfield::ro::IDT_VECTORING_ERR_CODE.read());
+        }
+
+        if matches!(
+            InterruptionType::from_bits(event.get_type() as u8),
+            Some(
+                InterruptionType::SoftwareInterrupt
+                    | InterruptionType::PrivilegedSoftwareException
+                    | InterruptionType::SoftwareException
+            )
+        ) {
+            vmfield::control::VMENTRY_INSTRUCTION_LEN
+                .write(vmfield::ro::VMEXIT_INSTRUCTION_LEN.read());
+        }
+
+        vmfield::control::VMENTRY_INTERRUPTION_INFO_FIELD.write(event.0);
+
+        true
+    }
+
+    /// Returns whether an event is already set up to be injected on the next VM entry.
+    ///
+    /// VM exits clear the valid bit of the VM-entry interruption-information field, so this is only
+    /// the case if an exit handler injected an event during the current VM exit.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.1 Basic VM-Exit Information
+    pub fn is_event_pending() -> bool {
+        EventInjection(vmread(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD) as u32).get_valid()
+            == VALID
+    }
+}
+
+/// The external interrupts queued for injection into the guest of a processor.
+///
+/// Like the IRR of a local APIC, each vector is pending at most once, and the highest vector is
+/// delivered first. The queue is a fixed-size bitmap, since memory cannot be allocated from a
+/// VM-exit handler.
+#[derive(Debug, Clone, Default)]
+pub struct PendingInterrupts {
+    /// One bit per vector, set while the vector is pending.
+    vectors: [u64; 4],
+}
+
+impl PendingInterrupts {
+    /// Creates an empty queue.
+    pub const fn new() -> Self {
+        Self { vectors: [0; 4] }
+    }
+
+    /// Marks a vector as pending. Queuing an already pending vector has no effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt.
+    pub fn push(&mut self, vector: u8) {
+        self.vectors[vector as usize / 64] |= 1 << (vector % 64);
+    }
+
+    /// Removes the highest pending vector from the queue.
+    ///
+    /// # Returns
+    ///
+    /// The highest pending vector, or `None` if no interrupt is pending.
+    pub fn pop(&mut self) -> Option<u8> {
+        let (index, bits) = self
+            .vectors
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .find(|(_, bits)| **bits != 0)?;
+        let bit = 63 - bits.leading_zeros();
+        *bits &= !(1 << bit);
+
+        Some((index * 64) as u8 + bit as u8)
+    }
+
+    /// Returns whether no interrupt is pending.
+    pub fn is_empty(&self) -> bool {
+        self.vectors.iter().all(|bits| *bits == 0)
+    }
 }
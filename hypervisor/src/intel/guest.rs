@@ -0,0 +1,174 @@
+//! Identifies the guests of the hypervisor and the EPT hierarchies that isolate them.
+//!
+//! Each guest owns a primary and a secondary EPT, handed to the hypervisor at startup as a
+//! `GuestEptConfig`. The default guest is the system that was running when the hypervisor was
+//! installed; its EPTs live in `SharedData` and are the ones hooks operate on. The EPTs of any further
+//! guests are kept in the `GuestRegistry`, and a processor runs a guest by loading that guest's EPTP
+//! into its VMCS (see `Vm::switch_guest`).
+
+use {
+    crate::{error::HypervisorError, intel::ept::paging::Ept},
+    alloc::boxed::Box,
+};
+
+/// The maximum number of guests besides the default guest.
+pub const MAX_GUESTS: usize = 4;
+
+/// Identifies a guest of the hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestId(u16);
+
+impl GuestId {
+    /// The system that was running when the hypervisor was installed. Every processor starts out
+    /// running this guest.
+    pub const DEFAULT: Self = Self(0);
+
+    /// Creates a guest identifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier. `0` refers to `GuestId::DEFAULT`.
+    pub const fn new(id: u16) -> Self {
+        Self(id)
+    }
+
+    /// Returns the raw value of the identifier.
+    pub const fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+/// The EPTs of a guest, as passed to `SharedData::new`.
+pub struct GuestEptConfig {
+    /// The guest the EPTs belong to.
+    pub guest_id: GuestId,
+
+    /// The primary EPT of the guest.
+    pub primary_ept: Box<Ept>,
+
+    /// The secondary EPT of the guest.
+    pub secondary_ept: Box<Ept>,
+}
+
+impl GuestEptConfig {
+    /// Creates the EPT configuration of a guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The guest the EPTs belong to.
+    /// * `primary_ept` - The primary EPT of the guest.
+    /// * `secondary_ept` - The secondary EPT of the guest.
+    pub fn new(guest_id: GuestId, primary_ept: Box<Ept>, secondary_ept: Box<Ept>) -> Self {
+        Self {
+            guest_id,
+            primary_ept,
+            secondary_ept,
+        }
+    }
+}
+
+/// The EPTs of a registered guest, along with their EPTPs.
+pub struct GuestEpts {
+    /// The primary EPT of the guest.
+    pub primary_ept: Box<Ept>,
+
+    /// The secondary EPT of the guest.
+    pub secondary_ept: Box<Ept>,
+
+    /// The EPTP of the primary EPT.
+    pub primary_eptp: u64,
+
+    /// The EPTP of the secondary EPT.
+    pub secondary_eptp: u64,
+}
+
+/// Registry of the EPTs of the guests besides the default guest, keyed by `GuestId`.
+pub struct GuestRegistry {
+    /// The registered guests. `None` entries are free slots.
+    guests: [Option<(GuestId, GuestEpts)>; MAX_GUESTS],
+}
+
+impl Default for GuestRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GuestRegistry {
+    /// Creates an empty `GuestRegistry`.
+    pub const fn new() -> Self {
+        const NO_GUEST: Option<(GuestId, GuestEpts)> = None;
+
+        Self {
+            guests: [NO_GUEST; MAX_GUESTS],
+        }
+    }
+
+    /// Registers the EPTs of a guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The EPTs of the guest. The default guest cannot be registered here.
+    ///
+    /// # Returns
+    ///
+    /// The registered EPTs on success, `Err(HypervisorError::GuestAlreadyRegistered)` if the guest is
+    /// the default guest or already registered, `Err(HypervisorError::GuestRegistryFull)` if no free
+    /// slot is left, or the error of creating the EPTPs.
+    pub fn register(&mut self, config: GuestEptConfig) -> Result<&GuestEpts, HypervisorError> {
+        if config.guest_id == GuestId::DEFAULT || self.get(config.guest_id).is_some() {
+            return Err(HypervisorError::GuestAlreadyRegistered);
+        }
+
+        let slot = self
+            .guests
+            .iter_mut()
+            .find(|guest| guest.is_none())
+            .ok_or(HypervisorError::GuestRegistryFull)?;
+
+        let epts = GuestEpts {
+            primary_eptp: config.primary_ept.create_eptp_with_wb_and_4lvl_walk()?,
+            secondary_eptp: config.secondary_ept.create_eptp_with_wb_and_4lvl_walk()?,
+            primary_ept: config.primary_ept,
+            secondary_ept: config.secondary_ept,
+        };
+
+        let (_, epts) = slot.insert((config.guest_id, epts));
+
+        Ok(epts)
+    }
+
+    /// Returns the EPTs of a registered guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The guest to look up.
+    ///
+    /// # Returns
+    ///
+    /// The EPTs of the guest, or `None` if it is not registered.
+    pub fn get(&self, guest_id: GuestId) -> Option<&GuestEpts> {
+        self.guests
+            .iter()
+            .flatten()
+            .find(|(id, _)| *id == guest_id)
+            .map(|(_, epts)| epts)
+    }
+
+    /// Returns the EPTs of a registered guest for modification.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The guest to look up.
+    ///
+    /// # Returns
+    ///
+    /// The EPTs of the guest, or `None` if it is not registered.
+    pub fn get_mut(&mut self, guest_id: GuestId) -> Option<&mut GuestEpts> {
+        self.guests
+            .iter_mut()
+            .flatten()
+            .find(|(id, _)| *id == guest_id)
+            .map(|(_, epts)| epts)
+    }
+}
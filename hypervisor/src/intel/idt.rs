@@ -0,0 +1,321 @@
+//! Provides a minimal host IDT for exceptions raised in VMX root operation.
+//!
+//! Without an IDT, any exception raised by the hypervisor itself escalates to a triple fault and
+//! the processor shuts down without a trace. The host IDT installs handlers for the exceptions a
+//! hypervisor bug is most likely to raise: #UD, #DF, #GP, and #PF. The handlers log the faulting
+//! RIP and error code over the logger, dump the exit trace of the processor, and halt it.
+//!
+//! NMIs are not errors: with "NMI exiting" an NMI that arrives while the hypervisor handles a VM exit
+//! is delivered through the host IDT. Its handler counts it in `HostIdt::pending_nmis`, enables
+//! "NMI-window exiting" in the current VMCS, and returns, so the NMI is injected into the guest once
+//! it can take it, just like an NMI that caused a VM exit.
+//!
+//! All other vectors are left non-present, so they raise #GP with an error code that identifies
+//! the vector, which is decoded by the handler. #DF runs on a separate interrupt stack (IST1), so a
+//! host stack overflow into the guard page is still reported instead of triple faulting.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 6.14 EXCEPTION AND INTERRUPT HANDLING IN 64-BIT MODE
+
+use {
+    crate::{
+        intel::{
+            postmortem::{dump_exit_trace, dump_last_exit_context},
+            support::{cr2, hlt},
+            vm::box_zeroed,
+            vmerror::ExceptionInterrupt,
+        },
+        logger,
+    },
+    alloc::boxed::Box,
+    core::{
+        arch::global_asm,
+        mem::offset_of,
+        sync::atomic::{AtomicU32, Ordering},
+    },
+    x86::{
+        bits64::paging::BASE_PAGE_SIZE,
+        segmentation::SegmentSelector,
+        vmx::vmcs::{self, control::PrimaryControls},
+    },
+};
+
+/// The interrupt stack table index used for #DF, since the faulting stack cannot be trusted.
+pub const DOUBLE_FAULT_IST_INDEX: u8 = 1;
+
+/// The size of the interrupt stack used for #DF in bytes.
+const EXCEPTION_STACK_SIZE: usize = 4 * BASE_PAGE_SIZE;
+
+/// The type of a 64-bit interrupt gate in the descriptor.
+const INTERRUPT_GATE_TYPE: u64 = 0xe;
+
+/// Bit 0 of an exception error code: the exception was caused by an event external to the program.
+const ERROR_CODE_EXT: u64 = 1 << 0;
+
+/// Bit 1 of an exception error code: the selector index refers to a gate descriptor in the IDT.
+const ERROR_CODE_IDT: u64 = 1 << 1;
+
+extern "efiapi" {
+    /// The entry point for NMIs, which marks the NMI pending and returns.
+    fn host_nmi();
+
+    /// The entry point for #UD, which pushes a dummy error code and the vector.
+    fn host_exception_invalid_opcode();
+
+    /// The entry point for #DF, which pushes the vector.
+    fn host_exception_double_fault();
+
+    /// The entry point for #GP, which pushes the vector.
+    fn host_exception_general_protection();
+
+    /// The entry point for #PF, which pushes the vector.
+    fn host_exception_page_fault();
+}
+
+global_asm!(
+    r#"
+// Counts the NMI in the `HostIdt` of this processor, found through the IDTR, and enables NMI-window
+// exiting, so the NMI is injected into the guest. Setting the control here, and not only when the
+// count is collected after the VM exit is handled, keeps an NMI that arrives after the collection
+// from waiting for the next VM exit. All registers are restored, since the interrupted code resumes.
+.global host_nmi
+host_nmi:
+    push rax
+    push rcx
+    sub rsp, 16
+    sidt [rsp]
+    mov rax, [rsp + 2]
+    add rsp, 16
+    lock inc dword ptr [rax + {pending_nmis}]
+    mov ecx, {primary_controls}
+    vmread rax, rcx
+    or eax, {nmi_window_exiting}
+    vmwrite rcx, rax
+    pop rcx
+    pop rax
+    iretq
+
+// The entry points of the host exception handlers. Each one pushes a dummy error code if the CPU
+// does not push one, and the vector, so every handler sees the same `HostExceptionFrame` layout.
+
+.global host_exception_invalid_opcode
+host_exception_invalid_opcode:
+    push 0
+    push 6
+    jmp host_exception_common
+
+.global host_exception_double_fault
+host_exception_double_fault:
+    push 8
+    jmp host_exception_common
+
+.global host_exception_general_protection
+host_exception_general_protection:
+    push 13
+    jmp host_exception_common
+
+.global host_exception_page_fault
+host_exception_page_fault:
+    push 14
+    jmp host_exception_common
+
+// Calls `handle_host_exception` with a pointer to the frame, aligning the stack and reserving the
+// shadow space the win64 ABI requires. The handler never returns.
+host_exception_common:
+    cld
+    mov rcx, rsp
+    and rsp, -16
+    sub rsp, 0x20
+    call {handler}
+    ud2
+"#,
+    handler = sym handle_host_exception,
+    pending_nmis = const offset_of!(HostIdt, pending_nmis),
+    primary_controls = const vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+    nmi_window_exiting = const PrimaryControls::NMI_WINDOW_EXITING.bits(),
+);
+
+/// The stack frame built by the exception entry points, followed by the frame pushed by the CPU.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Figure 6-9. IA-32e Mode Stack Usage After Privilege Level Change
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HostExceptionFrame {
+    /// The vector of the exception, pushed by the entry point.
+    pub vector: u64,
+
+    /// The error code pushed by the CPU, or 0 for exceptions without one.
+    pub error_code: u64,
+
+    /// The RIP of the faulting instruction.
+    pub rip: u64,
+
+    /// The CS selector at the time of the exception.
+    pub cs: u64,
+
+    /// The RFLAGS at the time of the exception.
+    pub rflags: u64,
+
+    /// The RSP at the time of the exception.
+    pub rsp: u64,
+
+    /// The SS selector at the time of the exception.
+    pub ss: u64,
+}
+
+/// A 64-bit interrupt gate descriptor in the IDT.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Figure 6-8. 64-Bit IDT Gate Descriptors
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct InterruptGate {
+    low: u64,
+    high: u64,
+}
+
+impl InterruptGate {
+    /// Builds a present, ring 0 interrupt gate.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The address of the entry point.
+    /// * `selector` - The code segment selector the entry point runs with.
+    /// * `ist` - The interrupt stack table index, or 0 to stay on the current stack.
+    fn new(handler: u64, selector: SegmentSelector, ist: u8) -> Self {
+        let low = (handler & 0xffff)
+            | (u64::from(selector.bits()) << 16)
+            | (u64::from(ist & 0x7) << 32)
+            | (INTERRUPT_GATE_TYPE << 40)
+            | (1 << 47)
+            | (((handler >> 16) & 0xffff) << 48);
+
+        Self {
+            low,
+            high: handler >> 32,
+        }
+    }
+}
+
+/// The host IDT of a processor, together with the interrupt stack used for #DF.
+///
+/// The host IDTR limit is set to 0xffff on every VM exit, so the table always covers all 256 vectors.
+#[repr(C, align(4096))]
+pub struct HostIdt {
+    /// The gate descriptors, indexed by vector. Must be the first field, since the NMI handler finds
+    /// the other fields through the IDTR base.
+    gates: [InterruptGate; 256],
+
+    /// The NMIs delivered through the host IDT that have not been queued for the guest yet.
+    pending_nmis: AtomicU32,
+
+    /// The interrupt stack referenced by IST1 in the host TSS.
+    exception_stack: [u8; EXCEPTION_STACK_SIZE],
+}
+
+impl HostIdt {
+    /// Allocates a host IDT with the handlers for NMIs, #UD, #DF, #GP, and #PF installed.
+    ///
+    /// # Arguments
+    ///
+    /// * `code_selector` - The host code segment selector.
+    ///
+    /// # Returns
+    ///
+    /// The host IDT, which must never be freed while the hypervisor runs.
+    pub fn new(code_selector: SegmentSelector) -> Box<Self> {
+        let mut idt = unsafe { box_zeroed::<Self>() };
+
+        let handlers: [(ExceptionInterrupt, unsafe extern "efiapi" fn(), u8); 5] = [
+            (ExceptionInterrupt::NonMaskableInterrupt, host_nmi, 0),
+            (
+                ExceptionInterrupt::InvalidOpcode,
+                host_exception_invalid_opcode,
+                0,
+            ),
+            (
+                ExceptionInterrupt::DoubleFault,
+                host_exception_double_fault,
+                DOUBLE_FAULT_IST_INDEX,
+            ),
+            (
+                ExceptionInterrupt::GeneralProtectionFault,
+                host_exception_general_protection,
+                0,
+            ),
+            (ExceptionInterrupt::PageFault, host_exception_page_fault, 0),
+        ];
+
+        for (vector, handler, ist) in handlers {
+            idt.gates[vector as usize] =
+                InterruptGate::new(handler as usize as u64, code_selector, ist);
+        }
+
+        idt
+    }
+
+    /// Returns the base address of the IDT, written to the host IDTR base.
+    pub fn base(&self) -> u64 {
+        self.gates.as_ptr() as u64
+    }
+
+    /// Returns the number of NMIs delivered through the host IDT since the last call, and resets it.
+    ///
+    /// Called after each VM exit is handled, to queue the NMIs for the guest.
+    pub fn take_pending_nmis(&self) -> u32 {
+        self.pending_nmis.swap(0, Ordering::Relaxed)
+    }
+
+    /// Returns the initial stack pointer of the #DF interrupt stack, written to IST1 of the host TSS.
+    pub fn exception_stack_top(&self) -> u64 {
+        self.exception_stack.as_ptr_range().end as u64
+    }
+}
+
+/// Logs an exception raised in VMX root operation and halts the processor.
+///
+/// Interrupts are disabled by the interrupt gate, so the processor stays halted until it is reset.
+/// The exception may have interrupted a log call, so the logger is prepared first to not deadlock on
+/// its lock.
+///
+/// # Arguments
+///
+/// * `frame` - The frame built by the entry point.
+extern "efiapi" fn handle_host_exception(frame: &HostExceptionFrame) -> ! {
+    let exception = ExceptionInterrupt::from_u32(frame.vector as u32);
+
+    logger::prepare_fault_report();
+
+    log::error!(
+        "[-] {:?} in the hypervisor at RIP {:#x}, error code {:#x}",
+        exception,
+        frame.rip,
+        frame.error_code
+    );
+    log::error!(
+        "[-] RSP {:#x}, RFLAGS {:#x}, CS {:#x}, SS {:#x}",
+        frame.rsp,
+        frame.rflags,
+        frame.cs,
+        frame.ss
+    );
+
+    match exception {
+        Some(ExceptionInterrupt::PageFault) => log::error!("[-] Faulting address: {:#x}", cr2()),
+        Some(ExceptionInterrupt::GeneralProtectionFault)
+            if frame.error_code & ERROR_CODE_IDT != 0 =>
+        {
+            log::error!(
+                "[-] Delivery of unhandled vector {:#x} (external: {})",
+                (frame.error_code >> 3) & 0x1fff,
+                frame.error_code & ERROR_CODE_EXT != 0
+            );
+        }
+        _ => {}
+    }
+
+    dump_last_exit_context();
+    dump_exit_trace();
+
+    loop {
+        hlt();
+    }
+}
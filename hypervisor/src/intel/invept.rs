@@ -3,6 +3,33 @@
 //! The INVEPT instruction invalidates entries in the translation lookaside buffer (TLB) and other processor structures
 //! that cache translations derived from EPT. It's used to ensure that modifications to EPT entries don't cause
 //! inconsistencies due to stale cached translations.
+//!
+//! Switching the EPTP of the current processor, see `activate_eptp`, must happen in this order:
+//!
+//! 1. All changes to the EPT entries are written to memory.
+//! 2. VMWRITE stores the new EPTP in the current VMCS.
+//! 3. INVEPT invalidates the cached translations.
+//! 4. VM entry loads the new EPTP.
+//!
+//! VMWRITE, INVEPT, and VM entry all execute on the same processor, which performs them in program order,
+//! and INVEPT is a serializing instruction, so the entry writes are globally visible and no cached
+//! translation survives by the time the VM entry walks the new EPT. The compiler must not reorder the steps
+//! either, which the inline assembly of VMWRITE and INVEPT already prevents, and `activate_eptp` enforces
+//! explicitly with a compiler fence.
+//!
+//! All-contexts invalidation ignores the EPTP, so it removes the stale translations of both the old and the
+//! new EPTP no matter which value the VMCS holds when INVEPT runs.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 9.3 SERIALIZING INSTRUCTIONS
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::vmfield::{self, vm_fail_to_error},
+    },
+    core::sync::atomic::{compiler_fence, Ordering},
+    x86::vmx::VmFail,
+};
 
 /// Represents the types of INVEPT operations.
 #[repr(u64)]
@@ -20,29 +47,78 @@ pub enum InveptType {
     AllContexts = 2,
 }
 
+/// Represents an INVEPT descriptor.
+///
+/// The descriptor is a 128-bit memory operand: bits 63:0 hold the EPTP for single-context
+/// invalidation, and bits 127:64 are reserved and must be zero.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.3 VMX INSTRUCTION REFERENCE, INVEPT
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InveptDescriptor {
+    /// The EPT pointer whose mappings are invalidated. Ignored for all-contexts invalidation.
+    eptp: u64,
+    /// Reserved (must be zero).
+    reserved: u64,
+}
+
+impl InveptDescriptor {
+    /// Creates a descriptor for single-context invalidation of the given EPTP.
+    ///
+    /// # Arguments
+    /// * `eptp` - The Extended Page Table Pointer whose mappings are invalidated.
+    pub const fn single_context(eptp: u64) -> Self {
+        Self { eptp, reserved: 0 }
+    }
+
+    /// Creates a descriptor for all-contexts invalidation, which ignores the EPTP.
+    pub const fn all_contexts() -> Self {
+        Self::single_context(0)
+    }
+
+    /// Returns the EPTP of the descriptor.
+    pub const fn eptp(&self) -> u64 {
+        self.eptp
+    }
+}
+
 /// Executes the INVEPT instruction.
 ///
 /// # Arguments
 /// * `invept_type` - The type of INVEPT operation to perform.
-/// * `eptp` - The EPT pointer used for Single Context INVEPT. It should be a 64-bit value formed by
-///   concatenating the EPTP's memory type (bits 2:0), page-walk length (bits 5:3), and address of the EPTP
-///   (bits 63:12). For All Contexts INVEPT, this value is ignored.
+/// * `descriptor` - The INVEPT descriptor, built for the same type of operation.
+///
+/// # Returns
+/// `Ok(())` if the invalidation succeeded, or an `Err(HypervisorError)` if INVEPT reported VMfailInvalid
+/// or VMfailValid, e.g. because the type is not supported or the EPTP is invalid.
 ///
-/// # Safety
-/// This function is unsafe because it involves inline assembly and direct interaction with CPU features.
-fn invept(invept_type: InveptType, eptp: u64) {
-    // The INVEPT descriptor is a 128-bit value. The first 64-bits (low part) should be 0 for All-Contexts
-    // and the EPTP for Single-Context. The second 64-bits (high part) should always be 0.
-    let descriptor: [u64; 2] = [eptp, 0];
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.2 CONVENTIONS
+fn invept(invept_type: InveptType, descriptor: &InveptDescriptor) -> Result<(), HypervisorError> {
+    /// RFLAGS.CF, set on VMfailInvalid.
+    const CARRY_FLAG: u64 = 1 << 0;
+    /// RFLAGS.ZF, set on VMfailValid.
+    const ZERO_FLAG: u64 = 1 << 6;
+
+    let rflags: u64;
 
     unsafe {
         core::arch::asm!(
         "invept {0}, [{1}]",
+        "pushfq",
+        "pop {2}",
         in(reg) invept_type as u64,
-        in(reg) &descriptor,
-        options(nostack)
+        in(reg) descriptor as *const InveptDescriptor,
+        lateout(reg) rflags,
         );
     };
+
+    if rflags & CARRY_FLAG != 0 {
+        Err(vm_fail_to_error(VmFail::VmFailInvalid))
+    } else if rflags & ZERO_FLAG != 0 {
+        Err(vm_fail_to_error(VmFail::VmFailValid))
+    } else {
+        Ok(())
+    }
 }
 
 /// Invalidates entries in the TLB and other processor structures that cache translations derived from EPT.
@@ -54,9 +130,15 @@ fn invept(invept_type: InveptType, eptp: u64) {
 /// * `eptp` - The Extended Page Table Pointer used for Single Context INVEPT.
 ///            It should be a 64-bit value formed by concatenating the EPTP's memory type (bits 2:0),
 ///            page-walk length (bits 5:3), and address of the EPTP (bits 63:12).
-pub fn invept_single_context(eptp: u64) {
+///
+/// # Returns
+/// `Ok(())` if the invalidation succeeded, or an `Err(HypervisorError)` if INVEPT failed.
+pub fn invept_single_context(eptp: u64) -> Result<(), HypervisorError> {
     // Perform the INVEPT operation for a single context.
-    invept(InveptType::SingleContext, eptp);
+    invept(
+        InveptType::SingleContext,
+        &InveptDescriptor::single_context(eptp),
+    )
 }
 
 /// Invalidates entries in the TLB and other processor structures that cache translations derived from EPT
@@ -64,8 +146,35 @@ pub fn invept_single_context(eptp: u64) {
 ///
 /// This function is used to invalidate guest-physical mappings and combined mappings associated with all
 /// EPT Pointer Table Roots (EPTRTAs) and, for combined mappings, for all VPIDs and PCIDs.
-pub fn invept_all_contexts() {
+///
+/// # Returns
+/// `Ok(())` if the invalidation succeeded, or an `Err(HypervisorError)` if INVEPT failed.
+pub fn invept_all_contexts() -> Result<(), HypervisorError> {
     // Perform the INVEPT operation for all contexts.
-    // The EPT pointer is irrelevant for this type of operation and is thus set to 0.
-    invept(InveptType::AllContexts, 0);
+    invept(InveptType::AllContexts, &InveptDescriptor::all_contexts())
+}
+
+/// Switches the current processor to an EPTP and invalidates the cached EPT translations, in the order
+/// described in the module documentation.
+///
+/// Must be called from a VM-exit handler or while setting up the current VMCS, so the next VM entry
+/// loads the new EPTP.
+///
+/// # Arguments
+/// * `eptp` - The EPTP to switch to.
+///
+/// # Returns
+/// `Ok(())` on success, or an `Err(HypervisorError)` if VMWRITE or INVEPT failed.
+pub fn activate_eptp(eptp: u64) -> Result<(), HypervisorError> {
+    vmfield::control::EPTP_FULL.try_write(eptp)?;
+    debug_assert_eq!(
+        vmfield::control::EPTP_FULL.read(),
+        eptp,
+        "INVEPT must follow the write of the new EPTP"
+    );
+
+    // Keep the EPT entry writes and the VMWRITE before the INVEPT.
+    compiler_fence(Ordering::SeqCst);
+
+    invept_all_contexts()
 }
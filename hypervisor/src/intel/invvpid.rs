@@ -4,7 +4,51 @@
 //! that are associated with a specific Virtual Processor Identifier (VPID). This is essential in virtualization
 //! environments to maintain consistency of memory translations across different virtual processors.
 
-pub const VPID_TAG: u16 = 0x1;
+use {
+    crate::intel::{
+        controls::{is_vmx_control_supported, VmxControl},
+        support::rdmsr,
+    },
+    bit_field::BitField,
+    core::sync::atomic::{AtomicU16, Ordering},
+    x86::vmx::vmcs,
+};
+
+/// The next VPID to assign. VPID 0 is used by VMX root operation and is never assigned to a guest.
+static NEXT_VPID: AtomicU16 = AtomicU16::new(1);
+
+/// Checks whether VPIDs can be enabled and invalidated with single-context INVVPID.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.10 VPID AND EPT CAPABILITIES
+///
+/// # Returns
+///
+/// Returns `true` if the enable-VPID control, INVVPID, and its single-context type are supported.
+pub fn is_vpid_supported() -> bool {
+    let capabilities = rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP);
+
+    // Bit 32: INVVPID is supported. Bit 41: Single-context INVVPID is supported.
+    capabilities.get_bit(32)
+        && capabilities.get_bit(41)
+        && is_vmx_control_supported(
+            VmxControl::ProcessorBased2,
+            vmcs::control::SecondaryControls::ENABLE_VPID.bits() as u64,
+        )
+}
+
+/// Allocates a unique, nonzero VPID for a virtual processor.
+///
+/// # Panics
+///
+/// Panics if all VPIDs have been assigned.
+pub fn allocate_vpid() -> u16 {
+    let vpid = NEXT_VPID.fetch_add(1, Ordering::Relaxed);
+    assert_ne!(
+        vpid, 0,
+        "VPIDs exhausted, VPID 0 must not be assigned to a guest"
+    );
+    vpid
+}
 
 /// Represents the types of INVVPID operations.
 #[repr(u64)]
@@ -0,0 +1,88 @@
+//! Preserves the guest's IA32_DEBUGCTL and last branch records (LBRs) across VM exits.
+//!
+//! Every VM exit clears IA32_DEBUGCTL, which turns off branch recording while the hypervisor runs. To
+//! get the guest value back, the "load debug controls" VM-entry control loads IA32_DEBUGCTL and DR7
+//! from the guest-state area, and the "save debug controls" VM-exit control saves them there. Without
+//! them, the guest's IA32_DEBUGCTL.LBR would be lost on the first VM exit and profilers stop recording.
+//!
+//! The LBR stack MSRs themselves (MSR_LBR_TOS, MSR_LASTBRANCH_n_FROM_IP/TO_IP, MSR_LER_FROM/TO_LIP,
+//! and the architectural IA32_LBR_n_FROM_IP/TO_IP/INFO) are model specific and not intercepted in the
+//! MSR bitmap, so the guest reads and writes them directly. The hypervisor never touches them and
+//! records no branches into them, since IA32_DEBUGCTL.LBR is clear in VMX root operation, so their
+//! contents are the guest's at every VM entry.
+//!
+//! With architectural LBRs, recording is enabled by IA32_LBR_CTL instead, which VM exits do not clear
+//! by default. If supported, the "clear IA32_LBR_CTL" VM-exit control and the "load guest IA32_LBR_CTL"
+//! VM-entry control switch it like IA32_DEBUGCTL. Otherwise, the hypervisor's own branches are recorded
+//! into the guest's LBR stack.
+//!
+//! The LBR state is not virtualized per guest: guests switched with `Vm::switch_guest` on the same
+//! processor share the physical LBR stack, like they share all other MSRs.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 17.4 LAST BRANCH, INTERRUPT, AND EXCEPTION RECORDING OVERVIEW
+
+use {
+    crate::intel::{
+        controls::{is_vmx_control_supported, VmxControl},
+        support::rdmsr,
+        vmfield,
+    },
+    x86::{msr, vmx::vmcs},
+};
+
+/// IA32_LBR_CTL, which enables architectural LBRs.
+pub const IA32_LBR_CTL: u32 = 0x14ce;
+
+/// CPUID.(EAX=07H,ECX=0):EDX.ARCH_LBR[bit 19]: architectural LBRs are supported.
+const ARCH_LBR_SUPPORTED: u32 = 1 << 19;
+
+/// The VM-entry control that loads DR7 and IA32_DEBUGCTL from the guest-state area.
+const LOAD_DEBUG_CONTROLS: u32 = vmcs::control::EntryControls::LOAD_DEBUG_CONTROLS.bits();
+
+/// The VM-exit control that saves DR7 and IA32_DEBUGCTL to the guest-state area.
+const SAVE_DEBUG_CONTROLS: u32 = vmcs::control::ExitControls::SAVE_DEBUG_CONTROLS.bits();
+
+/// The VM-entry control that loads IA32_LBR_CTL from the guest-state area.
+pub const LOAD_GUEST_LBR_CTL: u32 = 1 << 21;
+
+/// The VM-exit control that clears IA32_LBR_CTL after saving it to the guest-state area.
+const CLEAR_LBR_CTL: u32 = 1 << 26;
+
+/// Enables switching IA32_DEBUGCTL, and IA32_LBR_CTL if supported, between the guest and the host.
+///
+/// Must be called after the VM-entry and VM-exit controls have been written. The guest-state fields
+/// are initialized with the current values of the MSRs, which the guest inherits.
+pub fn setup_debug_controls() {
+    let mut entry_controls = vmfield::control::VMENTRY_CONTROLS.read();
+    let mut exit_controls = vmfield::control::VMEXIT_CONTROLS.read();
+
+    if is_vmx_control_supported(VmxControl::VmEntry, LOAD_DEBUG_CONTROLS as u64)
+        && is_vmx_control_supported(VmxControl::VmExit, SAVE_DEBUG_CONTROLS as u64)
+    {
+        entry_controls |= LOAD_DEBUG_CONTROLS;
+        exit_controls |= SAVE_DEBUG_CONTROLS;
+        vmfield::guest::IA32_DEBUGCTL_FULL.write(rdmsr(msr::IA32_DEBUGCTL));
+    } else {
+        log::warn!("Loading and saving debug controls is not supported, the guest loses IA32_DEBUGCTL on every VM exit");
+    }
+
+    if is_arch_lbr_supported() {
+        if is_vmx_control_supported(VmxControl::VmEntry, LOAD_GUEST_LBR_CTL as u64)
+            && is_vmx_control_supported(VmxControl::VmExit, CLEAR_LBR_CTL as u64)
+        {
+            entry_controls |= LOAD_GUEST_LBR_CTL;
+            exit_controls |= CLEAR_LBR_CTL;
+            vmfield::guest::IA32_LBR_CTL_FULL.write(rdmsr(IA32_LBR_CTL));
+        } else {
+            log::warn!("Switching IA32_LBR_CTL is not supported, VM exits are recorded in the guest's LBRs");
+        }
+    }
+
+    vmfield::control::VMENTRY_CONTROLS.write(entry_controls);
+    vmfield::control::VMEXIT_CONTROLS.write(exit_controls);
+}
+
+/// Checks whether the processor supports architectural LBRs.
+fn is_arch_lbr_supported() -> bool {
+    x86::cpuid::cpuid!(0x7, 0x0).edx & ARCH_LBR_SUPPORTED != 0
+}
@@ -1,21 +1,35 @@
 pub mod addresses;
+pub mod bitmap;
 pub mod capture;
+pub mod consistency;
 pub mod controls;
+pub mod decode;
 pub mod descriptor;
 pub mod ept;
 pub mod events;
+pub mod guest;
+pub mod idt;
 pub mod invept;
 pub mod invvpid;
+pub mod lbr;
 pub mod page;
 pub mod paging;
+pub mod pe;
+pub mod postmortem;
+pub mod reserved;
 pub mod segmentation;
 pub mod shared;
+pub mod stack;
 pub mod state;
 pub mod support;
 pub mod vm;
 pub mod vmcs;
 pub mod vmerror;
 pub mod vmexit;
+pub mod vmfield;
+pub mod vmfunc;
 pub mod vmlaunch;
 pub mod vmx;
 pub mod vmxon;
+pub mod watchdog;
+pub mod xstate;
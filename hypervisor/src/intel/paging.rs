@@ -34,6 +34,8 @@ pub struct PageTables {
     pdpt: Pdpt,
     /// Array of Page Directory Table (PDT).
     pd: [Pd; 512],
+    /// Page Table (PT) used to split the large page containing the host stack guard page.
+    guard_pt: Pt,
 }
 
 impl PageTables {
@@ -78,6 +80,52 @@ impl PageTables {
         log::debug!("Identity map built successfully");
     }
 
+    /// Unmaps the 4KB guard page below the host stack.
+    ///
+    /// The 2MB large page containing the guard page is split into 4KB pages that keep the identity
+    /// mapping, except for the guard page itself, which is marked not present. Only one guard page
+    /// is supported per page table hierarchy, which matches the single host stack of a processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `guard_page` - The 4KB-aligned address of the guard page.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::InvalidGuardPageAddress)` if the address is not
+    /// 4KB aligned, is not covered by the identity map, or a guard page has already been unmapped.
+    pub fn unmap_guard_page(&mut self, guard_page: u64) -> Result<(), HypervisorError> {
+        log::debug!("Unmapping host stack guard page at {:#x}", guard_page);
+
+        let pdpt_index = (guard_page >> 30) as usize;
+        let pd_index = ((guard_page >> 21) & 0x1ff) as usize;
+        let pt_index = ((guard_page >> BASE_PAGE_SHIFT) & 0x1ff) as usize;
+
+        if guard_page.trailing_zeros() < BASE_PAGE_SHIFT as u32 || pdpt_index >= self.pd.len() {
+            return Err(HypervisorError::InvalidGuardPageAddress);
+        }
+
+        // The guard PT is only used once, so a present entry means it is already in use.
+        if self.guard_pt.0.entries[0].present() {
+            return Err(HypervisorError::InvalidGuardPageAddress);
+        }
+
+        let pde = &mut self.pd[pdpt_index].0.entries[pd_index];
+        let large_page_pa = pde.pfn() << BASE_PAGE_SHIFT;
+
+        // Identity map the large page with 4KB pages, leaving the guard page not present.
+        for (i, pte) in self.guard_pt.0.entries.iter_mut().enumerate() {
+            pte.set_present(i != pt_index);
+            pte.set_writable(true);
+            pte.set_pfn((large_page_pa >> BASE_PAGE_SHIFT) + i as u64);
+        }
+
+        pde.set_large(false);
+        pde.set_pfn(addr_of!(self.guard_pt) as u64 >> BASE_PAGE_SHIFT);
+
+        Ok(())
+    }
+
     /// Gets the physical address of the PML4 table, ensuring it is 4KB aligned.
     ///
     /// This method is typically used to retrieve the address to be loaded into CR3.
@@ -128,7 +176,6 @@ pub struct Pdpt(Table);
 #[derive(Debug, Clone, Copy)]
 struct Pd(Table);
 
-/*
 /// Represents a Page-Table Entry (PTE) that maps a 4-KByte Page.
 ///
 /// PTEs are the lowest level in the standard x86-64 paging hierarchy and are used to map individual
@@ -137,7 +184,6 @@ struct Pd(Table);
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.5 Paging
 #[derive(Debug, Clone, Copy)]
 pub struct Pt(Table);
-*/
 
 /// General struct to represent a table in the standard paging structure.
 ///
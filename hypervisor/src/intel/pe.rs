@@ -0,0 +1,251 @@
+//! Resolves exported functions of PE images in guest memory.
+//!
+//! The image is read through the primary EPT, so guest pages remapped by hooks are read as the
+//! guest sees them. The image is assumed to be physically contiguous from its base, which holds for
+//! images loaded by the firmware in physical mode; images mapped by a guest OS generally are not,
+//! and their exports have to be resolved by the caller.
+//!
+//! Reference: Microsoft PE Format: Export Directory Table (https://learn.microsoft.com/en-us/windows/win32/debug/pe-format#export-directory-table)
+
+use crate::{error::HypervisorError, intel::ept::paging::Ept};
+
+/// The `MZ` signature of the DOS header.
+const DOS_SIGNATURE: u16 = 0x5a4d;
+
+/// Offset of `e_lfanew` (the offset of the NT headers) in the DOS header.
+const DOS_E_LFANEW_OFFSET: u64 = 0x3c;
+
+/// The `PE\0\0` signature at the start of the NT headers.
+const NT_SIGNATURE: u32 = 0x0000_4550;
+
+/// Offset of the optional header from the start of the NT headers.
+const OPTIONAL_HEADER_OFFSET: u64 = 0x18;
+
+/// The optional header magic of a PE32 image.
+const PE32_MAGIC: u16 = 0x10b;
+
+/// The optional header magic of a PE32+ image.
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+/// The maximum length of an export or forwarder name, including the terminating NUL.
+const MAX_NAME_LENGTH: usize = 256;
+
+/// The maximum number of forwarders followed before giving up, to break forwarding loops.
+const MAX_FORWARDER_DEPTH: usize = 8;
+
+/// Resolves the guest physical address of a function exported by name from a guest PE image.
+///
+/// Forwarded exports are followed if they forward to another export of the same module, by name
+/// (`MODULE.Function`) or by ordinal (`MODULE.#123`).
+///
+/// # Arguments
+///
+/// * `ept` - The EPT through which the guest memory is read.
+/// * `module_base_gpa` - The guest physical address of the image base.
+/// * `export_name` - The name of the exported function.
+///
+/// # Returns
+///
+/// The guest physical address of the function, `Err(HypervisorError::ExportNotFound)` if the image has
+/// no export of that name, `Err(HypervisorError::ExportForwardedToOtherModule)` if the export is
+/// forwarded to another module, or `Err(HypervisorError::InvalidGuestPeImage)` if the image is malformed.
+pub fn find_export_gpa(
+    ept: &Ept,
+    module_base_gpa: u64,
+    export_name: &str,
+) -> Result<u64, HypervisorError> {
+    let image = GuestImage::new(ept, module_base_gpa)?;
+
+    let mut rva = image.find_export_rva_by_name(export_name.as_bytes())?;
+
+    for _ in 0..MAX_FORWARDER_DEPTH {
+        if !image.export_directory.contains(&rva) {
+            return Ok(module_base_gpa + rva as u64);
+        }
+
+        rva = image.follow_forwarder(rva)?;
+    }
+
+    log::error!("Too many forwarders for export {}", export_name);
+    Err(HypervisorError::ExportForwardedToOtherModule)
+}
+
+/// A PE image in guest memory with a parsed export directory.
+struct GuestImage<'a> {
+    /// The EPT through which the guest memory is read.
+    ept: &'a Ept,
+    /// The guest physical address of the image base.
+    base: u64,
+    /// The RVA range of the export directory. Export RVAs within it point to forwarder strings.
+    export_directory: core::ops::Range<u32>,
+    /// The ordinal base of the exports.
+    ordinal_base: u32,
+    /// The number of entries in the export address table.
+    number_of_functions: u32,
+    /// The number of entries in the export name pointer table.
+    number_of_names: u32,
+    /// The RVA of the export address table.
+    address_of_functions: u32,
+    /// The RVA of the export name pointer table.
+    address_of_names: u32,
+    /// The RVA of the export ordinal table.
+    address_of_name_ordinals: u32,
+    /// The RVA of the module name.
+    name: u32,
+}
+
+impl<'a> GuestImage<'a> {
+    /// Parses the headers and the export directory of a guest PE image.
+    fn new(ept: &'a Ept, base: u64) -> Result<Self, HypervisorError> {
+        let read_u16 =
+            |gpa| read_guest::<u16>(ept, gpa).ok_or(HypervisorError::InvalidGuestPeImage);
+        let read_u32 =
+            |gpa| read_guest::<u32>(ept, gpa).ok_or(HypervisorError::InvalidGuestPeImage);
+
+        if read_u16(base)? != DOS_SIGNATURE {
+            return Err(HypervisorError::InvalidGuestPeImage);
+        }
+
+        let nt_headers = base + read_u32(base + DOS_E_LFANEW_OFFSET)? as u64;
+        if read_u32(nt_headers)? != NT_SIGNATURE {
+            return Err(HypervisorError::InvalidGuestPeImage);
+        }
+
+        // The export directory is the first data directory, whose offset depends on the image type.
+        let optional_header = nt_headers + OPTIONAL_HEADER_OFFSET;
+        let data_directories = match read_u16(optional_header)? {
+            PE32_MAGIC => optional_header + 0x60,
+            PE32_PLUS_MAGIC => optional_header + 0x70,
+            _ => return Err(HypervisorError::InvalidGuestPeImage),
+        };
+
+        let directory_rva = read_u32(data_directories)?;
+        let directory_size = read_u32(data_directories + 4)?;
+        if directory_rva == 0 || directory_size == 0 {
+            return Err(HypervisorError::ExportNotFound);
+        }
+
+        let directory = base + directory_rva as u64;
+
+        Ok(Self {
+            ept,
+            base,
+            export_directory: directory_rva..directory_rva.saturating_add(directory_size),
+            name: read_u32(directory + 0x0c)?,
+            ordinal_base: read_u32(directory + 0x10)?,
+            number_of_functions: read_u32(directory + 0x14)?,
+            number_of_names: read_u32(directory + 0x18)?,
+            address_of_functions: read_u32(directory + 0x1c)?,
+            address_of_names: read_u32(directory + 0x20)?,
+            address_of_name_ordinals: read_u32(directory + 0x24)?,
+        })
+    }
+
+    /// Finds the RVA of an export by name.
+    fn find_export_rva_by_name(&self, export_name: &[u8]) -> Result<u32, HypervisorError> {
+        for i in 0..self.number_of_names as u64 {
+            let name_rva = self.read_u32(self.address_of_names as u64 + i * 4)?;
+
+            let mut buffer = [0u8; MAX_NAME_LENGTH];
+            if self.read_name(name_rva, &mut buffer)? != export_name {
+                continue;
+            }
+
+            let index = self.read_u16(self.address_of_name_ordinals as u64 + i * 2)?;
+            return self.function_rva(index as u32);
+        }
+
+        Err(HypervisorError::ExportNotFound)
+    }
+
+    /// Resolves a forwarder string to the RVA of the export it forwards to within this module.
+    fn follow_forwarder(&self, forwarder_rva: u32) -> Result<u32, HypervisorError> {
+        let mut forwarder_buffer = [0u8; MAX_NAME_LENGTH];
+        let forwarder = self.read_name(forwarder_rva, &mut forwarder_buffer)?;
+
+        let separator = forwarder
+            .iter()
+            .rposition(|&c| c == b'.')
+            .ok_or(HypervisorError::InvalidGuestPeImage)?;
+        let (target_module, target_export) = (&forwarder[..separator], &forwarder[separator + 1..]);
+
+        // Only forwarders into this module can be followed, as other modules cannot be located.
+        let mut name_buffer = [0u8; MAX_NAME_LENGTH];
+        let module_name = self.read_name(self.name, &mut name_buffer)?;
+        let module_stem = module_name
+            .iter()
+            .rposition(|&c| c == b'.')
+            .map_or(module_name, |dot| &module_name[..dot]);
+
+        if !module_stem.eq_ignore_ascii_case(target_module) {
+            log::error!(
+                "Export is forwarded to another module: {}",
+                core::str::from_utf8(forwarder).unwrap_or("<invalid>")
+            );
+            return Err(HypervisorError::ExportForwardedToOtherModule);
+        }
+
+        match target_export.strip_prefix(b"#") {
+            Some(ordinal) => {
+                let ordinal = core::str::from_utf8(ordinal)
+                    .ok()
+                    .and_then(|ordinal| ordinal.parse::<u32>().ok())
+                    .ok_or(HypervisorError::InvalidGuestPeImage)?;
+                let index = ordinal
+                    .checked_sub(self.ordinal_base)
+                    .ok_or(HypervisorError::ExportNotFound)?;
+                self.function_rva(index)
+            }
+            None => self.find_export_rva_by_name(target_export),
+        }
+    }
+
+    /// Returns the RVA of the export address table entry with the given index.
+    fn function_rva(&self, index: u32) -> Result<u32, HypervisorError> {
+        if index >= self.number_of_functions {
+            return Err(HypervisorError::InvalidGuestPeImage);
+        }
+
+        self.read_u32(self.address_of_functions as u64 + index as u64 * 4)
+    }
+
+    /// Reads a NUL-terminated name at an RVA into a buffer and returns it without the NUL.
+    fn read_name<'b>(&self, rva: u32, buffer: &'b mut [u8]) -> Result<&'b [u8], HypervisorError> {
+        for i in 0..buffer.len() {
+            let c = read_guest::<u8>(self.ept, self.base + rva as u64 + i as u64)
+                .ok_or(HypervisorError::InvalidGuestPeImage)?;
+            if c == 0 {
+                return Ok(&buffer[..i]);
+            }
+            buffer[i] = c;
+        }
+
+        Err(HypervisorError::InvalidGuestPeImage)
+    }
+
+    /// Reads a `u16` at an RVA.
+    fn read_u16(&self, rva: u64) -> Result<u16, HypervisorError> {
+        read_guest(self.ept, self.base + rva).ok_or(HypervisorError::InvalidGuestPeImage)
+    }
+
+    /// Reads a `u32` at an RVA.
+    fn read_u32(&self, rva: u64) -> Result<u32, HypervisorError> {
+        read_guest(self.ept, self.base + rva).ok_or(HypervisorError::InvalidGuestPeImage)
+    }
+}
+
+/// Reads a value from guest physical memory through the EPT, one byte at a time so that values
+/// crossing a page boundary are translated correctly.
+fn read_guest<T: Copy + Default>(ept: &Ept, guest_pa: u64) -> Option<T> {
+    let mut value = T::default();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, core::mem::size_of::<T>())
+    };
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let host_pa = ept.gpa_to_hpa(guest_pa + i as u64)?;
+        *byte = unsafe { *(host_pa as *const u8) };
+    }
+
+    Some(value)
+}
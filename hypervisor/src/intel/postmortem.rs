@@ -0,0 +1,201 @@
+//! Records the context of the last VM exit of each processor for post-mortem debugging.
+//!
+//! The VM exit loop records the exit reason, the guest RIP and RSP, and a few general-purpose
+//! registers before handling each exit. If a VM-exit handler panics, the panic handler can print
+//! the record of the current processor to show where the guest was when things went wrong.
+//!
+//! Each VM also keeps an `ExitTrace` of its last `EXIT_TRACE_LENGTH` VM exits. Recording an exit
+//! only stores three integers, so the trace stays enabled and shows the control flow of the guest
+//! leading up to a crash.
+
+use {
+    crate::{
+        intel::{capture::Register, support::rdtsc, vm::Vm, vmerror::VmxBasicExitReason, vmfield},
+        logger::apic_id,
+    },
+    core::sync::atomic::{AtomicPtr, Ordering},
+    spin::Mutex,
+};
+
+/// The number of processors a context can be recorded for, indexed by the 8-bit initial APIC ID.
+const MAX_PROCESSORS: usize = 256;
+
+/// The number of VM exits kept in an `ExitTrace`.
+pub const EXIT_TRACE_LENGTH: usize = 256;
+
+/// The state of the guest at a VM exit.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitContext {
+    /// The basic exit reason.
+    pub exit_reason: VmxBasicExitReason,
+    /// The exit qualification.
+    pub exit_qualification: u64,
+    /// The guest RIP at the time of the VM exit.
+    pub guest_rip: u64,
+    /// The guest RSP at the time of the VM exit.
+    pub guest_rsp: u64,
+    /// The guest RAX at the time of the VM exit.
+    pub guest_rax: u64,
+    /// The guest RCX at the time of the VM exit.
+    pub guest_rcx: u64,
+    /// The guest RDX at the time of the VM exit.
+    pub guest_rdx: u64,
+    /// The guest RBX at the time of the VM exit.
+    pub guest_rbx: u64,
+}
+
+/// The last recorded exit context of each processor, indexed by APIC ID.
+static LAST_EXIT_CONTEXTS: [Mutex<Option<ExitContext>>; MAX_PROCESSORS] =
+    [const { Mutex::new(None) }; MAX_PROCESSORS];
+
+/// Records the context of the current VM exit for the current processor.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `exit_reason` - The basic exit reason of the current VM exit.
+pub fn record_exit_context(vm: &Vm, exit_reason: VmxBasicExitReason) {
+    let context = ExitContext {
+        exit_reason,
+        exit_qualification: vmfield::ro::EXIT_QUALIFICATION.read(),
+        guest_rip: vm.guest_reg(Register::Rip),
+        guest_rsp: vm.guest_reg(Register::Rsp),
+        guest_rax: vm.guest_reg(Register::Rax),
+        guest_rcx: vm.guest_reg(Register::Rcx),
+        guest_rdx: vm.guest_reg(Register::Rdx),
+        guest_rbx: vm.guest_reg(Register::Rbx),
+    };
+
+    *LAST_EXIT_CONTEXTS[apic_id() as usize].lock() = Some(context);
+}
+
+/// Returns the last recorded exit context of the current processor.
+///
+/// Does not block, so it can be called from a panic handler.
+///
+/// # Returns
+///
+/// The last `ExitContext`, or `None` if no VM exit has been recorded on this processor or the
+/// record is being updated.
+pub fn last_exit_context() -> Option<ExitContext> {
+    LAST_EXIT_CONTEXTS[apic_id() as usize]
+        .try_lock()
+        .and_then(|context| *context)
+}
+
+/// Logs the last recorded exit context of the current processor.
+///
+/// Intended to be called from the panic handler, or after a VM-exit handler failed.
+pub fn dump_last_exit_context() {
+    match last_exit_context() {
+        Some(context) => log::error!("[-] Last VM exit: {:#x?}", context),
+        None => log::error!("[-] No VM exit recorded on this processor"),
+    }
+}
+
+/// A VM exit recorded in an `ExitTrace`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitTraceEntry {
+    /// The basic exit reason, as its raw value.
+    pub exit_reason: u16,
+    /// The guest RIP at the time of the VM exit.
+    pub guest_rip: u64,
+    /// The TSC when the VM exit was recorded.
+    pub tsc: u64,
+}
+
+/// A circular buffer of the last `EXIT_TRACE_LENGTH` VM exits of a processor.
+///
+/// The all-zero bit pattern is an empty trace, so it can be allocated with `box_zeroed`.
+pub struct ExitTrace {
+    /// The recorded exits. Once the buffer is full, the oldest exit is overwritten.
+    entries: [ExitTraceEntry; EXIT_TRACE_LENGTH],
+    /// The total number of exits recorded, which selects the next entry to overwrite.
+    count: u64,
+}
+
+impl ExitTrace {
+    /// Records a VM exit, overwriting the oldest one if the trace is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `exit_reason` - The basic exit reason of the VM exit.
+    /// * `guest_rip` - The guest RIP at the time of the VM exit.
+    pub fn record(&mut self, exit_reason: VmxBasicExitReason, guest_rip: u64) {
+        self.entries[self.count as usize % EXIT_TRACE_LENGTH] = ExitTraceEntry {
+            exit_reason: exit_reason as u16,
+            guest_rip,
+            tsc: rdtsc(),
+        };
+        self.count = self.count.wrapping_add(1);
+    }
+
+    /// Returns the recorded exits, from the oldest to the most recent.
+    pub fn iter(&self) -> impl Iterator<Item = &ExitTraceEntry> {
+        let len = self.count.min(EXIT_TRACE_LENGTH as u64) as usize;
+        let first = self.count as usize - len;
+
+        (first..first + len).map(|index| &self.entries[index % EXIT_TRACE_LENGTH])
+    }
+
+    /// Logs the recorded exits, from the oldest to the most recent.
+    ///
+    /// Each exit is shown with the TSC cycles elapsed since the previous one.
+    pub fn dump(&self) {
+        log::error!(
+            "[-] Last {} of {} VM exits:",
+            self.count.min(EXIT_TRACE_LENGTH as u64),
+            self.count
+        );
+
+        let mut previous_tsc = None;
+        for entry in self.iter() {
+            let delta = previous_tsc.map_or(0, |tsc| entry.tsc.wrapping_sub(tsc));
+            previous_tsc = Some(entry.tsc);
+
+            match VmxBasicExitReason::from_u32(entry.exit_reason as u32) {
+                Some(exit_reason) => log::error!(
+                    "[-]   {} at guest RIP {:#x} (+{} TSC cycles)",
+                    exit_reason,
+                    entry.guest_rip,
+                    delta
+                ),
+                None => log::error!(
+                    "[-]   Exit reason {} at guest RIP {:#x} (+{} TSC cycles)",
+                    entry.exit_reason,
+                    entry.guest_rip,
+                    delta
+                ),
+            }
+        }
+    }
+}
+
+/// An unregistered slot, used to initialize `EXIT_TRACES`.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TRACE: AtomicPtr<ExitTrace> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The exit trace of each processor's VM, indexed by APIC ID, so the panic handler can reach it.
+static EXIT_TRACES: [AtomicPtr<ExitTrace>; MAX_PROCESSORS] = [NO_TRACE; MAX_PROCESSORS];
+
+/// Registers the exit trace of the current processor's VM, so `dump_exit_trace` can log it.
+///
+/// # Arguments
+///
+/// * `trace` - The exit trace of the VM, which must stay alive for as long as the processor is virtualized.
+pub fn register_exit_trace(trace: &ExitTrace) {
+    EXIT_TRACES[apic_id() as usize].store(trace as *const _ as *mut _, Ordering::Release);
+}
+
+/// Logs the exit trace of the current processor.
+///
+/// Intended to be called from the panic handler, or after a VM-exit handler failed. Both run on the
+/// processor that records the trace, so it is not being updated concurrently.
+pub fn dump_exit_trace() {
+    let trace = EXIT_TRACES[apic_id() as usize].load(Ordering::Acquire);
+
+    match unsafe { trace.as_ref() } {
+        Some(trace) => trace.dump(),
+        None => log::error!("[-] No exit trace registered on this processor"),
+    }
+}
@@ -0,0 +1,135 @@
+//! Tracks host physical memory used by the hypervisor itself.
+//!
+//! EPT remapping lets a guest page be backed by arbitrary host memory. Remapping a guest page onto
+//! the EPT, a VMCS, the host page tables, or a host stack would give the guest control over the
+//! hypervisor, so such memory is registered here and `Ept::remap_gpa_to_hpa` refuses to map it.
+//!
+//! The hypervisor runs on an identity map, so the addresses of its allocations are host physical addresses.
+
+use {
+    crate::error::HypervisorError,
+    alloc::{boxed::Box, vec},
+    core::{mem::size_of_val, ops::Range},
+    spin::Mutex,
+};
+
+/// The maximum number of reserved regions. Each processor registers a few per-VM regions.
+pub const MAX_RESERVED_REGIONS: usize = 1024;
+
+/// The registered regions, as `[start, end)` pairs, and the number of regions in use.
+type RegionList = (Box<[(u64, u64)]>, usize);
+
+/// Registry of host physical address ranges owned by the hypervisor.
+#[derive(Debug)]
+pub struct ReservedRegions {
+    /// The registered regions. The list is allocated on the heap, so that `SharedData` can be built
+    /// on the firmware stack.
+    regions: Mutex<RegionList>,
+}
+
+impl Default for ReservedRegions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReservedRegions {
+    /// Creates an empty `ReservedRegions`.
+    pub fn new() -> Self {
+        Self {
+            regions: Mutex::new((vec![(0, 0); MAX_RESERVED_REGIONS].into_boxed_slice(), 0)),
+        }
+    }
+
+    /// Registers the memory of the region list itself as owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::ReservedRegionsFull)` if no free slot is left.
+    pub fn reserve_storage(&self) -> Result<(), HypervisorError> {
+        let storage = {
+            let regions = self.regions.lock();
+            let start = regions.0.as_ptr() as u64;
+            start..start + size_of_val(&*regions.0) as u64
+        };
+
+        self.reserve(storage)
+    }
+
+    /// Registers a host physical address range as owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The host physical address range to reserve.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::ReservedRegionsFull)` if no free slot is left.
+    pub fn reserve(&self, range: Range<u64>) -> Result<(), HypervisorError> {
+        let mut regions = self.regions.lock();
+        let (list, count) = &mut *regions;
+
+        let slot = list
+            .get_mut(*count)
+            .ok_or(HypervisorError::ReservedRegionsFull)?;
+        *slot = (range.start, range.end);
+        *count += 1;
+
+        Ok(())
+    }
+
+    /// Registers the memory of an object as owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `object` - The object whose memory to reserve, which may be a slice.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::ReservedRegionsFull)` if no free slot is left.
+    pub fn reserve_object<T: ?Sized>(&self, object: &T) -> Result<(), HypervisorError> {
+        let start = object as *const T as *const u8 as u64;
+        self.reserve(start..start + size_of_val(object) as u64)
+    }
+
+    /// Returns a registered region by its index, in registration order.
+    ///
+    /// The lock is only held while the region is copied out, so callers can iterate the regions while
+    /// calling `check` (e.g. through `Ept::remap_gpa_to_hpa`).
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the region.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address range of the region, or `None` if `index` is out of range.
+    pub fn region(&self, index: usize) -> Option<Range<u64>> {
+        let regions = self.regions.lock();
+        let (list, count) = &*regions;
+
+        list[..*count].get(index).map(|&(start, end)| start..end)
+    }
+
+    /// Checks that a host physical address range does not overlap any reserved region.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The host physical address range to check.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the range is not reserved, or `Err(HypervisorError::RemapIntoReservedRegion)` otherwise.
+    pub fn check(&self, range: Range<u64>) -> Result<(), HypervisorError> {
+        let regions = self.regions.lock();
+        let (list, count) = &*regions;
+
+        match list[..*count]
+            .iter()
+            .any(|&(start, end)| range.start < end && start < range.end)
+        {
+            true => Err(HypervisorError::RemapIntoReservedRegion),
+            false => Ok(()),
+        }
+    }
+}
@@ -7,9 +7,79 @@
 
 use {
     bitfield::bitfield, core::arch::asm, x86::bits64::rflags::RFlags,
-    x86::segmentation::SegmentSelector,
+    x86::segmentation::SegmentSelector, x86::vmx::vmcs,
 };
 
+/// A guest segment register whose state is held in the VMCS guest-state area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+    Ldtr,
+    Tr,
+}
+
+impl Segment {
+    /// Returns the VMCS encodings of the selector, base, limit, and access rights fields of the segment.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Appendix B Field Encoding in VMCS
+    #[rustfmt::skip]
+    const fn fields(self) -> (u32, u32, u32, u32) {
+        match self {
+            Self::Es => (vmcs::guest::ES_SELECTOR, vmcs::guest::ES_BASE, vmcs::guest::ES_LIMIT, vmcs::guest::ES_ACCESS_RIGHTS),
+            Self::Cs => (vmcs::guest::CS_SELECTOR, vmcs::guest::CS_BASE, vmcs::guest::CS_LIMIT, vmcs::guest::CS_ACCESS_RIGHTS),
+            Self::Ss => (vmcs::guest::SS_SELECTOR, vmcs::guest::SS_BASE, vmcs::guest::SS_LIMIT, vmcs::guest::SS_ACCESS_RIGHTS),
+            Self::Ds => (vmcs::guest::DS_SELECTOR, vmcs::guest::DS_BASE, vmcs::guest::DS_LIMIT, vmcs::guest::DS_ACCESS_RIGHTS),
+            Self::Fs => (vmcs::guest::FS_SELECTOR, vmcs::guest::FS_BASE, vmcs::guest::FS_LIMIT, vmcs::guest::FS_ACCESS_RIGHTS),
+            Self::Gs => (vmcs::guest::GS_SELECTOR, vmcs::guest::GS_BASE, vmcs::guest::GS_LIMIT, vmcs::guest::GS_ACCESS_RIGHTS),
+            Self::Ldtr => (vmcs::guest::LDTR_SELECTOR, vmcs::guest::LDTR_BASE, vmcs::guest::LDTR_LIMIT, vmcs::guest::LDTR_ACCESS_RIGHTS),
+            Self::Tr => (vmcs::guest::TR_SELECTOR, vmcs::guest::TR_BASE, vmcs::guest::TR_LIMIT, vmcs::guest::TR_ACCESS_RIGHTS),
+        }
+    }
+
+    /// Returns the VMCS encoding of the selector field of the segment.
+    pub const fn selector_field(self) -> u32 {
+        self.fields().0
+    }
+
+    /// Returns the VMCS encoding of the base address field of the segment.
+    pub const fn base_field(self) -> u32 {
+        self.fields().1
+    }
+
+    /// Returns the VMCS encoding of the segment limit field of the segment.
+    pub const fn limit_field(self) -> u32 {
+        self.fields().2
+    }
+
+    /// Returns the VMCS encoding of the access rights field of the segment.
+    pub const fn access_rights_field(self) -> u32 {
+        self.fields().3
+    }
+}
+
+/// The state of a guest segment register, as held in the VMCS guest-state area.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.4.1 Guest Register State
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentDescriptor {
+    /// The segment selector.
+    pub selector: SegmentSelector,
+
+    /// The base address of the segment.
+    pub base: u64,
+
+    /// The segment limit, in bytes.
+    pub limit: u32,
+
+    /// The access rights of the segment, in VMX format.
+    pub access_rights: VmxSegmentAccessRights,
+}
+
 /// Converts native segment access rights to VMX format.
 ///
 /// Transforms the native access rights format used by the processor into the format expected by VMX for segment access rights. If the input is 0, indicating an unusable segment, it sets the corresponding VMX unusable flag.
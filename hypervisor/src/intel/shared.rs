@@ -1,12 +1,145 @@
 //! A crate for managing hypervisor functionality, particularly focused on
 //! Extended Page Tables (EPT) and Model-Specific Register (MSR) bitmaps.
-//! Includes support for primary and optional secondary EPTs.
+//! Includes support for primary and optional secondary EPTs, and the EPTs of additional guests.
 
 use {
-    crate::{error::HypervisorError, intel::ept::paging::Ept},
-    alloc::boxed::Box,
+    crate::{
+        error::HypervisorError,
+        intel::{
+            ept::{
+                cow::CowTracker,
+                hooks::{
+                    create_inline_hook_shadow_page, EptHookManager, HookConfig, HookReadPolicy,
+                    HookStrategy,
+                },
+                paging::{AccessType, Ept, WxPolicy},
+                temporary::TemporaryAccess,
+                throttle::ViolationThrottle,
+                tracking::WriteTracker,
+            },
+            guest::{GuestEptConfig, GuestId, GuestRegistry},
+            invept::{activate_eptp, invept_all_contexts},
+            page::Page,
+            pe::find_export_gpa,
+            reserved::ReservedRegions,
+            state::InitialGuestState,
+            vm::{box_zeroed, try_box_zeroed},
+            vmexit::{
+                cpuid::{CpuidCache, CpuidLatency, CpuidProfile},
+                msr::MsrAudit,
+                mtf::is_monitor_trap_flag_supported,
+                pseudo::PseudoInstructions,
+                rng::DeterministicRng,
+                vmcall::SharedPage,
+            },
+            vmfunc::EptpList,
+            watchdog::Watchdog,
+        },
+        logger::apic_id,
+    },
+    alloc::{boxed::Box, vec::Vec},
+    core::{
+        ops::Range,
+        ptr::NonNull,
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    spin::{Mutex, MutexGuard},
+    x86::bits64::paging::BASE_PAGE_SIZE,
 };
 
+/// The number of EPTPs that can be registered in `SharedData`.
+pub const MAX_EPTP_SLOTS: usize = 8;
+
+/// The number of page tables of each EPT that hiding hypervisor memory leaves free for hooks.
+pub const HOOK_RESERVED_PT_COUNT: usize = 16;
+
+/// The number of processors, by APIC ID, whose launch failures can be reported to the loader.
+pub const MAX_PROCESSORS: usize = 256;
+
+/// The value of `SharedData::loader_apic_id` while the loader is still running.
+const LOADER_RUNNING: u32 = u32::MAX;
+
+/// Identifies one of the EPTPs registered in `SharedData`.
+///
+/// Slots 0 and 1 are always used for the primary and secondary EPT. The remaining slots are free for
+/// other views of guest memory, such as additional hook sets or a sandbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptpSlot(usize);
+
+impl EptpSlot {
+    /// The primary EPT: the original guest memory, with hooked pages read-write only.
+    pub const PRIMARY: Self = Self(0);
+
+    /// The secondary EPT: hooked pages are execute-only and backed by their shadow pages.
+    pub const SECONDARY: Self = Self(1);
+
+    /// Creates a slot identifier from its index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the slot.
+    ///
+    /// # Returns
+    ///
+    /// The slot, or `None` if `index` is not below `MAX_EPTP_SLOTS`.
+    pub const fn new(index: usize) -> Option<Self> {
+        match index < MAX_EPTP_SLOTS {
+            true => Some(Self(index)),
+            false => None,
+        }
+    }
+
+    /// Returns the index of the slot.
+    pub const fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// The view of guest memory a processor runs on, identified by the EPTP it uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptView {
+    /// The EPTP registered in `EptpSlot::PRIMARY`.
+    Primary,
+
+    /// The EPTP registered in `EptpSlot::SECONDARY`.
+    Secondary,
+
+    /// The EPTP of the agent view, created with `SharedData::create_agent_view`.
+    Agent(EptpSlot),
+
+    /// An EPTP registered in another slot with `SharedData::set_eptp`.
+    Other(EptpSlot),
+
+    /// An EPTP that is not registered for the running guest, e.g. because it was unregistered since.
+    Unknown(u64),
+}
+
+impl core::fmt::Display for EptView {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Primary => write!(f, "primary"),
+            Self::Secondary => write!(f, "secondary"),
+            Self::Agent(slot) => write!(f, "agent (slot {})", slot.index()),
+            Self::Other(slot) => write!(f, "slot {}", slot.index()),
+            Self::Unknown(eptp) => write!(f, "unknown EPTP {:#x}", eptp),
+        }
+    }
+}
+
+/// The locked hook manager, with exclusive access to the primary and secondary EPT of the default guest.
+///
+/// Returned by `SharedData::lock_epts`. The lock is released when the guard is dropped.
+pub struct EptsGuard<'a> {
+    /// The hook manager.
+    pub hook_manager: MutexGuard<'a, EptHookManager>,
+
+    /// The primary EPT of the default guest.
+    pub primary_ept: &'a mut Ept,
+
+    /// The secondary EPT of the default guest.
+    pub secondary_ept: &'a mut Ept,
+}
+
 /// Represents shared data structures for hypervisor operations.
 ///
 /// This struct manages the MSR (Model-Specific Register) bitmap and Extended Page Tables (EPT)
@@ -16,42 +149,757 @@ pub struct SharedData {
     /// The primary EPT (Extended Page Tables) for the VM.
     pub primary_ept: Box<Ept>,
 
-    /// The secondary EPTP (Extended Page Tables Pointer) for the VM.
-    pub primary_eptp: u64,
-
     /// The secondary EPT (Extended Page Tables) for the VM.
     pub secondary_ept: Box<Ept>,
 
-    /// The secondary EPTP (Extended Page Tables Pointer) for the VM.
-    pub secondary_eptp: u64,
+    /// The registered EPTPs (Extended Page Tables Pointers), indexed by `EptpSlot`.
+    eptps: [Option<u64>; MAX_EPTP_SLOTS],
+
+    /// The EPTP list the guest switches between with VMFUNC, mirroring `eptps`. Empty slots are zero.
+    eptp_list: Box<EptpList>,
+
+    /// The EPT the guest's agent switches to with VMFUNC to reach pages hidden from the normal views, with its slot.
+    agent_view: Option<(EptpSlot, Box<Ept>)>,
+
+    /// The EPTs of the guests besides the default guest, whose EPTs are `primary_ept` and `secondary_ept`.
+    pub guests: GuestRegistry,
+
+    /// Registry of hooked pages whose guest writes are reported to a callback.
+    pub write_tracker: WriteTracker,
+
+    /// Registry of EPT hooks installed in the primary and secondary EPTs. Its lock also serializes the
+    /// changes to those EPTs made from VM-exit handlers, see `SharedData::lock_epts`.
+    pub hook_manager: Mutex<EptHookManager>,
+
+    /// The strategy of hooks installed without an explicit one. Selected with `HookStrategy::preferred` for the processor.
+    pub hook_strategy: HookStrategy,
+
+    /// The read policy of EPT-swap hooks installed without an explicit one. Defaults to `HookReadPolicy::Original`.
+    pub hook_read_policy: HookReadPolicy,
+
+    /// Permissions temporarily granted in the primary and secondary EPTs, restored on the next monitor trap flag VM exit.
+    pub temporary_access: TemporaryAccess,
+
+    /// Pages write-protected in the primary and secondary EPTs, copied to a snapshot on their first write.
+    pub cow_tracker: CowTracker,
+
+    /// Host memory owned by the hypervisor, which must never be remapped into the guest.
+    pub reserved_regions: ReservedRegions,
+
+    /// Per-page EPT violation counters, which coalesce the logging of repeated violations.
+    pub violation_throttle: ViolationThrottle,
+
+    /// The native results of the most frequently queried CPUID leaves, served by the CPUID VM-exit handler.
+    pub cpuid_cache: CpuidCache,
+
+    /// The feature set reported to the guest through CPUID. Must be selected before the processors are virtualized.
+    pub cpuid_profile: CpuidProfile,
+
+    /// The number of cycles the CPUID VM-exit handler is padded to, disabled unless configured.
+    pub cpuid_latency: CpuidLatency,
+
+    /// The source of RDRAND and RDSEED values for the guest, disabled unless deterministic randomness is needed.
+    pub rng: DeterministicRng,
+
+    /// Invalid opcode sequences recognized as pseudo-instructions when the guest executes them.
+    pub pseudo_instructions: PseudoInstructions,
+
+    /// Reports VM-exit handlers that run longer than a threshold, disabled unless configured.
+    pub watchdog: Watchdog,
+
+    /// The page a guest agent registered with VMCALL to exchange commands and responses.
+    pub shared_page: SharedPage,
+
+    /// The host physical address of the page that hidden hypervisor pages are mapped to in the guest.
+    pub decoy_page_pa: u64,
+
+    /// The address range of the loaded hypervisor image, which the guest executes until the loader returns.
+    pub loader_image: Range<u64>,
+
+    /// The APIC ID of the processor the loader returned on, or `LOADER_RUNNING`.
+    loader_apic_id: AtomicU32,
+
+    /// Whether the reserved regions have been hidden from the guest.
+    memory_hidden: AtomicBool,
+
+    /// The error each processor that could not be virtualized failed with, indexed by APIC ID. Allocated on the heap to keep `SharedData` small.
+    launch_failures: Box<[Mutex<Option<HypervisorError>>]>,
+
+    /// Whether the processor supports execute-only EPT translations, which hooks rely on to hide their shadow pages from reads.
+    pub execute_only_supported: bool,
+
+    /// The MSRs whose guest writes are recorded for diagnostics, independently of how they are handled.
+    pub msr_audit: MsrAudit,
+
+    /// The state to launch the guest with instead of the captured state of the processor, for testing.
+    pub initial_guest_state: Option<InitialGuestState>,
 }
 
 impl SharedData {
-    /// Creates a new instance of `SharedData` with primary and optionally secondary EPTs.
+    /// Creates a new instance of `SharedData` from the EPTs of each guest.
     ///
-    /// This function initializes the MSR bitmap and sets up the EPTs.
+    /// This function initializes the MSR bitmap and sets up the EPTs. The EPTs of the default guest
+    /// become `primary_ept` and `secondary_ept`, and the EPTs of all other guests are registered in
+    /// `guests`. All EPTs are reserved, so they cannot be remapped into a guest.
     ///
     /// # Arguments
     ///
-    /// * `primary_ept`: The primary EPT to be used.
-    /// * `secondary_ept`: The secondary EPT to be used if the feature is enabled.
+    /// * `guest_configs`: The EPTs of each guest. Must include the default guest.
     ///
     /// # Returns
     /// A result containing a boxed `SharedData` instance or an error of type `HypervisorError`.
-    pub fn new(
-        primary_ept: Box<Ept>,
-        secondary_ept: Box<Ept>,
-    ) -> Result<Box<Self>, HypervisorError> {
+    pub fn new(mut guest_configs: Vec<GuestEptConfig>) -> Result<Box<Self>, HypervisorError> {
         log::trace!("Initializing shared data");
 
+        let default_index = guest_configs
+            .iter()
+            .position(|config| config.guest_id == GuestId::DEFAULT)
+            .ok_or(HypervisorError::DefaultGuestMissing)?;
+        let GuestEptConfig {
+            primary_ept,
+            secondary_ept,
+            ..
+        } = guest_configs.swap_remove(default_index);
+
         let primary_eptp = primary_ept.create_eptp_with_wb_and_4lvl_walk()?;
         let secondary_eptp = secondary_ept.create_eptp_with_wb_and_4lvl_walk()?;
 
-        Ok(Box::new(Self {
+        let mut eptps = [None; MAX_EPTP_SLOTS];
+        eptps[EptpSlot::PRIMARY.index()] = Some(primary_eptp);
+        eptps[EptpSlot::SECONDARY.index()] = Some(secondary_eptp);
+
+        let execute_only_supported = Ept::is_execute_only_supported();
+        let hook_strategy =
+            HookStrategy::preferred(execute_only_supported, is_monitor_trap_flag_supported());
+        if !execute_only_supported && hook_strategy == HookStrategy::EptSwap {
+            log::warn!("Execute-only EPT translations are not supported, hooked pages are mapped read-execute and their shadow pages are readable by the guest");
+        } else if hook_strategy == HookStrategy::Mtf {
+            log::info!("Execute-only EPT translations are not supported, hooks single-step their shadow pages with the monitor trap flag");
+        }
+
+        let mut eptp_list = unsafe { box_zeroed::<EptpList>() };
+        eptp_list.0[EptpSlot::PRIMARY.index()] = primary_eptp;
+        eptp_list.0[EptpSlot::SECONDARY.index()] = secondary_eptp;
+
+        let mut shared_data = Box::new(Self {
             primary_ept,
-            primary_eptp,
             secondary_ept,
-            secondary_eptp,
-        }))
+            eptps,
+            eptp_list,
+            agent_view: None,
+            guests: GuestRegistry::new(),
+            write_tracker: WriteTracker::new(),
+            hook_manager: Mutex::new(EptHookManager::new(execute_only_supported)),
+            hook_strategy,
+            hook_read_policy: HookReadPolicy::Original,
+            temporary_access: TemporaryAccess::new(),
+            cow_tracker: CowTracker::new(),
+            reserved_regions: ReservedRegions::new(),
+            violation_throttle: ViolationThrottle::new(),
+            cpuid_cache: CpuidCache::capture(),
+            cpuid_profile: CpuidProfile::Native,
+            cpuid_latency: CpuidLatency::new(),
+            rng: DeterministicRng::new(),
+            pseudo_instructions: PseudoInstructions::new(),
+            watchdog: Watchdog::new(),
+            shared_page: SharedPage::new(),
+            // The decoy page is guest-visible by design, so it is leaked rather than reserved.
+            decoy_page_pa: Box::leak(unsafe { box_zeroed::<Page>() }) as *mut Page as u64,
+            loader_image: 0..0,
+            loader_apic_id: AtomicU32::new(LOADER_RUNNING),
+            memory_hidden: AtomicBool::new(false),
+            launch_failures: (0..MAX_PROCESSORS).map(|_| Mutex::new(None)).collect(),
+            execute_only_supported,
+            msr_audit: MsrAudit::new(),
+            initial_guest_state: None,
+        });
+
+        shared_data
+            .reserved_regions
+            .reserve_object(&*shared_data.primary_ept)?;
+        shared_data
+            .reserved_regions
+            .reserve_object(&*shared_data.secondary_ept)?;
+        shared_data
+            .reserved_regions
+            .reserve_object(&*shared_data.eptp_list)?;
+        shared_data.reserved_regions.reserve_object(&*shared_data)?;
+
+        // The large arrays of the registries are allocated separately and must be reserved as well.
+        shared_data.reserved_regions.reserve_storage()?;
+        shared_data
+            .hook_manager
+            .lock()
+            .reserve_storage(&shared_data.reserved_regions)?;
+        shared_data
+            .watchdog
+            .reserve_storage(&shared_data.reserved_regions)?;
+        shared_data
+            .msr_audit
+            .reserve_storage(&shared_data.reserved_regions)?;
+        shared_data
+            .reserved_regions
+            .reserve_object(&*shared_data.launch_failures)?;
+
+        for config in guest_configs {
+            log::trace!("Registering EPTs of guest {}", config.guest_id.value());
+            let epts = shared_data.guests.register(config)?;
+            shared_data
+                .reserved_regions
+                .reserve_object(&*epts.primary_ept)?;
+            shared_data
+                .reserved_regions
+                .reserve_object(&*epts.secondary_ept)?;
+        }
+
+        Ok(shared_data)
+    }
+
+    /// Locks the hook manager and grants exclusive access to the primary and secondary EPT with it.
+    ///
+    /// VM-exit handlers run on all processors at once and only share `SharedData`, so every change they
+    /// make to the hooks or to the EPTs of the default guest goes through this guard, instead of a
+    /// `&mut SharedData` that would alias the one of every other processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `shared_data` - The shared data, e.g. `Vm::shared_data`.
+    ///
+    /// # Safety
+    ///
+    /// `shared_data` must have been created from a mutable reference, and the EPTs must not be changed
+    /// without the guard while processors run the guest.
+    pub unsafe fn lock_epts<'a>(shared_data: NonNull<Self>) -> EptsGuard<'a> {
+        let hook_manager = unsafe { shared_data.as_ref() }.hook_manager.lock();
+        let shared_data = shared_data.as_ptr();
+
+        // The lock is held by a single processor, so the EPTs are not changed through other references.
+        EptsGuard {
+            hook_manager,
+            primary_ept: unsafe { &mut (*shared_data).primary_ept },
+            secondary_ept: unsafe { &mut (*shared_data).secondary_ept },
+        }
+    }
+
+    /// Returns the EPTP registered in a slot.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot to read.
+    ///
+    /// # Returns
+    ///
+    /// The EPTP, or `Err(HypervisorError::EptpSlotEmpty)` if no EPTP is registered in the slot.
+    pub fn eptp(&self, slot: EptpSlot) -> Result<u64, HypervisorError> {
+        self.eptps[slot.index()].ok_or(HypervisorError::EptpSlotEmpty)
+    }
+
+    /// Registers an EPTP in a slot, replacing the previous one.
+    ///
+    /// The EPTP is validated like the ones created by `Ept::create_eptp_with_wb_and_4lvl_walk`. The
+    /// EPT it points to must stay alive for as long as it is registered, and should be registered in
+    /// `reserved_regions` so it cannot be remapped into the guest.
+    ///
+    /// The EPTP is also written to the same index of the EPTP list, so the guest can switch to it with
+    /// VMFUNC if EPTP switching is enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot to register the EPTP in. The primary and secondary slots cannot be replaced.
+    /// * `eptp` - The EPTP to register.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or an `Err(HypervisorError)` if the slot is reserved or the EPTP is invalid.
+    pub fn set_eptp(&mut self, slot: EptpSlot, eptp: u64) -> Result<(), HypervisorError> {
+        if slot == EptpSlot::PRIMARY || slot == EptpSlot::SECONDARY {
+            return Err(HypervisorError::InvalidEptpSlot);
+        }
+
+        Ept::validate_eptp(eptp)?;
+        self.eptps[slot.index()] = Some(eptp);
+        self.eptp_list.0[slot.index()] = eptp;
+
+        Ok(())
+    }
+
+    /// Returns the physical address of the EPTP list, for the EPTP_LIST_ADDRESS VMCS field.
+    pub fn eptp_list_pa(&self) -> u64 {
+        &*self.eptp_list as *const EptpList as u64
+    }
+
+    /// Returns the EPTP of a guest registered in a slot.
+    ///
+    /// Guests other than the default guest only have the primary and secondary slots.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The guest whose EPTP to read.
+    /// * `slot` - The slot to read.
+    ///
+    /// # Returns
+    ///
+    /// The EPTP, `Err(HypervisorError::GuestNotFound)` if the guest is not registered, or
+    /// `Err(HypervisorError::EptpSlotEmpty)` if no EPTP is registered in the slot.
+    pub fn guest_eptp(&self, guest_id: GuestId, slot: EptpSlot) -> Result<u64, HypervisorError> {
+        if guest_id == GuestId::DEFAULT {
+            return self.eptp(slot);
+        }
+
+        let epts = self
+            .guests
+            .get(guest_id)
+            .ok_or(HypervisorError::GuestNotFound)?;
+
+        match slot {
+            EptpSlot::PRIMARY => Ok(epts.primary_eptp),
+            EptpSlot::SECONDARY => Ok(epts.secondary_eptp),
+            _ => Err(HypervisorError::EptpSlotEmpty),
+        }
+    }
+
+    /// Finds the view of a guest that an EPTP belongs to.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The guest the EPTP is used by.
+    /// * `eptp` - The EPTP to look up, e.g. from `Vm::current_eptp`.
+    ///
+    /// # Returns
+    ///
+    /// The view, or `EptView::Unknown` if the EPTP is not registered in any slot of the guest.
+    pub fn ept_view(&self, guest_id: GuestId, eptp: u64) -> EptView {
+        let slot = (0..MAX_EPTP_SLOTS)
+            .filter_map(EptpSlot::new)
+            .find(|&slot| self.guest_eptp(guest_id, slot).ok() == Some(eptp));
+
+        match slot {
+            Some(EptpSlot::PRIMARY) => EptView::Primary,
+            Some(EptpSlot::SECONDARY) => EptView::Secondary,
+            Some(slot) if guest_id == GuestId::DEFAULT && self.agent_slot() == Some(slot) => {
+                EptView::Agent(slot)
+            }
+            Some(slot) => EptView::Other(slot),
+            None => EptView::Unknown(eptp),
+        }
+    }
+
+    /// Switches the current processor to the EPTP of a guest registered in a slot.
+    ///
+    /// Writes the EPTP to the current VMCS and invalidates the EPT caches with `activate_eptp`, so it
+    /// must be called from a VM-exit handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The guest running on the current processor.
+    /// * `slot` - The slot of the EPTP to activate.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or an `Err(HypervisorError)` if the guest or the slot is not registered.
+    pub fn set_active_eptp(
+        &self,
+        guest_id: GuestId,
+        slot: EptpSlot,
+    ) -> Result<(), HypervisorError> {
+        let eptp = self.guest_eptp(guest_id, slot)?;
+
+        activate_eptp(eptp)
+    }
+
+    /// Sets the W^X policy of the primary EPT.
+    ///
+    /// Only later permission changes through the primary EPT are affected. The secondary EPT keeps
+    /// allowing writable and executable pages, since hooks map their pages execute-only there.
+    ///
+    /// # Arguments
+    ///
+    /// * `wx_policy` - The new policy, e.g. `WxPolicy::Log` for research or `WxPolicy::Strip` for hardening.
+    pub fn set_wx_policy(&mut self, wx_policy: WxPolicy) {
+        self.primary_ept.set_wx_policy(wx_policy);
+    }
+
+    /// Records why the current processor could not be virtualized, for `take_launch_failure`.
+    ///
+    /// # Arguments
+    ///
+    /// * `apic_id` - The APIC ID of the current processor.
+    /// * `error` - The error launching the guest failed with.
+    pub fn report_launch_failure(&self, apic_id: u32, error: HypervisorError) {
+        match self.launch_failures.get(apic_id as usize) {
+            Some(failure) => *failure.lock() = Some(error),
+            None => log::error!("Processor {} failed to launch: {}", apic_id, error),
+        }
+    }
+
+    /// Takes the error a processor failed to launch the guest with, so the loader can report it.
+    ///
+    /// # Arguments
+    ///
+    /// * `apic_id` - The APIC ID of the processor.
+    ///
+    /// # Returns
+    ///
+    /// The error, or `None` if the processor has been virtualized or has not been started.
+    pub fn take_launch_failure(&self, apic_id: u32) -> Option<HypervisorError> {
+        self.launch_failures.get(apic_id as usize)?.lock().take()
+    }
+
+    /// Registers the loaded hypervisor image, so it is reserved and hidden together with the other
+    /// hypervisor memory once the loader has returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The address range of the loaded image.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::ReservedRegionsFull)` if the image cannot be reserved.
+    pub fn set_loader_image(&mut self, image: Range<u64>) -> Result<(), HypervisorError> {
+        self.reserved_regions.reserve(image.clone())?;
+        self.loader_image = image;
+
+        Ok(())
+    }
+
+    /// Marks the loader as finished, so the hypervisor memory is hidden as soon as the current
+    /// processor has left the loader image.
+    ///
+    /// Called by the loader in the guest, on the last processor to execute it, right before returning.
+    /// Nothing may call back into the image afterwards (e.g. event notifications), since the guest reads
+    /// the decoy page there once it is hidden.
+    pub fn finish_loading(&self) {
+        self.loader_apic_id.store(apic_id(), Ordering::Release);
+    }
+
+    /// Hides the hypervisor memory from the guest once the loader has returned.
+    ///
+    /// Called on every VM exit. The reserved regions, including the loader image, are hidden once by
+    /// the processor the loader returned on, at its first VM exit outside the image. Every processor
+    /// then invalidates its EPT caches once, dropping the translations of the hidden pages it cached.
+    /// Hiding is best-effort: a guest that can see our memory is still correctly virtualized.
+    ///
+    /// # Arguments
+    ///
+    /// * `shared_data` - The shared data, e.g. `Vm::shared_data`.
+    /// * `memory_hidden` - Whether the current processor has invalidated its EPT caches since the
+    ///   memory was hidden. Set once it has.
+    /// * `guest_rip` - The guest RIP of the current VM exit. The loader runs identity mapped, so it is
+    ///   compared with the physical address range of the image.
+    ///
+    /// # Safety
+    ///
+    /// The same as for `lock_epts`.
+    pub unsafe fn hide_hypervisor_memory(
+        shared_data: NonNull<Self>,
+        memory_hidden: &mut bool,
+        guest_rip: u64,
+    ) {
+        if *memory_hidden {
+            return;
+        }
+
+        let shared = unsafe { shared_data.as_ref() };
+
+        if !shared.memory_hidden.load(Ordering::Acquire) {
+            if shared.loader_apic_id.load(Ordering::Acquire) != apic_id()
+                || shared.loader_image.contains(&guest_rip)
+            {
+                return;
+            }
+
+            let epts = unsafe { Self::lock_epts(shared_data) };
+            if let Err(e) = Self::hide_reserved_regions(
+                epts.primary_ept,
+                epts.secondary_ept,
+                &shared.reserved_regions,
+                shared.decoy_page_pa,
+            ) {
+                log::warn!("Failed to hide hypervisor memory from the guest: {:?}", e);
+            }
+            shared.memory_hidden.store(true, Ordering::Release);
+            drop(epts);
+        }
+
+        match invept_all_contexts() {
+            Ok(()) => *memory_hidden = true,
+            Err(e) => log::warn!("Failed to invalidate the EPT caches: {:?}", e),
+        }
+    }
+
+    /// Hides the hypervisor's memory from the guest.
+    ///
+    /// Every 4KB page that lies entirely within a reserved region is remapped to the decoy page in
+    /// both EPTs, so a guest scanning physical memory reads the decoy page instead of the hypervisor
+    /// image, the EPTs, VMCSs, host page tables, or host stacks. The host accesses its memory through
+    /// the host page tables and is not affected. Pages only partially covered by a reserved region are
+    /// shared with guest memory (e.g. pool allocations) and stay visible. Pages that are already hidden
+    /// are skipped.
+    ///
+    /// Each 2MB page containing hidden memory is split using a page table from the allocator of each
+    /// EPT, as long as `HOOK_RESERVED_PT_COUNT` page tables remain free for hooks. The caller is
+    /// responsible for invalidating the EPT caches (`invept_all_contexts`) if the EPTs are in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT of the default guest.
+    /// * `secondary_ept` - The secondary EPT of the default guest.
+    /// * `reserved_regions` - The regions to hide.
+    /// * `decoy_page_pa` - The host physical address of the page to map hidden pages to.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or an `Err(HypervisorError)` if a page could not be hidden, e.g.
+    /// `HypervisorError::HidingPtBudgetExhausted` if no page table is left for a split. Pages hidden
+    /// before the failure stay hidden.
+    pub fn hide_reserved_regions(
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        reserved_regions: &ReservedRegions,
+        decoy_page_pa: u64,
+    ) -> Result<(), HypervisorError> {
+        let mut index = 0;
+
+        while let Some(region) = reserved_regions.region(index) {
+            // Only hide pages that are entirely owned by the hypervisor.
+            let first_page =
+                (region.start + BASE_PAGE_SIZE as u64 - 1) & !(BASE_PAGE_SIZE as u64 - 1);
+            let last_page = region.end & !(BASE_PAGE_SIZE as u64 - 1);
+
+            for guest_pa in (first_page..last_page).step_by(BASE_PAGE_SIZE) {
+                Self::hide_page(
+                    primary_ept,
+                    secondary_ept,
+                    reserved_regions,
+                    decoy_page_pa,
+                    guest_pa,
+                )?;
+            }
+
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Remaps a single guest page to the decoy page in both EPTs, unless it is already hidden.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary_ept` - The primary EPT of the default guest.
+    /// * `secondary_ept` - The secondary EPT of the default guest.
+    /// * `reserved_regions` - The reserved regions, which the decoy page is not part of.
+    /// * `decoy_page_pa` - The host physical address of the page to map the page to.
+    /// * `guest_pa` - The page-aligned guest physical address of the page to hide.
+    fn hide_page(
+        primary_ept: &mut Ept,
+        secondary_ept: &mut Ept,
+        reserved_regions: &ReservedRegions,
+        decoy_page_pa: u64,
+        guest_pa: u64,
+    ) -> Result<(), HypervisorError> {
+        if primary_ept.gpa_to_hpa(guest_pa) == Some(decoy_page_pa) {
+            return Ok(());
+        }
+
+        let (primary_index, primary_split) = Self::split_for_hiding(primary_ept, guest_pa)?;
+        let secondary_index = match Self::split_for_hiding(secondary_ept, guest_pa) {
+            Ok((secondary_index, _)) => secondary_index,
+            Err(e) => {
+                if primary_split {
+                    primary_ept.merge_4kb_to_2mb(guest_pa)?;
+                }
+                return Err(e);
+            }
+        };
+
+        primary_ept.remap_gpa_to_hpa(guest_pa, decoy_page_pa, primary_index, reserved_regions)?;
+        secondary_ept.remap_gpa_to_hpa(guest_pa, decoy_page_pa, secondary_index, reserved_regions)
+    }
+
+    /// Splits the 2MB page containing a page to hide in an EPT, unless it is split already.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept` - The EPT to split the page in, with a page table from its own allocator.
+    /// * `guest_pa` - The page-aligned guest physical address of the page to hide.
+    ///
+    /// # Returns
+    ///
+    /// The index of the page table mapping the page and whether the 2MB page was split here, or
+    /// `Err(HypervisorError::HidingPtBudgetExhausted)` if splitting would leave fewer than
+    /// `HOOK_RESERVED_PT_COUNT` page tables for hooks.
+    fn split_for_hiding(ept: &mut Ept, guest_pa: u64) -> Result<(usize, bool), HypervisorError> {
+        if let Some(pt_table_index) = ept.split_pt_index(guest_pa) {
+            return Ok((pt_table_index, false));
+        }
+
+        if ept.free_pt_count() <= HOOK_RESERVED_PT_COUNT {
+            return Err(HypervisorError::HidingPtBudgetExhausted);
+        }
+
+        Ok((ept.split_2mb_to_4kb_alloc(guest_pa)?, true))
+    }
+
+    /// Creates the agent view, an EPT the guest only reaches by switching to it with VMFUNC.
+    ///
+    /// The agent view starts as a copy of the primary EPT and is registered in `slot`, so an agent in
+    /// the guest can switch to it with VMFUNC (ECX set to the slot index) to reach the pages mapped with
+    /// `map_agent_page`, and switch back to `EptpSlot::PRIMARY` afterwards. Later changes to the primary
+    /// EPT, such as new hooks, are not reflected in the agent view.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot to register the agent view in. The primary and secondary slots cannot be used.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::AgentViewAlreadyCreated)` if the agent view exists,
+    /// or an `Err(HypervisorError)` if the slot is reserved.
+    pub fn create_agent_view(&mut self, slot: EptpSlot) -> Result<(), HypervisorError> {
+        if self.agent_view.is_some() {
+            return Err(HypervisorError::AgentViewAlreadyCreated);
+        }
+
+        let mut agent_ept = unsafe { try_box_zeroed::<Ept>() }?;
+        agent_ept.clone_from(&self.primary_ept)?;
+        self.reserved_regions.reserve_object(&*agent_ept)?;
+
+        self.set_eptp(slot, agent_ept.create_eptp_with_wb_and_4lvl_walk()?)?;
+        self.agent_view = Some((slot, agent_ept));
+
+        Ok(())
+    }
+
+    /// Returns the slot of the agent view, or `None` if it has not been created.
+    pub fn agent_slot(&self) -> Option<EptpSlot> {
+        self.agent_view.as_ref().map(|(slot, _)| *slot)
+    }
+
+    /// Allocates a host page and maps it into the agent view only, e.g. for hook metadata or a buffer
+    /// the hypervisor shares with an agent in the guest.
+    ///
+    /// The page is reserved and hidden behind the decoy page in the primary and secondary EPTs, so the
+    /// guest cannot reach it through its normal views. The caller is responsible for invalidating the
+    /// EPT caches (`invept_all_contexts`) if the EPTs are in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The page-aligned guest physical address the page is mapped at in the agent view.
+    /// * `access_type` - The permissions of the page in the agent view.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address of the page, `Err(HypervisorError::AgentViewMissing)` if the agent
+    /// view has not been created, or the error of the failed EPT operation.
+    pub fn map_agent_page(
+        &mut self,
+        guest_pa: u64,
+        access_type: AccessType,
+    ) -> Result<u64, HypervisorError> {
+        if self.agent_view.is_none() {
+            return Err(HypervisorError::AgentViewMissing);
+        }
+
+        let page = Box::leak(unsafe { box_zeroed::<Page>() });
+        self.reserved_regions.reserve_object(page)?;
+        let host_pa = page as *mut Page as u64;
+
+        // The host is identity-mapped, so the page is visible at its own address in the normal views.
+        Self::hide_page(
+            &mut self.primary_ept,
+            &mut self.secondary_ept,
+            &self.reserved_regions,
+            self.decoy_page_pa,
+            host_pa,
+        )?;
+
+        let (_, agent_ept) = self
+            .agent_view
+            .as_mut()
+            .ok_or(HypervisorError::AgentViewMissing)?;
+        agent_ept.map_hidden_page(guest_pa, host_pa, access_type)?;
+
+        Ok(host_pa)
+    }
+
+    /// Write-protects a guest physical address range for copy-on-write snapshots.
+    ///
+    /// Every 4KB page touched by the range is made read-only in both EPTs. The first guest write to
+    /// each page copies its original contents into a snapshot buffer, which can be read with
+    /// `CowTracker::for_each_snapshot`, and makes the page writable again.
+    ///
+    /// The caller is responsible for invalidating the EPT caches (`invept_all_contexts`) if the EPTs
+    /// are in use.
+    ///
+    /// # Arguments
+    ///
+    /// * `start_gpa` - The guest physical address of the start of the range.
+    /// * `len` - The length of the range in bytes.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::CowPageHooked)` if the range contains a hooked page,
+    /// or the error of the failed EPT operation.
+    pub fn protect_for_cow(&mut self, start_gpa: u64, len: usize) -> Result<(), HypervisorError> {
+        // Hooked pages rely on being writable in the primary EPT to swap back from the secondary EPT.
+        let first_page = start_gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        let end = start_gpa + len as u64;
+        if (first_page..end)
+            .step_by(BASE_PAGE_SIZE)
+            .any(|guest_pa| self.hook_manager.get_mut().is_enabled(guest_pa))
+        {
+            return Err(HypervisorError::CowPageHooked);
+        }
+
+        self.cow_tracker.protect(
+            &mut self.primary_ept,
+            &mut self.secondary_ept,
+            start_gpa,
+            len,
+        )
+    }
+
+    /// Hooks a function exported by name from a guest PE image.
+    ///
+    /// Resolves the export through the primary EPT, creates a shadow page that jumps to the handler,
+    /// and installs an EPT hook on the function's page with the strategy in `hook_strategy` and the
+    /// read policy in `hook_read_policy`.
+    ///
+    /// # Arguments
+    ///
+    /// * `module_base_gpa` - The guest physical address of the image base. The image must be physically contiguous.
+    /// * `export_name` - The name of the exported function.
+    /// * `handler_pa` - The address of the handler the function jumps to.
+    ///
+    /// # Returns
+    ///
+    /// The guest physical address of the hooked function, or an `Err(HypervisorError)` if the
+    /// export cannot be resolved or the hook cannot be installed.
+    pub fn install_hook_by_name(
+        &mut self,
+        module_base_gpa: u64,
+        export_name: &str,
+        handler_pa: u64,
+    ) -> Result<u64, HypervisorError> {
+        let function_gpa = find_export_gpa(&self.primary_ept, module_base_gpa, export_name)?;
+        log::debug!("Resolved export {} to GPA {:#x}", export_name, function_gpa);
+
+        let shadow_page_pa =
+            create_inline_hook_shadow_page(&self.primary_ept, function_gpa, handler_pa)?;
+
+        self.hook_manager.get_mut().install(
+            &mut self.primary_ept,
+            &mut self.secondary_ept,
+            HookConfig {
+                guest_pa: function_gpa,
+                shadow_page_pa,
+                strategy: self.hook_strategy,
+                read_policy: self.hook_read_policy,
+            },
+            &self.reserved_regions,
+        )?;
+
+        Ok(function_gpa)
     }
 }
@@ -0,0 +1,64 @@
+//! Allocates the per-processor host stack used for VM-exit handling.
+//!
+//! The stack is preceded by a guard page that is left non-present in the host page tables, so a
+//! stack overflow in VMX root operation (e.g. deep logging while handling a VM exit) faults on the
+//! guard page instead of silently corrupting adjacent memory such as the EPT or the VMCS.
+//!
+//! The page fault cannot be delivered on the exhausted stack and escalates to a double fault, which
+//! the host IDT handles on its own interrupt stack: it logs the fault and halts the processor. This
+//! is deliberate: a deterministic crash is preferable to corrupted state.
+
+use {
+    crate::intel::page::Page,
+    alloc::alloc::{alloc_zeroed, handle_alloc_error},
+    core::alloc::Layout,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The usable size of the host stack in bytes, excluding the guard page.
+pub const HOST_STACK_SIZE: usize = 0x10 * BASE_PAGE_SIZE;
+
+/// The size of the guard page below the host stack in bytes.
+pub const HOST_STACK_GUARD_SIZE: usize = BASE_PAGE_SIZE;
+
+/// A host stack with a guard page at its lowest address.
+///
+/// The memory is never freed, since the hypervisor never stops running on it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HostStack {
+    /// The initial stack pointer. Must stay the first field, as `switch_stack` loads it from offset 0.
+    pub top: u64,
+
+    /// The address of the guard page, which is directly below the usable stack.
+    pub guard_page: u64,
+}
+
+impl HostStack {
+    /// Allocates a new host stack of `HOST_STACK_SIZE` bytes with a guard page below it.
+    ///
+    /// The guard page is only allocated here; it is unmapped from the host page tables when the VM is created.
+    ///
+    /// # Returns
+    ///
+    /// The allocated `HostStack`. Aborts through `handle_alloc_error` if the allocation fails.
+    pub fn allocate() -> Self {
+        let layout =
+            Layout::array::<Page>((HOST_STACK_GUARD_SIZE + HOST_STACK_SIZE) / BASE_PAGE_SIZE)
+                .unwrap();
+        let memory = unsafe { alloc_zeroed(layout) };
+        if memory.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        let guard_page = memory as u64;
+        let top = guard_page + layout.size() as u64 - 0x10;
+
+        Self { top, guard_page }
+    }
+
+    /// Returns the address range of the usable stack, excluding the guard page.
+    pub fn range(&self) -> core::ops::Range<u64> {
+        self.guard_page + HOST_STACK_GUARD_SIZE as u64..self.top
+    }
+}
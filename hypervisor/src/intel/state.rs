@@ -1,3 +1,29 @@
+use {
+    crate::intel::{
+        segmentation::{Segment, SegmentDescriptor},
+        support::rdmsr,
+    },
+    alloc::vec::Vec,
+};
+
+/// Blocking by STI (bit 0 of the interruptibility state): interrupts are blocked for one instruction after STI.
+pub const BLOCKING_BY_STI: u32 = 1 << 0;
+
+/// Blocking by MOV SS (bit 1 of the interruptibility state): interrupts and debug exceptions are
+/// blocked for one instruction after MOV SS or POP SS.
+pub const BLOCKING_BY_MOV_SS: u32 = 1 << 1;
+
+/// BS (bit 14 of the pending debug exceptions): a single-step trap is pending and delivered after VM entry.
+pub const PENDING_DEBUG_SINGLE_STEP: u64 = 1 << 14;
+
+/// The bits of the pending debug exceptions that must be 0: bits 11:4, 13, 15, and 63:17.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.5 Checks on Guest Non-Register State
+pub const PENDING_DEBUG_RESERVED: u64 = !0b1_0101_0000_0000_1111;
+
+/// BTF (bit 1 of IA32_DEBUGCTL): RFLAGS.TF single-steps on branches instead of instructions.
+pub const DEBUGCTL_BTF: u64 = 1 << 1;
+
 /// Represents the activity state of a logical processor in VMX operation.
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -15,3 +41,61 @@ pub enum GuestActivityState {
     /// The logical processor is inactive because it is waiting for a startup-IPI (SIPI).
     WaitForSipi = 0x00000003,
 }
+
+impl GuestActivityState {
+    /// Returns whether a VM entry may put the processor into the activity state.
+    ///
+    /// The active state is always supported, and the others are reported in bits 8:6 of IA32_VMX_MISC.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.6 MISCELLANEOUS DATA
+    pub fn is_supported(self) -> bool {
+        match self {
+            Self::Active => true,
+            _ => rdmsr(x86::msr::IA32_VMX_MISC) & (1 << (5 + self as u32)) != 0,
+        }
+    }
+}
+
+/// A guest state that replaces the state captured from the processor when the VM is launched.
+///
+/// By default, the guest continues executing the UEFI code that installed the hypervisor. With an
+/// initial state, the guest instead starts at `rip` with its own stack and address space, e.g. in a
+/// minimal test payload that exercises VM-exit handlers with a known state. The processor never
+/// returns to the driver then, so only the processor that installs the hypervisor first is virtualized.
+///
+/// All other state, including RFLAGS, CR0, CR4, the descriptor tables, and the general-purpose
+/// registers, stays that of the processor, so the payload must run in 64-bit mode.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.4.1 Guest Register State
+#[derive(Debug, Clone)]
+pub struct InitialGuestState {
+    /// The guest RIP to launch at.
+    pub rip: u64,
+
+    /// The guest RSP, which must point to writable memory if the payload uses the stack.
+    pub rsp: u64,
+
+    /// The guest CR3, the physical address of the PML4 that maps the payload and its stack.
+    pub cr3: u64,
+
+    /// The segment registers to override, e.g. CS and SS matching the GDT of the payload.
+    pub segments: Vec<(Segment, SegmentDescriptor)>,
+}
+
+impl InitialGuestState {
+    /// Creates an initial state that keeps the segment registers of the processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `rip` - The guest RIP to launch at.
+    /// * `rsp` - The guest RSP.
+    /// * `cr3` - The guest CR3.
+    pub fn new(rip: u64, rsp: u64, cr3: u64) -> Self {
+        Self {
+            rip,
+            rsp,
+            cr3,
+            segments: Vec::new(),
+        }
+    }
+}
@@ -99,6 +99,11 @@ pub fn cr4_write(val: x86::controlregs::Cr4) {
     unsafe { x86::controlregs::cr4_write(val) };
 }
 
+/// Reads the CR2 register.
+pub fn cr2() -> u64 {
+    unsafe { x86::controlregs::cr2() as u64 }
+}
+
 /// Writes a value to the Cr2 register.
 pub fn cr2_write(val: u64) {
     unsafe { x86::controlregs::cr2_write(val) };
@@ -180,6 +185,26 @@ pub fn outb(port: u16, val: u8) {
     unsafe { x86::io::outb(port, val) };
 }
 
+/// Reads 16-bits from an IO port.
+pub fn inw(port: u16) -> u16 {
+    unsafe { x86::io::inw(port) }
+}
+
+/// Writes 16-bits to an IO port.
+pub fn outw(port: u16, val: u16) {
+    unsafe { x86::io::outw(port, val) };
+}
+
+/// Reads 32-bits from an IO port.
+pub fn inl(port: u16) -> u32 {
+    unsafe { x86::io::inl(port) }
+}
+
+/// Writes 32-bits to an IO port.
+pub fn outl(port: u16, val: u32) {
+    unsafe { x86::io::outl(port, val) };
+}
+
 /// Reads the IDTR register.
 pub fn sidt() -> x86::dtables::DescriptorTablePointer<u64> {
     let mut idtr = x86::dtables::DescriptorTablePointer::<u64>::default();
@@ -193,3 +218,35 @@ pub fn sgdt() -> x86::dtables::DescriptorTablePointer<u64> {
     unsafe { x86::dtables::sgdt(&mut gdtr) };
     gdtr
 }
+
+/// Writes back and invalidates the cache lines covering a memory range.
+///
+/// Uses CLFLUSHOPT if supported, which is weakly ordered and therefore followed by SFENCE, and
+/// CLFLUSH otherwise. The cache line size is taken from CPUID.01H:EBX[15:8].
+///
+/// # Arguments
+///
+/// * `address` - The start of the range.
+/// * `len` - The length of the range in bytes.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Volume 2A: CLFLUSHOPT—Flush Cache Line Optimized
+pub fn flush_cache_range(address: u64, len: usize) {
+    const CLFLUSHOPT_SUPPORTED: u32 = 1 << 23;
+
+    let line_size = u64::from((x86::cpuid::cpuid!(0x1).ebx >> 8) & 0xff) * 8;
+    let line_size = line_size.max(32);
+    let use_clflushopt = x86::cpuid::cpuid!(0x7, 0x0).ebx & CLFLUSHOPT_SUPPORTED != 0;
+
+    let mut line = address & !(line_size - 1);
+    while line < address + len as u64 {
+        unsafe {
+            match use_clflushopt {
+                true => asm!("clflushopt [{}]", in(reg) line, options(nostack)),
+                false => asm!("clflush [{}]", in(reg) line, options(nostack)),
+            }
+        }
+        line += line_size;
+    }
+
+    unsafe { asm!("sfence", options(nostack)) };
+}
@@ -9,26 +9,76 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            capture::GuestRegisters,
+            addresses::{PagingMode, PhysicalAddress},
+            bitmap::{IoBitmap, MsrBitmap},
+            capture::{restore_registers, GuestRegisters, Register},
+            consistency::check_guest_state,
+            controls::{is_vmx_control_supported, VmxControl},
+            decode::decode_current_instruction,
             descriptor::Descriptors,
-            page::Page,
+            ept::{paging::Ept, throttle::ViolationStreak},
+            events::{EventInjection, PendingInterrupts},
+            guest::GuestId,
+            idt::{HostIdt, DOUBLE_FAULT_IST_INDEX},
+            invept::activate_eptp,
+            invvpid::{allocate_vpid, is_vpid_supported},
+            lbr::{setup_debug_controls, IA32_LBR_CTL, LOAD_GUEST_LBR_CTL},
             paging::PageTables,
-            shared::SharedData,
-            support::{rdmsr, vmclear, vmptrld, vmread},
+            postmortem::ExitTrace,
+            segmentation::{Segment, SegmentDescriptor, VmxSegmentAccessRights},
+            shared::{EptView, EptpSlot, EptsGuard, SharedData},
+            stack::{HostStack, HOST_STACK_GUARD_SIZE, HOST_STACK_SIZE},
+            state::{
+                GuestActivityState, InitialGuestState, BLOCKING_BY_MOV_SS, BLOCKING_BY_STI,
+                DEBUGCTL_BTF, PENDING_DEBUG_SINGLE_STEP,
+            },
+            support::{rdmsr, vmclear, vmptrld, vmread, vmwrite, vmxoff, wrmsr},
             vmcs::Vmcs,
-            vmerror::{VmInstructionError, VmxBasicExitReason},
+            vmerror::{VmEntryFailure, VmInstructionErrorNumber, VmxBasicExitReason},
+            vmexit::{
+                interrupt::set_interrupt_window_exiting,
+                monitor_mwait::setup_monitor_mwait_exiting, msr::MsrAccessType,
+                preemption_timer::setup_preemption_timer, pseudo::setup_pseudo_instructions,
+                rdtsc::TSC_MULTIPLIER_FRACTION_BITS, rng::setup_rng_exiting,
+            },
+            vmfield,
+            vmfunc::setup_eptp_switching,
             vmlaunch::launch_vm,
+            xstate::ExtendedState,
         },
+        logger::apic_id,
     },
     alloc::alloc::handle_alloc_error,
     alloc::boxed::Box,
     bit_field::BitField,
     core::alloc::Layout,
     core::ptr::NonNull,
+    iced_x86::Instruction,
     log::*,
-    x86::{bits64::rflags::RFlags, vmx::vmcs},
+    x86::{
+        bits64::{paging::BASE_PAGE_SIZE, rflags::RFlags},
+        controlregs::{cr3_write, cr4, cr4_write, Cr0, Cr4},
+        dtables::{lgdt, lidt, DescriptorTablePointer},
+        msr::{IA32_FS_BASE, IA32_GS_BASE},
+        segmentation::{load_cs, load_ds, load_es, load_fs, load_gs, load_ss, SegmentSelector},
+        vmx::vmcs::{
+            self,
+            control::{EntryControls, ExitControls, PrimaryControls, SecondaryControls},
+        },
+    },
 };
 
+/// CR0 bits that are always owned by the hypervisor, regardless of the mask passed to `Vm::set_cr0_mask`.
+///
+/// None: the guest may change any CR0 bit that VMX operation allows it to change.
+pub const CR0_FORCE_OWNED: u64 = 0;
+
+/// CR4 bits that are always owned by the hypervisor, regardless of the mask passed to `Vm::set_cr4_mask`.
+///
+/// CR4.VMXE must stay set in VMX non-root operation and is hidden from the guest, which is also
+/// told by CPUID that VMX is not supported. The guest reads it from the shadow, where it is clear.
+pub const CR4_FORCE_OWNED: u64 = Cr4::CR4_ENABLE_VMX.bits() as u64;
+
 /// Represents a Virtual Machine (VM) instance, encapsulating its state and control mechanisms.
 ///
 /// This structure manages the VM's lifecycle, including setup, execution, and handling of VM-exits.
@@ -44,6 +94,9 @@ pub struct Vm {
     /// Descriptor tables for the host state.
     pub host_descriptor: Descriptors,
 
+    /// The IDT and #DF interrupt stack for exceptions in VMX root operation.
+    pub host_idt: Box<HostIdt>,
+
     /// Paging tables for the host.
     pub host_paging: Box<PageTables>,
 
@@ -51,11 +104,47 @@ pub struct Vm {
     pub guest_registers: GuestRegisters,
 
     /// Bitmap controlling MSR read/write operations.
-    pub msr_bitmap: Box<Page>,
+    pub msr_bitmap: Box<MsrBitmap>,
+
+    /// Bitmaps controlling which I/O ports cause VM exits. All ports are passed through by default.
+    pub io_bitmap: Box<IoBitmap>,
 
     /// Flag indicating if the VM has been launched.
     pub has_launched: bool,
 
+    /// The VPID of the virtual processor, or `None` if VPIDs are not supported.
+    pub vpid: Option<u16>,
+
+    /// Number of intercepted NMIs that still have to be injected into the guest.
+    pub pending_nmis: u32,
+
+    /// External interrupts queued by the hypervisor that still have to be injected into the guest.
+    pub pending_interrupts: PendingInterrupts,
+
+    /// The guest physical address of the MTF hook whose shadow page is mapped for the instruction being single-stepped.
+    pub stepped_hook_page: Option<u64>,
+
+    /// The consecutive EPT violations of the processor at the same address, used to detect hook livelocks.
+    pub violation_streak: ViolationStreak,
+
+    /// The guest running on the processor, whose EPTs are loaded into the VMCS.
+    pub guest_id: GuestId,
+
+    /// The initial APIC ID of the processor the VM runs on.
+    pub apic_id: u32,
+
+    /// The guest's x87, SSE, and AVX register state, saved while a VM exit is handled.
+    pub extended_state: ExtendedState,
+
+    /// The last VM exits of the processor, recorded by the VM exit loop for post-mortem debugging.
+    pub exit_trace: Box<ExitTrace>,
+
+    /// Whether the processor has invalidated its EPT caches since the hypervisor memory was hidden.
+    pub memory_hidden: bool,
+
+    /// The number of VM exits on the processor whose handler returned `ExitType::ExitHypervisor`.
+    pub failed_exits: u32,
+
     /// Shared data across processors for synchronization and state management.
     pub shared_data: NonNull<SharedData>,
 }
@@ -70,6 +159,7 @@ impl Vm {
     ///
     /// - `guest_registers`: The initial state of guest registers for the VM.
     /// - `shared_data`: Mutable reference to shared data used across processors.
+    /// - `host_stack`: The host stack of the current processor, whose guard page is unmapped from the host paging.
     ///
     /// # Returns
     ///
@@ -78,6 +168,7 @@ impl Vm {
     pub fn new(
         guest_registers: &GuestRegisters,
         shared_data: &mut SharedData,
+        host_stack: &HostStack,
     ) -> Result<Self, HypervisorError> {
         debug!("Creating VM");
         let mut vmcs_region = unsafe { box_zeroed::<Vmcs>() };
@@ -89,16 +180,71 @@ impl Vm {
         debug!("Building Identity Paging for Host");
         host_paging.build_identity();
 
+        // A host stack overflow during VM-exit handling faults on the guard page instead of corrupting memory.
+        debug!("Host stack range: {:#x?}", host_stack.range());
+        host_paging.unmap_guard_page(host_stack.guard_page)?;
+
+        debug!("Allocating MSR Bitmap");
+        let mut msr_bitmap = unsafe { box_zeroed::<MsrBitmap>() };
+        msr_bitmap.passthrough_x2apic_msrs();
+        msr_bitmap.intercept_feature_control();
+        msr_bitmap.intercept_misc_enable();
+        msr_bitmap.intercept_writes(shared_data.msr_audit.msrs());
+
+        let io_bitmap = unsafe { box_zeroed::<IoBitmap>() };
+
+        let vpid = if is_vpid_supported() {
+            Some(allocate_vpid())
+        } else {
+            warn!("VPIDs are not supported, the TLB is flushed on every VM entry and exit");
+            None
+        };
+        debug!("VPID: {:?}", vpid);
+
+        trace!("Creating host descriptor tables and IDT");
+        let mut host_descriptor = Descriptors::new_for_host();
+        let host_idt = HostIdt::new(host_descriptor.cs);
+        host_descriptor
+            .tss
+            .set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, host_idt.exception_stack_top());
+
+        // None of the per-processor structures may ever be remapped into the guest.
+        let reserved_regions = &shared_data.reserved_regions;
+        reserved_regions.reserve_object(&*vmcs_region)?;
+        reserved_regions.reserve_object(&*host_paging)?;
+        reserved_regions.reserve_object(&*msr_bitmap)?;
+        reserved_regions.reserve_object(&*io_bitmap)?;
+        reserved_regions.reserve_object(&*host_idt)?;
+        reserved_regions.reserve(
+            host_stack.guard_page
+                ..host_stack.guard_page + (HOST_STACK_GUARD_SIZE + HOST_STACK_SIZE) as u64,
+        )?;
+
+        // The memory is hidden once the loader has returned, see `SharedData::hide_hypervisor_memory`.
+
         debug!("VM created");
 
         Ok(Self {
             vmcs_region,
             host_paging,
-            host_descriptor: Descriptors::new_for_host(),
+            host_descriptor,
+            host_idt,
             guest_descriptor: Descriptors::new_from_current(),
             guest_registers: guest_registers.clone(),
-            msr_bitmap: unsafe { box_zeroed::<Page>() },
+            msr_bitmap,
+            io_bitmap,
             has_launched: false,
+            vpid,
+            pending_nmis: 0,
+            pending_interrupts: PendingInterrupts::new(),
+            stepped_hook_page: None,
+            violation_streak: ViolationStreak::new(),
+            guest_id: GuestId::DEFAULT,
+            apic_id: apic_id(),
+            extended_state: ExtendedState::new(),
+            exit_trace: unsafe { box_zeroed::<ExitTrace>() },
+            memory_hidden: false,
+            failed_exits: 0,
             shared_data: unsafe { NonNull::new_unchecked(shared_data as *mut _) },
         })
     }
@@ -141,17 +287,124 @@ impl Vm {
     pub fn setup_vmcs(&mut self) -> Result<(), HypervisorError> {
         debug!("Setting up VMCS");
 
-        let primary_eptp = unsafe { self.shared_data.as_ref().primary_eptp };
+        let primary_eptp =
+            unsafe { self.shared_data.as_ref() }.guest_eptp(self.guest_id, EptpSlot::PRIMARY)?;
 
         Vmcs::setup_guest_registers_state(&self.guest_descriptor, &self.guest_registers);
-        Vmcs::setup_host_registers_state(&self.host_descriptor, &self.host_paging)?;
-        Vmcs::setup_vmcs_control_fields(primary_eptp, &self.msr_bitmap)?;
+        if let Some(initial_state) = &unsafe { self.shared_data.as_ref() }.initial_guest_state {
+            self.apply_initial_guest_state(initial_state);
+        }
+        Vmcs::setup_host_registers_state(&self.host_descriptor, &self.host_idt, &self.host_paging)?;
+        Vmcs::setup_vmcs_control_fields(
+            primary_eptp,
+            &self.msr_bitmap,
+            &self.io_bitmap,
+            self.vpid,
+        )?;
+
+        // Only own the bits the hypervisor depends on, and let the guest see its own values.
+        self.set_cr0_mask(0);
+        self.set_cr0_shadow(vmfield::guest::CR0.read());
+        self.set_cr4_mask(0);
+        self.set_cr4_shadow(vmfield::guest::CR4.read() & !CR4_FORCE_OWNED);
+
+        setup_preemption_timer();
+        setup_monitor_mwait_exiting();
+        setup_debug_controls();
+        setup_eptp_switching(self.eptp_list_pa());
+        setup_rng_exiting(unsafe { self.shared_data.as_ref() }.rng.is_enabled());
+        setup_pseudo_instructions(
+            !unsafe { self.shared_data.as_ref() }
+                .pseudo_instructions
+                .is_empty(),
+        );
 
         debug!("VMCS setup successfully!");
 
         Ok(())
     }
 
+    /// Overrides the captured guest state in the VMCS with an initial state, before the VM is launched.
+    ///
+    /// # Arguments
+    ///
+    /// * `initial_state` - The RIP, RSP, CR3, and segment registers to launch the guest with.
+    fn apply_initial_guest_state(&mut self, initial_state: &InitialGuestState) {
+        debug!(
+            "Overriding the initial guest state: RIP {:#x}, RSP {:#x}, CR3 {:#x}",
+            initial_state.rip, initial_state.rsp, initial_state.cr3
+        );
+
+        vmfield::guest::RIP.write(initial_state.rip);
+        vmfield::guest::RSP.write(initial_state.rsp);
+        vmfield::guest::CR3.write(initial_state.cr3);
+
+        // Keep the cached copy in sync with the VMCS, like `set_guest_reg`.
+        self.guest_registers.rip = initial_state.rip;
+        self.guest_registers.rsp = initial_state.rsp;
+
+        for (segment, descriptor) in &initial_state.segments {
+            self.set_guest_segment(*segment, descriptor);
+        }
+    }
+
+    /// Causes VM exits on guest accesses to an I/O port of this processor, handled by `handle_io_instruction`.
+    ///
+    /// The processor consults the I/O bitmaps on every I/O instruction, so this takes effect on the
+    /// next access without touching the VMCS. Accesses of several bytes that include the port are
+    /// trapped as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port to trap.
+    pub fn trap_io_port(&mut self, port: u16) {
+        self.io_bitmap.set_intercept(port, true);
+    }
+
+    /// Lets the guest access an I/O port of this processor directly again, after `trap_io_port`.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The I/O port to pass through.
+    pub fn untrap_io_port(&mut self, port: u16) {
+        self.io_bitmap.set_intercept(port, false);
+    }
+
+    /// Switches the processor to another guest by loading the guest's primary EPTP into the VMCS.
+    ///
+    /// The guest's memory is visible from the next VM entry on. EPTP switching with VMFUNC is only
+    /// enabled for the default guest, whose EPTs are in the EPTP list. Only the EPT hierarchy is switched;
+    /// the register state stays that of the processor, so the caller is responsible for giving the
+    /// guest a state that is valid in its memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - The guest to run. Must be the default guest or registered in `SharedData::guests`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` on success, or an `Err(HypervisorError)` if the guest is not registered.
+    pub fn switch_guest(&mut self, guest_id: GuestId) -> Result<(), HypervisorError> {
+        let primary_eptp =
+            unsafe { self.shared_data.as_ref() }.guest_eptp(guest_id, EptpSlot::PRIMARY)?;
+
+        activate_eptp(primary_eptp)?;
+        self.guest_id = guest_id;
+        setup_eptp_switching(self.eptp_list_pa());
+
+        Ok(())
+    }
+
+    /// Returns the EPTP list the running guest may switch between with VMFUNC.
+    ///
+    /// Only the default guest gets the EPTP list, so other guests cannot switch to its EPTs.
+    fn eptp_list_pa(&self) -> Option<u64> {
+        match self.guest_id {
+            GuestId::DEFAULT => Some(unsafe { self.shared_data.as_ref() }.eptp_list_pa()),
+            _ => None,
+        }
+    }
+
     /// Executes the VM, running in a loop until a VM-exit occurs.
     ///
     /// Launches or resumes the VM based on its current state, handling VM-exits as they occur.
@@ -162,32 +415,789 @@ impl Vm {
     /// Returns `Ok(VmxBasicExitReason)` indicating the reason for the VM-exit, or an `Err(HypervisorError)`
     /// if the VM fails to launch or an unknown exit reason is encountered.
     pub fn run(&mut self) -> Result<VmxBasicExitReason, HypervisorError> {
+        // A VMLAUNCH with invalid guest state fails without saying why, so check the state first.
+        if !self.has_launched {
+            self.validate_guest_state()?;
+        }
+
+        // Delivering an event wakes a halted processor, and most events cannot be injected into the
+        // HLT state, so the processor enters the active state to deliver it, like on hardware.
+        if EventInjection::is_event_pending()
+            && vmfield::guest::ACTIVITY_STATE.read() == GuestActivityState::Hlt as u32
+        {
+            vmfield::guest::ACTIVITY_STATE.write(GuestActivityState::Active as u32);
+        }
+
         // Run the VM until the VM-exit occurs.
         let flags = unsafe { launch_vm(&mut self.guest_registers, u64::from(self.has_launched)) };
         Self::vm_succeed(RFlags::from_raw(flags))?;
-        self.has_launched = true;
         trace!("VM-exit occurred!");
 
-        // VM-exit occurred. Copy the guest register values from VMCS so that
-        // `self.registers` is complete and up to date.
-        self.guest_registers.rip = vmread(vmcs::guest::RIP);
-        self.guest_registers.rsp = vmread(vmcs::guest::RSP);
-        self.guest_registers.rflags = vmread(vmcs::guest::RFLAGS);
-
-        let exit_reason = vmread(vmcs::ro::EXIT_REASON) as u32;
+        let exit_reason = vmfield::ro::EXIT_REASON.read();
 
         let Some(basic_exit_reason) = VmxBasicExitReason::from_u32(exit_reason) else {
             error!("Unknown exit reason: {:#x}", exit_reason);
             return Err(HypervisorError::UnknownVMExitReason);
         };
 
+        // A VM entry that fails while or after loading the guest state exits with bit 31 of the exit
+        // reason set (e.g. exit reasons 33, 34, and 41). The guest did not run, so there is nothing to handle.
+        if exit_reason.get_bit(31) {
+            let qualification = vmfield::ro::EXIT_QUALIFICATION.read();
+            error!(
+                "VM entry failed: {}",
+                VmEntryFailure::Exit(basic_exit_reason, qualification)
+            );
+            return Err(HypervisorError::VmEntryFailed(VmEntryFailure::Exit(
+                basic_exit_reason,
+                qualification,
+            )));
+        }
+
+        self.has_launched = true;
+
+        // VM-exit occurred. Copy the guest register values from VMCS so that
+        // `self.registers` is complete and up to date.
+        self.guest_registers.rip = vmfield::guest::RIP.read();
+        self.guest_registers.rsp = vmfield::guest::RSP.read();
+        self.guest_registers.rflags = vmfield::guest::RFLAGS.read();
+
         return Ok(basic_exit_reason);
     }
 
+    /// Leaves VMX operation and continues running the guest on the current processor without the hypervisor.
+    ///
+    /// Used when the first VM entry failed, so the guest-state area of the VMCS still holds the state
+    /// the processor had before it was virtualized. A VM entry that failed while loading the guest
+    /// state exits with the host state loaded, so CR3, the descriptor tables, and the segment registers
+    /// are reloaded from the guest-state area. TR keeps the host TSS, which the guest never uses.
+    ///
+    /// # Safety
+    ///
+    /// The guest must not have run yet, and the VMCS of the VM must be current.
+    pub unsafe fn resume_without_vmx(&self) -> ! {
+        let cr3 = vmfield::guest::CR3.read();
+        let idtr = DescriptorTablePointer::<u64> {
+            limit: vmread(vmcs::guest::IDTR_LIMIT) as u16,
+            base: vmread(vmcs::guest::IDTR_BASE) as *const u64,
+        };
+        let selector = |field| SegmentSelector::from_raw(vmread(field) as u16);
+        let cs = selector(vmcs::guest::CS_SELECTOR);
+        let ss = selector(vmcs::guest::SS_SELECTOR);
+        let ds = selector(vmcs::guest::DS_SELECTOR);
+        let es = selector(vmcs::guest::ES_SELECTOR);
+        let fs = selector(vmcs::guest::FS_SELECTOR);
+        let gs = selector(vmcs::guest::GS_SELECTOR);
+        let fs_base = vmfield::guest::FS_BASE.read();
+        let gs_base = vmfield::guest::GS_BASE.read();
+
+        if let Err(e) = vmxoff() {
+            panic!("Failed to leave VMX operation: {:?}", e);
+        }
+
+        unsafe {
+            cr4_write(cr4() - Cr4::CR4_ENABLE_VMX);
+            cr3_write(cr3);
+            lgdt(&self.guest_descriptor.gdtr);
+            lidt(&idtr);
+            load_ss(ss);
+            load_ds(ds);
+            load_es(es);
+            load_fs(fs);
+            load_gs(gs);
+            load_cs(cs);
+        }
+
+        // Loading FS and GS replaces their bases with the 32-bit bases of their descriptors.
+        wrmsr(IA32_FS_BASE, fs_base);
+        wrmsr(IA32_GS_BASE, gs_base);
+
+        unsafe { restore_registers(&self.guest_registers) }
+    }
+
+    /// Checks the guest state in the VMCS against the VM-entry guest-state consistency rules.
+    ///
+    /// Called before VMLAUNCH, so a bad CR0/CR4, IA32_EFER, RFLAGS, or segment register state is
+    /// reported with the violated rule instead of as an opaque VM-entry failure.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if no checked rule is violated, or `Err(HypervisorError::InvalidGuestState)`
+    /// identifying the first violated rule.
+    pub fn validate_guest_state(&self) -> Result<(), HypervisorError> {
+        check_guest_state(self).map_err(|violation| {
+            error!("Invalid guest state: {}", violation);
+            HypervisorError::InvalidGuestState(violation)
+        })
+    }
+
+    /// Reads the current value of a guest register.
+    ///
+    /// General-purpose registers are read from the save area filled in by `launch_vm` on VM-exit.
+    /// RSP, RIP, and RFLAGS are not part of that save area; the processor keeps them in the
+    /// guest-state area of the VMCS, so they are read from there.
+    ///
+    /// # Arguments
+    ///
+    /// * `register`: The guest register to read.
+    ///
+    /// # Returns
+    ///
+    /// The value of the register.
+    pub fn guest_reg(&self, register: Register) -> u64 {
+        match register {
+            Register::Rsp => vmfield::guest::RSP.read(),
+            Register::Rip => vmfield::guest::RIP.read(),
+            Register::Rflags => vmfield::guest::RFLAGS.read(),
+            _ => self.guest_registers.get(register),
+        }
+    }
+
+    /// Sets the value of a guest register.
+    ///
+    /// General-purpose registers are written to the save area, from which `launch_vm` restores them
+    /// on the next VM-entry. RSP, RIP, and RFLAGS are written to the VMCS guest-state area, and the
+    /// cached copy in `guest_registers` is kept in sync.
+    ///
+    /// # Arguments
+    ///
+    /// * `register`: The guest register to write.
+    /// * `value`: The new value of the register.
+    pub fn set_guest_reg(&mut self, register: Register, value: u64) {
+        match register {
+            Register::Rsp => vmfield::guest::RSP.write(value),
+            Register::Rip => vmfield::guest::RIP.write(value),
+            Register::Rflags => vmfield::guest::RFLAGS.write(value),
+            _ => {}
+        }
+
+        self.guest_registers.set(register, value);
+    }
+
+    /// Reads the state of a guest segment register from the VMCS guest-state area.
+    ///
+    /// # Arguments
+    ///
+    /// * `segment`: The segment register to read, including LDTR and TR.
+    ///
+    /// # Returns
+    ///
+    /// The selector, base, limit, and access rights of the segment.
+    pub fn guest_segment(&self, segment: Segment) -> SegmentDescriptor {
+        SegmentDescriptor {
+            selector: SegmentSelector::from_raw(vmread(segment.selector_field()) as u16),
+            base: vmread(segment.base_field()),
+            limit: vmread(segment.limit_field()) as u32,
+            access_rights: VmxSegmentAccessRights(vmread(segment.access_rights_field()) as u32),
+        }
+    }
+
+    /// Writes the state of a guest segment register to the VMCS guest-state area.
+    ///
+    /// The state is checked on the next VM-entry, so it must be consistent with the guest's
+    /// operating mode, e.g. the access rights of an unusable segment must have bit 16 set.
+    ///
+    /// # Arguments
+    ///
+    /// * `segment`: The segment register to write, including LDTR and TR.
+    /// * `descriptor`: The new selector, base, limit, and access rights of the segment.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.2 Checks on Guest Segment Registers
+    pub fn set_guest_segment(&mut self, segment: Segment, descriptor: &SegmentDescriptor) {
+        vmwrite(segment.selector_field(), descriptor.selector.bits());
+        vmwrite(segment.base_field(), descriptor.base);
+        vmwrite(segment.limit_field(), descriptor.limit);
+        vmwrite(segment.access_rights_field(), descriptor.access_rights.0);
+    }
+
+    /// Returns the guest's RFLAGS, with the reserved bits dropped.
+    ///
+    /// Exit handlers emulating an instruction use this together with `set_guest_flag` to report the
+    /// result through the flags, e.g. CF for RDRAND or ZF for a failed compare.
+    pub fn guest_flags(&self) -> RFlags {
+        RFlags::from_bits_truncate(self.guest_reg(Register::Rflags))
+    }
+
+    /// Sets or clears flags in the guest's RFLAGS.
+    ///
+    /// Bit 1 is reserved and always written as set, and all other reserved bits are written as clear,
+    /// as required by the VM-entry checks.
+    ///
+    /// # Arguments
+    ///
+    /// * `flag` - The flags to change, e.g. `RFlags::FLAGS_CF`.
+    /// * `value` - Whether the flags are set or cleared.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.4 Checks on Guest RIP, RFLAGS, and SSP
+    pub fn set_guest_flag(&mut self, flag: RFlags, value: bool) {
+        let mut flags = self.guest_flags();
+        flags.set(flag, value);
+        flags.insert(RFlags::FLAGS_A1);
+
+        self.set_guest_reg(Register::Rflags, flags.bits());
+    }
+
+    /// Queues an external interrupt for injection into the guest.
+    ///
+    /// The interrupt is injected on the next interrupt-window VM exit, i.e. as soon as the guest is
+    /// interruptible. Must be called on the processor the VM runs on, with its VMCS loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt, from 32 to 255.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the interrupt was queued, or `Err(HypervisorError::InvalidInterruptVector)` for
+    /// vectors reserved for exceptions.
+    pub fn queue_interrupt(&mut self, vector: u8) -> Result<(), HypervisorError> {
+        if vector < 32 {
+            return Err(HypervisorError::InvalidInterruptVector);
+        }
+
+        self.pending_interrupts.push(vector);
+        set_interrupt_window_exiting(true);
+
+        Ok(())
+    }
+
+    /// Injects an interrupt into the guest, or defers it until the guest is interruptible.
+    ///
+    /// If the guest is interruptible and no other event is injected on the next VM entry, the
+    /// interrupt is delivered through the guest IDT right away. Otherwise, it is queued like with
+    /// `queue_interrupt` and injected on the next interrupt-window VM exit.
+    ///
+    /// The interrupt is injected as an external interrupt rather than a software interrupt, since
+    /// the latter would push the address after a non-existent `INT n` instruction as the return
+    /// address, and raise #GP in user mode for gates with a DPL of 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The vector of the interrupt, from 32 to 255.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the interrupt was injected or queued, or `Err(HypervisorError::InvalidInterruptVector)`
+    /// for vectors reserved for exceptions.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.6.1 Vectored-Event Injection
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<(), HypervisorError> {
+        if vector < 32 {
+            return Err(HypervisorError::InvalidInterruptVector);
+        }
+
+        // Queued interrupts were requested earlier, so they must not be overtaken.
+        if !self.is_interruptible()
+            || !self.pending_interrupts.is_empty()
+            || EventInjection::is_event_pending()
+        {
+            return self.queue_interrupt(vector);
+        }
+
+        EventInjection::vmentry_inject_interrupt(vector);
+        trace!("Injected interrupt {:#x}", vector);
+
+        Ok(())
+    }
+
+    /// Returns whether the guest can receive an external interrupt on the next VM entry.
+    ///
+    /// External interrupts are masked while RFLAGS.IF is clear, and during the instruction after
+    /// STI or MOV SS. A halted guest is woken up by the interrupt, but a guest in the shutdown or
+    /// wait-for-SIPI state cannot receive one.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.4.2 Guest Non-Register State
+    pub fn is_interruptible(&self) -> bool {
+        let interruptibility_state = vmfield::guest::INTERRUPTIBILITY_STATE.read();
+        let activity_state = vmfield::guest::ACTIVITY_STATE.read();
+
+        self.guest_flags().contains(RFlags::FLAGS_IF)
+            && interruptibility_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0
+            && activity_state <= GuestActivityState::Hlt as u32
+    }
+
+    /// Scales the guest's TSC relative to the host's, to dilate guest time.
+    ///
+    /// Enables "use TSC offsetting" and "use TSC scaling", and sets the TSC multiplier to
+    /// `numerator / denominator` in its fixed-point format with 48 fraction bits, rounded down. RDTSC,
+    /// RDTSCP, and reads of IA32_TSC in the guest return the host TSC multiplied by the ratio, plus
+    /// the TSC offset. A ratio of 1/1 disables the scaling again. Must be called on the processor the
+    /// VM runs on, with its VMCS loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `numerator` - The numerator of the ratio of the guest TSC frequency to the host TSC frequency.
+    /// * `denominator` - The denominator of the ratio.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::TscScalingUnsupported)` if the processor does not
+    /// support TSC scaling, or `Err(HypervisorError::InvalidTscScale)` if the ratio is zero or does
+    /// not fit the 16 integer bits of the multiplier.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.6.5 Time-Stamp Counter Offset and Multiplier
+    pub fn set_tsc_scale(
+        &mut self,
+        numerator: u64,
+        denominator: u64,
+    ) -> Result<(), HypervisorError> {
+        const USE_TSC_OFFSETTING: u32 = PrimaryControls::USE_TSC_OFFSETTING.bits();
+        const USE_TSC_SCALING: u32 = SecondaryControls::USE_TSC_SCALING.bits();
+
+        if denominator == 0 {
+            return Err(HypervisorError::InvalidTscScale);
+        }
+
+        let multiplier =
+            (u128::from(numerator) << TSC_MULTIPLIER_FRACTION_BITS) / u128::from(denominator);
+        if multiplier == 0 || multiplier > u128::from(u64::MAX) {
+            return Err(HypervisorError::InvalidTscScale);
+        }
+
+        let secondary_controls = vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+
+        if multiplier == 1 << TSC_MULTIPLIER_FRACTION_BITS {
+            vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS
+                .write(secondary_controls & !USE_TSC_SCALING);
+            return Ok(());
+        }
+
+        if !is_vmx_control_supported(VmxControl::ProcessorBased, USE_TSC_OFFSETTING as u64)
+            || !is_vmx_control_supported(VmxControl::ProcessorBased2, USE_TSC_SCALING as u64)
+        {
+            return Err(HypervisorError::TscScalingUnsupported);
+        }
+
+        let primary_controls = vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read();
+        vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS
+            .write(primary_controls | USE_TSC_OFFSETTING);
+        vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS
+            .write(secondary_controls | USE_TSC_SCALING);
+        vmfield::control::TSC_MULTIPLIER_FULL.write(multiplier as u64);
+
+        trace!("TSC multiplier set to {:#x}", multiplier);
+
+        Ok(())
+    }
+
+    /// Saves the guest's extended register state (x87, SSE, AVX, and the other XCR0 components).
+    ///
+    /// Called by the dispatch loop right after a VM exit, so exit handlers can use SSE registers freely.
+    pub fn save_guest_extended_state(&mut self) {
+        self.extended_state.save();
+    }
+
+    /// Restores the guest's extended register state saved by `save_guest_extended_state`.
+    ///
+    /// Called by the dispatch loop right before the next VM entry.
+    pub fn restore_guest_extended_state(&mut self) {
+        self.extended_state.restore();
+    }
+
+    /// Advances the guest's instruction pointer past the instruction that caused the VM-exit.
+    ///
+    /// The length is taken from the VM-exit instruction-length field rather than assumed by the
+    /// handler, so multi-byte instructions (e.g. CPUID vs. RDMSR/WRMSR with prefixes) are skipped
+    /// correctly. The dispatch loop calls this when a handler returns `ExitType::IncrementRIP`.
+    ///
+    /// Completing the instruction also has its architectural side effects: blocking by a preceding
+    /// STI or MOV SS ends, and a guest single-stepping with RFLAGS.TF gets the #DB it expects after
+    /// the instruction as a pending debug exception. Stale blocking or a missing BS bit would fail
+    /// the next VM entry if the guest is halted afterwards.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.5 Information for VM Exits Due to Instruction Execution
+    /// and 27.3.1.5 Checks on Guest Non-Register State
+    pub fn advance_rip(&mut self) {
+        trace!("Advancing guest RIP...");
+        let len = u64::from(vmfield::ro::VMEXIT_INSTRUCTION_LEN.read());
+        let rip = self.guest_reg(Register::Rip) + len;
+        self.set_guest_reg(Register::Rip, rip);
+        trace!("Guest RIP advanced to: {:#x}", rip);
+
+        let interruptibility_state = vmfield::guest::INTERRUPTIBILITY_STATE.read();
+        if interruptibility_state & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) != 0 {
+            vmfield::guest::INTERRUPTIBILITY_STATE
+                .write(interruptibility_state & !(BLOCKING_BY_STI | BLOCKING_BY_MOV_SS));
+        }
+
+        if self.guest_flags().contains(RFlags::FLAGS_TF)
+            && vmfield::guest::IA32_DEBUGCTL_FULL.read() & DEBUGCTL_BTF == 0
+        {
+            let pending_debug_exceptions = vmfield::guest::PENDING_DBG_EXCEPTIONS.read();
+            vmfield::guest::PENDING_DBG_EXCEPTIONS
+                .write(pending_debug_exceptions | PENDING_DEBUG_SINGLE_STEP);
+        }
+    }
+
+    /// Sets the CR0 guest/host mask. `CR0_FORCE_OWNED` bits are always added to the mask.
+    ///
+    /// For bits set in the mask (owned by the hypervisor), guest reads of CR0 return the value of
+    /// the read shadow, and guest writes that would change the shadow value cause a VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - The CR0 bits to be owned by the hypervisor.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.6 Guest/Host Masks and Read Shadows for CR0 and CR4
+    pub fn set_cr0_mask(&mut self, mask: u64) {
+        vmfield::control::CR0_GUEST_HOST_MASK.write(mask | CR0_FORCE_OWNED);
+    }
+
+    /// Sets the CR0 read shadow, the value the guest reads for the bits owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `shadow` - The CR0 value presented to the guest.
+    pub fn set_cr0_shadow(&mut self, shadow: u64) {
+        vmfield::control::CR0_READ_SHADOW.write(shadow);
+    }
+
+    /// Sets the CR4 guest/host mask. `CR4_FORCE_OWNED` bits are always added to the mask.
+    ///
+    /// For bits set in the mask (owned by the hypervisor), guest reads of CR4 return the value of
+    /// the read shadow, and guest writes that would change the shadow value cause a VM exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - The CR4 bits to be owned by the hypervisor.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.6 Guest/Host Masks and Read Shadows for CR0 and CR4
+    pub fn set_cr4_mask(&mut self, mask: u64) {
+        vmfield::control::CR4_GUEST_HOST_MASK.write(mask | CR4_FORCE_OWNED);
+    }
+
+    /// Sets the CR4 read shadow, the value the guest reads for the bits owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `shadow` - The CR4 value presented to the guest.
+    pub fn set_cr4_shadow(&mut self, shadow: u64) {
+        vmfield::control::CR4_READ_SHADOW.write(shadow);
+    }
+
+    /// Reads guest physical memory into a buffer.
+    ///
+    /// Guest physical addresses are translated through the primary EPT, so hooked pages read as
+    /// their original contents. The read may span page boundaries.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to read from.
+    /// * `buf` - The buffer to fill.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())`, or `Err(HypervisorError::GuestPhysicalAddressNotMapped)` if any page of
+    /// the range is not mapped, in which case `buf` is left unchanged.
+    pub fn read_guest_phys(&self, guest_pa: u64, buf: &mut [u8]) -> Result<(), HypervisorError> {
+        self.access_guest_memory(
+            guest_pa,
+            buf.len(),
+            Self::translate_guest_pa,
+            |host_va, offset, count| unsafe {
+                core::ptr::copy_nonoverlapping(
+                    host_va as *const u8,
+                    buf[offset..].as_mut_ptr(),
+                    count,
+                )
+            },
+        )
+    }
+
+    /// Writes a buffer to guest physical memory.
+    ///
+    /// Guest physical addresses are translated through the primary EPT, so writes to hooked pages
+    /// modify their original contents, not the shadow pages. The write may span page boundaries.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to write to.
+    /// * `buf` - The bytes to write.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())`, or `Err(HypervisorError::GuestPhysicalAddressNotMapped)` if any page of
+    /// the range is not mapped, in which case nothing is written.
+    pub fn write_guest_phys(&mut self, guest_pa: u64, buf: &[u8]) -> Result<(), HypervisorError> {
+        self.access_guest_memory(
+            guest_pa,
+            buf.len(),
+            Self::translate_guest_pa,
+            |host_va, offset, count| unsafe {
+                core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), host_va as *mut u8, count)
+            },
+        )
+    }
+
+    /// Reads guest virtual memory into a buffer.
+    ///
+    /// The address is translated with the guest's current paging structures (CR3), and then
+    /// read like `read_guest_phys`. If the guest has paging disabled, the address is used as a
+    /// guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_va` - The guest virtual address to read from.
+    /// * `buf` - The buffer to fill.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())`, or an `Err(HypervisorError)` if any page of the range is not mapped by the
+    /// guest or the EPT, in which case `buf` is left unchanged.
+    pub fn read_guest_virt(&self, guest_va: u64, buf: &mut [u8]) -> Result<(), HypervisorError> {
+        self.access_guest_memory(
+            guest_va,
+            buf.len(),
+            Self::translate_guest_va,
+            |host_va, offset, count| unsafe {
+                core::ptr::copy_nonoverlapping(
+                    host_va as *const u8,
+                    buf[offset..].as_mut_ptr(),
+                    count,
+                )
+            },
+        )
+    }
+
+    /// Writes a buffer to guest virtual memory.
+    ///
+    /// The address is translated like in `read_guest_virt`. Guest page protections are not
+    /// checked, so read-only guest pages can be written.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_va` - The guest virtual address to write to.
+    /// * `buf` - The bytes to write.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())`, or an `Err(HypervisorError)` if any page of the range is not mapped by the
+    /// guest or the EPT, in which case nothing is written.
+    pub fn write_guest_virt(&mut self, guest_va: u64, buf: &[u8]) -> Result<(), HypervisorError> {
+        self.access_guest_memory(
+            guest_va,
+            buf.len(),
+            Self::translate_guest_va,
+            |host_va, offset, count| unsafe {
+                core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), host_va as *mut u8, count)
+            },
+        )
+    }
+
+    /// Determines the guest's current paging mode from its CR0, CR4, and IA32_EFER.LMA.
+    ///
+    /// IA32_EFER.LMA is taken from the "IA-32e mode guest" VM-entry control, which the processor
+    /// updates on every VM exit, so it is correct whether or not the guest IA32_EFER field is saved.
+    ///
+    /// # Returns
+    ///
+    /// The `PagingMode` used to translate guest virtual addresses.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.1.1 Four Paging Modes
+    pub fn guest_paging_mode(&self) -> PagingMode {
+        const IA32E_MODE_GUEST: u32 = 1 << 9;
+
+        let guest_cr0 = Cr0::from_bits_truncate(vmfield::guest::CR0.read() as usize);
+        let guest_cr4 = Cr4::from_bits_truncate(vmfield::guest::CR4.read() as usize);
+        let long_mode_active = vmfield::control::VMENTRY_CONTROLS.read() & IA32E_MODE_GUEST != 0;
+
+        if !guest_cr0.contains(Cr0::CR0_ENABLE_PAGING) {
+            PagingMode::Disabled
+        } else if long_mode_active && guest_cr4.contains(Cr4::CR4_ENABLE_LA57) {
+            PagingMode::Level5
+        } else if long_mode_active {
+            PagingMode::Level4
+        } else if guest_cr4.contains(Cr4::CR4_ENABLE_PAE) {
+            PagingMode::Pae
+        } else {
+            PagingMode::Bits32 {
+                pse: guest_cr4.contains(Cr4::CR4_ENABLE_PSE),
+            }
+        }
+    }
+
+    /// Reads the value of an MSR as seen by the guest.
+    ///
+    /// MSRs switched between the guest and the host by VM entries and exits are read from their
+    /// guest-state field: IA32_FS_BASE, IA32_GS_BASE, and IA32_SYSENTER_CS/ESP/EIP always, and
+    /// IA32_EFER, IA32_PAT, IA32_DEBUGCTL, and IA32_LBR_CTL if the VM-exit controls save them. No MSR-store area is used, so all
+    /// other MSRs still hold the guest value while a VM exit is handled, and are read with RDMSR.
+    ///
+    /// If IA32_EFER is not saved, LMA and LME of the hardware register reflect the host, so both are
+    /// taken from the "IA-32e mode guest" VM-entry control instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The address of the MSR.
+    ///
+    /// # Returns
+    ///
+    /// The guest value of the MSR.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.3.1 Saving Control Registers, Debug Registers, and MSRs
+    pub fn read_guest_msr(&self, msr: u32) -> u64 {
+        if let Some(field) = guest_msr_field(msr, MsrAccessType::Read) {
+            return vmread(field);
+        }
+
+        match msr {
+            x86::msr::IA32_EFER => {
+                let long_mode = if vmfield::control::VMENTRY_CONTROLS.read()
+                    & EntryControls::IA32E_MODE_GUEST.bits()
+                    != 0
+                {
+                    EFER_LME | EFER_LMA
+                } else {
+                    0
+                };
+                (rdmsr(msr) & !(EFER_LME | EFER_LMA)) | long_mode
+            }
+            _ => rdmsr(msr),
+        }
+    }
+
+    /// Writes the value of an MSR as seen by the guest.
+    ///
+    /// The counterpart of `read_guest_msr`: MSRs loaded by VM entries are written to their
+    /// guest-state field, all other MSRs with WRMSR. If IA32_EFER is not loaded on VM entry, LMA and
+    /// LME of the hardware register are left to the host, since the processor derives them from the
+    /// "IA-32e mode guest" VM-entry control on the next VM entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The address of the MSR.
+    /// * `value` - The value to write.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.2.1 Loading Guest Control Registers, Debug Registers, and MSRs
+    pub fn write_guest_msr(&mut self, msr: u32, value: u64) {
+        if let Some(field) = guest_msr_field(msr, MsrAccessType::Write) {
+            vmwrite(field, value);
+            return;
+        }
+
+        match msr {
+            x86::msr::IA32_EFER => {
+                let long_mode = rdmsr(msr) & (EFER_LME | EFER_LMA);
+                wrmsr(msr, (value & !(EFER_LME | EFER_LMA)) | long_mode);
+            }
+            _ => wrmsr(msr, value),
+        }
+    }
+
+    /// Decodes the guest instruction at the current guest RIP.
+    ///
+    /// The instruction bytes are read with `read_guest_virt` and decoded with the bitness of the
+    /// guest's current code segment (16, 32, or 64-bit).
+    ///
+    /// # Returns
+    ///
+    /// Returns the decoded `Instruction`, or `None` if the instruction could not be read or is invalid.
+    pub fn decode_current_instruction(&self) -> Option<Instruction> {
+        decode_current_instruction(self)
+    }
+
+    /// Accesses a range of guest memory page by page.
+    ///
+    /// Every page of the range is translated before `access` is called for the first one, so an
+    /// unmapped page fails the whole access without side effects. The host physical address of each page
+    /// is accessed through `PhysicalAddress::to_host_va`, which fails instead of dereferencing it as a host
+    /// pointer once the host is no longer identity mapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The guest address of the start of the range.
+    /// * `len` - The length of the range in bytes.
+    /// * `translate` - Translates a guest address to a host physical address.
+    /// * `access` - Called with the host virtual address, the offset into the range, and the byte count of each chunk.
+    fn access_guest_memory(
+        &self,
+        address: u64,
+        len: usize,
+        translate: fn(&Self, u64) -> Result<u64, HypervisorError>,
+        mut access: impl FnMut(u64, usize, usize),
+    ) -> Result<(), HypervisorError> {
+        let host_va = |guest_address| PhysicalAddress::to_host_va(translate(self, guest_address)?);
+
+        for_each_guest_page(address, len, |guest_address, _, _| {
+            host_va(guest_address).map(|_| ())
+        })?;
+
+        for_each_guest_page(address, len, |guest_address, offset, count| {
+            access(host_va(guest_address)?, offset, count);
+            Ok(())
+        })
+    }
+
+    /// Returns the EPTP the current processor runs on, read from the current VMCS.
+    ///
+    /// Must be called from a VM-exit handler, since the EPTP changes whenever the handlers or the guest
+    /// (with VMFUNC) switch views.
+    pub fn current_eptp(&self) -> u64 {
+        vmfield::control::EPTP_FULL.read()
+    }
+
+    /// Returns the view of guest memory the current processor runs on, e.g. to tell whether it is on the
+    /// primary or the secondary EPT of the running guest.
+    ///
+    /// Must be called from a VM-exit handler, like `current_eptp`.
+    pub fn current_ept_view(&self) -> EptView {
+        unsafe { self.shared_data.as_ref() }.ept_view(self.guest_id, self.current_eptp())
+    }
+
+    /// Locks the hook manager and the EPTs of the default guest, to change them from a VM-exit handler.
+    ///
+    /// See `SharedData::lock_epts`.
+    pub fn lock_epts(&self) -> EptsGuard<'_> {
+        unsafe { SharedData::lock_epts(self.shared_data) }
+    }
+
+    /// Returns the primary EPT of the running guest.
+    ///
+    /// # Returns
+    ///
+    /// The EPT, or `Err(HypervisorError::GuestNotFound)` if the running guest is no longer registered.
+    pub fn guest_primary_ept(&self) -> Result<&Ept, HypervisorError> {
+        let shared_data = unsafe { self.shared_data.as_ref() };
+
+        match self.guest_id {
+            GuestId::DEFAULT => Ok(&shared_data.primary_ept),
+            guest_id => Ok(&shared_data
+                .guests
+                .get(guest_id)
+                .ok_or(HypervisorError::GuestNotFound)?
+                .primary_ept),
+        }
+    }
+
+    /// Translates a guest physical address to a host physical address through the primary EPT of the running guest.
+    fn translate_guest_pa(&self, guest_pa: u64) -> Result<u64, HypervisorError> {
+        self.guest_primary_ept()?
+            .gpa_to_hpa(guest_pa)
+            .ok_or(HypervisorError::GuestPhysicalAddressNotMapped)
+    }
+
+    /// Translates a guest virtual address to a host physical address with the guest's paging structures and the primary EPT.
+    ///
+    /// The guest's paging structures are read through the primary EPT as well, so they are found even if
+    /// guest physical memory is not identity mapped.
+    fn translate_guest_va(&self, guest_va: u64) -> Result<u64, HypervisorError> {
+        let guest_pa = PhysicalAddress::from_guest_va(
+            self.guest_paging_mode(),
+            vmfield::guest::CR3.read(),
+            guest_va,
+            |table_gpa| PhysicalAddress::to_host_va(self.translate_guest_pa(table_gpa).ok()?).ok(),
+        )
+        .ok_or(HypervisorError::GuestVirtualAddressNotMapped)?;
+
+        self.translate_guest_pa(guest_pa.pa())
+    }
+
     /// Verifies that the `launch_vm` function executed successfully.
     ///
     /// This method checks the RFlags for indications of failure from the `launch_vm` function.
-    /// If a failure is detected, it will panic with a detailed error message.
+    /// If VMLAUNCH or VMRESUME failed with a valid VMCS, the VM-instruction error number is
+    /// returned in `HypervisorError::VmEntryFailed`. Failures while loading the guest state are
+    /// reported by `run` from the exit reason instead.
     ///
     /// # Arguments
     ///
@@ -198,17 +1208,14 @@ impl Vm {
     /// - 31.4 VM INSTRUCTION ERROR NUMBERS
     fn vm_succeed(flags: RFlags) -> Result<(), HypervisorError> {
         if flags.contains(RFlags::FLAGS_ZF) {
-            let instruction_error = vmread(vmcs::ro::VM_INSTRUCTION_ERROR) as u32;
-            return match VmInstructionError::from_u32(instruction_error) {
-                Some(error) => {
-                    error!("VM instruction error: {:?}", error);
-                    Err(HypervisorError::VmInstructionError)
-                }
-                None => {
-                    error!("Unknown VM instruction error: {:#x}", instruction_error);
-                    Err(HypervisorError::UnknownVMInstructionError)
-                }
-            };
+            let instruction_error = vmfield::ro::VM_INSTRUCTION_ERROR.read();
+            error!(
+                "VM entry failed: {}",
+                VmInstructionErrorNumber(instruction_error)
+            );
+            return Err(HypervisorError::VmEntryFailed(
+                VmEntryFailure::InstructionError(instruction_error),
+            ));
         } else if flags.contains(RFlags::FLAGS_CF) {
             error!("VM instruction failed due to carry flag being set");
             return Err(HypervisorError::VMFailToLaunch);
@@ -218,6 +1225,89 @@ impl Vm {
     }
 }
 
+/// Splits a range of guest memory into chunks that do not cross a 4KB page boundary.
+///
+/// Guest pages are only contiguous in host memory within a page, so each chunk must be translated separately.
+///
+/// # Arguments
+///
+/// * `address` - The guest address of the start of the range.
+/// * `len` - The length of the range in bytes.
+/// * `callback` - Called with the guest address, the offset into the range, and the byte count of each chunk.
+fn for_each_guest_page(
+    address: u64,
+    len: usize,
+    mut callback: impl FnMut(u64, usize, usize) -> Result<(), HypervisorError>,
+) -> Result<(), HypervisorError> {
+    let mut offset = 0;
+    while offset < len {
+        let guest_address = address.wrapping_add(offset as u64);
+        let page_remaining = BASE_PAGE_SIZE - (guest_address as usize & (BASE_PAGE_SIZE - 1));
+        let count = page_remaining.min(len - offset);
+        callback(guest_address, offset, count)?;
+        offset += count;
+    }
+
+    Ok(())
+}
+
+/// IA32_EFER.LME (bit 8), which enables IA-32e mode.
+const EFER_LME: u64 = 1 << 8;
+
+/// IA32_EFER.LMA (bit 10), which indicates that IA-32e mode is active.
+const EFER_LMA: u64 = 1 << 10;
+
+/// Returns the guest-state field that holds the guest value of an MSR, if any.
+///
+/// The guest values of IA32_EFER, IA32_PAT, and IA32_DEBUGCTL are only held in the VMCS if they are
+/// saved on VM exit (for reads) or loaded on VM entry (for writes). IA32_LBR_CTL is saved on every VM
+/// exit if it can be loaded on VM entry, so it is held in the VMCS while it is loaded.
+///
+/// # Arguments
+///
+/// * `msr` - The address of the MSR.
+/// * `access_type` - Whether the guest value is read or written.
+///
+/// # Returns
+///
+/// The encoding of the guest-state field, or `None` if the guest value is held in the MSR itself.
+fn guest_msr_field(msr: u32, access_type: MsrAccessType) -> Option<u32> {
+    let (efer_in_vmcs, pat_in_vmcs, debugctl_in_vmcs) = match access_type {
+        MsrAccessType::Read => {
+            let exit_controls =
+                ExitControls::from_bits_truncate(vmfield::control::VMEXIT_CONTROLS.read());
+            (
+                exit_controls.contains(ExitControls::SAVE_IA32_EFER),
+                exit_controls.contains(ExitControls::SAVE_IA32_PAT),
+                exit_controls.contains(ExitControls::SAVE_DEBUG_CONTROLS),
+            )
+        }
+        MsrAccessType::Write => {
+            let entry_controls =
+                EntryControls::from_bits_truncate(vmfield::control::VMENTRY_CONTROLS.read());
+            (
+                entry_controls.contains(EntryControls::LOAD_IA32_EFER),
+                entry_controls.contains(EntryControls::LOAD_IA32_PAT),
+                entry_controls.contains(EntryControls::LOAD_DEBUG_CONTROLS),
+            )
+        }
+    };
+    let lbr_ctl_in_vmcs = vmfield::control::VMENTRY_CONTROLS.read() & LOAD_GUEST_LBR_CTL != 0;
+
+    match msr {
+        x86::msr::IA32_FS_BASE => Some(vmcs::guest::FS_BASE),
+        x86::msr::IA32_GS_BASE => Some(vmcs::guest::GS_BASE),
+        x86::msr::IA32_SYSENTER_CS => Some(vmcs::guest::IA32_SYSENTER_CS),
+        x86::msr::IA32_SYSENTER_ESP => Some(vmcs::guest::IA32_SYSENTER_ESP),
+        x86::msr::IA32_SYSENTER_EIP => Some(vmcs::guest::IA32_SYSENTER_EIP),
+        x86::msr::IA32_EFER if efer_in_vmcs => Some(vmcs::guest::IA32_EFER_FULL),
+        x86::msr::IA32_PAT if pat_in_vmcs => Some(vmcs::guest::IA32_PAT_FULL),
+        x86::msr::IA32_DEBUGCTL if debugctl_in_vmcs => Some(vmcs::guest::IA32_DEBUGCTL_FULL),
+        IA32_LBR_CTL if lbr_ctl_in_vmcs => Some(vmfield::guest::IA32_LBR_CTL_FULL.encoding()),
+        _ => None,
+    }
+}
+
 /// Allocates and zeros memory for a given type, returning a boxed instance.
 ///
 /// # Safety
@@ -231,12 +1321,27 @@ impl Vm {
 ///
 /// # Panics
 ///
-/// Panics if memory allocation fails.
+/// Panics if memory allocation fails. Use `try_box_zeroed` for large allocations that may fail.
 pub unsafe fn box_zeroed<T>() -> Box<T> {
+    unsafe { try_box_zeroed() }.unwrap_or_else(|_| handle_alloc_error(Layout::new::<T>()))
+}
+
+/// Allocates and zeros memory for a given type, returning a boxed instance or an error if the
+/// allocation fails.
+///
+/// # Safety
+///
+/// The all-zero bit pattern must be a valid value of `T`.
+///
+/// # Returns
+///
+/// Returns a `Box<T>` pointing to the zero-initialized memory of type `T`, or
+/// `Err(HypervisorError::OutOfMemory)` if the allocator cannot satisfy the allocation.
+pub unsafe fn try_box_zeroed<T>() -> Result<Box<T>, HypervisorError> {
     let layout = Layout::new::<T>();
     let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) }.cast::<T>();
     if ptr.is_null() {
-        handle_alloc_error(layout);
+        return Err(HypervisorError::OutOfMemory);
     }
-    unsafe { Box::from_raw(ptr) }
+    Ok(unsafe { Box::from_raw(ptr) })
 }
@@ -8,12 +8,13 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            bitmap::{IoBitmap, MsrBitmap},
             capture::GuestRegisters,
-            controls::{adjust_vmx_controls, VmxControl},
+            controls::{adjust_vmx_controls, is_vmx_control_supported, VmxControl},
             descriptor::Descriptors,
+            idt::HostIdt,
             invept::invept_single_context,
-            invvpid::{invvpid_single_context, VPID_TAG},
-            page::Page,
+            invvpid::invvpid_single_context,
             paging::PageTables,
             segmentation::{access_rights_from_native, lar, lsl},
             support::{cr0, cr3, rdmsr, sidt, vmread, vmwrite},
@@ -133,9 +134,10 @@ impl Vmcs {
     ///
     /// # Arguments
     /// * `host_descriptor` - Descriptor tables for the host.
+    /// * `host_idt` - The IDT for exceptions in VMX root operation.
     /// * `host_paging` - Paging tables for the host.
     #[rustfmt::skip]
-    pub fn setup_host_registers_state(host_descriptor: &Descriptors, host_paging: &Box<PageTables>) -> Result<(), HypervisorError> {
+    pub fn setup_host_registers_state(host_descriptor: &Descriptors, host_idt: &HostIdt, host_paging: &Box<PageTables>) -> Result<(), HypervisorError> {
         log::debug!("Setting up Host Registers State");
 
         let pml4_pa = host_paging.get_pml4_pa()?;
@@ -149,7 +151,7 @@ impl Vmcs {
 
         vmwrite(vmcs::host::TR_BASE, host_descriptor.tss.base);
         vmwrite(vmcs::host::GDTR_BASE, host_descriptor.gdtr.base as u64);
-        vmwrite(vmcs::host::IDTR_BASE, u64::MAX); // Bogus. No proper exception handling.
+        vmwrite(vmcs::host::IDTR_BASE, host_idt.base());
 
         log::debug!("Host Registers State setup successfully!");
 
@@ -165,39 +167,68 @@ impl Vmcs {
     /// - 25.8 VM-ENTRY CONTROL FIELDS
     ///
     /// # Arguments
-    /// * `shared_data` - Shared data between processors.
+    /// * `primary_eptp` - The EPTP of the primary EPT.
+    /// * `msr_bitmap` - The MSR bitmap.
+    /// * `io_bitmap` - The I/O bitmaps.
+    /// * `vpid` - The VPID of the virtual processor, or `None` to run without a VPID.
     #[rustfmt::skip]
-    pub fn setup_vmcs_control_fields(primary_eptp: u64, msr_bitmap: &Box<Page>) -> Result<(), HypervisorError> {
+    pub fn setup_vmcs_control_fields(primary_eptp: u64, msr_bitmap: &Box<MsrBitmap>, io_bitmap: &IoBitmap, vpid: Option<u16>) -> Result<(), HypervisorError> {
         log::debug!("Setting up VMCS Control Fields");
 
-        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits() | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits()) as u64;
+        const PRIMARY_CTL: u64 = (vmcs::control::PrimaryControls::SECONDARY_CONTROLS.bits() | vmcs::control::PrimaryControls::USE_MSR_BITMAPS.bits() | vmcs::control::PrimaryControls::USE_IO_BITMAPS.bits()) as u64;
         const SECONDARY_CTL: u64 = (vmcs::control::SecondaryControls::ENABLE_RDTSCP.bits()
             | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS.bits()
             | vmcs::control::SecondaryControls::ENABLE_INVPCID.bits()
-            | vmcs::control::SecondaryControls::ENABLE_VPID.bits()
-            | vmcs::control::SecondaryControls::ENABLE_EPT.bits()
-            | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST.bits()) as u64;
+            | vmcs::control::SecondaryControls::ENABLE_EPT.bits()) as u64;
+        const ENABLE_VPID_CTL: u64 = vmcs::control::SecondaryControls::ENABLE_VPID.bits() as u64;
+        const UNRESTRICTED_GUEST_CTL: u64 = vmcs::control::SecondaryControls::UNRESTRICTED_GUEST.bits() as u64;
+        const MODE_BASED_EPT_CTL: u64 = vmcs::control::SecondaryControls::MODE_BASED_EPT.bits() as u64;
         const ENTRY_CTL: u64 = vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as u64;
         const EXIT_CTL: u64 = vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() as u64;
-        const PINBASED_CTL: u64 = 0;
+        const PINBASED_CTL: u64 = (vmcs::control::PinbasedControls::NMI_EXITING.bits() | vmcs::control::PinbasedControls::VIRTUAL_NMIS.bits()) as u64;
 
         vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL));
-        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL));
+        // Unrestricted guest lets the guest run with CR0.PE and CR0.PG cleared (real mode and unpaged protected mode),
+        // which is needed after INIT-SIPI-SIPI and for hooking before the OS enables paging. It requires EPT.
+        let secondary_ctl = if is_vmx_control_supported(VmxControl::ProcessorBased2, UNRESTRICTED_GUEST_CTL) {
+            SECONDARY_CTL | UNRESTRICTED_GUEST_CTL
+        } else {
+            log::warn!("Unrestricted guest is not supported, the guest cannot run in real mode or without paging");
+            SECONDARY_CTL
+        };
+        // Mode-based execute control splits the EPT execute permission into supervisor-mode and user-mode execute,
+        // so hooks can target only one of them. The EPT tables always grant both, so enabling it changes nothing by itself.
+        let secondary_ctl = if is_vmx_control_supported(VmxControl::ProcessorBased2, MODE_BASED_EPT_CTL) {
+            secondary_ctl | MODE_BASED_EPT_CTL
+        } else {
+            log::debug!("Mode-based execute control is not supported, EPT execute permissions apply to all modes");
+            secondary_ctl
+        };
+        // With a VPID, VM entries and exits do not flush the TLB; guest mappings are invalidated with INVVPID instead.
+        let secondary_ctl = match vpid {
+            Some(_) => secondary_ctl | ENABLE_VPID_CTL,
+            None => secondary_ctl,
+        };
+
+        vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, secondary_ctl));
         vmwrite(vmcs::control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL));
         vmwrite(vmcs::control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL));
         vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL));
 
-        vmwrite(vmcs::control::CR0_READ_SHADOW, cr0().bits() as u64);
-        vmwrite(vmcs::control::CR4_READ_SHADOW, Cr4::read_raw());
-
         vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, msr_bitmap.as_ref() as *const _ as u64);
+        vmwrite(vmcs::control::IO_BITMAP_A_ADDR_FULL, io_bitmap.a_address());
+        vmwrite(vmcs::control::IO_BITMAP_B_ADDR_FULL, io_bitmap.b_address());
         //vmwrite(vmcs::control::EXCEPTION_BITMAP, 1u64 << (ExceptionInterrupt::Breakpoint as u32));
 
         vmwrite(vmcs::control::EPTP_FULL, primary_eptp);
-        vmwrite(vmcs::control::VPID, VPID_TAG);
 
-        invept_single_context(primary_eptp);
-        invvpid_single_context(VPID_TAG);
+        invept_single_context(primary_eptp)?;
+
+        if let Some(vpid) = vpid {
+            assert_ne!(vpid, 0, "VPID 0 must not be assigned to a guest");
+            vmwrite(vmcs::control::VPID, vpid);
+            invvpid_single_context(vpid);
+        }
 
         log::debug!("VMCS Control Fields setup successfully!");
 
@@ -370,6 +370,47 @@ impl core::fmt::Display for VmInstructionError {
     }
 }
 
+/// Formats a raw VM-instruction error number with its description.
+///
+/// Unlike `VmInstructionError`, this also covers error numbers that have no known variant.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VmInstructionErrorNumber(pub u32);
+
+impl core::fmt::Display for VmInstructionErrorNumber {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match VmInstructionError::from_u32(self.0) {
+            Some(error) => write!(f, "{}", error),
+            None => write!(f, "{}: Unknown VM-instruction error", self.0),
+        }
+    }
+}
+
+/// Describes why a VM entry failed.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.8 VM-ENTRY FAILURES DURING OR AFTER LOADING GUEST STATE
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VmEntryFailure {
+    /// VMLAUNCH or VMRESUME failed before loading the guest state, with the VM-instruction error number.
+    InstructionError(u32),
+
+    /// The VM entry failed while or after loading the guest state and caused a VM exit with bit 31 of
+    /// the exit reason set, with the basic exit reason and the exit qualification.
+    Exit(VmxBasicExitReason, u64),
+}
+
+impl core::fmt::Display for VmEntryFailure {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::InstructionError(error) => write!(f, "{}", VmInstructionErrorNumber(*error)),
+            Self::Exit(basic_exit_reason, qualification) => write!(
+                f,
+                "{} (exit qualification {:#x})",
+                basic_exit_reason, qualification
+            ),
+        }
+    }
+}
+
 /// Represents the exit qualification for EPT Violations.
 ///
 /// This struct interprets the exit qualification for EPT Violations as described in
@@ -383,11 +424,16 @@ pub struct EptViolationExitQualification {
     pub writable: bool,
     pub executable: bool,
     pub user_mode_executable: bool,
+    /// Bit 7: The guest linear address field of the VMCS holds the linear address that caused the violation.
     pub guest_linear_address_valid: bool,
+    /// Bit 8: Only meaningful if `guest_linear_address_valid` is set. Set if the violation was caused by
+    /// the access to the translated guest-physical address, clear if it was caused by an access to a
+    /// guest paging-structure entry during translation.
     pub guest_physical_access: bool,
     pub supervisor_user_mode: bool,
     pub linear_address_read_write: bool,
     pub linear_address_executable: bool,
+    /// Bit 12: The violation was caused by IRET and virtual-NMI blocking was cleared by it.
     pub nmi_unblocking_due_to_iret: bool,
     pub shadow_stack_access: bool,
     pub supervisor_shadow_stack_control: bool,
@@ -419,6 +465,13 @@ impl EptViolationExitQualification {
             asynchronous_access: value & (1 << 16) != 0,
         }
     }
+
+    /// Checks whether the violation was caused by the access to the translated guest-physical address
+    /// of a known guest linear address, as opposed to a guest paging-structure access or an access
+    /// without a linear address (e.g. by the processor itself).
+    pub fn is_translated_linear_access(&self) -> bool {
+        self.guest_linear_address_valid && self.guest_physical_access
+    }
 }
 
 impl core::fmt::Display for EptViolationExitQualification {
@@ -0,0 +1,242 @@
+//! Handles the VM exits caused by APIC virtualization.
+//!
+//! The local APIC is not virtualized by default, so these exits only occur once the "virtualize APIC
+//! accesses", "APIC-register virtualization", or "virtual-interrupt delivery" controls are enabled
+//! and a virtual-APIC page is set up. The handlers emulate accesses against the virtual-APIC page
+//! and forward writes to the physical local APIC, which the guest otherwise owns.
+//!
+//! - APIC-access VM exits are fault-like: the access has not been performed and RIP points at the
+//!   accessing instruction, which is emulated and skipped.
+//! - APIC-write and virtualized-EOI VM exits are trap-like: the write has already been performed
+//!   on the virtual-APIC page and RIP points at the next instruction.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.4 VIRTUALIZING MEMORY-MAPPED APIC ACCESSES
+
+use {
+    crate::intel::{
+        decode::{decode_store_operand, guest_register},
+        support::{rdmsr, wrmsr},
+        vm::Vm,
+        vmexit::{dispatch::handle_unhandled_exit, ExitType},
+        vmfield,
+    },
+    iced_x86::OpKind,
+};
+
+/// The offset of the EOI register on the APIC page.
+const APIC_EOI: u64 = 0xb0;
+
+/// The offset of the low half of the interrupt command register on the APIC page.
+const APIC_ICR_LOW: u64 = 0x300;
+
+/// The offset of the high half of the interrupt command register on the APIC page.
+const APIC_ICR_HIGH: u64 = 0x310;
+
+/// The first x2APIC MSR, which corresponds to offset 0 of the APIC page.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// IA32_APIC_BASE.EXTD (bit 10), set while the local APIC is in x2APIC mode.
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+
+/// Bits 51:12 of IA32_APIC_BASE, the physical address of the xAPIC MMIO page.
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// How the guest accessed the APIC-access page, from bits 15:12 of the exit qualification.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-6. Exit Qualification for APIC-Access VM Exits from Linear Accesses and Guest-Physical Accesses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApicAccessType {
+    LinearRead,
+    LinearWrite,
+    Other(u64),
+}
+
+impl ApicAccessType {
+    fn from_exit_qualification(exit_qualification: u64) -> Self {
+        match (exit_qualification >> 12) & 0xf {
+            0 => Self::LinearRead,
+            1 => Self::LinearWrite,
+            access_type => Self::Other(access_type),
+        }
+    }
+}
+
+/// Handles an APIC-access VM exit by emulating the access against the virtual-APIC page.
+///
+/// Reads load the register from the virtual-APIC page into the destination register of the
+/// instruction. Writes store the value to the virtual-APIC page and forward it to the physical local
+/// APIC. Instruction fetches and accesses during event delivery cannot be emulated and are treated
+/// like unhandled VM exits.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - The accessing instruction was emulated and is skipped.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.4.3 Virtualizing Memory-Mapped APIC Accesses
+pub fn handle_apic_access(vm: &mut Vm) -> ExitType {
+    log::trace!("Handling APIC access VM exit...");
+
+    let exit_qualification = vmfield::ro::EXIT_QUALIFICATION.read();
+    let offset = exit_qualification & 0xfff;
+
+    match ApicAccessType::from_exit_qualification(exit_qualification) {
+        ApicAccessType::LinearRead => {
+            let Some(instruction) = vm.decode_current_instruction() else {
+                log::error!("Failed to decode the APIC read at offset {:#x}", offset);
+                return handle_unhandled_exit(vm);
+            };
+
+            let destination = match instruction.op0_kind() {
+                OpKind::Register => guest_register(instruction.op0_register()),
+                _ => None,
+            };
+
+            let Some(destination) = destination else {
+                log::error!(
+                    "Unsupported APIC read at offset {:#x}: {:?}",
+                    offset,
+                    instruction
+                );
+                return handle_unhandled_exit(vm);
+            };
+
+            // 32-bit destinations are zero-extended in 64-bit mode, and APIC registers are 32-bit.
+            let value = read_virtual_apic(offset);
+            vm.set_guest_reg(destination, u64::from(value));
+            log::trace!("APIC read at offset {:#x}: {:#x}", offset, value);
+        }
+        ApicAccessType::LinearWrite => {
+            let Some(value) = decode_store_operand(vm).and_then(|store| store.value) else {
+                log::error!("Failed to decode the APIC write at offset {:#x}", offset);
+                return handle_unhandled_exit(vm);
+            };
+
+            write_virtual_apic(offset, value as u32);
+            forward_write(offset);
+            log::trace!("APIC write at offset {:#x}: {:#x}", offset, value);
+        }
+        ApicAccessType::Other(access_type) => {
+            log::error!(
+                "Cannot emulate APIC access of type {} at offset {:#x}",
+                access_type,
+                offset
+            );
+            return handle_unhandled_exit(vm);
+        }
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Handles an APIC-write VM exit by forwarding the write to the physical local APIC.
+///
+/// With APIC-register virtualization, writes to most APIC registers are performed on the
+/// virtual-APIC page and followed by this VM exit, with the offset of the register in the exit
+/// qualification.
+///
+/// # Arguments
+///
+/// * `_vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The exit is trap-like, so RIP already points past the write.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.4.3.3 APIC-Write Emulation
+pub fn handle_apic_write(_vm: &mut Vm) -> ExitType {
+    let offset = vmfield::ro::EXIT_QUALIFICATION.read() & 0xfff;
+    log::trace!("Handling APIC write VM exit at offset {:#x}...", offset);
+
+    forward_write(offset);
+
+    ExitType::Continue
+}
+
+/// Handles a virtualized-EOI VM exit by signaling the end of the interrupt to the physical local APIC.
+///
+/// With virtual-interrupt delivery, EOIs for vectors set in the EOI-exit bitmap are virtualized and
+/// followed by this VM exit, with the vector in the exit qualification.
+///
+/// # Arguments
+///
+/// * `_vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The exit is trap-like, so RIP already points past the write.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.1.4 EOI Virtualization
+pub fn handle_virtualized_eoi(_vm: &mut Vm) -> ExitType {
+    let vector = vmfield::ro::EXIT_QUALIFICATION.read() & 0xff;
+    log::trace!(
+        "Handling virtualized EOI VM exit for vector {:#x}...",
+        vector
+    );
+
+    write_physical_apic(APIC_EOI, 0);
+
+    ExitType::Continue
+}
+
+/// Forwards a write to the virtual-APIC page to the physical local APIC.
+///
+/// A write to the low half of the ICR sends an IPI, so the full ICR is forwarded. Writes to the high
+/// half only take effect with the next write to the low half.
+///
+/// # Arguments
+///
+/// * `offset` - The offset of the written register on the APIC page.
+fn forward_write(offset: u64) {
+    match offset {
+        APIC_ICR_HIGH => {}
+        APIC_ICR_LOW => {
+            let icr = (u64::from(read_virtual_apic(APIC_ICR_HIGH)) << 32)
+                | u64::from(read_virtual_apic(APIC_ICR_LOW));
+            write_physical_icr(icr);
+        }
+        _ => write_physical_apic(offset, read_virtual_apic(offset)),
+    }
+}
+
+/// Reads a register from the virtual-APIC page of the current VMCS.
+fn read_virtual_apic(offset: u64) -> u32 {
+    let address = vmfield::control::VIRT_APIC_ADDR_FULL.read() + (offset & !0x3);
+    unsafe { core::ptr::read_volatile(address as *const u32) }
+}
+
+/// Writes a register to the virtual-APIC page of the current VMCS.
+fn write_virtual_apic(offset: u64, value: u32) {
+    let address = vmfield::control::VIRT_APIC_ADDR_FULL.read() + (offset & !0x3);
+    unsafe { core::ptr::write_volatile(address as *mut u32, value) };
+}
+
+/// Writes a 32-bit register of the physical local APIC, in xAPIC or x2APIC mode.
+fn write_physical_apic(offset: u64, value: u32) {
+    let apic_base = rdmsr(x86::msr::IA32_APIC_BASE);
+
+    if apic_base & APIC_BASE_X2APIC_ENABLE != 0 {
+        wrmsr(X2APIC_MSR_BASE + (offset >> 4) as u32, u64::from(value));
+    } else {
+        let address = (apic_base & APIC_BASE_ADDRESS_MASK) + offset;
+        unsafe { core::ptr::write_volatile(address as *mut u32, value) };
+    }
+}
+
+/// Writes the interrupt command register of the physical local APIC, which sends an IPI.
+///
+/// In x2APIC mode, the ICR is a single 64-bit MSR. In xAPIC mode, the high half must be written
+/// first, since writing the low half sends the IPI.
+fn write_physical_icr(icr: u64) {
+    let apic_base = rdmsr(x86::msr::IA32_APIC_BASE);
+
+    if apic_base & APIC_BASE_X2APIC_ENABLE != 0 {
+        wrmsr(X2APIC_MSR_BASE + (APIC_ICR_LOW >> 4) as u32, icr);
+    } else {
+        write_physical_apic(APIC_ICR_HIGH, (icr >> 32) as u32);
+        write_physical_apic(APIC_ICR_LOW, icr as u32);
+    }
+}
@@ -1,12 +1,33 @@
 //! Handles CPU-related virtualization tasks, specifically intercepting and managing
 //! the `CPUID` instruction in a VM to control the exposure of CPU features to the guest.
+//!
+//! `CPUID` unconditionally causes a VM exit, so the most frequently queried leaves are served from
+//! a `CpuidCache` captured once at initialization instead of executing `CPUID` on every exit.
+//!
+//! A `CpuidProfile` can hide the features of newer microarchitectures from the guest, so software can
+//! be tested against an older feature set without the physical hardware.
+//!
+//! A `CpuidLatency` can pad the handler to a fixed number of cycles, so timing `CPUID` does not reveal
+//! which leaves the hypervisor serves from the cache or modifies.
 
 #![allow(dead_code)]
 
 use {
-    crate::intel::{capture::GuestRegisters, vmexit::ExitType},
+    crate::intel::{
+        support::{cr4, rdtsc},
+        vm::Vm,
+        vmexit::{
+            monitor_mwait::{mwait_action, MwaitAction},
+            ExitType,
+        },
+        vmfield,
+    },
     bitfield::BitMut,
-    x86::cpuid::cpuid,
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    x86::{
+        controlregs::{xcr0, Cr4},
+        cpuid::{cpuid, CpuIdResult},
+    },
 };
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -22,6 +43,9 @@ pub enum CpuidLeaf {
     /// CPUID function for extended feature information.
     ExtendedFeatureInformation = 0x7,
 
+    /// CPUID function for processor extended state enumeration (XSAVE).
+    ExtendedStateInformation = 0xD,
+
     /// Hypervisor vendor information leaf.
     HypervisorVendor = 0x40000000,
 
@@ -53,22 +77,309 @@ pub enum CpuidLeaf {
 /// Enumerates specific feature bits in the ECX register for CPUID instruction results.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum FeatureBits {
+    /// Bit 3 of ECX for CPUID with EAX=1, indicating MONITOR/MWAIT support.
+    MonitorMwaitSupport = 3,
     /// Bit 5 of ECX for CPUID with EAX=1, indicating VMX support.
-    HypervisorVmxSupportBit = 5,
+    HypervisorVmxSupport = 5,
     /// Bit 31 of ECX for CPUID with EAX=1, indicating hypervisor presence.
-    HypervisorPresentBit = 31,
+    HypervisorPresent = 31,
+}
+
+/// The leaves served from the `CpuidCache`, with the sub-leaf they are cached for.
+/// `None` matches any sub-leaf, since leaves 0 and 1 ignore ECX.
+const CACHED_LEAVES: [(u32, Option<u32>); 4] = [
+    (CpuidLeaf::VendorInfo as u32, None),
+    (CpuidLeaf::FeatureInformation as u32, None),
+    (CpuidLeaf::ExtendedFeatureInformation as u32, Some(0)),
+    (CpuidLeaf::ExtendedStateInformation as u32, Some(0)),
+];
+
+/// AND masks for the registers of a leaf, as `(leaf, sub-leaf, [EAX, EBX, ECX, EDX])`.
+/// Bits cleared in a mask are hidden from the guest. `None` matches any sub-leaf.
+type CpuidMask = (u32, Option<u32>, [u32; 4]);
+
+/// Leaf 7 EDX bits reporting speculative execution mitigations, which microcode updates add to older
+/// processors too: MD_CLEAR (10), IBRS/IBPB (26), STIBP (27), L1D_FLUSH (28), IA32_ARCH_CAPABILITIES (29),
+/// and SSBD (31). Profiles keep them, so the guest still applies its mitigations.
+const SPECULATION_CONTROL_BITS: u32 = 1 << 10 | 1 << 26 | 1 << 27 | 1 << 28 | 1 << 29 | 1 << 31;
+
+/// A reduced feature set presented to the guest through `CPUID`.
+///
+/// A profile only hides features from the `CPUID` results. The guest can still execute the
+/// instructions of hidden features, so it only constrains software that checks `CPUID` first,
+/// which is what compatibility testing needs. The masks are applied on top of the native results,
+/// so a profile never reports a feature the processor lacks.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 3-8. Information Returned by CPUID Instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuidProfile {
+    /// The native feature set. This is the default.
+    Native,
+
+    /// The feature set of Nehalem (SSE4.2, POPCNT, no AVX).
+    ///
+    /// - Leaf 1 ECX: PCLMULQDQ (1), SDBG (11), FMA (12), PCID (17), x2APIC (21), MOVBE (22),
+    ///   TSC-deadline (24), AES-NI (25), XSAVE (26), OSXSAVE (27), AVX (28), F16C (29), and RDRAND (30).
+    /// - Leaf 7 sub-leaf 0: all of EBX and ECX, and EDX except the speculation control bits.
+    /// - Leaf 7 sub-leaf 1: all of EAX.
+    /// - Leaf 0x80000001 ECX: LZCNT (5) and PREFETCHW (8). EDX: 1GB pages (26).
+    Nehalem,
+
+    /// The feature set of Haswell (AVX2, BMI1/2, FMA, TSX, no AVX-512).
+    ///
+    /// - Leaf 7 sub-leaf 0 EBX: SGX (2), MPX (14), RDSEED (18), ADX (19), SMAP (20), CLFLUSHOPT (23),
+    ///   CLWB (24), Intel PT (25), SHA (29), and all AVX-512 bits (16, 17, 21, 26, 27, 28, 30, 31).
+    ///   ECX: all. EDX: all except the speculation control bits.
+    /// - Leaf 7 sub-leaf 1: all of EAX.
+    /// - Leaf 0xD sub-leaf 0 EAX/EDX: all XCR0 components but x87, SSE, and AVX (0-2).
+    /// - Leaf 0xD sub-leaf 1 EAX: XSAVEC (1), XGETBV with ECX=1 (2), and XSAVES (3).
+    Haswell,
+
+    /// The native feature set without AVX-512.
+    ///
+    /// - Leaf 7 sub-leaf 0 EBX: AVX512F (16), AVX512DQ (17), AVX512_IFMA (21), AVX512PF (26), AVX512ER (27),
+    ///   AVX512CD (28), AVX512BW (30), and AVX512VL (31). ECX: AVX512_VBMI (1), AVX512_VBMI2 (6),
+    ///   AVX512_VNNI (11), AVX512_BITALG (12), and AVX512_VPOPCNTDQ (14). EDX: AVX512_4VNNIW (2),
+    ///   AVX512_4FMAPS (3), AVX512_VP2INTERSECT (8), and AVX512_FP16 (23).
+    /// - Leaf 7 sub-leaf 1 EAX: AVX512_BF16 (5).
+    /// - Leaf 0xD sub-leaf 0 EAX: the opmask, ZMM_Hi256, and Hi16_ZMM XCR0 components (5-7).
+    NoAvx512,
+}
+
+impl CpuidProfile {
+    /// Returns the masks of the profile.
+    #[rustfmt::skip]
+    fn masks(&self) -> &'static [CpuidMask] {
+        const EXTENDED_FUNCTION_INFORMATION: u32 = 0x8000_0001;
+
+        match self {
+            Self::Native => &[],
+            Self::Nehalem => &[
+                (CpuidLeaf::FeatureInformation as u32, None, [!0, !0, !(1 << 1 | 1 << 11 | 1 << 12 | 1 << 17 | 1 << 21 | 1 << 22 | 1 << 24 | 1 << 25 | 1 << 26 | 1 << 27 | 1 << 28 | 1 << 29 | 1 << 30), !0]),
+                (CpuidLeaf::ExtendedFeatureInformation as u32, Some(0), [!0, 0, 0, SPECULATION_CONTROL_BITS]),
+                (CpuidLeaf::ExtendedFeatureInformation as u32, Some(1), [0, !0, !0, !0]),
+                (EXTENDED_FUNCTION_INFORMATION, None, [!0, !0, !(1 << 5 | 1 << 8), !(1 << 26)]),
+            ],
+            Self::Haswell => &[
+                (CpuidLeaf::ExtendedFeatureInformation as u32, Some(0), [!0, !(1 << 2 | 1 << 14 | 1 << 16 | 1 << 17 | 1 << 18 | 1 << 19 | 1 << 20 | 1 << 21 | 1 << 23 | 1 << 24 | 1 << 25 | 1 << 26 | 1 << 27 | 1 << 28 | 1 << 29 | 1 << 30 | 1 << 31), 0, SPECULATION_CONTROL_BITS]),
+                (CpuidLeaf::ExtendedFeatureInformation as u32, Some(1), [0, !0, !0, !0]),
+                (CpuidLeaf::ExtendedStateInformation as u32, Some(0), [0b111, !0, !0, 0]),
+                (CpuidLeaf::ExtendedStateInformation as u32, Some(1), [!(1 << 1 | 1 << 2 | 1 << 3), !0, !0, !0]),
+            ],
+            Self::NoAvx512 => &[
+                (CpuidLeaf::ExtendedFeatureInformation as u32, Some(0), [!0, !(1 << 16 | 1 << 17 | 1 << 21 | 1 << 26 | 1 << 27 | 1 << 28 | 1 << 30 | 1 << 31), !(1 << 1 | 1 << 6 | 1 << 11 | 1 << 12 | 1 << 14), !(1 << 2 | 1 << 3 | 1 << 8 | 1 << 23)]),
+                (CpuidLeaf::ExtendedFeatureInformation as u32, Some(1), [!(1 << 5), !0, !0, !0]),
+                (CpuidLeaf::ExtendedStateInformation as u32, Some(0), [!(1 << 5 | 1 << 6 | 1 << 7), !0, !0, !0]),
+            ],
+        }
+    }
+
+    /// Hides the features of a leaf that are not part of the profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf being queried (EAX).
+    /// * `sub_leaf` - The sub-leaf being queried (ECX).
+    /// * `result` - The native result of the leaf, which is masked in place.
+    pub fn apply(&self, leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) {
+        let masks = self
+            .masks()
+            .iter()
+            .filter(|&&(masked_leaf, masked_sub_leaf, _)| {
+                masked_leaf == leaf
+                    && masked_sub_leaf.is_none_or(|masked_sub_leaf| masked_sub_leaf == sub_leaf)
+            });
+
+        for (_, _, [eax, ebx, ecx, edx]) in masks {
+            result.eax &= eax;
+            result.ebx &= ebx;
+            result.ecx &= ecx;
+            result.edx &= edx;
+        }
+    }
+}
+
+/// The native `CPUID` results of the most frequently queried leaves, captured once at initialization.
+///
+/// The cache holds the unmodified results, so changing how `handle_cpuid` masks a leaf does not
+/// require invalidating it. Fields that depend on the state of the current processor are patched
+/// in when a leaf is served:
+///
+/// - Leaf 1 EBX[31:24]: the initial APIC ID.
+/// - Leaf 1 ECX[27] (OSXSAVE) and leaf 7 ECX[4] (OSPKE): mirrors of the guest's CR4.OSXSAVE and CR4.PKE.
+///
+/// Leaf 0xD sub-leaf 0 reports the XSAVE area size for the enabled XCR0 features, so it is only
+/// served while XCR0 matches the value it was captured with.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 3-8. Information Returned by CPUID Instruction
+pub struct CpuidCache {
+    /// The cached results, in the order of `CACHED_LEAVES`.
+    results: [CpuIdResult; CACHED_LEAVES.len()],
+
+    /// The value of XCR0 when the cache was captured, or `None` if XSAVE was not enabled.
+    xcr0: Option<u64>,
+
+    /// Whether the cache may be used. Once invalidated, every leaf is executed live.
+    valid: AtomicBool,
+}
+
+impl CpuidCache {
+    /// Captures the native results of the cached leaves on the current processor.
+    pub fn capture() -> Self {
+        Self {
+            results: CACHED_LEAVES.map(|(leaf, sub_leaf)| cpuid!(leaf, sub_leaf.unwrap_or(0))),
+            xcr0: current_xcr0(),
+            valid: AtomicBool::new(true),
+        }
+    }
+
+    /// Looks up the native result of a leaf, patched for the current processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `leaf` - The leaf being queried (EAX).
+    /// * `sub_leaf` - The sub-leaf being queried (ECX).
+    /// * `apic_id` - The initial APIC ID of the current processor.
+    /// * `guest_cr4` - The guest's CR4, which the OSXSAVE and OSPKE bits mirror. The host's CR4 differs.
+    ///
+    /// # Returns
+    ///
+    /// The cached result, or `None` if the leaf is not cached, the cache was invalidated, or the
+    /// cached result is stale and `CPUID` must be executed live.
+    pub fn get(
+        &self,
+        leaf: u32,
+        sub_leaf: u32,
+        apic_id: u32,
+        guest_cr4: Cr4,
+    ) -> Option<CpuIdResult> {
+        if !self.valid.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let index = CACHED_LEAVES
+            .iter()
+            .position(|&(cached_leaf, cached_sub_leaf)| {
+                cached_leaf == leaf
+                    && cached_sub_leaf.is_none_or(|cached_sub_leaf| cached_sub_leaf == sub_leaf)
+            })?;
+        let mut result = self.results[index];
+
+        match leaf {
+            leaf if leaf == CpuidLeaf::FeatureInformation as u32 => {
+                result.ebx = (result.ebx & 0x00ff_ffff) | (apic_id << 24);
+                result
+                    .ecx
+                    .set_bit(27, guest_cr4.contains(Cr4::CR4_ENABLE_OS_XSAVE));
+            }
+            leaf if leaf == CpuidLeaf::ExtendedFeatureInformation as u32 => {
+                result
+                    .ecx
+                    .set_bit(4, guest_cr4.contains(Cr4::CR4_ENABLE_PROTECTION_KEY));
+            }
+            leaf if leaf == CpuidLeaf::ExtendedStateInformation as u32
+                && current_xcr0() != self.xcr0 =>
+            {
+                return None;
+            }
+            _ => {}
+        }
+
+        Some(result)
+    }
+
+    /// Invalidates the cache, so every leaf is executed live from then on.
+    ///
+    /// Must be called whenever the native results of a cached leaf change. The MSR handler calls it
+    /// when the guest toggles the IA32_MISC_ENABLE bit that limits the maximum basic leaf reported by
+    /// leaf 0.
+    pub fn invalidate(&self) {
+        self.valid.store(false, Ordering::Release);
+    }
+}
+
+/// Pads the `CPUID` VM-exit handler to a fixed number of cycles.
+///
+/// `CPUID` always causes a VM exit, and the handler takes a different number of cycles depending on
+/// the leaf: cached leaves are cheaper than leaves executed live, and some leaves are modified. Software
+/// probing for a hypervisor can time `CPUID` and recognize that fingerprint. With a target latency
+/// configured, the handler busy-waits until the target has elapsed since it was entered, so every leaf
+/// takes the same time as seen by the guest.
+///
+/// This trades throughput for stealth: every `CPUID` takes at least the target latency, which should be
+/// slightly above the slowest leaf, and the processor spins instead of running the guest. A handler
+/// that already exceeded the target (e.g. because of a logging call) is not padded. The VM-exit and
+/// VM-entry transitions themselves still take time, so this only hides the variation, not the exit.
+pub struct CpuidLatency {
+    /// The number of TSC cycles the handler is padded to, or 0 if padding is disabled.
+    target_cycles: AtomicU64,
+}
+
+impl Default for CpuidLatency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuidLatency {
+    /// Creates the latency setting with padding disabled.
+    pub const fn new() -> Self {
+        Self {
+            target_cycles: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets the number of TSC cycles the handler is padded to. 0 disables padding, which is the default.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_cycles` - The minimum number of TSC cycles between entering the handler and returning from it.
+    pub fn set_target(&self, target_cycles: u64) {
+        self.target_cycles.store(target_cycles, Ordering::Relaxed);
+    }
+
+    /// Returns the number of TSC cycles the handler is padded to, or 0 if padding is disabled.
+    pub fn target(&self) -> u64 {
+        self.target_cycles.load(Ordering::Relaxed)
+    }
+
+    /// Busy-waits until the target latency has elapsed since the handler was entered.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry_tsc` - The TSC when the handler was entered.
+    pub fn pad(&self, entry_tsc: u64) {
+        let target_cycles = self.target();
+        if target_cycles == 0 {
+            return;
+        }
+
+        let deadline = entry_tsc.saturating_add(target_cycles);
+        while rdtsc() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Reads XCR0, which is only accessible while CR4.OSXSAVE is set.
+fn current_xcr0() -> Option<u64> {
+    match cr4().contains(Cr4::CR4_ENABLE_OS_XSAVE) {
+        true => Some(unsafe { xcr0() }.bits()),
+        false => None,
+    }
 }
 
 /// Handles the `CPUID` VM-exit.
 ///
 /// This function is invoked when the guest executes the `CPUID` instruction.
-/// The handler retrieves the results of the `CPUID` instruction executed on
-/// the host and then modifies or masks certain bits, if necessary, before
-/// returning the results to the guest.
+/// The handler retrieves the native results of the `CPUID` instruction, either from the
+/// `CpuidCache` in the shared data or by executing it on the host, applies the `CpuidProfile` of the
+/// shared data, and then modifies or masks certain bits, if necessary, before returning the results to the guest.
+/// If the `CpuidLatency` of the shared data has a target, the handler is padded to it before returning.
 ///
 /// # Arguments
 ///
-/// * `registers` - A mutable reference to the guest's current register state.
+/// * `vm` - The VM of the current processor.
 ///
 /// # Returns
 ///
@@ -76,14 +387,20 @@ enum FeatureBits {
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 10.
 #[rustfmt::skip]
-pub fn handle_cpuid(guest_registers: &mut GuestRegisters) -> ExitType {
+pub fn handle_cpuid(vm: &mut Vm) -> ExitType {
+    let entry_tsc = rdtsc();
     log::trace!("Handling CPUID VM exit...");
 
-    let leaf = guest_registers.rax as u32;
-    let sub_leaf = guest_registers.rcx as u32;
+    let leaf = vm.guest_registers.rax as u32;
+    let sub_leaf = vm.guest_registers.rcx as u32;
 
-    // Execute CPUID instruction on the host and retrieve the result
-    let mut cpuid_result = cpuid!(leaf, sub_leaf);
+    // Serve hot leaves from the cache, and execute CPUID on the host for all others.
+    let guest_cr4 = Cr4::from_bits_truncate(vmfield::guest::CR4.read() as usize);
+    let cached_result = unsafe { vm.shared_data.as_ref() }.cpuid_cache.get(leaf, sub_leaf, vm.apic_id, guest_cr4);
+    let mut cpuid_result = cached_result.unwrap_or_else(|| cpuid!(leaf, sub_leaf));
+
+    // Hide the features the selected profile lacks before the hypervisor-specific adjustments.
+    unsafe { vm.shared_data.as_ref() }.cpuid_profile.apply(leaf, sub_leaf, &mut cpuid_result);
 
     log::trace!("Before modification: CPUID Leaf: {:#x}, EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}", leaf, cpuid_result.eax, cpuid_result.ebx, cpuid_result.ecx, cpuid_result.edx);
 
@@ -92,10 +409,15 @@ pub fn handle_cpuid(guest_registers: &mut GuestRegisters) -> ExitType {
         leaf if leaf == CpuidLeaf::FeatureInformation as u32 => {
             log::trace!("CPUID leaf 1 detected (Standard Feature Information).");
             // Hide hypervisor presence by setting the appropriate bit in ECX.
-            cpuid_result.ecx.set_bit(FeatureBits::HypervisorPresentBit as usize, false);
+            cpuid_result.ecx.set_bit(FeatureBits::HypervisorPresent as usize, false);
 
             // Hide VMX support by setting the appropriate bit in ECX.
-            cpuid_result.ecx.set_bit(FeatureBits::HypervisorVmxSupportBit as usize, false);
+            cpuid_result.ecx.set_bit(FeatureBits::HypervisorVmxSupport as usize, false);
+
+            // Guests checking CPUID fall back to HLT or polling if MONITOR and MWAIT are denied.
+            if mwait_action() == MwaitAction::Deny {
+                cpuid_result.ecx.set_bit(FeatureBits::MonitorMwaitSupport as usize, false);
+            }
         },
         // Handle CPUID for hypervisor vendor information.
         leaf if leaf == CpuidLeaf::HypervisorVendor as u32 => {
@@ -126,12 +448,15 @@ pub fn handle_cpuid(guest_registers: &mut GuestRegisters) -> ExitType {
     log::trace!("After modification: CPUID Leaf: {:#x}, EAX: {:#x}, EBX: {:#x}, ECX: {:#x}, EDX: {:#x}", leaf, cpuid_result.eax, cpuid_result.ebx, cpuid_result.ecx, cpuid_result.edx);
 
     // Update the guest registers
-    guest_registers.rax = cpuid_result.eax as u64;
-    guest_registers.rbx = cpuid_result.ebx as u64;
-    guest_registers.rcx = cpuid_result.ecx as u64;
-    guest_registers.rdx = cpuid_result.edx as u64;
+    vm.guest_registers.rax = cpuid_result.eax as u64;
+    vm.guest_registers.rbx = cpuid_result.ebx as u64;
+    vm.guest_registers.rcx = cpuid_result.ecx as u64;
+    vm.guest_registers.rdx = cpuid_result.edx as u64;
 
     log::trace!("CPUID VMEXIT handled successfully!");
 
+    // Pad the handler last, so the deadline covers all of its work.
+    unsafe { vm.shared_data.as_ref() }.cpuid_latency.pad(entry_tsc);
+
     ExitType::IncrementRIP
 }
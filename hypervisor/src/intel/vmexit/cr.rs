@@ -0,0 +1,99 @@
+//! Handles VM exits due to control-register accesses.
+//!
+//! These exits only occur when the guest tries to change a CR0 or CR4 bit owned by the hypervisor
+//! (see `Vm::set_cr0_mask` and `Vm::set_cr4_mask`) to a value different from its read shadow. The
+//! write is emulated: bits not owned by the hypervisor are written to the guest register, and the
+//! read shadow is updated so the guest reads back what it wrote. Owned bits keep their real value.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-3. Exit Qualification for Control-Register Accesses
+
+use {
+    crate::intel::{
+        capture::Register,
+        events::EventInjection,
+        vm::{Vm, CR4_FORCE_OWNED},
+        vmexit::{hv_bail, ExitType},
+        vmfield,
+    },
+    bit_field::BitField,
+    x86::controlregs::Cr0,
+};
+
+/// The access type in bits 5:4 of the exit qualification.
+const ACCESS_TYPE_MOV_TO_CR: u64 = 0;
+const ACCESS_TYPE_CLTS: u64 = 2;
+const ACCESS_TYPE_LMSW: u64 = 3;
+
+/// Handles a control-register access VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - If the access was emulated.
+/// * `ExitType::Continue` - If an exception was injected instead.
+/// * `ExitType::ExitHypervisor` - If the access is not one that causes VM exits.
+pub fn handle_cr_access(vm: &mut Vm) -> ExitType {
+    let qualification = vmfield::ro::EXIT_QUALIFICATION.read();
+    let cr = qualification.get_bits(0..4);
+    let access_type = qualification.get_bits(4..6);
+
+    log::debug!(
+        "Handling CR{} access VM exit, access type {}",
+        cr,
+        access_type
+    );
+
+    match (cr, access_type) {
+        (0, ACCESS_TYPE_MOV_TO_CR) | (4, ACCESS_TYPE_MOV_TO_CR) => {
+            let Some(register) = Register::from_gpr_index(qualification.get_bits(8..12)) else {
+                hv_bail!("Exit qualification encodes a 4-bit register index");
+            };
+            let value = vm.guest_reg(register);
+
+            if cr == 0 {
+                write_cr0(vm, value);
+            } else {
+                // The guest has been told VMX is unsupported, so enabling it faults like on real hardware.
+                if value & CR4_FORCE_OWNED != 0 {
+                    EventInjection::vmentry_inject_gp(0);
+                    return ExitType::Continue;
+                }
+                write_cr4(vm, value);
+            }
+        }
+        (0, ACCESS_TYPE_CLTS) => {
+            let shadow = vmfield::control::CR0_READ_SHADOW.read();
+            write_cr0(vm, shadow & !(Cr0::CR0_TASK_SWITCHED.bits() as u64));
+        }
+        (0, ACCESS_TYPE_LMSW) => {
+            // LMSW loads CR0 bits 3:0 from bits 31:16 of the qualification, but cannot clear PE.
+            let shadow = vmfield::control::CR0_READ_SHADOW.read();
+            let source = qualification.get_bits(16..32) & 0xf;
+            write_cr0(vm, (shadow & !0xe) | source);
+        }
+        _ => hv_bail!("Unexpected control-register access: {:#x}", qualification),
+    }
+
+    ExitType::IncrementRIP
+}
+
+/// Emulates a guest write to CR0.
+fn write_cr0(vm: &mut Vm, value: u64) {
+    let mask = vmfield::control::CR0_GUEST_HOST_MASK.read();
+    let guest_cr0 = vmfield::guest::CR0.read();
+
+    vmfield::guest::CR0.write((value & !mask) | (guest_cr0 & mask));
+    vm.set_cr0_shadow(value);
+}
+
+/// Emulates a guest write to CR4.
+fn write_cr4(vm: &mut Vm, value: u64) {
+    let mask = vmfield::control::CR4_GUEST_HOST_MASK.read();
+    let guest_cr4 = vmfield::guest::CR4.read();
+
+    vmfield::guest::CR4.write((value & !mask) | (guest_cr4 & mask));
+    vm.set_cr4_shadow(value);
+}
@@ -0,0 +1,190 @@
+//! Dispatches VM exits to their handlers through a table indexed by basic exit reason.
+//!
+//! The table is initialized at compile time with the default handler of each exit reason, and exit
+//! reasons without a handler dispatch to `handle_unhandled_exit`. Handlers can be replaced at runtime
+//! with `register_exit_handler`, e.g. to swap in a custom CPUID handler for testing.
+//!
+//! Handlers are plain `fn` pointers, since memory cannot be allocated from a VM-exit handler.
+
+use {
+    crate::intel::{
+        vm::Vm,
+        vmerror::VmxBasicExitReason,
+        vmexit::{
+            apic::{handle_apic_access, handle_apic_write, handle_virtualized_eoi},
+            cpuid::handle_cpuid,
+            cr::handle_cr_access,
+            ept::{handle_ept_misconfiguration, handle_ept_violation},
+            exception::{handle_exception, handle_undefined_opcode_exception},
+            halt::handle_halt,
+            init::handle_init_signal,
+            interrupt::handle_interrupt_window,
+            invd::handle_invd,
+            invept::handle_invept,
+            invvpid::handle_invvpid,
+            io::handle_io_instruction,
+            monitor_mwait::{handle_monitor, handle_mwait},
+            msr::{handle_msr_access, MsrAccessType},
+            mtf::handle_monitor_trap_flag,
+            nmi::handle_nmi_window,
+            preemption_timer::handle_preemption_timer,
+            rdtsc::{handle_rdtsc, handle_rdtscp},
+            rng::{handle_rdrand, handle_rdseed},
+            sipi::handle_sipi_signal,
+            smi::handle_smi,
+            triple_fault::handle_triple_fault,
+            vmcall::handle_vmcall,
+            vmfunc::handle_vmfunc,
+            vmx::handle_vmx_instruction,
+            xsetbv::handle_xsetbv,
+            ExitType,
+        },
+        vmfield,
+    },
+    spin::RwLock,
+};
+
+/// Handler for a VM exit, invoked on the processor the exit occurred on.
+pub type ExitHandler = fn(vm: &mut Vm) -> ExitType;
+
+/// The number of entries in the dispatch table, one more than the highest basic exit reason.
+const EXIT_REASON_COUNT: usize = VmxBasicExitReason::InstructionTimeout as usize + 1;
+
+/// The handler of each basic exit reason.
+static EXIT_HANDLERS: RwLock<[ExitHandler; EXIT_REASON_COUNT]> = RwLock::new(default_handlers());
+
+/// Builds the dispatch table with the default handler of each exit reason.
+#[rustfmt::skip]
+const fn default_handlers() -> [ExitHandler; EXIT_REASON_COUNT] {
+    use VmxBasicExitReason::*;
+
+    let mut handlers = [handle_unhandled_exit as ExitHandler; EXIT_REASON_COUNT];
+
+    handlers[ExceptionOrNmi as usize] = handle_exception;
+    handlers[InterruptWindow as usize] = handle_interrupt_window;
+    handlers[NmiWindow as usize] = handle_nmi_window;
+    handlers[TripleFault as usize] = handle_triple_fault;
+    handlers[InitSignal as usize] = |vm| handle_init_signal(&mut vm.guest_registers);
+    handlers[StartupIpi as usize] = handle_sipi_signal;
+    handlers[Hlt as usize] = |_| handle_halt();
+    handlers[Cpuid as usize] = handle_cpuid;
+    handlers[ControlRegisterAccesses as usize] = handle_cr_access;
+
+    // SMX instructions and RSM are not supported in the guest.
+    handlers[Getsec as usize] = |_| handle_undefined_opcode_exception();
+    handlers[Rsm as usize] = |_| handle_undefined_opcode_exception();
+
+    // Nested VMX is not supported, so the guest sees VMX as unavailable.
+    handlers[Vmclear as usize] = handle_vmx_instruction;
+    handlers[Vmlaunch as usize] = handle_vmx_instruction;
+    handlers[Vmptrld as usize] = handle_vmx_instruction;
+    handlers[Vmptrst as usize] = handle_vmx_instruction;
+    handlers[Vmread as usize] = handle_vmx_instruction;
+    handlers[Vmresume as usize] = handle_vmx_instruction;
+    handlers[Vmwrite as usize] = handle_vmx_instruction;
+    handlers[Vmxoff as usize] = handle_vmx_instruction;
+    handlers[Vmxon as usize] = handle_vmx_instruction;
+
+    // Only delivered under the dual-monitor treatment of SMIs, which is never activated.
+    handlers[IoSystemManagementInterrupt as usize] = |_| handle_smi();
+    handlers[OtherSmi as usize] = |_| handle_smi();
+
+    handlers[Rdmsr as usize] = |vm| handle_msr_access(vm, MsrAccessType::Read);
+    handlers[Wrmsr as usize] = |vm| handle_msr_access(vm, MsrAccessType::Write);
+    handlers[Invd as usize] = |vm| handle_invd(&mut vm.guest_registers);
+    handlers[Rdtsc as usize] = |vm| handle_rdtsc(&mut vm.guest_registers);
+    handlers[Rdtscp as usize] = handle_rdtscp;
+    handlers[Rdrand as usize] = handle_rdrand;
+    handlers[Rdseed as usize] = handle_rdseed;
+    handlers[EptViolation as usize] = handle_ept_violation;
+    handlers[EptMisconfiguration as usize] = handle_ept_misconfiguration;
+    handlers[Invept as usize] = |_| handle_invept();
+    handlers[Invvpid as usize] = |_| handle_invvpid();
+    handlers[Xsetbv as usize] = |vm| handle_xsetbv(&mut vm.guest_registers);
+    handlers[VmxPreemptionTimerExpired as usize] = handle_preemption_timer;
+    handlers[MonitorTrapFlag as usize] = handle_monitor_trap_flag;
+    handlers[Vmfunc as usize] = handle_vmfunc;
+    handlers[Vmcall as usize] = handle_vmcall;
+
+    // Only occur for ports trapped with `Vm::trap_io_port`.
+    handlers[IoInstruction as usize] = handle_io_instruction;
+
+    // Only occur once a `MwaitAction` other than `MwaitAction::Passthrough` is selected.
+    handlers[Monitor as usize] = handle_monitor;
+    handlers[Mwait as usize] = handle_mwait;
+
+    // Only occur once APIC virtualization is enabled.
+    handlers[ApicAccess as usize] = handle_apic_access;
+    handlers[ApicWrite as usize] = handle_apic_write;
+    handlers[VirtualizedEoi as usize] = handle_virtualized_eoi;
+
+    handlers
+}
+
+/// Invokes the handler registered for a VM exit.
+///
+/// The table lock is only held while the handler is looked up, so handlers may register other handlers.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `basic_exit_reason` - The basic exit reason of the VM exit.
+///
+/// # Returns
+///
+/// The `ExitType` returned by the handler.
+pub fn dispatch_exit(vm: &mut Vm, basic_exit_reason: VmxBasicExitReason) -> ExitType {
+    let handler = EXIT_HANDLERS.read()[basic_exit_reason as usize];
+    handler(vm)
+}
+
+/// Replaces the handler of an exit reason on all processors.
+///
+/// The new handler is used from the next VM exit with that reason on. Whether the exit actually occurs
+/// depends on the VM-execution controls, which are not changed.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The exit reason to handle.
+/// * `handler` - The new handler.
+///
+/// # Returns
+///
+/// The previous handler, so it can be called from the new one or registered again later.
+pub fn register_exit_handler(
+    basic_exit_reason: VmxBasicExitReason,
+    handler: ExitHandler,
+) -> ExitHandler {
+    core::mem::replace(
+        &mut EXIT_HANDLERS.write()[basic_exit_reason as usize],
+        handler,
+    )
+}
+
+/// Restores the default handler of an exit reason on all processors.
+///
+/// # Arguments
+///
+/// * `basic_exit_reason` - The exit reason whose handler to restore.
+pub fn reset_exit_handler(basic_exit_reason: VmxBasicExitReason) {
+    register_exit_handler(
+        basic_exit_reason,
+        default_handlers()[basic_exit_reason as usize],
+    );
+}
+
+/// Handles VM exits that have no handler.
+///
+/// The hypervisor cannot safely resume a guest whose VM exit it does not understand, so this panics
+/// with the name of the exit reason.
+pub fn handle_unhandled_exit(_vm: &mut Vm) -> ExitType {
+    let exit_reason = vmfield::ro::EXIT_REASON.read();
+
+    match VmxBasicExitReason::from_u32(exit_reason) {
+        Some(basic_exit_reason) => panic!(
+            "Unhandled VM exit reason: {} ({:?})",
+            basic_exit_reason, basic_exit_reason
+        ),
+        None => panic!("Unhandled VM exit reason: {:#x}", exit_reason),
+    }
+}
@@ -1,66 +1,351 @@
-use {
-    crate::intel::{
-        invept::invept_all_contexts, support::vmread, support::vmwrite, vm::Vm,
-        vmerror::EptViolationExitQualification, vmexit::ExitType,
+use crate::intel::{
+    decode::{ept_violation_access, store_operand},
+    ept::{
+        hooks::HookStrategy,
+        paging::{Ept, WxPolicy},
     },
-    x86::vmx::vmcs,
+    events::EventInjection,
+    guest::GuestId,
+    invept::invept_all_contexts,
+    shared::EptpSlot,
+    vm::Vm,
+    vmerror::EptViolationExitQualification,
+    vmexit::{
+        hv_assert, mtf::set_monitor_trap_flag, nmi::restore_nmi_blocking_after_iret, ExitType,
+    },
+    vmfield,
 };
 
 /// Handle VM exits for EPT violations. Violations are thrown whenever an operation is performed on an EPT entry that does not provide permissions to access that page.
+/// Repeated violations on the same page are only logged as periodic summaries, unless verbose logging is enabled in `ViolationThrottle`.
+/// A hook that keeps the guest faulting at the same address and RIP without progress is disabled, see `break_hook_livelock`.
+/// The EPTP swaps only depend on the permissions of the violated entry, so a 2MB hook swaps for its whole region like a 4KB hook for its page.
 /// 29.3.3.2 EPT Violations
 /// Table 28-7. Exit Qualification for EPT Violations
 #[rustfmt::skip]
 pub fn handle_ept_violation(vm: &mut Vm) -> ExitType {
-    log::debug!("Handling EPT Violation VM exit...");
-
-    let guest_physical_address = vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL);
-    log::debug!("EPT Violation: Guest Physical Address: {:#x}", guest_physical_address);
+    let guest_physical_address = vmfield::ro::GUEST_PHYSICAL_ADDR_FULL.read();
 
-    // Translate the page from a physical address to virtual so we can read its memory.
-    //let va = PhysicalAddress::va_from_pa(guest_physical_address);
-    //log::debug!("EPT Violation: Guest Virtual Address: {:#x}", va);
+    // Hot hooks cause thousands of violations per second, so only log the details of some of them.
+    let verbose = unsafe { vm.shared_data.as_ref() }.violation_throttle.record(guest_physical_address);
+    if verbose {
+        log::debug!("Handling EPT Violation VM exit...");
+        log::debug!("EPT Violation: Guest Physical Address: {:#x}", guest_physical_address);
+    }
 
     // Log the detailed information about the EPT violation
-    let exit_qualification_value = vmread(vmcs::ro::EXIT_QUALIFICATION);
+    let exit_qualification_value = vmfield::ro::EXIT_QUALIFICATION.read();
     let ept_violation_qualification = EptViolationExitQualification::from_exit_qualification(exit_qualification_value);
-    log::debug!("Exit Qualification for EPT Violations: {}", ept_violation_qualification);
+    if verbose {
+        log::debug!("Exit Qualification for EPT Violations: {}", ept_violation_qualification);
+    }
+
+    // The guest linear address is only reported if the violation was caused by a linear-address access.
+    if verbose && ept_violation_qualification.guest_linear_address_valid {
+        let guest_linear_address = vmfield::ro::GUEST_LINEAR_ADDR.read();
+        let access = if ept_violation_qualification.is_translated_linear_access() { "translated address" } else { "paging-structure entry" };
+        log::debug!("EPT Violation: Guest Linear Address: {:#x} (access to {})", guest_linear_address, access);
+    }
+
+    // The event being delivered when the violation occurred never reached the guest, so deliver it again on VM entry.
+    let reinjected = EventInjection::vmentry_reinject_vectoring_event();
+
+    // The faulting IRET will be re-executed, so the virtual-NMI blocking it cleared must be restored.
+    // The bit is undefined if the violation occurred during event delivery.
+    if !reinjected && ept_violation_qualification.nmi_unblocking_due_to_iret {
+        restore_nmi_blocking_after_iret();
+    }
+
+    // Self-heal a misconfigured hook instead of letting the guest fault on the same instruction forever.
+    if vm.violation_streak.record(guest_physical_address, vmfield::guest::RIP.read()) && break_hook_livelock(vm, guest_physical_address) {
+        return ExitType::Continue;
+    }
+
+    // The first write to a copy-on-write page copies it to its snapshot, after which the write is re-executed.
+    if ept_violation_qualification.data_write && !ept_violation_qualification.writable && handle_cow_write(vm, guest_physical_address) {
+        return ExitType::Continue;
+    }
+
+    // Hooks using the MTF strategy execute their shadow page one instruction at a time instead of swapping EPTPs.
+    if ept_violation_qualification.instruction_fetch && step_mtf_hook(vm, guest_physical_address) {
+        return ExitType::Continue;
+    }
+
+    // Report writes to tracked pages before the page is swapped back to the primary EPTP.
+    if ept_violation_qualification.data_write {
+        report_tracked_write(vm, guest_physical_address, &ept_violation_qualification);
+    }
+
+    // Under W^X, executing a page that is writable in the primary EPT means running freshly writable memory.
+    // Hooked pages are read-write only in the primary EPT by design, so their execution is expected.
+    if ept_violation_qualification.instruction_fetch && ept_violation_qualification.writable {
+        let shared_data = unsafe { vm.shared_data.as_ref() };
+        if shared_data.primary_ept.wx_policy() != WxPolicy::Disabled && !shared_data.hook_manager.lock().is_enabled(guest_physical_address) {
+            log::warn!("W^X: guest executes writable GPA {:#x} at RIP {:#x}", guest_physical_address, vmfield::guest::RIP.read());
+        }
+    }
 
     // If the page is Read/Write, then we need to swap it to the secondary EPTP
     if ept_violation_qualification.readable && ept_violation_qualification.writable && !ept_violation_qualification.executable {
-        //log::trace!("EPT Violation: Execute acccess attempted on Guest Physical Address: {:#x} / Guest Virtual Address: {:#x}", guest_physical_address, va);
         // Change to the secondary EPTP and invalidate the EPT cache.
         // The hooked page that is Execute-Only will be executed from the secondary EPTP.
         // if Read or Write occurs on that page, then a vmexit will occur
         // and we can swap the page back to the primary EPTP, (original page) with RW permissions.
-        let secondary_eptp = unsafe { vm.shared_data.as_ref().secondary_eptp };
-        vmwrite(vmcs::control::EPTP_FULL, secondary_eptp);
-        invept_all_contexts();
-        //invept_single_context(secondary_eptp);
+        hv_assert!(switch_eptp(vm, EptpSlot::SECONDARY, verbose), "The secondary EPTP of guest {} is not registered", vm.guest_id.value());
     }
 
-    // If the page is Execute-Only, then we need to swap it back to the primary EPTP
-    if !ept_violation_qualification.readable && !ept_violation_qualification.writable && ept_violation_qualification.executable {
+    // If the page is Execute-Only, then we need to swap it back to the primary EPTP.
+    // Without execute-only support, hooked pages are Read-Execute instead and only writes cause a violation.
+    if !ept_violation_qualification.writable && ept_violation_qualification.executable {
         // Change to the primary EPTP and invalidate the EPT cache.
         // The original page that is Read-Write-Only will be executed from the primary EPTP.
         // if Execute occurs on that page, then a vmexit will occur
         // and we can swap the page back to the secondary EPTP, (hooked page) with X permissions.
-        let primary_eptp = unsafe { vm.shared_data.as_ref().primary_eptp };
-        vmwrite(vmcs::control::EPTP_FULL, primary_eptp);
-        invept_all_contexts();
-        //invept_single_context(primary_eptp);
+        hv_assert!(switch_eptp(vm, EptpSlot::PRIMARY, verbose), "The primary EPTP of guest {} is not registered", vm.guest_id.value());
     }
 
-    log::debug!("EPT Violation handled successfully!");
+    if verbose {
+        log::debug!("EPT Violation handled successfully!");
+    }
 
     // Do not increment RIP, since we want it to execute the same instruction again.
     ExitType::Continue
 }
 
+/// Switches the current processor to the EPTP of the running guest registered in a slot.
+///
+/// The primary and secondary slots are always registered, so failing to switch to them is a bug.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `slot` - The slot of the EPTP to switch to.
+/// * `verbose` - Whether to log the views switched between.
+///
+/// # Returns
+///
+/// Whether the EPTP was switched. It is not if the slot is not registered.
+fn switch_eptp(vm: &Vm, slot: EptpSlot, verbose: bool) -> bool {
+    let previous_view = verbose.then(|| vm.current_ept_view());
+
+    if unsafe { vm.shared_data.as_ref() }
+        .set_active_eptp(vm.guest_id, slot)
+        .is_err()
+    {
+        return false;
+    }
+
+    if let Some(previous_view) = previous_view {
+        log::debug!(
+            "Processor {} swapped from {} to {}",
+            vm.apic_id,
+            previous_view,
+            vm.current_ept_view()
+        );
+    }
+
+    true
+}
+
+/// Disables the hook on a page the guest is livelocked on, restoring the original permissions.
+///
+/// Called once the processor has faulted `LIVELOCK_THRESHOLD` times in a row at the same guest physical
+/// address and RIP. Switches back to the primary EPTP, in which the unhooked page is fully accessible.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `guest_physical_address` - The guest physical address the processor keeps faulting on.
+///
+/// # Returns
+///
+/// Whether a hook was disabled and the primary EPTP activated. Livelocks on pages without an enabled
+/// hook are only logged.
+fn break_hook_livelock(vm: &mut Vm, guest_physical_address: u64) -> bool {
+    {
+        let mut epts = vm.lock_epts();
+
+        if !epts.hook_manager.is_enabled(guest_physical_address) {
+            log::error!(
+                "EPT violation livelock detected at GPA {:#x}, RIP {:#x}",
+                guest_physical_address,
+                vmfield::guest::RIP.read()
+            );
+            return false;
+        }
+
+        log::error!(
+            "Hook livelock detected at GPA {:#x}, RIP {:#x}, disabling the hook",
+            guest_physical_address,
+            vmfield::guest::RIP.read()
+        );
+
+        if let Err(e) = epts.hook_manager.set_enabled(
+            epts.primary_ept,
+            epts.secondary_ept,
+            guest_physical_address,
+            false,
+            &unsafe { vm.shared_data.as_ref() }.reserved_regions,
+        ) {
+            log::error!(
+                "Failed to disable the hook at {:#x}: {}",
+                guest_physical_address,
+                e
+            );
+            return false;
+        }
+    }
+
+    vm.violation_streak.reset();
+
+    // The hook is disabled either way. If the switch failed, the violation is handled as usual.
+    switch_eptp(vm, EptpSlot::PRIMARY, true)
+}
+
+/// Copies the page being written to into its snapshot if it is write-protected for copy-on-write.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `guest_physical_address` - The guest physical address being written to.
+///
+/// # Returns
+///
+/// Whether the page is write-protected for copy-on-write and is now writable in both EPTs. A page that
+/// stays non-writable in one of them, e.g. a hooked page, is left to the usual handling.
+fn handle_cow_write(vm: &mut Vm, guest_physical_address: u64) -> bool {
+    let epts = vm.lock_epts();
+
+    match unsafe { vm.shared_data.as_ref() }
+        .cow_tracker
+        .copy_on_write(epts.primary_ept, epts.secondary_ept, guest_physical_address)
+    {
+        Ok(false) => false,
+        Ok(true) => {
+            if let Err(e) = invept_all_contexts() {
+                log::error!("Failed to invalidate EPT contexts: {}", e);
+            }
+            true
+        }
+        Err(e) => {
+            log::error!(
+                "Failed to copy-on-write page at {:#x}: {}",
+                guest_physical_address,
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Maps the shadow page of an MTF hook and single-steps the instruction being fetched from it.
+///
+/// `handle_monitor_trap_flag` maps the original page back after the instruction.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `guest_physical_address` - The guest physical address being executed.
+///
+/// # Returns
+///
+/// Whether the page has an enabled MTF hook and the instruction is being stepped.
+fn step_mtf_hook(vm: &mut Vm, guest_physical_address: u64) -> bool {
+    {
+        let mut epts = vm.lock_epts();
+
+        if epts.hook_manager.strategy(guest_physical_address) != Some(HookStrategy::Mtf) {
+            return false;
+        }
+
+        if let Err(e) = epts.hook_manager.begin_step(
+            epts.primary_ept,
+            epts.secondary_ept,
+            guest_physical_address,
+            &unsafe { vm.shared_data.as_ref() }.reserved_regions,
+        ) {
+            log::error!(
+                "Failed to map the shadow page of the hook at {:#x}: {}",
+                guest_physical_address,
+                e
+            );
+            return false;
+        }
+    }
+
+    if let Err(e) = set_monitor_trap_flag(true) {
+        log::error!(
+            "Failed to single-step the hook at {:#x}: {}",
+            guest_physical_address,
+            e
+        );
+    }
+    vm.stepped_hook_page = Some(guest_physical_address);
+
+    if let Err(e) = invept_all_contexts() {
+        log::error!("Failed to invalidate EPT contexts: {}", e);
+    }
+
+    true
+}
+
+/// Invokes the write-tracking callback registered for the page being written to, if any.
+///
+/// The start and the written bytes are decoded from the faulting instruction. If the instruction
+/// cannot be decoded, the callback is invoked with the reported guest physical address, and if the
+/// written value cannot be determined, with an empty slice.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `guest_physical_address` - The guest physical address being written to.
+/// * `qualification` - The exit qualification of the EPT violation.
+fn report_tracked_write(
+    vm: &Vm,
+    guest_physical_address: u64,
+    qualification: &EptViolationExitQualification,
+) {
+    let Some(callback) = (unsafe {
+        vm.shared_data
+            .as_ref()
+            .write_tracker
+            .find(guest_physical_address)
+    }) else {
+        return;
+    };
+
+    let Some(instruction) = vm.decode_current_instruction() else {
+        log::trace!(
+            "Failed to decode the write to tracked page at {:#x}",
+            guest_physical_address
+        );
+        callback(guest_physical_address, &[]);
+        return;
+    };
+
+    let access = ept_violation_access(vm, &instruction, qualification);
+    let store = store_operand(vm, &instruction);
+    log::trace!(
+        "Write to tracked page at {:#x}: {:x?}, {:x?}",
+        guest_physical_address,
+        access,
+        store
+    );
+
+    let guest_pa = access.map_or(guest_physical_address, |access| access.guest_pa);
+    match store.and_then(|store| store.value.map(|value| (value.to_le_bytes(), store.size))) {
+        Some((bytes, size)) => callback(guest_pa, &bytes[..size]),
+        None => callback(guest_pa, &[]),
+    }
+}
+
 /// Handles an EPT misconfiguration VM exit.
 ///
 /// This function is invoked when an EPT misconfiguration VM exit occurs, indicating
-/// an issue with the Extended Page Tables (EPT) setup. It logs the faulting
-/// guest physical address and triggers a breakpoint exception for immediate debugging.
+/// an issue with the Extended Page Tables (EPT) setup. It walks the entries of the active EPT
+/// that translate the faulting guest physical address, logs the malformed entry and the reason it
+/// is malformed (write without read access, unsupported execute-only access, reserved bits, or a
+/// reserved memory type), and triggers a breakpoint exception for immediate debugging.
 ///
 /// # Safety
 ///
@@ -74,14 +359,24 @@ pub fn handle_ept_violation(vm: &mut Vm) -> ExitType {
 ///
 /// Reference: 29.3.3.1 EPT Misconfigurations
 #[rustfmt::skip]
-pub fn handle_ept_misconfiguration() -> ExitType {
+pub fn handle_ept_misconfiguration(vm: &mut Vm) -> ExitType {
     log::debug!("Handling EPT Misconfiguration VM exit...");
 
     // Retrieve the guest physical address that caused the EPT misconfiguration.
-    let guest_physical_address = vmread(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL);
+    let guest_physical_address = vmfield::ro::GUEST_PHYSICAL_ADDR_FULL.read();
+    let eptp = vmfield::control::EPTP_FULL.read();
 
     // Log the critical error information.
-    log::trace!("EPT Misconfiguration: Faulting guest address: {:#x}. This is a critical error that cannot be safely ignored.", guest_physical_address);
+    log::error!("EPT Misconfiguration: Faulting guest address: {:#x}, EPTP: {:#x}. This is a critical error that cannot be safely ignored.", guest_physical_address, eptp);
+
+    // Point at the exact malformed entry, if the active EPT is one we own.
+    match active_ept(vm, eptp) {
+        Some(ept) => match ept.find_misconfiguration(guest_physical_address) {
+            Some(misconfiguration) => log::error!("EPT Misconfiguration: {:?} entry {} ({:#x}): {}", misconfiguration.level, misconfiguration.index, misconfiguration.entry, misconfiguration.cause),
+            None => log::error!("EPT Misconfiguration: No malformed entry found, the EPT may have been modified since the VM exit"),
+        },
+        None => log::error!("EPT Misconfiguration: The active EPTP does not belong to the primary or secondary EPT of guest {}", vm.guest_id.value()),
+    }
 
     // Trigger a breakpoint exception to halt execution for debugging.
     // Continuing after this point is unsafe due to the potential for system instability.
@@ -93,3 +388,46 @@ pub fn handle_ept_misconfiguration() -> ExitType {
     // We may chose to exit the hypervisor here instead of triggering a breakpoint exception.
     return ExitType::ExitHypervisor;
 }
+
+/// Finds the EPT of the running guest that an EPTP points to.
+///
+/// Only the primary and secondary EPTs are owned by the hypervisor. EPTPs registered in other slots
+/// are not known to belong to an `Ept`.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+/// * `eptp` - The EPTP to look up.
+///
+/// # Returns
+///
+/// The EPT, or `None` if the EPTP does not point to the primary or secondary EPT of the guest.
+fn active_ept(vm: &Vm, eptp: u64) -> Option<&Ept> {
+    let shared_data = unsafe { vm.shared_data.as_ref() };
+
+    let (primary_ept, secondary_ept, primary_eptp, secondary_eptp) = match vm.guest_id {
+        GuestId::DEFAULT => (
+            &*shared_data.primary_ept,
+            &*shared_data.secondary_ept,
+            shared_data.eptp(EptpSlot::PRIMARY).ok()?,
+            shared_data.eptp(EptpSlot::SECONDARY).ok()?,
+        ),
+        guest_id => {
+            let epts = shared_data.guests.get(guest_id)?;
+            (
+                &*epts.primary_ept,
+                &*epts.secondary_ept,
+                epts.primary_eptp,
+                epts.secondary_eptp,
+            )
+        }
+    };
+
+    if eptp == primary_eptp {
+        Some(primary_ept)
+    } else if eptp == secondary_eptp {
+        Some(secondary_ept)
+    } else {
+        None
+    }
+}
@@ -8,9 +8,15 @@ use {
         support::vmread,
         vm::Vm,
         vmerror::{
-            EptViolationExitQualification, ExceptionInterrupt, VmExitInterruptionInformation,
+            EptViolationExitQualification, ExceptionInterrupt, InterruptionType,
+            VmExitInterruptionInformation,
+        },
+        vmexit::{
+            hv_bail,
+            nmi::{handle_nmi, restore_nmi_blocking_after_iret},
+            pseudo::handle_pseudo_instruction,
+            ExitType,
         },
-        vmexit::ExitType,
     },
     x86::vmx::vmcs,
 };
@@ -19,7 +25,12 @@ use {
 ///
 /// This function is called when the VM exits due to an exception or NMI.
 /// It determines the type of exception, handles it accordingly, and prepares
-/// the VM for resumption.
+/// the VM for resumption. NMIs are forwarded to `handle_nmi`, which queues them for
+/// injection once the guest can receive them.
+///
+/// If the exception was caused by a fault during IRET, the processor reports "NMI unblocking
+/// due to IRET" and the guest's virtual-NMI blocking has already been cleared. Blocking by NMI
+/// is restored before the fault is re-injected so the guest cannot take a nested NMI.
 ///
 /// # Arguments
 ///
@@ -29,14 +40,19 @@ use {
 /// # Returns
 ///
 /// * `ExitType::Continue` - Indicating that VM execution should continue after handling the exception
+/// * `ExitType::ExitHypervisor` - If the exception is not one the handler knows how to reflect
 #[rustfmt::skip]
-pub fn handle_exception(_vm: &mut Vm) -> ExitType {
+pub fn handle_exception(vm: &mut Vm) -> ExitType {
     log::debug!("Handling ExceptionOrNmi VM exit...");
 
     let interruption_info_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
     let interruption_error_code_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
 
     if let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) {
+        if interruption_info.interruption_type == InterruptionType::NonMaskableInterrupt {
+            return handle_nmi(vm);
+        }
+
         if let Some(exception_interrupt) = ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
             match exception_interrupt {
                 ExceptionInterrupt::PageFault => {
@@ -53,17 +69,25 @@ pub fn handle_exception(_vm: &mut Vm) -> ExitType {
                     EventInjection::vmentry_inject_bp();
                 },
                 ExceptionInterrupt::InvalidOpcode => {
+                    // Registered pseudo-instructions are performed and skipped, any other #UD belongs to the guest.
+                    if handle_pseudo_instruction(vm) {
+                        return ExitType::Continue;
+                    }
                     EventInjection::vmentry_inject_ud();
                 },
                 _ => {
-                    panic!("Unhandled exception: {:?}", exception_interrupt);
+                    hv_bail!("Unhandled exception: {:?}", exception_interrupt);
                 }
             }
+
+            if interruption_info.nmi_unblocking_due_to_iret {
+                restore_nmi_blocking_after_iret();
+            }
         } else {
-            panic!("Invalid Exception Interrupt Vector: {}", interruption_info.vector);
+            hv_bail!("Invalid Exception Interrupt Vector: {}", interruption_info.vector);
         }
     } else {
-        panic!("Invalid VM Exit Interruption Information");
+        hv_bail!("Invalid VM Exit Interruption Information");
     }
 
     log::debug!("Exception Handled successfully!");
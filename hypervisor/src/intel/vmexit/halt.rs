@@ -4,18 +4,28 @@
 //! like `HLT`, facilitating appropriate responses and actions in a virtualized environment.
 //! Essential for managing VM execution flow and state in response to guest actions.
 
-use crate::intel::vmexit::ExitType;
+use crate::intel::{state::GuestActivityState, vmexit::ExitType, vmfield};
 
 /// Handles the VM exit caused by a `HLT` instruction.
 ///
 /// Responds to a `HLT` instruction executed by the guest by incrementing the instruction
-/// pointer (RIP) to continue execution after the `HLT`. This ensures the virtual machine
-/// does not halt and continues processing subsequent instructions.
+/// pointer (RIP) past the `HLT` and entering the guest in the HLT activity state, so the processor
+/// stays halted until an interrupt, NMI, or VM exit wakes it up, like on hardware. If the processor
+/// cannot enter the HLT state on VM entry, the guest continues with the next instruction instead.
+///
+/// RIP must point past the `HLT` when the guest is entered in the HLT state, and advancing it ends
+/// any blocking by STI, which the HLT state does not allow.
 ///
 /// # Returns
 ///
 /// Returns `ExitType::IncrementRIP` to indicate that the VM's instruction pointer should
 /// be incremented to continue execution.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3.1.5 Checks on Guest Non-Register State
 pub fn handle_halt() -> ExitType {
+    if GuestActivityState::Hlt.is_supported() {
+        vmfield::guest::ACTIVITY_STATE.write(GuestActivityState::Hlt as u32);
+    }
+
     ExitType::IncrementRIP
 }
@@ -16,6 +16,7 @@ use {
             vmwrite,
         },
         vmexit::ExitType,
+        vmfield,
     },
     x86::{
         bits64::rflags,
@@ -204,17 +205,32 @@ pub fn handle_init_signal(guest_registers: &mut GuestRegisters) -> ExitType {
     vmwrite(vmcs::control::VMENTRY_CONTROLS, vmentry_controls);
 
     //
-    // Invalidate TLB for current VPID
+    // Invalidate TLB for current VPID, if VPIDs are enabled
     //
-    invvpid_single_context(vmread(vmcs::control::VPID) as _);
+    let vpid = vmfield::control::VPID.try_read().unwrap_or(0);
+    if vpid != 0 {
+        invvpid_single_context(vpid);
+    }
 
     //
-    // Set the activity state to "Wait for SIPI".
+    // INIT discards blocking by STI, MOV SS, and NMI, and any pending debug exceptions. Blocking by
+    // STI or MOV SS also requires the active state, so it must not be carried into wait-for-SIPI.
+    //
+    vmfield::guest::INTERRUPTIBILITY_STATE.write(0);
+    vmfield::guest::PENDING_DBG_EXCEPTIONS.write(0);
+
     //
-    vmwrite(
-        vmcs::guest::ACTIVITY_STATE,
-        GuestActivityState::WaitForSipi as u32,
-    );
+    // Set the activity state to "Wait for SIPI". Without support for it, the processor stays active
+    // in the reset state and starts executing at the reset vector instead of waiting for a SIPI.
+    //
+    if GuestActivityState::WaitForSipi.is_supported() {
+        vmwrite(
+            vmcs::guest::ACTIVITY_STATE,
+            GuestActivityState::WaitForSipi as u32,
+        );
+    } else {
+        log::warn!("The wait-for-SIPI activity state is not supported, the processor stays active after INIT");
+    }
 
     ExitType::Continue
 }
@@ -244,6 +260,14 @@ fn adjust_guest_cr0(cr0: Cr0) -> u64 {
         // if the guest is unrestricted, only set these bits if the guest requested them to be set
         new_cr0 &= !(Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING);
         new_cr0 |= cr0 & (Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING);
+    } else if !cr0.contains(Cr0::CR0_PROTECTED_MODE | Cr0::CR0_ENABLE_PAGING) {
+        // Without unrestricted guest, IA32_VMX_CR0_FIXED0 forces CR0.PE and CR0.PG to 1, so the
+        // guest cannot enter the real-mode state it asked for.
+        log::warn!(
+            "Unrestricted guest is disabled, guest CR0 {:#x} is forced to {:#x}",
+            cr0.bits(),
+            new_cr0.bits()
+        );
     }
 
     new_cr0.bits() as u64
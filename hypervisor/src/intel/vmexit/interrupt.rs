@@ -0,0 +1,61 @@
+//! Injects external interrupts queued by the hypervisor into the guest.
+//!
+//! An interrupt can only be injected while the guest is interruptible: RFLAGS.IF is set and there
+//! is no blocking by STI or MOV SS. `Vm::inject_interrupt` injects the vector right away if that
+//! is the case. Otherwise, it and `Vm::queue_interrupt` queue the vector and enable
+//! "interrupt-window exiting", which causes a VM exit at the beginning of the first instruction at
+//! which the guest is interruptible. The interrupt-window exit then injects the highest queued
+//! vector, one per exit, and disables the control once the queue is empty.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.6.2 Processor-Based VM-Execution Controls
+//! and 26.7.5 Interrupt-Window Exiting and Virtual-Interrupt Delivery
+
+use {
+    crate::intel::{events::EventInjection, vm::Vm, vmexit::ExitType, vmfield},
+    x86::vmx::vmcs,
+};
+
+/// Handles a VM exit caused by the interrupt window opening.
+///
+/// Injects the highest queued interrupt into the guest. Interrupt-window exiting stays enabled
+/// while more interrupts are pending, so each of them is injected on its own exit.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - No guest instruction caused this exit, so RIP is not advanced.
+pub fn handle_interrupt_window(vm: &mut Vm) -> ExitType {
+    log::debug!("Handling interrupt window VM exit...");
+
+    if let Some(vector) = vm.pending_interrupts.pop() {
+        EventInjection::vmentry_inject_interrupt(vector);
+        log::trace!("Injected interrupt {:#x}", vector);
+    }
+
+    if vm.pending_interrupts.is_empty() {
+        set_interrupt_window_exiting(false);
+    }
+
+    ExitType::Continue
+}
+
+/// Enables or disables the "interrupt-window exiting" primary processor-based VM-execution control.
+///
+/// # Arguments
+///
+/// * `enable` - Whether VM exits should occur when the guest can receive an external interrupt.
+pub fn set_interrupt_window_exiting(enable: bool) {
+    let mut controls = vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read();
+    let interrupt_window_exiting = vmcs::control::PrimaryControls::INTERRUPT_WINDOW_EXITING.bits();
+
+    if enable {
+        controls |= interrupt_window_exiting;
+    } else {
+        controls &= !interrupt_window_exiting;
+    }
+
+    vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.write(controls);
+}
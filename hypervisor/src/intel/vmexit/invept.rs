@@ -13,7 +13,9 @@ pub fn handle_invept() -> ExitType {
     log::debug!("Handling INVEPT VM exit...");
 
     // Invalidate all EPT contexts to sync guest VM memory accesses with the host.
-    invept_all_contexts();
+    if let Err(e) = invept_all_contexts() {
+        log::error!("Failed to invalidate EPT contexts: {}", e);
+    }
 
     log::debug!("INVEPT VM exit handled successfully!");
 
@@ -0,0 +1,109 @@
+//! Handles VM exits caused by I/O instructions.
+//!
+//! Only ports trapped with `Vm::trap_io_port` cause VM exits, since the I/O bitmaps pass all other
+//! ports through. The default handler logs the access and performs it on the physical port, so a
+//! trapped port keeps working. To emulate or filter a port instead, register a custom handler for
+//! `VmxBasicExitReason::IoInstruction` with `register_exit_handler` and decode the access with
+//! `IoExitQualification`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+
+use crate::intel::{
+    capture::Register,
+    support::{inb, inl, inw, outb, outl, outw},
+    vm::Vm,
+    vmexit::{dispatch::handle_unhandled_exit, ExitType},
+    vmfield,
+};
+
+/// The direction of an I/O instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoDirection {
+    /// `OUT` or `OUTS`.
+    Out,
+    /// `IN` or `INS`.
+    In,
+}
+
+/// The exit qualification of an I/O instruction VM exit.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 28-5. Exit Qualification for I/O Instructions
+#[derive(Debug, Clone, Copy)]
+pub struct IoExitQualification {
+    /// The size of the access in bytes: 1, 2, or 4.
+    pub size: usize,
+    /// Whether the instruction reads from or writes to the port.
+    pub direction: IoDirection,
+    /// Whether the instruction is `INS` or `OUTS`.
+    pub string: bool,
+    /// Whether the string instruction has a `REP` prefix.
+    pub rep: bool,
+    /// Whether the port is encoded as an immediate, as opposed to DX.
+    pub immediate: bool,
+    /// The I/O port.
+    pub port: u16,
+}
+
+impl IoExitQualification {
+    /// Decodes the raw 64-bit exit qualification value.
+    pub fn from_exit_qualification(value: u64) -> Self {
+        Self {
+            size: (value & 0x7) as usize + 1,
+            direction: match value & (1 << 3) != 0 {
+                true => IoDirection::In,
+                false => IoDirection::Out,
+            },
+            string: value & (1 << 4) != 0,
+            rep: value & (1 << 5) != 0,
+            immediate: value & (1 << 6) != 0,
+            port: (value >> 16) as u16,
+        }
+    }
+}
+
+/// Handles an I/O instruction VM exit by performing the access on the physical port.
+///
+/// `IN` writes the value to AL, AX, or EAX, leaving the rest of RAX unchanged except that a 32-bit
+/// `IN` clears bits 63:32, like on hardware. String instructions are not emulated.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the I/O instruction.
+pub fn handle_io_instruction(vm: &mut Vm) -> ExitType {
+    let qualification =
+        IoExitQualification::from_exit_qualification(vmfield::ro::EXIT_QUALIFICATION.read());
+    log::trace!("Handling I/O instruction VM exit: {:x?}", qualification);
+
+    if qualification.string {
+        log::error!(
+            "String I/O to trapped port {:#x} cannot be emulated",
+            qualification.port
+        );
+        return handle_unhandled_exit(vm);
+    }
+
+    let port = qualification.port;
+    let rax = vm.guest_reg(Register::Rax);
+
+    match qualification.direction {
+        IoDirection::In => {
+            let rax = match qualification.size {
+                1 => (rax & !0xff) | u64::from(inb(port)),
+                2 => (rax & !0xffff) | u64::from(inw(port)),
+                _ => u64::from(inl(port)),
+            };
+            vm.set_guest_reg(Register::Rax, rax);
+        }
+        IoDirection::Out => match qualification.size {
+            1 => outb(port, rax as u8),
+            2 => outw(port, rax as u16),
+            _ => outl(port, rax as u32),
+        },
+    }
+
+    ExitType::IncrementRIP
+}
@@ -1,20 +1,71 @@
+pub mod apic;
 pub mod cpuid;
+pub mod cr;
+pub mod dispatch;
 pub mod ept;
 pub mod exception;
 pub mod halt;
 pub mod init;
+pub mod interrupt;
 pub mod invd;
 pub mod invept;
 pub mod invvpid;
+pub mod io;
+pub mod monitor_mwait;
 pub mod msr;
+pub mod mtf;
+pub mod nmi;
+pub mod preemption_timer;
+pub mod pseudo;
 pub mod rdtsc;
+pub mod rng;
 pub mod sipi;
+pub mod smi;
+pub mod triple_fault;
+pub mod vmcall;
+pub mod vmfunc;
+pub mod vmx;
 pub mod xsetbv;
 
 /// Represents the type of VM exit.
 #[derive(PartialOrd, PartialEq)]
 pub enum ExitType {
+    /// The handler could not handle the VM exit, e.g. because an `hv_assert!` failed. A #UD is injected
+    /// at the instruction that caused the VM exit, unless the handler already queued an event, and
+    /// the exit context is logged for the first few failures of each processor.
     ExitHypervisor,
     IncrementRIP,
     Continue,
 }
+
+/// Checks an invariant in a VM-exit handler without panicking.
+///
+/// The panic handler never returns, so a panic in a VM-exit handler hangs the processor for good.
+/// If the condition is false, this logs the message with the file and line of the assertion instead,
+/// and returns `ExitType::ExitHypervisor` from the handler.
+///
+/// Can only be used in functions returning `ExitType`. Without a message, the condition is logged.
+pub macro hv_assert {
+    ($condition:expr $(,)?) => {
+        $crate::intel::vmexit::hv_assert!($condition, "{}", stringify!($condition))
+    },
+    ($condition:expr, $($arg:tt)+) => {
+        if !$condition {
+            $crate::intel::vmexit::hv_bail!($($arg)+);
+        }
+    },
+}
+
+/// Fails a VM-exit handler without panicking, like an `hv_assert!` whose condition is false.
+///
+/// Logs the message with the file and line and returns `ExitType::ExitHypervisor` from the handler.
+/// Use it where a handler would otherwise call `panic!` or `unreachable!`.
+pub macro hv_bail($($arg:tt)+) {{
+    log::error!(
+        "VM-exit handler failed at {}:{}: {}",
+        file!(),
+        line!(),
+        format_args!($($arg)+)
+    );
+    return $crate::intel::vmexit::ExitType::ExitHypervisor;
+}}
@@ -0,0 +1,153 @@
+//! Handles VM exits caused by MONITOR and MWAIT.
+//!
+//! By default, MONITOR and MWAIT run natively and never exit. With a `MwaitAction` other than
+//! `MwaitAction::Passthrough`, the "MONITOR exiting" and "MWAIT exiting" controls are enabled on
+//! processors virtualized afterwards, so the guest cannot park a processor in MWAIT outside of the
+//! hypervisor's control, e.g. to wait out an interception:
+//!
+//! - `MwaitAction::Idle` virtualizes MWAIT as an idle that wakes up immediately. The architecture
+//!   allows MWAIT to exit for implementation-specific events, so guests already re-check their
+//!   wake-up condition in a loop, like after an emulated `HLT`.
+//! - `MwaitAction::Deny` injects #UD for both instructions and hides CPUID.01H:ECX.MONITOR[bit 3],
+//!   so guests that check CPUID fall back to `HLT` or polling.
+//!
+//! Both controls are always enabled together, since a handler for only one of them would leave the
+//! other instruction without one, which crashes the hypervisor when it exits.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.1.3 Instructions That Cause VM Exits Conditionally
+
+use {
+    crate::intel::{
+        capture::Register,
+        controls::{is_vmx_control_supported, VmxControl},
+        events::EventInjection,
+        vm::Vm,
+        vmexit::{exception::handle_undefined_opcode_exception, ExitType},
+        vmfield,
+    },
+    core::sync::atomic::{AtomicU8, Ordering},
+    x86::vmx::vmcs,
+};
+
+/// The primary processor-based control that causes MONITOR to exit.
+const MONITOR_EXITING: u32 = vmcs::control::PrimaryControls::MONITOR_EXITING.bits();
+
+/// The primary processor-based control that causes MWAIT to exit.
+const MWAIT_EXITING: u32 = vmcs::control::PrimaryControls::MWAIT_EXITING.bits();
+
+/// ECX bit 0 of MWAIT: treat interrupts as break events even if masked by RFLAGS.IF.
+const MWAIT_INTERRUPT_BREAK_EVENT: u64 = 1 << 0;
+
+/// How the hypervisor treats MONITOR and MWAIT executed by the guest.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MwaitAction {
+    /// MONITOR and MWAIT run natively without exiting. This is the default.
+    Passthrough = 0,
+
+    /// MONITOR is skipped and MWAIT is emulated as an idle that wakes up immediately.
+    Idle = 1,
+
+    /// MONITOR and MWAIT raise #UD, and MONITOR/MWAIT support is hidden from CPUID.
+    Deny = 2,
+}
+
+/// The treatment of MONITOR and MWAIT, as a `MwaitAction`.
+static MWAIT_ACTION: AtomicU8 = AtomicU8::new(MwaitAction::Passthrough as u8);
+
+/// Sets how MONITOR and MWAIT are treated on all processors.
+///
+/// The exiting controls are only written while the VMCS is set up, so changing between
+/// `MwaitAction::Passthrough` and the other actions takes effect on processors virtualized
+/// afterwards. Changing between `MwaitAction::Idle` and `MwaitAction::Deny` takes effect immediately.
+///
+/// # Arguments
+///
+/// * `action` - The new action.
+pub fn set_mwait_action(action: MwaitAction) {
+    MWAIT_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// Returns how MONITOR and MWAIT are treated.
+pub fn mwait_action() -> MwaitAction {
+    match MWAIT_ACTION.load(Ordering::Relaxed) {
+        1 => MwaitAction::Idle,
+        2 => MwaitAction::Deny,
+        _ => MwaitAction::Passthrough,
+    }
+}
+
+/// Enables or disables MONITOR and MWAIT exiting in the current VMCS, depending on the `MwaitAction`.
+///
+/// Must be called after the primary processor-based controls have been written. The controls are
+/// only enabled if the processor supports both of them, otherwise the instructions keep running natively.
+pub fn setup_monitor_mwait_exiting() {
+    let mut controls = vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read();
+    let exiting = MONITOR_EXITING | MWAIT_EXITING;
+
+    if mwait_action() == MwaitAction::Passthrough {
+        controls &= !exiting;
+    } else if is_vmx_control_supported(VmxControl::ProcessorBased, exiting as u64) {
+        controls |= exiting;
+    } else {
+        log::warn!("MONITOR and MWAIT exiting are not supported, the guest executes them natively");
+    }
+
+    vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.write(controls);
+}
+
+/// Handles the MONITOR VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `MONITOR` instruction, unless it is denied.
+/// * `ExitType::Continue` - If #UD was injected.
+pub fn handle_monitor(vm: &mut Vm) -> ExitType {
+    log::trace!(
+        "Handling MONITOR VM exit for address {:#x}...",
+        vm.guest_reg(Register::Rax)
+    );
+
+    match mwait_action() {
+        MwaitAction::Deny => handle_undefined_opcode_exception(),
+        _ => ExitType::IncrementRIP,
+    }
+}
+
+/// Handles the MWAIT VM exit.
+///
+/// The hints in EAX only select the C-state to enter, which an immediate wake-up never reaches. ECX
+/// bit 0 is the only extension defined, so other set bits raise #GP(0) like on hardware.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `MWAIT` instruction, unless it is denied.
+/// * `ExitType::Continue` - If #UD or #GP was injected.
+pub fn handle_mwait(vm: &mut Vm) -> ExitType {
+    let hints = vm.guest_reg(Register::Rax) as u32;
+    let extensions = vm.guest_reg(Register::Rcx) as u32 as u64;
+    log::trace!(
+        "Handling MWAIT VM exit with hints {:#x} and extensions {:#x}...",
+        hints,
+        extensions
+    );
+
+    if mwait_action() == MwaitAction::Deny {
+        return handle_undefined_opcode_exception();
+    }
+
+    if extensions & !MWAIT_INTERRUPT_BREAK_EVENT != 0 {
+        EventInjection::vmentry_inject_gp(0);
+        return ExitType::Continue;
+    }
+
+    ExitType::IncrementRIP
+}
@@ -1,8 +1,259 @@
 //! Provides virtual machine management capabilities, specifically for handling MSR
 //! read and write operations. It ensures that guest MSR accesses are properly
 //! intercepted and handled, with support for injecting faults for unauthorized accesses.
+//!
+//! IA32_FEATURE_CONTROL is always intercepted, so the guest can be shown a shadow value instead of
+//! the real one, e.g. locked with VMX disabled to turn away nested VMX attempts.
+//!
+//! Writes to IA32_MISC_ENABLE are intercepted as well. Toggling its "Limit CPUID Maxval" bit changes
+//! the maximum basic leaf reported by `CPUID` leaf 0, so the `CpuidCache` is invalidated.
+//!
+//! Independently of that, the `MsrAudit` in `SharedData` records guest writes to a configurable set
+//! of MSRs without changing how they are handled, to see what the guest does to sensitive MSRs.
 
-use crate::intel::{capture::GuestRegisters, events::EventInjection, vmexit::ExitType};
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            capture::Register, events::EventInjection, reserved::ReservedRegions, vm::Vm,
+            vmexit::ExitType,
+        },
+    },
+    alloc::{boxed::Box, vec},
+    core::sync::atomic::{AtomicBool, Ordering},
+    spin::Mutex,
+    x86::msr::{IA32_FEATURE_CONTROL, IA32_MISC_ENABLE},
+};
+
+/// IA32_FEATURE_CONTROL.Lock (bit 0). Once set, writes to the MSR raise #GP until the next reset.
+const FEATURE_CONTROL_LOCK: u64 = 1 << 0;
+
+/// IA32_MISC_ENABLE.Limit CPUID Maxval (bit 22). When set, `CPUID` leaf 0 reports 2 as the maximum
+/// basic leaf.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 2-2. IA-32 Architectural MSRs
+const MISC_ENABLE_LIMIT_CPUID_MAXVAL: u64 = 1 << 22;
+
+/// A value of IA32_FEATURE_CONTROL that is locked with VMX disabled both inside and outside SMX.
+/// Guests that check it before executing VMXON see that VMX is unavailable.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 24.7 ENABLING AND ENTERING VMX OPERATION
+pub const FEATURE_CONTROL_LOCKED_WITHOUT_VMX: u64 = FEATURE_CONTROL_LOCK;
+
+/// The value of IA32_FEATURE_CONTROL shown to the guest, or `None` to show the real value.
+static FEATURE_CONTROL_SHADOW: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Sets the value of IA32_FEATURE_CONTROL shown to the guest on all processors.
+///
+/// The hypervisor itself needs the real MSR to stay locked with VMX enabled, so guest writes never
+/// reach it. While the shadow value is unlocked, guest writes update the shadow value, like they
+/// would update the MSR before firmware locks it. Once it is locked, writes raise #GP.
+///
+/// # Arguments
+///
+/// * `value` - The value to show, e.g. `FEATURE_CONTROL_LOCKED_WITHOUT_VMX`, or `None` to reflect the
+///   real (locked) value. `None` is the default.
+pub fn set_feature_control_shadow(value: Option<u64>) {
+    *FEATURE_CONTROL_SHADOW.lock() = value;
+}
+
+/// The number of MSRs whose writes can be audited.
+pub const MAX_AUDITED_MSRS: usize = 32;
+
+/// The number of MSR writes kept in the `MsrAudit` log.
+pub const MSR_AUDIT_LENGTH: usize = 256;
+
+/// MSRs that can be written but not read. Reading them raises #GP, so the `MsrAudit` does not record
+/// their old value.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 2-2. IA-32 Architectural MSRs
+const WRITE_ONLY_MSRS: [u32; 4] = [
+    0x49,  // IA32_PRED_CMD
+    0x10b, // IA32_FLUSH_CMD
+    0x80b, // IA32_X2APIC_EOI
+    0x83f, // IA32_X2APIC_SELF_IPI
+];
+
+/// A guest write to an audited MSR.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsrWrite {
+    /// The MSR address.
+    pub msr: u32,
+    /// The initial APIC ID of the processor that wrote the MSR.
+    pub apic_id: u32,
+    /// The value of the MSR before the write, as seen by the guest, or `None` if the MSR is write-only.
+    pub old_value: Option<u64>,
+    /// The value written by the guest.
+    pub new_value: u64,
+    /// The guest RIP of the `WRMSR` instruction.
+    pub guest_rip: u64,
+}
+
+/// A circular buffer of the last `MSR_AUDIT_LENGTH` audited MSR writes of all processors.
+struct MsrWriteLog {
+    /// The recorded writes, allocated on the heap. Once the buffer is full, the oldest write is overwritten.
+    entries: Box<[MsrWrite]>,
+    /// The total number of writes recorded, which selects the next entry to overwrite.
+    count: u64,
+}
+
+/// Records guest writes to a set of MSRs, for reverse engineering what the guest does to them.
+///
+/// Writes to the audited MSRs are intercepted in the MSR bitmap of every processor virtualized after
+/// the MSR is added, and handled like any other intercepted write. Auditing can be turned on and off
+/// at any time; while it is off, the writes still cause VM exits but are not recorded.
+///
+/// Only writes to MSRs in the ranges `handle_msr_access` accepts are recorded. The old value is read
+/// before the write is performed, except for the MSRs in `WRITE_ONLY_MSRS`.
+pub struct MsrAudit {
+    /// Whether writes to the audited MSRs are recorded.
+    enabled: AtomicBool,
+    /// The audited MSRs. Only the first `msr_count` are valid.
+    msrs: [u32; MAX_AUDITED_MSRS],
+    /// The number of audited MSRs.
+    msr_count: usize,
+    /// The recorded writes.
+    log: Mutex<MsrWriteLog>,
+}
+
+impl Default for MsrAudit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MsrAudit {
+    /// Creates a disabled audit without MSRs.
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            msrs: [0; MAX_AUDITED_MSRS],
+            msr_count: 0,
+            log: Mutex::new(MsrWriteLog {
+                entries: vec![MsrWrite::default(); MSR_AUDIT_LENGTH].into_boxed_slice(),
+                count: 0,
+            }),
+        }
+    }
+
+    /// Registers the memory of the write log as owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::ReservedRegionsFull)` if no free slot is left.
+    pub fn reserve_storage(
+        &self,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        reserved_regions.reserve_object(&*self.log.lock().entries)
+    }
+
+    /// Adds an MSR to the audited set.
+    ///
+    /// Must be called before the processors are virtualized, since the MSR bitmaps are set up then.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR address.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::MsrAuditSetFull)` if `MAX_AUDITED_MSRS` MSRs are
+    /// already audited.
+    pub fn add_msr(&mut self, msr: u32) -> Result<(), HypervisorError> {
+        if self.msrs().contains(&msr) {
+            return Ok(());
+        }
+
+        if self.msr_count == MAX_AUDITED_MSRS {
+            return Err(HypervisorError::MsrAuditSetFull);
+        }
+
+        self.msrs[self.msr_count] = msr;
+        self.msr_count += 1;
+
+        Ok(())
+    }
+
+    /// Returns the audited MSRs.
+    pub fn msrs(&self) -> &[u32] {
+        &self.msrs[..self.msr_count]
+    }
+
+    /// Starts or stops recording writes to the audited MSRs on all processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to record the writes.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns whether writes to the MSR are currently recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - The MSR address.
+    pub fn is_audited(&self, msr: u32) -> bool {
+        self.enabled.load(Ordering::Relaxed) && self.msrs().contains(&msr)
+    }
+
+    /// Records a write, overwriting the oldest one if the log is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `write` - The write to record.
+    pub fn record(&self, write: MsrWrite) {
+        let mut log = self.log.lock();
+        let index = log.count as usize % MSR_AUDIT_LENGTH;
+        log.entries[index] = write;
+        log.count = log.count.wrapping_add(1);
+    }
+
+    /// Calls `f` with each recorded write, from the oldest to the most recent.
+    ///
+    /// The log is locked while `f` runs, so `f` must not cause VM exits that write audited MSRs.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The function to call with each write.
+    pub fn for_each(&self, mut f: impl FnMut(&MsrWrite)) {
+        let log = self.log.lock();
+        let len = log.count.min(MSR_AUDIT_LENGTH as u64) as usize;
+        let first = log.count as usize - len;
+
+        for index in first..first + len {
+            f(&log.entries[index % MSR_AUDIT_LENGTH]);
+        }
+    }
+
+    /// Logs the recorded writes, from the oldest to the most recent.
+    pub fn dump(&self) {
+        log::info!("Audited MSR writes:");
+
+        self.for_each(|write| match write.old_value {
+            Some(old_value) => log::info!(
+                "  vcpu-{} wrote MSR {:#x} at guest RIP {:#x}: {:#x} -> {:#x}",
+                write.apic_id,
+                write.msr,
+                write.guest_rip,
+                old_value,
+                write.new_value
+            ),
+            None => log::info!(
+                "  vcpu-{} wrote MSR {:#x} at guest RIP {:#x}: {:#x}",
+                write.apic_id,
+                write.msr,
+                write.guest_rip,
+                write.new_value
+            ),
+        });
+    }
+}
 
 /// Enum representing the type of MSR access.
 ///
@@ -18,11 +269,12 @@ pub enum MsrAccessType {
 /// range, a reserved range, or a synthetic MSR range used by Hyper-V.
 /// For valid MSRs, the function will either read or write to the MSR based
 /// on the access type. For reserved or synthetic MSRs, a general protection
-/// fault is injected.
+/// fault is injected. MSRs held in the VMCS while the guest runs, such as IA32_FS_BASE,
+/// are accessed through `Vm::read_guest_msr` and `Vm::write_guest_msr` like all others.
 ///
 /// # Arguments
 ///
-/// * `registers` - A mutable reference to the guest's current register state.
+/// * `vm` - A mutable reference to the VM of the current processor.
 /// * `access_type` - The type of MSR access (read or write).
 ///
 /// # Returns
@@ -31,10 +283,7 @@ pub enum MsrAccessType {
 ///
 /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: RDMSR—Read From Model Specific Register or WRMSR—Write to Model Specific Register
 /// and Table C-1. Basic Exit Reasons 31 and 32.
-pub fn handle_msr_access(
-    guest_registers: &mut GuestRegisters,
-    access_type: MsrAccessType,
-) -> ExitType {
+pub fn handle_msr_access(vm: &mut Vm, access_type: MsrAccessType) -> ExitType {
     log::debug!("Handling MSR VM exit...");
 
     /// Constants related to MSR addresses and ranges.
@@ -47,7 +296,14 @@ pub fn handle_msr_access(
     const HYPERV_MSR_START: u64 = 0x40000000;
     const HYPERV_MSR_END: u64 = 0x4000FFFF;
 
-    let msr_id = guest_registers.rcx;
+    let msr_id = vm.guest_registers.rcx;
+
+    if msr_id == IA32_FEATURE_CONTROL as u64 {
+        if let MsrAccessType::Write = access_type {
+            audit_msr_write(vm, IA32_FEATURE_CONTROL);
+        }
+        return handle_feature_control_access(vm, access_type);
+    }
 
     // If the MSR address falls within a synthetic or reserved range, inject a general protection fault.
     /*
@@ -67,13 +323,27 @@ pub fn handle_msr_access(
         log::trace!("Valid MSR access attempted: {:#x}", msr_id);
         match access_type {
             MsrAccessType::Read => {
-                let msr_value = unsafe { x86::msr::rdmsr(msr_id as _) };
-                guest_registers.rdx = msr_value >> 32;
-                guest_registers.rax = msr_value & MSR_MASK_LOW;
+                let msr_value = vm.read_guest_msr(msr_id as _);
+                vm.guest_registers.rdx = msr_value >> 32;
+                vm.guest_registers.rax = msr_value & MSR_MASK_LOW;
             }
             MsrAccessType::Write => {
-                let msr_value = (guest_registers.rdx << 32) | (guest_registers.rax & MSR_MASK_LOW);
-                unsafe { x86::msr::wrmsr(msr_id as _, msr_value) };
+                audit_msr_write(vm, msr_id as u32);
+
+                let msr_value =
+                    (vm.guest_registers.rdx << 32) | (vm.guest_registers.rax & MSR_MASK_LOW);
+
+                // The cached leaf 0 would keep reporting the previous maximum basic leaf.
+                if msr_id == IA32_MISC_ENABLE as u64
+                    && (vm.read_guest_msr(IA32_MISC_ENABLE) ^ msr_value)
+                        & MISC_ENABLE_LIMIT_CPUID_MAXVAL
+                        != 0
+                {
+                    log::debug!("Guest toggled Limit CPUID Maxval, invalidating the CPUID cache");
+                    unsafe { vm.shared_data.as_ref() }.cpuid_cache.invalidate();
+                }
+
+                vm.write_guest_msr(msr_id as _, msr_value);
             }
         }
     } else {
@@ -87,3 +357,64 @@ pub fn handle_msr_access(
 
     ExitType::IncrementRIP
 }
+
+/// Records the current `WRMSR` of the guest if the MSR is audited, before the write is handled.
+///
+/// Must only be called for MSRs in the ranges `handle_msr_access` accepts. The old value is read
+/// unless the MSR is in `WRITE_ONLY_MSRS`.
+///
+/// # Arguments
+///
+/// * `vm` - A reference to the VM of the current processor.
+/// * `msr` - The MSR address from ECX.
+fn audit_msr_write(vm: &Vm, msr: u32) {
+    let msr_audit = &unsafe { vm.shared_data.as_ref() }.msr_audit;
+    if !msr_audit.is_audited(msr) {
+        return;
+    }
+
+    msr_audit.record(MsrWrite {
+        msr,
+        apic_id: vm.apic_id,
+        old_value: (!WRITE_ONLY_MSRS.contains(&msr)).then(|| vm.read_guest_msr(msr)),
+        new_value: (vm.guest_registers.rdx << 32) | (vm.guest_registers.rax & u32::MAX as u64),
+        guest_rip: vm.guest_reg(Register::Rip),
+    });
+}
+
+/// Emulates an access to IA32_FEATURE_CONTROL against its shadow value.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+/// * `access_type` - The type of MSR access (read or write).
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - If the access was emulated.
+/// * `ExitType::Continue` - If #GP was injected for a write to the locked MSR.
+fn handle_feature_control_access(vm: &mut Vm, access_type: MsrAccessType) -> ExitType {
+    let mut shadow = FEATURE_CONTROL_SHADOW.lock();
+    let value = shadow.unwrap_or_else(|| vm.read_guest_msr(IA32_FEATURE_CONTROL));
+
+    match access_type {
+        MsrAccessType::Read => {
+            log::trace!("IA32_FEATURE_CONTROL read: {:#x}", value);
+            vm.guest_registers.rdx = value >> 32;
+            vm.guest_registers.rax = value & u32::MAX as u64;
+        }
+        MsrAccessType::Write if value & FEATURE_CONTROL_LOCK != 0 => {
+            log::trace!("IA32_FEATURE_CONTROL write while locked");
+            EventInjection::vmentry_inject_gp(0);
+            return ExitType::Continue;
+        }
+        MsrAccessType::Write => {
+            let new_value =
+                (vm.guest_registers.rdx << 32) | (vm.guest_registers.rax & u32::MAX as u64);
+            log::trace!("IA32_FEATURE_CONTROL write: {:#x}", new_value);
+            *shadow = Some(new_value);
+        }
+    }
+
+    ExitType::IncrementRIP
+}
@@ -0,0 +1,115 @@
+//! Single-steps the guest with the monitor trap flag (MTF).
+//!
+//! With the monitor trap flag set, VM entry is followed by a VM exit as soon as the guest has executed
+//! one instruction. This is used to step over a page whose permissions were granted temporarily with
+//! `Ept::with_temporary_access`: the MTF VM exit restores the saved permissions and clears the flag.
+//! Hooks using `HookStrategy::Mtf` are stepped the same way: the MTF VM exit maps the original page
+//! back in place of the shadow page.
+//!
+//! Both the step and the MTF VM exit only invalidate the EPT caches of the current processor. Other
+//! processors may keep translations cached during the step until they invalidate their own caches,
+//! see `HookStrategy::Mtf`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.2 Monitor Trap Flag
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            controls::{is_vmx_control_supported, VmxControl},
+            invept::invept_all_contexts,
+            vm::Vm,
+            vmexit::ExitType,
+            vmfield,
+        },
+    },
+    x86::vmx::vmcs,
+};
+
+/// The primary processor-based control that sets the monitor trap flag.
+const MONITOR_TRAP_FLAG: u32 = vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG.bits();
+
+/// Sets or clears the monitor trap flag in the current VMCS.
+///
+/// # Arguments
+///
+/// * `enabled` - Whether a VM exit occurs after the next guest instruction.
+///
+/// # Returns
+///
+/// `Ok(())` on success, or `Err(HypervisorError::MonitorTrapFlagUnsupported)` if the flag is set on a
+/// processor that does not support it.
+pub fn set_monitor_trap_flag(enabled: bool) -> Result<(), HypervisorError> {
+    if enabled && !is_monitor_trap_flag_supported() {
+        return Err(HypervisorError::MonitorTrapFlagUnsupported);
+    }
+
+    let controls = vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read();
+    let controls = match enabled {
+        true => controls | MONITOR_TRAP_FLAG,
+        false => controls & !MONITOR_TRAP_FLAG,
+    };
+    vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.write(controls);
+
+    Ok(())
+}
+
+/// Checks whether the processor supports the monitor trap flag.
+pub fn is_monitor_trap_flag_supported() -> bool {
+    is_vmx_control_supported(VmxControl::ProcessorBased, MONITOR_TRAP_FLAG as u64)
+}
+
+/// Handles the monitor trap flag VM exit.
+///
+/// Clears the monitor trap flag, restores the permissions this processor granted temporarily in the
+/// primary and secondary EPTs, and maps back the original page of the MTF hook it stepped, if any.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The stepped instruction has already completed, so RIP is not advanced.
+pub fn handle_monitor_trap_flag(vm: &mut Vm) -> ExitType {
+    log::trace!("Handling monitor trap flag VM exit...");
+
+    if let Err(e) = set_monitor_trap_flag(false) {
+        log::error!("Failed to clear the monitor trap flag: {}", e);
+    }
+
+    let stepped_hook_page = vm.stepped_hook_page.take();
+    let shared_data = unsafe { vm.shared_data.as_ref() };
+    let mut epts = vm.lock_epts();
+
+    for ept in [&mut *epts.primary_ept, &mut *epts.secondary_ept] {
+        match shared_data.temporary_access.restore(ept) {
+            Ok(0) => {}
+            Ok(released) => log::trace!("Restored {} temporary grants", released),
+            Err(e) => log::error!("Failed to restore temporarily granted permissions: {}", e),
+        }
+    }
+
+    if let Some(guest_pa) = stepped_hook_page {
+        if let Err(e) = epts.hook_manager.end_step(
+            epts.primary_ept,
+            epts.secondary_ept,
+            guest_pa,
+            &shared_data.reserved_regions,
+        ) {
+            log::error!(
+                "Failed to unmap the shadow page of the hook at {:#x}: {}",
+                guest_pa,
+                e
+            );
+        }
+    }
+
+    drop(epts);
+
+    if let Err(e) = invept_all_contexts() {
+        log::error!("Failed to invalidate EPT contexts: {}", e);
+    }
+
+    ExitType::Continue
+}
@@ -0,0 +1,141 @@
+//! Handles non-maskable interrupts (NMIs) that arrive while the guest is running.
+//!
+//! With "NMI exiting" enabled, every NMI causes a VM exit instead of being delivered through the
+//! guest IDT. The hypervisor queues each NMI and re-injects it once the guest is able to take it,
+//! using "NMI-window exiting" to find out when that is. "Virtual NMIs" is enabled as well, so the
+//! processor tracks NMI blocking for the guest in the guest interruptibility state and the
+//! NMI-window exit only fires once that virtual-NMI blocking is gone.
+//!
+//! How this avoids losing or double-delivering NMIs:
+//! - An NMI that causes a VM exit is never delivered to the guest by the processor, so it is
+//!   counted exactly once in `Vm::pending_nmis` and injected exactly once from the NMI-window exit.
+//! - Injecting an NMI through the VM-entry interruption-information field sets virtual-NMI
+//!   blocking, which the guest clears by executing IRET. Only then does the next NMI-window exit
+//!   occur, so queued NMIs are delivered one at a time, just like on bare metal.
+//! - If a VM exit is caused by a fault during IRET, bit 12 of the VM-exit interruption information
+//!   ("NMI unblocking due to IRET") is set and the virtual-NMI blocking has already been cleared.
+//!   When such a fault is re-injected, blocking by NMI must be restored before VM-entry (see
+//!   `restore_nmi_blocking_after_iret`), otherwise the guest could take a nested NMI.
+//! - If a VM exit occurs while an injected NMI is delivered through the guest IDT, the NMI is
+//!   reported in the IDT-vectoring information instead of being delivered. It was already removed
+//!   from `Vm::pending_nmis`, so it is injected again from the exit handler rather than queued again
+//!   (see `EventInjection::vmentry_reinject_vectoring_event`).
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.3 CHANGES TO INSTRUCTION BEHAVIOR IN VMX NON-ROOT OPERATION,
+//! 26.6.1 Pin-Based VM-Execution Controls and 28.2.3 Information About NMI Unblocking Due to IRET
+
+use {
+    crate::intel::{events::EventInjection, vm::Vm, vmexit::ExitType, vmfield},
+    x86::vmx::vmcs,
+};
+
+/// Guest interruptibility state bit indicating blocking by NMI.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: Table 25-3. Format of Interruptibility State
+const BLOCKING_BY_NMI: u32 = 1 << 3;
+
+/// Handles a VM exit caused by an NMI arriving while the guest is running.
+///
+/// The NMI is queued and NMI-window exiting is enabled, so it is injected as soon as the guest
+/// is not blocking NMIs.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The NMI does not correspond to a guest instruction, so RIP is not advanced.
+pub fn handle_nmi(vm: &mut Vm) -> ExitType {
+    log::debug!("Handling NMI VM exit...");
+
+    vm.pending_nmis += 1;
+    set_nmi_window_exiting(true);
+
+    log::trace!("Queued NMI, pending NMIs: {}", vm.pending_nmis);
+
+    ExitType::Continue
+}
+
+/// Queues the NMIs that arrived while the hypervisor was handling a VM exit.
+///
+/// Such NMIs are delivered through the host IDT instead of causing a VM exit. Its handler already
+/// enabled NMI-window exiting, so they only have to be counted. Must be called after each VM exit
+/// is handled, before the guest is resumed.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+pub fn queue_host_nmis(vm: &mut Vm) {
+    let host_nmis = vm.host_idt.take_pending_nmis();
+    if host_nmis == 0 {
+        return;
+    }
+
+    vm.pending_nmis += host_nmis;
+    set_nmi_window_exiting(true);
+
+    log::trace!(
+        "Queued {} NMIs from VMX root operation, pending NMIs: {}",
+        host_nmis,
+        vm.pending_nmis
+    );
+}
+
+/// Handles a VM exit caused by the NMI window opening.
+///
+/// Injects one queued NMI into the guest. NMI-window exiting stays enabled while more NMIs are
+/// pending; the next NMI-window exit only occurs after the guest has executed IRET.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - No guest instruction caused this exit, so RIP is not advanced.
+pub fn handle_nmi_window(vm: &mut Vm) -> ExitType {
+    log::debug!("Handling NMI window VM exit...");
+
+    if vm.pending_nmis > 0 {
+        vm.pending_nmis -= 1;
+        EventInjection::vmentry_inject_nmi();
+    }
+
+    if vm.pending_nmis == 0 {
+        set_nmi_window_exiting(false);
+    }
+
+    log::trace!("Injected NMI, pending NMIs: {}", vm.pending_nmis);
+
+    ExitType::Continue
+}
+
+/// Restores blocking by NMI in the guest interruptibility state.
+///
+/// Must be called when an event is re-injected for a VM exit that reported "NMI unblocking due to
+/// IRET", unless the re-injected event is a double fault.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 28.2.3 Information About NMI Unblocking Due to IRET
+pub fn restore_nmi_blocking_after_iret() {
+    let interruptibility_state = vmfield::guest::INTERRUPTIBILITY_STATE.read();
+    vmfield::guest::INTERRUPTIBILITY_STATE.write(interruptibility_state | BLOCKING_BY_NMI);
+}
+
+/// Enables or disables the "NMI-window exiting" primary processor-based VM-execution control.
+///
+/// # Arguments
+///
+/// * `enable` - Whether VM exits should occur when the guest can receive an NMI.
+fn set_nmi_window_exiting(enable: bool) {
+    let mut controls = vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read();
+    let nmi_window_exiting = vmcs::control::PrimaryControls::NMI_WINDOW_EXITING.bits();
+
+    if enable {
+        controls |= nmi_window_exiting;
+    } else {
+        controls &= !nmi_window_exiting;
+    }
+
+    vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.write(controls);
+}
@@ -0,0 +1,142 @@
+//! Runs periodic hypervisor work with the VMX-preemption timer.
+//!
+//! The VMX-preemption timer counts down while the guest runs and causes a VM exit when it reaches
+//! zero. A single callback can be registered before the processors are virtualized, and is then
+//! invoked on every processor each time its timer expires, after which the timer is reloaded.
+//!
+//! The timer counts down by one every time bit X of the TSC changes, where X is reported in
+//! IA32_VMX_MISC bits 4:0, so the period given in TSC cycles is scaled down by 2^X. The timer value
+//! is saved on every VM exit, so other VM exits do not restart the period.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.1 VMX-Preemption Timer
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            controls::{is_vmx_control_supported, VmxControl},
+            support::rdmsr,
+            vm::Vm,
+            vmexit::ExitType,
+            vmfield,
+        },
+    },
+    spin::Once,
+    x86::vmx::vmcs,
+};
+
+/// Callback invoked on the current processor whenever its VMX-preemption timer expires.
+pub type PreemptionTimerCallback = fn(vm: &mut Vm);
+
+/// The pin-based control that activates the VMX-preemption timer.
+const ACTIVATE_PREEMPTION_TIMER: u64 =
+    vmcs::control::PinbasedControls::VMX_PREEMPTION_TIMER.bits() as u64;
+
+/// The VM-exit control that saves the remaining VMX-preemption timer value on VM exit.
+const SAVE_PREEMPTION_TIMER: u64 =
+    vmcs::control::ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() as u64;
+
+/// A registered periodic callback.
+struct PreemptionTimer {
+    /// The callback to invoke when the timer expires.
+    callback: PreemptionTimerCallback,
+
+    /// The value the timer is reloaded with, in timer ticks.
+    reload_value: u32,
+}
+
+/// The registered periodic callback, if any.
+static PREEMPTION_TIMER: Once<PreemptionTimer> = Once::new();
+
+/// Registers the callback to run periodically on every processor.
+///
+/// Must be called before the processors are virtualized, since the timer is only activated while
+/// the VMCS is set up.
+///
+/// # Arguments
+///
+/// * `callback` - The callback to invoke when the timer expires.
+/// * `period_tsc_cycles` - The period in TSC cycles. It is rounded down to the timer's granularity,
+///   and clamped to at least one and at most 2^32 - 1 timer ticks.
+///
+/// # Returns
+///
+/// `Ok(())` on success, `Err(HypervisorError::PreemptionTimerUnsupported)` if the processor does not
+/// support activating and saving the timer, or `Err(HypervisorError::PreemptionTimerAlreadyRegistered)`
+/// if a callback has already been registered.
+pub fn register_preemption_timer(
+    callback: PreemptionTimerCallback,
+    period_tsc_cycles: u64,
+) -> Result<(), HypervisorError> {
+    if !is_preemption_timer_supported() {
+        return Err(HypervisorError::PreemptionTimerUnsupported);
+    }
+
+    if PREEMPTION_TIMER.is_completed() {
+        return Err(HypervisorError::PreemptionTimerAlreadyRegistered);
+    }
+
+    let reload_value = u32::try_from(period_tsc_cycles >> preemption_timer_rate())
+        .unwrap_or(u32::MAX)
+        .max(1);
+    log::debug!("Preemption timer reload value: {:#x} ticks", reload_value);
+
+    PREEMPTION_TIMER.call_once(|| PreemptionTimer {
+        callback,
+        reload_value,
+    });
+
+    Ok(())
+}
+
+/// Activates the VMX-preemption timer in the current VMCS if a callback has been registered.
+///
+/// Must be called after the pin-based and VM-exit controls have been written.
+pub fn setup_preemption_timer() {
+    let Some(timer) = PREEMPTION_TIMER.get() else {
+        return;
+    };
+
+    let pin_controls = vmfield::control::PINBASED_EXEC_CONTROLS.read();
+    vmfield::control::PINBASED_EXEC_CONTROLS.write(pin_controls | ACTIVATE_PREEMPTION_TIMER as u32);
+
+    let exit_controls = vmfield::control::VMEXIT_CONTROLS.read();
+    vmfield::control::VMEXIT_CONTROLS.write(exit_controls | SAVE_PREEMPTION_TIMER as u32);
+
+    vmfield::guest::VMX_PREEMPTION_TIMER_VALUE.write(timer.reload_value);
+}
+
+/// Handles the VMX-preemption timer expired VM exit.
+///
+/// Runs the registered callback and reloads the timer.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The exit is not caused by an instruction, so RIP is not advanced.
+pub fn handle_preemption_timer(vm: &mut Vm) -> ExitType {
+    log::trace!("Handling VMX-preemption timer expired VM exit...");
+
+    if let Some(timer) = PREEMPTION_TIMER.get() {
+        (timer.callback)(vm);
+        vmfield::guest::VMX_PREEMPTION_TIMER_VALUE.write(timer.reload_value);
+    }
+
+    ExitType::Continue
+}
+
+/// Checks whether the processor supports activating the VMX-preemption timer and saving its value on VM exit.
+fn is_preemption_timer_supported() -> bool {
+    is_vmx_control_supported(VmxControl::PinBased, ACTIVATE_PREEMPTION_TIMER)
+        && is_vmx_control_supported(VmxControl::VmExit, SAVE_PREEMPTION_TIMER)
+}
+
+/// Returns X, where the timer counts down by one every time bit X of the TSC changes.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.6 MISCELLANEOUS DATA
+fn preemption_timer_rate() -> u64 {
+    rdmsr(x86::msr::IA32_VMX_MISC) & 0b1_1111
+}
@@ -0,0 +1,187 @@
+//! Implements pseudo-instructions: invalid opcode sequences the hypervisor recognizes.
+//!
+//! A guest agent can execute a registered byte sequence that raises #UD on hardware, e.g. `0f 0b`
+//! (UD2) followed by a tag, to request a hypervisor action. The #UD causes a VM exit through the
+//! exception bitmap, the bytes at the guest RIP are compared with the registered sequences, and on
+//! a match the action runs and the sequence is skipped. Any other #UD is re-injected unchanged.
+//!
+//! Unlike VMCALL, this does not need the guest to be aware of the hypervisor interface, and the
+//! sequence is free to carry operands in its bytes. #UD is only intercepted while at least one
+//! pseudo-instruction is registered, and sequences must be registered before the processors are
+//! virtualized, since the exception bitmap is only written while the VMCS is set up.
+//!
+//! Like the other registries, the pseudo-instructions have a fixed capacity, and their actions are
+//! plain `fn` pointers, since memory cannot be allocated from a VM-exit handler.
+
+use crate::{
+    error::HypervisorError,
+    intel::{
+        capture::Register,
+        decode::{read_current_instruction_bytes, MAX_INSTRUCTION_LENGTH},
+        vm::Vm,
+        vmerror::ExceptionInterrupt,
+        vmfield,
+    },
+};
+
+/// The maximum number of pseudo-instructions that can be registered at the same time.
+pub const MAX_PSEUDO_INSTRUCTIONS: usize = 16;
+
+/// Action performed when the guest executes a pseudo-instruction.
+///
+/// The guest RIP still points at the pseudo-instruction while the action runs, and is advanced past
+/// it afterwards. The action communicates with the guest through its registers.
+pub type PseudoInstructionAction = fn(vm: &mut Vm);
+
+/// A single registered pseudo-instruction.
+#[derive(Debug, Clone, Copy)]
+struct PseudoInstruction {
+    /// The byte sequence, of which the first `length` bytes are used.
+    bytes: [u8; MAX_INSTRUCTION_LENGTH],
+
+    /// The length of the byte sequence.
+    length: usize,
+
+    /// The action to perform.
+    action: PseudoInstructionAction,
+}
+
+/// Registry of the pseudo-instructions recognized on #UD.
+#[derive(Debug)]
+pub struct PseudoInstructions {
+    /// The registered pseudo-instructions. `None` entries are free slots.
+    instructions: [Option<PseudoInstruction>; MAX_PSEUDO_INSTRUCTIONS],
+}
+
+impl Default for PseudoInstructions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PseudoInstructions {
+    /// Creates an empty `PseudoInstructions`.
+    pub const fn new() -> Self {
+        Self {
+            instructions: [None; MAX_PSEUDO_INSTRUCTIONS],
+        }
+    }
+
+    /// Registers a pseudo-instruction.
+    ///
+    /// The sequence must raise #UD on hardware at its first byte, e.g. start with UD2 (`0f 0b`),
+    /// otherwise the processor executes it instead. No sequence may be a prefix of another.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The byte sequence of the pseudo-instruction, at most 15 bytes long.
+    /// * `action` - The action to perform when the guest executes it.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, `Err(HypervisorError::InvalidPseudoInstruction)` if the sequence is empty,
+    /// too long, or overlaps a registered sequence, or `Err(HypervisorError::PseudoInstructionsFull)`
+    /// if no free slot is left.
+    pub fn register(
+        &mut self,
+        bytes: &[u8],
+        action: PseudoInstructionAction,
+    ) -> Result<(), HypervisorError> {
+        if bytes.is_empty() || bytes.len() > MAX_INSTRUCTION_LENGTH {
+            return Err(HypervisorError::InvalidPseudoInstruction);
+        }
+
+        let overlaps = self.instructions.iter().flatten().any(|instruction| {
+            let length = instruction.length.min(bytes.len());
+            instruction.bytes[..length] == bytes[..length]
+        });
+        if overlaps {
+            return Err(HypervisorError::InvalidPseudoInstruction);
+        }
+
+        let slot = self
+            .instructions
+            .iter_mut()
+            .find(|instruction| instruction.is_none())
+            .ok_or(HypervisorError::PseudoInstructionsFull)?;
+
+        let mut instruction = PseudoInstruction {
+            bytes: [0; MAX_INSTRUCTION_LENGTH],
+            length: bytes.len(),
+            action,
+        };
+        instruction.bytes[..bytes.len()].copy_from_slice(bytes);
+        *slot = Some(instruction);
+
+        Ok(())
+    }
+
+    /// Returns whether no pseudo-instruction is registered.
+    pub fn is_empty(&self) -> bool {
+        self.instructions.iter().all(Option::is_none)
+    }
+
+    /// Finds the pseudo-instruction the given bytes start with.
+    fn find(&self, bytes: &[u8]) -> Option<PseudoInstruction> {
+        self.instructions
+            .iter()
+            .flatten()
+            .find(|instruction| bytes.starts_with(&instruction.bytes[..instruction.length]))
+            .copied()
+    }
+}
+
+/// Enables or disables #UD interception in the exception bitmap of the current VMCS.
+///
+/// # Arguments
+///
+/// * `enable` - Whether #UD should cause VM exits.
+pub fn setup_pseudo_instructions(enable: bool) {
+    const INVALID_OPCODE: u32 = 1 << ExceptionInterrupt::InvalidOpcode as u32;
+
+    let mut exception_bitmap = vmfield::control::EXCEPTION_BITMAP.read();
+
+    if enable {
+        exception_bitmap |= INVALID_OPCODE;
+    } else {
+        exception_bitmap &= !INVALID_OPCODE;
+    }
+
+    vmfield::control::EXCEPTION_BITMAP.write(exception_bitmap);
+}
+
+/// Performs the pseudo-instruction at the current guest RIP, if any.
+///
+/// Called for #UD exceptions. On a match, the action runs and RIP is advanced past the sequence.
+/// RIP is advanced here, since the VM-exit instruction length is not reported for exceptions.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// Whether a pseudo-instruction was performed. If not, the #UD must be re-injected.
+pub fn handle_pseudo_instruction(vm: &mut Vm) -> bool {
+    let instructions = &unsafe { vm.shared_data.as_ref() }.pseudo_instructions;
+    if instructions.is_empty() {
+        return false;
+    }
+
+    let mut bytes = [0u8; MAX_INSTRUCTION_LENGTH];
+    let Some(length) = read_current_instruction_bytes(vm, &mut bytes) else {
+        return false;
+    };
+
+    let Some(instruction) = instructions.find(&bytes[..length]) else {
+        return false;
+    };
+
+    let rip = vm.guest_reg(Register::Rip);
+    log::trace!("Performing pseudo-instruction at {:#x}", rip);
+
+    (instruction.action)(vm);
+    vm.set_guest_reg(Register::Rip, rip + instruction.length as u64);
+
+    true
+}
@@ -1,10 +1,13 @@
 //! Handles RDTSC virtualization tasks, specifically intercepting and managing
 //! the `RDTSC` (Read Time-Stamp Counter) instruction in a VM to ensure appropriate time
 //! information is provided to the guest while maintaining the integrity of the hypervisor.
+//!
+//! The guest-visible timestamp honors TSC scaling (`Vm::set_tsc_scale`) and TSC offsetting the same
+//! way the processor does when the instructions do not cause VM exits.
 
 use {
-    crate::intel::{capture::GuestRegisters, vmexit::ExitType},
-    x86::time::rdtsc,
+    crate::intel::{capture::GuestRegisters, vm::Vm, vmexit::ExitType, vmfield},
+    x86::{time::rdtsc, vmx::vmcs},
 };
 
 /*
@@ -13,11 +16,15 @@ User can add the following later:
 - https://github.com/not-matthias/rdtsc_bench/blob/main/src/main.rs
 */
 
+/// The number of fraction bits of the TSC multiplier. A multiplier of `1 << 48` is a ratio of 1.0.
+pub const TSC_MULTIPLIER_FRACTION_BITS: u32 = 48;
+
 /// Handles the `RDTSC` VM-exit.
 ///
 /// This function is invoked when the guest executes the `RDTSC` instruction.
-/// It reads the current value of the host's time-stamp counter and updates the guest's
-/// RAX and RDX registers with the low and high 32-bits of the counter, respectively.
+/// It reads the current value of the host's time-stamp counter, converts it to the guest's
+/// timestamp with `guest_tsc`, and updates the guest's RAX and RDX registers with the low and
+/// high 32-bits of the counter, respectively.
 ///
 /// # Arguments
 ///
@@ -32,7 +39,7 @@ pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
     log::debug!("Handling RDTSC VM exit...");
 
     // Read the time stamp counter.
-    let rdtsc_value: u64 = unsafe { rdtsc() };
+    let rdtsc_value: u64 = guest_tsc(unsafe { rdtsc() });
 
     // Update the guest's RAX and RDX registers.
     guest_registers.rax = rdtsc_value & 0xFFFFFFFF; // Low 32 bits
@@ -42,3 +49,66 @@ pub fn handle_rdtsc(guest_registers: &mut GuestRegisters) -> ExitType {
 
     ExitType::IncrementRIP
 }
+
+/// Handles the `RDTSCP` VM-exit.
+///
+/// Like `handle_rdtsc`, and additionally loads the guest's IA32_TSC_AUX into RCX.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `RDTSCP` instruction in the VM.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual, Table C-1. Basic Exit Reasons 51.
+pub fn handle_rdtscp(vm: &mut Vm) -> ExitType {
+    log::debug!("Handling RDTSCP VM exit...");
+
+    let rdtsc_value = guest_tsc(unsafe { rdtsc() });
+    let tsc_aux = vm.read_guest_msr(x86::msr::IA32_TSC_AUX);
+
+    vm.guest_registers.rax = rdtsc_value & 0xFFFFFFFF;
+    vm.guest_registers.rdx = rdtsc_value >> 32;
+    vm.guest_registers.rcx = tsc_aux & 0xFFFFFFFF;
+
+    ExitType::IncrementRIP
+}
+
+/// Converts a host timestamp to the timestamp the guest reads with RDTSC and RDTSCP.
+///
+/// With "use TSC scaling", the timestamp is multiplied by the TSC multiplier, a fixed-point number
+/// with 48 fraction bits. With "use TSC offsetting", the TSC offset is then added.
+///
+/// # Arguments
+///
+/// * `host_tsc` - The timestamp read by the host.
+///
+/// # Returns
+///
+/// The guest-visible timestamp.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.3 CHANGES TO INSTRUCTION BEHAVIOR IN VMX NON-ROOT OPERATION
+pub fn guest_tsc(host_tsc: u64) -> u64 {
+    let primary_controls = vmcs::control::PrimaryControls::from_bits_truncate(
+        vmfield::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read(),
+    );
+    if !primary_controls.contains(vmcs::control::PrimaryControls::USE_TSC_OFFSETTING) {
+        return host_tsc;
+    }
+
+    let secondary_controls = vmcs::control::SecondaryControls::from_bits_truncate(
+        vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read(),
+    );
+    let scaled_tsc =
+        match secondary_controls.contains(vmcs::control::SecondaryControls::USE_TSC_SCALING) {
+            true => {
+                ((host_tsc as u128 * vmfield::control::TSC_MULTIPLIER_FULL.read() as u128)
+                    >> TSC_MULTIPLIER_FRACTION_BITS) as u64
+            }
+            false => host_tsc,
+        };
+
+    scaled_tsc.wrapping_add(vmfield::control::TSC_OFFSET_FULL.read())
+}
@@ -0,0 +1,184 @@
+//! Intercepts RDRAND and RDSEED and emulates them with a deterministic PRNG.
+//!
+//! For reproducible guest execution, e.g. record/replay or fuzzing, the guest's hardware randomness
+//! can be replaced with values from a seeded PRNG kept in `SharedData`. Interception is disabled by
+//! default, and is only enabled on processors that support the "RDRAND exiting" and "RDSEED exiting"
+//! secondary processor-based controls.
+//!
+//! The PRNG is SplitMix64, advanced with a single atomic add, so values are never handed out twice.
+//! Which processor receives which value depends on the order the guest executes the instructions in.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.6.2 Processor-Based VM-Execution Controls
+//! and Table 28-13. Format of the VM-Exit Instruction-Information Field as Used for RDRAND, RDSEED, TPAUSE, and UMWAIT
+
+use {
+    crate::intel::{
+        capture::Register,
+        controls::{is_vmx_control_supported, VmxControl},
+        vm::Vm,
+        vmexit::ExitType,
+        vmfield,
+    },
+    bit_field::BitField,
+    core::sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    x86::{bits64::rflags::RFlags, vmx::vmcs},
+};
+
+/// The secondary processor-based control that causes RDRAND to exit.
+const RDRAND_EXITING: u32 = vmcs::control::SecondaryControls::RDRAND_EXITING.bits();
+
+/// The secondary processor-based control that causes RDSEED to exit.
+const RDSEED_EXITING: u32 = vmcs::control::SecondaryControls::RDSEED_EXITING.bits();
+
+/// The increment of the SplitMix64 state, derived from the golden ratio.
+const SPLITMIX64_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Deterministic source of the values returned to the guest by RDRAND and RDSEED.
+pub struct DeterministicRng {
+    /// Whether RDRAND and RDSEED are intercepted.
+    enabled: AtomicBool,
+
+    /// The SplitMix64 state.
+    state: AtomicU64,
+}
+
+impl Default for DeterministicRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeterministicRng {
+    /// Creates a disabled PRNG with a zero seed.
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            state: AtomicU64::new(0),
+        }
+    }
+
+    /// Enables or disables interception of RDRAND and RDSEED.
+    ///
+    /// Takes effect on processors virtualized afterwards, since the controls are only written while
+    /// the VMCS is set up.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether the guest should receive values from this PRNG.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns whether RDRAND and RDSEED are intercepted.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Restarts the sequence of values from a seed.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - The seed to restart from. The same seed always produces the same sequence.
+    pub fn seed(&self, seed: u64) {
+        self.state.store(seed, Ordering::Relaxed);
+    }
+
+    /// Returns the next value of the sequence.
+    pub fn next(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(SPLITMIX64_GAMMA, Ordering::Relaxed)
+            .wrapping_add(SPLITMIX64_GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Enables or disables RDRAND and RDSEED exiting in the current VMCS.
+///
+/// Must be called after the secondary processor-based controls have been written. Each control is
+/// only enabled if the processor supports it, otherwise the instruction keeps running natively.
+///
+/// # Arguments
+///
+/// * `enable` - Whether RDRAND and RDSEED should cause VM exits.
+pub fn setup_rng_exiting(enable: bool) {
+    let mut controls = vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+
+    for (control, name) in [(RDRAND_EXITING, "RDRAND"), (RDSEED_EXITING, "RDSEED")] {
+        if !enable {
+            controls &= !control;
+        } else if is_vmx_control_supported(VmxControl::ProcessorBased2, control as u64) {
+            controls |= control;
+        } else {
+            log::warn!(
+                "{} exiting is not supported, the guest receives hardware randomness",
+                name
+            );
+        }
+    }
+
+    vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS.write(controls);
+}
+
+/// Handles the RDRAND VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `RDRAND` instruction in the VM.
+pub fn handle_rdrand(vm: &mut Vm) -> ExitType {
+    log::trace!("Handling RDRAND VM exit...");
+    emulate_random_instruction(vm)
+}
+
+/// Handles the RDSEED VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the `RDSEED` instruction in the VM.
+pub fn handle_rdseed(vm: &mut Vm) -> ExitType {
+    log::trace!("Handling RDSEED VM exit...");
+    emulate_random_instruction(vm)
+}
+
+/// Writes the next PRNG value to the destination register of the exiting RDRAND or RDSEED, and reports success.
+///
+/// Like on hardware, a 32-bit destination is zero-extended and a 16-bit destination keeps the upper
+/// bits of the register. CF is set and OF, SF, ZF, AF, and PF are cleared.
+fn emulate_random_instruction(vm: &mut Vm) -> ExitType {
+    let instruction_info = vmfield::ro::VMEXIT_INSTRUCTION_INFO.read() as u64;
+    let Some(register) = Register::from_gpr_index(instruction_info.get_bits(3..7)) else {
+        unreachable!("Instruction information encodes a 4-bit register index");
+    };
+
+    let random = unsafe { vm.shared_data.as_ref() }.rng.next();
+    let value = match instruction_info.get_bits(11..13) {
+        0 => (vm.guest_reg(register) & !0xffff) | (random & 0xffff),
+        1 => random & 0xffff_ffff,
+        _ => random,
+    };
+    vm.set_guest_reg(register, value);
+
+    vm.set_guest_flag(RFlags::FLAGS_CF, true);
+    vm.set_guest_flag(
+        RFlags::FLAGS_OF
+            | RFlags::FLAGS_SF
+            | RFlags::FLAGS_ZF
+            | RFlags::FLAGS_AF
+            | RFlags::FLAGS_PF,
+        false,
+    );
+
+    log::trace!("Returned {:#x} in {:?}", value, register);
+
+    ExitType::IncrementRIP
+}
@@ -4,43 +4,65 @@
 //! and startup of Application Processors (APs) in a virtualized environment. Essential for simulating
 //! multi-processor startup sequences within a VM, aligning with the MP initialization protocol.
 //! Credits to Satoshi Tanada: https://github.com/tandasat/MiniVisorPkg/blob/master/Sources/HostMain.c
+//!
+//! The MP initialization protocol sends INIT, followed by two SIPIs in case the first one is lost.
+//! The INIT VM exit puts the processor in the wait-for-SIPI activity state, and the first SIPI starts
+//! it in real mode at the vectored address. SIPIs arriving outside the wait-for-SIPI state are
+//! blocked by the processor, and the handler ignores any that still reach it, so the redundant
+//! second SIPI never restarts an AP that is already running.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.2 OTHER CAUSES OF VM EXITS
+//! and 11.4.4.1 Typical BSP Initialization Sequence
 
 use {
     crate::intel::{
-        capture::GuestRegisters,
-        state::GuestActivityState,
-        support::{vmread, vmwrite},
-        vmexit::ExitType,
+        capture::Register, segmentation::Segment, state::GuestActivityState, vm::Vm,
+        vmexit::ExitType, vmfield,
     },
-    x86::vmx::vmcs,
+    bit_field::BitField,
+    x86::segmentation::SegmentSelector,
 };
 
 /// Emulates the effect of a Startup IPI (SIPI) signal within the VM.
 ///
-/// Upon receiving a SIPI, this function adjusts the guest's code segment selector,
-/// base, and instruction pointer to reflect the startup vector indicated by the SIPI.
-/// It ensures that subsequent SIPI signals, if any, are ignored once the AP is out of
-/// the wait-for-SIPI state, following VMX and MP initialization protocols.
+/// Upon receiving a SIPI in the wait-for-SIPI state, this function points the guest's code segment
+/// at the startup vector indicated by the SIPI (selector `vector << 8`, base `vector << 12`), sets
+/// the instruction pointer to 0, and makes the processor active. The segment limit and access rights
+/// keep the real-mode values set by the INIT VM exit. The processor starts without blocking or
+/// pending debug exceptions, since nothing executed in the wait-for-SIPI state.
 ///
 /// # Arguments
 ///
-/// - `guest_registers`: A mutable reference to the guest's general-purpose registers. Currently unused.
+/// - `vm`: The VM of the current processor.
 ///
 /// # Returns
 ///
 /// Returns `ExitType::Continue` to indicate the VM should continue execution.
-pub fn handle_sipi_signal(guest_registers: &mut GuestRegisters) -> ExitType {
-    let vector = vmread(vmcs::ro::EXIT_QUALIFICATION);
+pub fn handle_sipi_signal(vm: &mut Vm) -> ExitType {
+    // The exit qualification holds the SIPI vector in bits 7:0.
+    let vector = vmfield::ro::EXIT_QUALIFICATION.read().get_bits(0..8);
+
+    let activity_state = vmfield::guest::ACTIVITY_STATE.read();
+    if activity_state != GuestActivityState::WaitForSipi as u32 {
+        log::debug!(
+            "Ignoring SIPI with vector {:#x} in activity state {}",
+            vector,
+            activity_state
+        );
+        return ExitType::Continue;
+    }
+
+    log::debug!("Starting processor at {:#x} after SIPI", vector << 12);
 
-    vmwrite(vmcs::guest::CS_SELECTOR, vector << 8);
-    vmwrite(vmcs::guest::CS_BASE, vector << 12);
-    guest_registers.rip = 0x0u64;
-    vmwrite(vmcs::guest::RIP, guest_registers.rip);
+    let mut cs = vm.guest_segment(Segment::Cs);
+    cs.selector = SegmentSelector::from_raw((vector << 8) as u16);
+    cs.base = vector << 12;
+    vm.set_guest_segment(Segment::Cs, &cs);
+    vm.set_guest_reg(Register::Rip, 0);
 
-    vmwrite(
-        vmcs::guest::ACTIVITY_STATE,
-        GuestActivityState::Active as u32,
-    );
+    vmfield::guest::INTERRUPTIBILITY_STATE.write(0);
+    vmfield::guest::PENDING_DBG_EXCEPTIONS.write(0);
+    vmfield::guest::ACTIVITY_STATE.write(GuestActivityState::Active as u32);
 
     ExitType::Continue
 }
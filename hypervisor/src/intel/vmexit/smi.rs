@@ -0,0 +1,65 @@
+//! Handles System Management Interrupt (SMI) related VM exits.
+//!
+//! Dual-monitor treatment of SMIs and SMM is not supported: the hypervisor never activates it, so
+//! SMIs always use the default treatment. Under the default treatment, an SMI that arrives in VMX
+//! root or non-root operation is delivered directly to the SMM handler of the firmware, which saves
+//! and restores the VMX state (including the current VMCS) itself and resumes the interrupted code
+//! with an RSM. No VM exit occurs and no VMCS state has to be restored by the hypervisor.
+//!
+//! The I/O SMI and other SMI VM exits are only delivered to an SMM-transfer monitor under the
+//! dual-monitor treatment, so they are not expected here. They are handled defensively by logging
+//! and resuming the guest without modifying its state.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 32.14 DEFAULT TREATMENT OF SMIS AND SMM WITH VMX OPERATION AND SMX OPERATION,
+//! 32.15 DUAL-MONITOR TREATMENT OF SMIs AND SMM
+
+use {
+    crate::intel::{support::rdmsr, vmexit::ExitType, vmfield},
+    bit_field::BitField,
+};
+
+/// Handles the I/O SMI and other SMI VM exits.
+///
+/// The SMI has already been serviced when the exit is reported, so the guest is resumed at the
+/// same instruction without modifying its state.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - The guest is resumed without advancing RIP.
+pub fn handle_smi() -> ExitType {
+    // Bit 0 is set for an I/O SMI, and bit 1 if the SMI arrived immediately after an I/O instruction retired.
+    let exit_qualification = vmfield::ro::EXIT_QUALIFICATION.read();
+
+    log::warn!(
+        "Unexpected SMI VM exit (exit qualification {:#x}), dual-monitor treatment is not supported",
+        exit_qualification
+    );
+
+    ExitType::Continue
+}
+
+/// Logs the state of the SMM monitor and warns if dual-monitor treatment could be activated.
+///
+/// A valid IA32_SMM_MONITOR_CTL allows software to activate the dual-monitor treatment with a
+/// VMCALL in VMX root operation. The hypervisor never does so, so SMIs keep the default treatment.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 32.15.5 Enabling the Dual-Monitor Treatment
+pub fn log_smm_monitor_state() {
+    let dual_monitor_supported = rdmsr(x86::msr::IA32_VMX_BASIC).get_bit(49);
+
+    if !dual_monitor_supported {
+        log::debug!("Dual-monitor treatment of SMIs is not supported by the processor");
+        return;
+    }
+
+    let smm_monitor_ctl = rdmsr(x86::msr::IA32_SMM_MONITOR_CTL);
+
+    if smm_monitor_ctl.get_bit(0) {
+        log::warn!(
+            "IA32_SMM_MONITOR_CTL is valid ({:#x}), but dual-monitor treatment of SMIs is not supported and will not be activated",
+            smm_monitor_ctl
+        );
+    } else {
+        log::debug!("SMIs use the default treatment");
+    }
+}
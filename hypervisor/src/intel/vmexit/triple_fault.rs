@@ -0,0 +1,104 @@
+//! Handles VM exits caused by a guest triple fault.
+//!
+//! On bare metal, a triple fault puts the processor into the shutdown state and the chipset resets
+//! the machine, so nothing is left to diagnose. Under the hypervisor it causes a VM exit instead,
+//! which is used to log the state of the crashed guest before the machine is reset like on bare
+//! metal, or the processor is parked for debugging.
+//!
+//! Devirtualizing the processor is not an option: the guest state is unrecoverable, and resuming it
+//! without the hypervisor would only cause the same shutdown.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 27.2 OTHER CAUSES OF VM EXITS
+
+use {
+    crate::intel::{
+        capture::Register,
+        postmortem::{dump_exit_trace, dump_last_exit_context},
+        support::{cli, cr2, hlt, outb},
+        vm::Vm,
+        vmexit::ExitType,
+        vmfield,
+    },
+    core::sync::atomic::{AtomicU8, Ordering},
+};
+
+/// What the hypervisor does after a guest triple fault has been logged.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TripleFaultAction {
+    /// Resets the machine, like the chipset does on bare metal. This is the default.
+    Reset = 0,
+
+    /// Parks the processor with interrupts disabled, so the log and the machine state can be inspected.
+    Halt = 1,
+}
+
+/// The action taken after a guest triple fault, as a `TripleFaultAction`.
+static TRIPLE_FAULT_ACTION: AtomicU8 = AtomicU8::new(TripleFaultAction::Reset as u8);
+
+/// The reset control register of the chipset.
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+
+/// Requests a hard reset of the processors (bit 1) and the system (bit 2).
+const RESET_CONTROL_HARD_RESET: u8 = 0x6;
+
+/// Sets the action taken on all processors after a guest triple fault.
+///
+/// # Arguments
+///
+/// * `action` - The new action.
+pub fn set_triple_fault_action(action: TripleFaultAction) {
+    TRIPLE_FAULT_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// Handles a triple fault of the guest.
+///
+/// Logs the guest registers, control registers, and the recorded context of this VM exit, then
+/// resets the machine or parks the processor, depending on the `TripleFaultAction`.
+///
+/// CR2 is not part of the VMCS guest-state area and is not changed by VM exits, so the host's CR2
+/// still holds the address of the guest's last page fault.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// Does not return.
+pub fn handle_triple_fault(vm: &mut Vm) -> ExitType {
+    log::error!("Guest triple fault!");
+    log::error!(
+        "RIP: {:#x} RSP: {:#x} RFLAGS: {:#x}",
+        vm.guest_reg(Register::Rip),
+        vm.guest_reg(Register::Rsp),
+        vm.guest_reg(Register::Rflags)
+    );
+    log::error!(
+        "CR0: {:#x} CR2: {:#x} CR3: {:#x} CR4: {:#x}",
+        vmfield::guest::CR0.read(),
+        cr2(),
+        vmfield::guest::CR3.read(),
+        vmfield::guest::CR4.read()
+    );
+    log::error!(
+        "Interruptibility state: {:#x} Activity state: {:#x}",
+        vmfield::guest::INTERRUPTIBILITY_STATE.read(),
+        vmfield::guest::ACTIVITY_STATE.read()
+    );
+    log::error!("Guest registers: {:#x?}", vm.guest_registers);
+    dump_last_exit_context();
+    dump_exit_trace();
+
+    if TRIPLE_FAULT_ACTION.load(Ordering::Relaxed) == TripleFaultAction::Reset as u8 {
+        log::error!("Resetting the system");
+        outb(RESET_CONTROL_PORT, RESET_CONTROL_HARD_RESET);
+    }
+
+    // Also reached if the reset did not take effect.
+    log::error!("Halting the processor");
+    cli();
+    loop {
+        hlt();
+    }
+}
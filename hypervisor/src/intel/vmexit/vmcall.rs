@@ -0,0 +1,310 @@
+//! Handles VMCALL, the interface a guest agent uses to talk to the hypervisor.
+//!
+//! The agent executes VMCALL at CPL 0 with `VMCALL_SIGNATURE` in RAX, a `VmcallCommand` in RCX,
+//! and the argument of the command in RDX. The hypervisor writes a `VmcallStatus` to RAX and resumes
+//! the guest after the VMCALL. A VMCALL without the signature raises #UD, like on hardware outside
+//! VMX operation, so guests unaware of the hypervisor see nothing unusual.
+//!
+//! The agent can register one page of its own memory as a shared page, e.g. a command ring. The
+//! hypervisor then reads commands from it and writes responses to it with `SharedPage::read` and
+//! `SharedPage::write`, instead of passing every byte through registers.
+//!
+//! The shared page stays guest memory, so its contents are untrusted and can change at any time. The
+//! hypervisor copies them out before parsing them and never keeps a pointer into the page. Every access
+//! translates the guest physical address again, and if the page no longer maps the host page that was
+//! registered, e.g. because it has since been hidden or remapped, the registration is dropped instead
+//! of writing to the new page. The hypervisor cannot tell when the guest frees the page, so the agent
+//! must unregister it first, or the guest data that later reuses the page is read as commands and
+//! overwritten by responses.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            capture::Register, ept::mtrr::MemoryType, events::EventInjection, guest::GuestId,
+            vm::Vm, vmexit::ExitType, vmfield,
+        },
+    },
+    spin::Mutex,
+    x86::bits64::paging::BASE_PAGE_SIZE,
+};
+
+/// The value of RAX that identifies a VMCALL to the hypervisor: "illusion" in ASCII.
+pub const VMCALL_SIGNATURE: u64 = 0x696c_6c75_7369_6f6e;
+
+/// A command requested with VMCALL, passed in RCX.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcallCommand {
+    /// Registers the page at the guest physical address in RDX as the shared page, replacing the
+    /// previous one.
+    RegisterSharedPage = 1,
+
+    /// Unregisters the shared page.
+    UnregisterSharedPage = 2,
+}
+
+impl VmcallCommand {
+    /// Converts the value of RCX into a command.
+    ///
+    /// # Returns
+    ///
+    /// The command, or `None` if the value is not a known command.
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            1 => Some(Self::RegisterSharedPage),
+            2 => Some(Self::UnregisterSharedPage),
+            _ => None,
+        }
+    }
+}
+
+/// The result of a VMCALL, returned in RAX.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmcallStatus {
+    /// The command succeeded.
+    Success = 0,
+
+    /// RCX does not hold a known `VmcallCommand`.
+    UnknownCommand = 1,
+
+    /// The guest physical address is not page aligned.
+    UnalignedAddress = 2,
+
+    /// The guest physical address is not mapped in the guest's EPT.
+    NotMapped = 3,
+
+    /// The page is not write-back memory, e.g. MMIO.
+    NotRam = 4,
+
+    /// The page is hypervisor memory, or hidden from the guest.
+    Reserved = 5,
+}
+
+/// The shared page registered by a guest agent.
+#[derive(Debug, Clone, Copy)]
+pub struct SharedPageRegistration {
+    /// The guest that registered the page.
+    pub guest_id: GuestId,
+
+    /// The page-aligned guest physical address of the page.
+    pub guest_pa: u64,
+
+    /// The host physical address the page was mapped to when it was registered.
+    pub host_pa: u64,
+}
+
+/// The page a guest agent registered with `VmcallCommand::RegisterSharedPage`.
+///
+/// Only one page can be registered at a time, by any guest.
+pub struct SharedPage {
+    /// The registered page, or `None` if no page is registered.
+    registration: Mutex<Option<SharedPageRegistration>>,
+}
+
+impl Default for SharedPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SharedPage {
+    /// Creates an empty `SharedPage`.
+    pub const fn new() -> Self {
+        Self {
+            registration: Mutex::new(None),
+        }
+    }
+
+    /// Returns the registered page, or `None` if no page is registered.
+    pub fn registration(&self) -> Option<SharedPageRegistration> {
+        *self.registration.lock()
+    }
+
+    /// Unregisters the shared page, if any.
+    pub fn unregister(&self) {
+        *self.registration.lock() = None;
+    }
+
+    /// Reads from the shared page, e.g. the next command of the agent.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM of the current processor, running the guest that registered the page.
+    /// * `offset` - The offset within the page to read from.
+    /// * `buf` - The buffer to fill. The read must not cross the end of the page.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or an `Err(HypervisorError)` if no page is registered by the running guest,
+    /// the page has been remapped, or the read is out of bounds.
+    pub fn read(&self, vm: &Vm, offset: usize, buf: &mut [u8]) -> Result<(), HypervisorError> {
+        let guest_pa = self.validate(vm, offset, buf.len())?;
+        vm.read_guest_phys(guest_pa, buf)
+    }
+
+    /// Writes to the shared page, e.g. the response to a command of the agent.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM of the current processor, running the guest that registered the page.
+    /// * `offset` - The offset within the page to write to.
+    /// * `buf` - The bytes to write. The write must not cross the end of the page.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or an `Err(HypervisorError)` if no page is registered by the running guest,
+    /// the page has been remapped, or the write is out of bounds.
+    pub fn write(&self, vm: &mut Vm, offset: usize, buf: &[u8]) -> Result<(), HypervisorError> {
+        let guest_pa = self.validate(vm, offset, buf.len())?;
+        vm.write_guest_phys(guest_pa, buf)
+    }
+
+    /// Checks that an access is within the page, and that the page still maps the registered host page.
+    ///
+    /// A page that has been remapped is unregistered, so it is not written to.
+    ///
+    /// # Returns
+    ///
+    /// The guest physical address to access.
+    fn validate(&self, vm: &Vm, offset: usize, len: usize) -> Result<u64, HypervisorError> {
+        let mut registration = self.registration.lock();
+
+        let Some(page) = *registration else {
+            return Err(HypervisorError::SharedPageNotRegistered);
+        };
+
+        if page.guest_id != vm.guest_id {
+            return Err(HypervisorError::SharedPageNotRegistered);
+        }
+
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > BASE_PAGE_SIZE)
+        {
+            return Err(HypervisorError::SharedPageOutOfBounds);
+        }
+
+        if validate_page(vm, page.guest_pa) != Ok(page.host_pa) {
+            log::warn!(
+                "Shared page GPA {:#x} no longer maps HPA {:#x}, unregistering it",
+                page.guest_pa,
+                page.host_pa
+            );
+            *registration = None;
+            return Err(HypervisorError::SharedPageRemapped);
+        }
+
+        Ok(page.guest_pa + offset as u64)
+    }
+}
+
+/// Handles a VMCALL.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - To move past the VMCALL after a command ran.
+/// * `ExitType::Continue` - If #UD was injected for a VMCALL without the signature or from CPL > 0.
+pub fn handle_vmcall(vm: &mut Vm) -> ExitType {
+    // CPL is the DPL of SS, in bits 6:5 of its access rights.
+    let cpl = (vmfield::guest::SS_ACCESS_RIGHTS.read() >> 5) & 0x3;
+
+    if vm.guest_reg(Register::Rax) != VMCALL_SIGNATURE || cpl != 0 {
+        EventInjection::vmentry_inject_ud();
+        return ExitType::Continue;
+    }
+
+    let command = vm.guest_reg(Register::Rcx);
+    let argument = vm.guest_reg(Register::Rdx);
+    log::debug!(
+        "VMCALL command {:#x} with argument {:#x}",
+        command,
+        argument
+    );
+
+    let status = match VmcallCommand::from_u64(command) {
+        Some(VmcallCommand::RegisterSharedPage) => register_shared_page(vm, argument),
+        Some(VmcallCommand::UnregisterSharedPage) => {
+            unsafe { vm.shared_data.as_ref() }.shared_page.unregister();
+            VmcallStatus::Success
+        }
+        None => VmcallStatus::UnknownCommand,
+    };
+
+    vm.set_guest_reg(Register::Rax, status as u64);
+
+    ExitType::IncrementRIP
+}
+
+/// Validates a guest page and registers it as the shared page.
+fn register_shared_page(vm: &Vm, guest_pa: u64) -> VmcallStatus {
+    let host_pa = match validate_page(vm, guest_pa) {
+        Ok(host_pa) => host_pa,
+        Err(status) => {
+            log::warn!(
+                "Refusing to register GPA {:#x} as the shared page: {:?}",
+                guest_pa,
+                status
+            );
+            return status;
+        }
+    };
+
+    log::info!(
+        "Registered GPA {:#x} (HPA {:#x}) as the shared page",
+        guest_pa,
+        host_pa
+    );
+
+    *unsafe { vm.shared_data.as_ref() }
+        .shared_page
+        .registration
+        .lock() = Some(SharedPageRegistration {
+        guest_id: vm.guest_id,
+        guest_pa,
+        host_pa,
+    });
+
+    VmcallStatus::Success
+}
+
+/// Checks that a guest page is ordinary guest RAM the hypervisor may write to.
+///
+/// The page must be mapped write-back in the primary EPT of the running guest, which excludes MMIO and
+/// other ranges the MTRRs mark uncacheable, and must not be hypervisor memory or the decoy page that
+/// hidden hypervisor memory is mapped to.
+///
+/// # Returns
+///
+/// The host physical address of the page, or the `VmcallStatus` describing why it was refused.
+fn validate_page(vm: &Vm, guest_pa: u64) -> Result<u64, VmcallStatus> {
+    if guest_pa & (BASE_PAGE_SIZE as u64 - 1) != 0 {
+        return Err(VmcallStatus::UnalignedAddress);
+    }
+
+    let ept = vm
+        .guest_primary_ept()
+        .map_err(|_| VmcallStatus::NotMapped)?;
+    let host_pa = ept.gpa_to_hpa(guest_pa).ok_or(VmcallStatus::NotMapped)?;
+
+    if ept.page_memory_type(guest_pa) != Some(MemoryType::WriteBack) {
+        return Err(VmcallStatus::NotRam);
+    }
+
+    let shared_data = unsafe { vm.shared_data.as_ref() };
+    if host_pa == shared_data.decoy_page_pa
+        || shared_data
+            .reserved_regions
+            .check(host_pa..host_pa + BASE_PAGE_SIZE as u64)
+            .is_err()
+    {
+        return Err(VmcallStatus::Reserved);
+    }
+
+    Ok(host_pa)
+}
@@ -0,0 +1,58 @@
+//! Handles VM exits caused by VMFUNC.
+//!
+//! VMFUNC only causes a VM exit if the requested VM function is not enabled, or if EPTP switching
+//! was requested with an index beyond the EPTP list or to an invalid EPTP. Real hardware without a
+//! hypervisor raises #UD for VMFUNC, so malformed calls are answered with #UD as well.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.6 VM Functions
+
+use crate::intel::{
+    capture::Register, events::EventInjection, guest::GuestId, shared::EptpSlot, vm::Vm,
+    vmexit::ExitType, vmfunc::VMFUNC_EPTP_SWITCHING,
+};
+
+/// Handles the VMFUNC VM exit.
+///
+/// The function number in EAX must be EPTP switching, and the EPTP index in ECX must select a
+/// registered `EptpSlot` of the default guest, whose EPTs are the only ones in the EPTP list. Valid
+/// requests are carried out by the hypervisor, and all others inject #UD.
+///
+/// # Arguments
+///
+/// * `vm` - The VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::IncrementRIP` - If the EPTP was switched.
+/// * `ExitType::Continue` - If #UD was injected, so the guest's handler sees the faulting VMFUNC.
+pub fn handle_vmfunc(vm: &mut Vm) -> ExitType {
+    let function = vm.guest_reg(Register::Rax) & 0xffff_ffff;
+    let eptp_index = vm.guest_reg(Register::Rcx) & 0xffff_ffff;
+    log::trace!(
+        "Handling VMFUNC VM exit: function {:#x}, EPTP index {:#x}",
+        function,
+        eptp_index
+    );
+
+    let slot = EptpSlot::new(eptp_index as usize)
+        .filter(|_| function == VMFUNC_EPTP_SWITCHING && vm.guest_id == GuestId::DEFAULT);
+
+    let Some(slot) = slot else {
+        log::debug!(
+            "Invalid VMFUNC: function {:#x}, EPTP index {:#x}",
+            function,
+            eptp_index
+        );
+        EventInjection::vmentry_inject_ud();
+        return ExitType::Continue;
+    };
+
+    match unsafe { vm.shared_data.as_ref() }.set_active_eptp(vm.guest_id, slot) {
+        Ok(()) => ExitType::IncrementRIP,
+        Err(e) => {
+            log::debug!("VMFUNC to EPTP index {:#x} failed: {}", eptp_index, e);
+            EventInjection::vmentry_inject_ud();
+            ExitType::Continue
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Handles VM exits caused by the guest executing VMX instructions.
+//!
+//! Nested virtualization is not supported, so a guest that tries to run its own hypervisor or
+//! sandbox must see VMX as unavailable instead of crashing the whole stack. The guest is already told
+//! so by CPUID, by the CR4 read shadow, where CR4.VMXE is always clear and cannot be set, and by the
+//! IA32_FEATURE_CONTROL shadow value if one is set (see `set_feature_control_shadow`).
+//!
+//! With CR4.VMXE clear, every VMX instruction raises #UD outside VMX operation, including VMXON,
+//! which raises #GP for a locked IA32_FEATURE_CONTROL only once CR4.VMXE is set. The handler
+//! therefore injects #UD for all of them, which is what the guest would get on hardware without VMX.
+//!
+//! INVEPT and INVVPID are handled separately by `handle_invept` and `handle_invvpid`.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.3 VMX INSTRUCTIONS
+
+use {
+    crate::intel::{
+        events::EventInjection,
+        vm::Vm,
+        vmerror::VmxBasicExitReason,
+        vmexit::{dispatch::handle_unhandled_exit, ExitType},
+        vmfield,
+    },
+    core::sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of VMX instructions executed by the guest on all processors.
+static NESTED_VMX_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of VMX instructions the guest has executed on all processors since boot.
+pub fn nested_vmx_attempts() -> u64 {
+    NESTED_VMX_ATTEMPTS.load(Ordering::Relaxed)
+}
+
+/// Handles a VM exit caused by VMXON, VMXOFF, VMCLEAR, VMPTRLD, VMPTRST, VMREAD, VMWRITE, VMLAUNCH,
+/// or VMRESUME by logging the attempt and injecting #UD.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the VM of the current processor.
+///
+/// # Returns
+///
+/// * `ExitType::Continue` - To deliver the #UD at the VMX instruction, without advancing RIP.
+pub fn handle_vmx_instruction(vm: &mut Vm) -> ExitType {
+    let exit_reason = vmfield::ro::EXIT_REASON.read();
+    let Some(basic_exit_reason) = VmxBasicExitReason::from_u32(exit_reason) else {
+        return handle_unhandled_exit(vm);
+    };
+
+    let attempts = NESTED_VMX_ATTEMPTS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    log::warn!(
+        "Nested VMX attempt #{} on processor {}: {} at RIP {:#x}, injecting #UD",
+        attempts,
+        vm.apic_id,
+        basic_exit_reason,
+        vmfield::guest::RIP.read()
+    );
+
+    EventInjection::vmentry_inject_ud();
+
+    ExitType::Continue
+}
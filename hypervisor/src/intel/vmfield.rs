@@ -0,0 +1,332 @@
+//! Provides typed access to VMCS fields.
+//!
+//! The raw `vmread`/`vmwrite` wrappers in `support` take a bare field encoding and return or accept
+//! a `u64`, so reading a 32-bit field or writing a read-only field is only caught at runtime, if at
+//! all. `VmcsField` carries the width and the access type of a field in its type: reads return the
+//! field's natural Rust integer type, and writes to read-only (VM-exit information) fields do not
+//! compile. Field definitions are checked against their encoding at compile time.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.11.2 VMREAD, VMWRITE, and Encodings of VMCS Fields
+
+use {
+    crate::{error::HypervisorError, intel::vmerror::VmInstructionError},
+    core::marker::PhantomData,
+    x86::vmx::{vmcs, VmFail},
+};
+
+/// The width of a VMCS field and the Rust type used to represent its value.
+pub trait FieldWidth {
+    /// The Rust type of the field value.
+    type Value: Copy;
+
+    /// The width encoding in bits 14:13 of the field encoding.
+    const ENCODING: u32;
+
+    /// Converts a raw value returned by `VMREAD` into the field value.
+    fn from_raw(raw: u64) -> Self::Value;
+
+    /// Converts a field value into the raw value passed to `VMWRITE`.
+    fn into_raw(value: Self::Value) -> u64;
+}
+
+/// A 16-bit VMCS field.
+pub struct Bits16;
+
+/// A 64-bit VMCS field.
+pub struct Bits64;
+
+/// A 32-bit VMCS field.
+pub struct Bits32;
+
+/// A natural-width VMCS field (64 bits on processors that support Intel 64).
+pub struct Natural;
+
+impl FieldWidth for Bits16 {
+    type Value = u16;
+    const ENCODING: u32 = 0;
+
+    fn from_raw(raw: u64) -> u16 {
+        raw as u16
+    }
+
+    fn into_raw(value: u16) -> u64 {
+        u64::from(value)
+    }
+}
+
+impl FieldWidth for Bits64 {
+    type Value = u64;
+    const ENCODING: u32 = 1;
+
+    fn from_raw(raw: u64) -> u64 {
+        raw
+    }
+
+    fn into_raw(value: u64) -> u64 {
+        value
+    }
+}
+
+impl FieldWidth for Bits32 {
+    type Value = u32;
+    const ENCODING: u32 = 2;
+
+    fn from_raw(raw: u64) -> u32 {
+        raw as u32
+    }
+
+    fn into_raw(value: u32) -> u64 {
+        u64::from(value)
+    }
+}
+
+impl FieldWidth for Natural {
+    type Value = u64;
+    const ENCODING: u32 = 3;
+
+    fn from_raw(raw: u64) -> u64 {
+        raw
+    }
+
+    fn into_raw(value: u64) -> u64 {
+        value
+    }
+}
+
+/// Whether a VMCS field can be written.
+pub trait FieldAccess {
+    /// `true` if `VMWRITE` to the field is allowed.
+    const WRITABLE: bool;
+}
+
+/// A read-only VM-exit information field.
+pub struct ReadOnly;
+
+/// A control, guest-state, or host-state field.
+pub struct ReadWrite;
+
+impl FieldAccess for ReadOnly {
+    const WRITABLE: bool = false;
+}
+
+impl FieldAccess for ReadWrite {
+    const WRITABLE: bool = true;
+}
+
+/// A VMCS field with a statically known width and access type.
+pub struct VmcsField<W: FieldWidth, A: FieldAccess> {
+    /// The field encoding passed to `VMREAD`/`VMWRITE`.
+    encoding: u32,
+    _marker: PhantomData<(W, A)>,
+}
+
+impl<W: FieldWidth, A: FieldAccess> VmcsField<W, A> {
+    /// The field type encoding (bits 11:10) of VM-exit information fields.
+    const EXIT_INFORMATION_TYPE: u32 = 1;
+
+    /// Defines a VMCS field.
+    ///
+    /// Panics (at compile time when used in a `const`) if the width or access type does not match
+    /// the field encoding.
+    ///
+    /// # Arguments
+    ///
+    /// * `encoding` - The field encoding, e.g. `x86::vmx::vmcs::guest::RIP`.
+    pub const fn new(encoding: u32) -> Self {
+        assert!(
+            (encoding >> 13) & 0b11 == W::ENCODING,
+            "VMCS field width does not match its encoding"
+        );
+        assert!(
+            ((encoding >> 10) & 0b11 == Self::EXIT_INFORMATION_TYPE) != A::WRITABLE,
+            "VMCS field access type does not match its encoding"
+        );
+
+        Self {
+            encoding,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the field encoding.
+    pub const fn encoding(&self) -> u32 {
+        self.encoding
+    }
+
+    /// Reads the field from the current VMCS.
+    ///
+    /// # Returns
+    ///
+    /// Returns the field value, or an `Err(HypervisorError)` if `VMREAD` failed.
+    pub fn try_read(&self) -> Result<W::Value, HypervisorError> {
+        let raw = unsafe { x86::bits64::vmx::vmread(self.encoding) }.map_err(vm_fail_to_error)?;
+        Ok(W::from_raw(raw))
+    }
+
+    /// Reads the field from the current VMCS.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `VMREAD` failed, e.g. if there is no current VMCS.
+    pub fn read(&self) -> W::Value {
+        self.try_read().unwrap()
+    }
+}
+
+impl<W: FieldWidth> VmcsField<W, ReadWrite> {
+    /// Writes the field in the current VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value to write.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())`, or an `Err(HypervisorError)` if `VMWRITE` failed.
+    pub fn try_write(&self, value: W::Value) -> Result<(), HypervisorError> {
+        unsafe { x86::bits64::vmx::vmwrite(self.encoding, W::into_raw(value)) }
+            .map_err(vm_fail_to_error)
+    }
+
+    /// Writes the field in the current VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value to write.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `VMWRITE` failed, e.g. if there is no current VMCS.
+    pub fn write(&self, value: W::Value) {
+        self.try_write(value).unwrap()
+    }
+}
+
+/// Converts the failure status of a VMX instruction into a `HypervisorError`.
+///
+/// For `VMfailValid`, the VM-instruction error field is read and logged.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 31.2 CONVENTIONS
+pub(crate) fn vm_fail_to_error(fail: VmFail) -> HypervisorError {
+    match fail {
+        VmFail::VmFailValid => {
+            let instruction_error =
+                unsafe { x86::bits64::vmx::vmread(vmcs::ro::VM_INSTRUCTION_ERROR) }.unwrap_or(0)
+                    as u32;
+            match VmInstructionError::from_u32(instruction_error) {
+                Some(error) => log::error!("VMX instruction failed: {}", error),
+                None => log::error!(
+                    "VMX instruction failed: unknown error {:#x}",
+                    instruction_error
+                ),
+            }
+            HypervisorError::VmInstructionError
+        }
+        VmFail::VmFailInvalid => HypervisorError::VmFailInvalid,
+    }
+}
+
+/// Typed VMCS control fields.
+pub mod control {
+    use super::*;
+
+    pub const PINBASED_EXEC_CONTROLS: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::PINBASED_EXEC_CONTROLS);
+    pub const PRIMARY_PROCBASED_EXEC_CONTROLS: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    pub const SECONDARY_PROCBASED_EXEC_CONTROLS: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS);
+    pub const EXCEPTION_BITMAP: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::EXCEPTION_BITMAP);
+    pub const VMEXIT_CONTROLS: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::VMEXIT_CONTROLS);
+    pub const VMENTRY_CONTROLS: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::VMENTRY_CONTROLS);
+    pub const VMENTRY_INTERRUPTION_INFO_FIELD: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD);
+    pub const VMENTRY_EXCEPTION_ERR_CODE: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE);
+    pub const VMENTRY_INSTRUCTION_LEN: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::control::VMENTRY_INSTRUCTION_LEN);
+    pub const CR0_GUEST_HOST_MASK: VmcsField<Natural, ReadWrite> =
+        VmcsField::new(vmcs::control::CR0_GUEST_HOST_MASK);
+    pub const CR4_GUEST_HOST_MASK: VmcsField<Natural, ReadWrite> =
+        VmcsField::new(vmcs::control::CR4_GUEST_HOST_MASK);
+    pub const CR0_READ_SHADOW: VmcsField<Natural, ReadWrite> =
+        VmcsField::new(vmcs::control::CR0_READ_SHADOW);
+    pub const CR4_READ_SHADOW: VmcsField<Natural, ReadWrite> =
+        VmcsField::new(vmcs::control::CR4_READ_SHADOW);
+    pub const EPTP_FULL: VmcsField<Bits64, ReadWrite> = VmcsField::new(vmcs::control::EPTP_FULL);
+    pub const TSC_OFFSET_FULL: VmcsField<Bits64, ReadWrite> =
+        VmcsField::new(vmcs::control::TSC_OFFSET_FULL);
+    pub const TSC_MULTIPLIER_FULL: VmcsField<Bits64, ReadWrite> =
+        VmcsField::new(vmcs::control::TSC_MULTIPLIER_FULL);
+    pub const VIRT_APIC_ADDR_FULL: VmcsField<Bits64, ReadWrite> =
+        VmcsField::new(vmcs::control::VIRT_APIC_ADDR_FULL);
+    pub const VPID: VmcsField<Bits16, ReadWrite> = VmcsField::new(vmcs::control::VPID);
+    pub const VM_FUNCTION_CONTROLS: VmcsField<Bits64, ReadWrite> =
+        VmcsField::new(vmcs::control::VM_FUNCTION_CONTROLS_FULL);
+    pub const EPTP_LIST_ADDR: VmcsField<Bits64, ReadWrite> =
+        VmcsField::new(vmcs::control::EPTP_LIST_ADDR_FULL);
+}
+
+/// Typed VMCS guest-state fields.
+pub mod guest {
+    use super::*;
+
+    pub const CR0: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::CR0);
+    pub const CR3: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::CR3);
+    pub const CR4: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::CR4);
+    pub const RSP: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::RSP);
+    pub const RIP: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::RIP);
+    pub const RFLAGS: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::RFLAGS);
+    pub const ES_BASE: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::ES_BASE);
+    pub const CS_BASE: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::CS_BASE);
+    pub const SS_BASE: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::SS_BASE);
+    pub const DS_BASE: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::DS_BASE);
+    pub const FS_BASE: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::FS_BASE);
+    pub const GS_BASE: VmcsField<Natural, ReadWrite> = VmcsField::new(vmcs::guest::GS_BASE);
+    pub const CS_ACCESS_RIGHTS: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::guest::CS_ACCESS_RIGHTS);
+    pub const SS_ACCESS_RIGHTS: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::guest::SS_ACCESS_RIGHTS);
+    pub const INTERRUPTIBILITY_STATE: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::guest::INTERRUPTIBILITY_STATE);
+    pub const ACTIVITY_STATE: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::guest::ACTIVITY_STATE);
+    pub const PENDING_DBG_EXCEPTIONS: VmcsField<Natural, ReadWrite> =
+        VmcsField::new(vmcs::guest::PENDING_DBG_EXCEPTIONS);
+    pub const IA32_DEBUGCTL_FULL: VmcsField<Bits64, ReadWrite> =
+        VmcsField::new(vmcs::guest::IA32_DEBUGCTL_FULL);
+    /// Guest IA32_LBR_CTL, which the `x86` crate does not define.
+    pub const IA32_LBR_CTL_FULL: VmcsField<Bits64, ReadWrite> = VmcsField::new(0x2816);
+    pub const VMX_PREEMPTION_TIMER_VALUE: VmcsField<Bits32, ReadWrite> =
+        VmcsField::new(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE);
+}
+
+/// Typed VMCS read-only (VM-exit information) fields.
+pub mod ro {
+    use super::*;
+
+    pub const VM_INSTRUCTION_ERROR: VmcsField<Bits32, ReadOnly> =
+        VmcsField::new(vmcs::ro::VM_INSTRUCTION_ERROR);
+    pub const EXIT_REASON: VmcsField<Bits32, ReadOnly> = VmcsField::new(vmcs::ro::EXIT_REASON);
+    pub const VMEXIT_INTERRUPTION_INFO: VmcsField<Bits32, ReadOnly> =
+        VmcsField::new(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
+    pub const VMEXIT_INTERRUPTION_ERR_CODE: VmcsField<Bits32, ReadOnly> =
+        VmcsField::new(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
+    pub const IDT_VECTORING_INFO: VmcsField<Bits32, ReadOnly> =
+        VmcsField::new(vmcs::ro::IDT_VECTORING_INFO);
+    pub const IDT_VECTORING_ERR_CODE: VmcsField<Bits32, ReadOnly> =
+        VmcsField::new(vmcs::ro::IDT_VECTORING_ERR_CODE);
+    pub const VMEXIT_INSTRUCTION_LEN: VmcsField<Bits32, ReadOnly> =
+        VmcsField::new(vmcs::ro::VMEXIT_INSTRUCTION_LEN);
+    pub const VMEXIT_INSTRUCTION_INFO: VmcsField<Bits32, ReadOnly> =
+        VmcsField::new(vmcs::ro::VMEXIT_INSTRUCTION_INFO);
+    pub const EXIT_QUALIFICATION: VmcsField<Natural, ReadOnly> =
+        VmcsField::new(vmcs::ro::EXIT_QUALIFICATION);
+    pub const GUEST_LINEAR_ADDR: VmcsField<Natural, ReadOnly> =
+        VmcsField::new(vmcs::ro::GUEST_LINEAR_ADDR);
+    pub const GUEST_PHYSICAL_ADDR_FULL: VmcsField<Bits64, ReadOnly> =
+        VmcsField::new(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL);
+}
@@ -0,0 +1,73 @@
+//! Intel® 64 and IA-32 Architectures Software Developer's Manual: 26.5.6 VM Functions
+//!
+//! VM functions are invoked by the guest with VMFUNC and run without a VM exit. VM function 0 (EPTP
+//! switching) loads one of the EPTPs in the EPTP list, selected by ECX, which lets the guest switch
+//! between EPT views far more cheaply than through a VM exit. The EPTP list is the 4KB page of 512
+//! EPTPs kept in `SharedData`.
+
+use {
+    crate::intel::{
+        controls::{is_vmx_control_supported, VmxControl},
+        support::rdmsr,
+        vmfield,
+    },
+    bit_field::BitField,
+    x86::vmx::vmcs,
+};
+
+/// The number of EPTPs in an EPTP list.
+pub const EPTP_LIST_LENGTH: usize = 512;
+
+/// The VM function that switches to an EPTP in the EPTP list.
+pub const VMFUNC_EPTP_SWITCHING: u64 = 0;
+
+/// The IA32_VMX_VMFUNC MSR, which reports the supported VM functions.
+const IA32_VMX_VMFUNC: u32 = 0x491;
+
+/// The secondary processor-based control that enables VM functions.
+const ENABLE_VM_FUNCTIONS: u32 = vmcs::control::SecondaryControls::ENABLE_VM_FUNCTIONS.bits();
+
+/// The page of EPTPs the guest can switch to with VMFUNC.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.6.14 VM-Function Controls
+#[repr(C, align(4096))]
+pub struct EptpList(pub [u64; EPTP_LIST_LENGTH]);
+
+/// Checks whether the processor supports EPTP switching with VMFUNC.
+///
+/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: A.11 VM FUNCTIONS
+pub fn is_eptp_switching_supported() -> bool {
+    is_vmx_control_supported(VmxControl::ProcessorBased2, ENABLE_VM_FUNCTIONS as u64)
+        && rdmsr(IA32_VMX_VMFUNC).get_bit(VMFUNC_EPTP_SWITCHING as usize)
+}
+
+/// Enables or disables EPTP switching in the current VMCS.
+///
+/// Must be called after the secondary processor-based controls have been written. Does nothing if
+/// EPTP switching is not supported.
+///
+/// # Arguments
+///
+/// * `eptp_list_pa` - The physical address of the EPTP list to switch between, or `None` to disable
+///   EPTP switching, e.g. for a guest that must not reach the EPTs of another guest.
+pub fn setup_eptp_switching(eptp_list_pa: Option<u64>) {
+    if !is_eptp_switching_supported() {
+        return;
+    }
+
+    let secondary_controls = vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+
+    match eptp_list_pa {
+        Some(eptp_list_pa) => {
+            vmfield::control::EPTP_LIST_ADDR.write(eptp_list_pa);
+            vmfield::control::VM_FUNCTION_CONTROLS.write(1 << VMFUNC_EPTP_SWITCHING);
+            vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS
+                .write(secondary_controls | ENABLE_VM_FUNCTIONS);
+        }
+        None => {
+            vmfield::control::SECONDARY_PROCBASED_EXEC_CONTROLS
+                .write(secondary_controls & !ENABLE_VM_FUNCTIONS);
+            vmfield::control::VM_FUNCTION_CONTROLS.write(0);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Detects VM-exit handlers that run for too long.
+//!
+//! The VM exit loop reports the start and the end of each handler to the `Watchdog`. A handler
+//! that exceeds the configured threshold is reported with its exit reason and the guest RIP when it
+//! finally returns. A handler that never returns, e.g. because of an infinite loop, cannot be
+//! interrupted, since the host has no interrupt handlers. Instead, every processor checks the
+//! handlers running on the other processors whenever it handles a VM exit itself, and reports the
+//! ones that exceeded the threshold. This relies on other processors exiting regularly, which a
+//! registered VMX-preemption timer callback guarantees even for an idle guest.
+//!
+//! The watchdog is disabled by default, and costs two atomic stores per VM exit while enabled.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{reserved::ReservedRegions, support::rdtsc, vmerror::VmxBasicExitReason},
+    },
+    alloc::boxed::Box,
+    core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+};
+
+/// The number of processors the watchdog can track, indexed by the 8-bit initial APIC ID.
+const MAX_PROCESSORS: usize = 256;
+
+/// What the watchdog does when a handler exceeds the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// Log the exit reason and the guest RIP, and keep running.
+    Log,
+
+    /// Log the exit reason and the guest RIP, and panic. The panic handler prints the last VM exit
+    /// of the reporting processor and halts it, so the system stops in a diagnosable state instead
+    /// of hanging silently.
+    Panic,
+}
+
+/// The handler running on a processor.
+struct RunningHandler {
+    /// The TSC when the handler started, or 0 if no handler is running.
+    start_tsc: AtomicU64,
+
+    /// The basic exit reason the handler was dispatched for.
+    exit_reason: AtomicU16,
+
+    /// The guest RIP at the VM exit.
+    guest_rip: AtomicU64,
+
+    /// Whether another processor has already reported the handler as stuck.
+    reported: AtomicBool,
+}
+
+/// An idle processor, used to initialize `Watchdog::handlers`.
+#[allow(clippy::declare_interior_mutable_const)]
+const IDLE: RunningHandler = RunningHandler {
+    start_tsc: AtomicU64::new(0),
+    exit_reason: AtomicU16::new(0),
+    guest_rip: AtomicU64::new(0),
+    reported: AtomicBool::new(false),
+};
+
+/// Watchdog for the VM-exit handlers of all processors.
+pub struct Watchdog {
+    /// The maximum duration of a handler in TSC cycles, or 0 if the watchdog is disabled.
+    threshold_tsc: AtomicU64,
+
+    /// Whether to panic instead of only logging when a handler exceeds the threshold.
+    panic: AtomicBool,
+
+    /// The handler running on each processor, indexed by APIC ID.
+    /// Allocated on the heap, so that `SharedData` can be built on the firmware stack.
+    handlers: Box<[RunningHandler]>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watchdog {
+    /// Creates a disabled `Watchdog`.
+    pub fn new() -> Self {
+        Self {
+            threshold_tsc: AtomicU64::new(0),
+            panic: AtomicBool::new(false),
+            handlers: (0..MAX_PROCESSORS).map(|_| IDLE).collect(),
+        }
+    }
+
+    /// Registers the memory of the per-processor handlers as owned by the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved_regions` - The host memory owned by the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` on success, or `Err(HypervisorError::ReservedRegionsFull)` if no free slot is left.
+    pub fn reserve_storage(
+        &self,
+        reserved_regions: &ReservedRegions,
+    ) -> Result<(), HypervisorError> {
+        reserved_regions.reserve_object(&*self.handlers)
+    }
+
+    /// Enables or disables the watchdog.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold_tsc` - The maximum duration of a handler in TSC cycles, or 0 to disable the watchdog.
+    /// * `action` - What to do when a handler exceeds the threshold.
+    pub fn configure(&self, threshold_tsc: u64, action: WatchdogAction) {
+        self.panic
+            .store(action == WatchdogAction::Panic, Ordering::Relaxed);
+        self.threshold_tsc.store(threshold_tsc, Ordering::Release);
+    }
+
+    /// Records the start of a handler on the current processor, and checks the handlers of all other processors.
+    ///
+    /// # Arguments
+    ///
+    /// * `apic_id` - The APIC ID of the current processor.
+    /// * `exit_reason` - The basic exit reason the handler is dispatched for.
+    /// * `guest_rip` - The guest RIP at the VM exit.
+    pub fn enter(&self, apic_id: u32, exit_reason: VmxBasicExitReason, guest_rip: u64) {
+        let threshold_tsc = self.threshold_tsc.load(Ordering::Acquire);
+        if threshold_tsc == 0 {
+            return;
+        }
+
+        let now = rdtsc();
+
+        for (index, handler) in self.handlers.iter().enumerate() {
+            if index == apic_id as usize {
+                continue;
+            }
+
+            let start_tsc = handler.start_tsc.load(Ordering::Acquire);
+            if start_tsc == 0 || now.saturating_sub(start_tsc) <= threshold_tsc {
+                continue;
+            }
+
+            // Report each stuck handler once, not on every VM exit of every other processor.
+            if !handler.reported.swap(true, Ordering::Relaxed) {
+                self.expire(
+                    index,
+                    handler.exit_reason.load(Ordering::Relaxed),
+                    handler.guest_rip.load(Ordering::Relaxed),
+                    now - start_tsc,
+                    "is still running after",
+                );
+            }
+        }
+
+        let Some(handler) = self.handlers.get(apic_id as usize) else {
+            return;
+        };
+
+        handler
+            .exit_reason
+            .store(exit_reason as u16, Ordering::Relaxed);
+        handler.guest_rip.store(guest_rip, Ordering::Relaxed);
+        handler.reported.store(false, Ordering::Relaxed);
+        handler.start_tsc.store(now, Ordering::Release);
+    }
+
+    /// Records the end of the handler on the current processor, and reports it if it exceeded the threshold.
+    ///
+    /// # Arguments
+    ///
+    /// * `apic_id` - The APIC ID of the current processor.
+    pub fn leave(&self, apic_id: u32) {
+        let Some(handler) = self.handlers.get(apic_id as usize) else {
+            return;
+        };
+
+        let start_tsc = handler.start_tsc.swap(0, Ordering::AcqRel);
+        let threshold_tsc = self.threshold_tsc.load(Ordering::Acquire);
+        if start_tsc == 0 || threshold_tsc == 0 {
+            return;
+        }
+
+        let elapsed = rdtsc().saturating_sub(start_tsc);
+        if elapsed > threshold_tsc && !handler.reported.load(Ordering::Relaxed) {
+            self.expire(
+                apic_id as usize,
+                handler.exit_reason.load(Ordering::Relaxed),
+                handler.guest_rip.load(Ordering::Relaxed),
+                elapsed,
+                "took",
+            );
+        }
+    }
+
+    /// Reports a handler that exceeded the threshold, and panics if configured to.
+    fn expire(&self, apic_id: usize, exit_reason: u16, guest_rip: u64, elapsed: u64, state: &str) {
+        match VmxBasicExitReason::from_u32(exit_reason as u32) {
+            Some(exit_reason) => log::error!(
+                "Watchdog: handler for {} on processor {} at guest RIP {:#x} {} {} TSC cycles",
+                exit_reason,
+                apic_id,
+                guest_rip,
+                state,
+                elapsed
+            ),
+            None => log::error!(
+                "Watchdog: handler for exit reason {} on processor {} at guest RIP {:#x} {} {} TSC cycles",
+                exit_reason,
+                apic_id,
+                guest_rip,
+                state,
+                elapsed
+            ),
+        }
+
+        if self.panic.load(Ordering::Relaxed) {
+            panic!(
+                "VM-exit handler on processor {} exceeded the watchdog threshold",
+                apic_id
+            );
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Saves and restores the guest's x87, SSE, and AVX register state around VM-exit handling.
+//!
+//! VM exits do not switch the extended register state, so the host runs with the guest's FPU, XMM,
+//! and YMM registers loaded. Rust code in exit handlers may use SSE registers (e.g. for copies and
+//! formatting), which would silently corrupt the guest's values. The dispatch loop therefore saves
+//! the guest's state right after each VM exit and restores it before the next VM entry.
+//!
+//! XCR0 is not switched by VM exits either and always holds the guest's value, so XSAVE with XCR0
+//! saves exactly the components the guest has enabled. If the host has not enabled XSAVE in CR4,
+//! the legacy FXSAVE area (x87 and SSE) is saved instead.
+//!
+//! Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 13 MANAGING STATE USING THE XSAVE FEATURE SET
+
+use {
+    crate::intel::support::cr4,
+    alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error},
+    core::{alloc::Layout, arch::asm, ptr::NonNull},
+    x86::controlregs::Cr4,
+};
+
+/// The size of the legacy FXSAVE area.
+const FXSAVE_AREA_SIZE: usize = 512;
+
+/// The alignment XSAVE requires for its save area. Also satisfies FXSAVE's 16-byte alignment.
+const XSAVE_AREA_ALIGNMENT: usize = 64;
+
+/// The instruction last used to save the state, which determines how it is restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SavedWith {
+    /// Nothing has been saved yet.
+    Nothing,
+    /// The state was saved with FXSAVE64.
+    Fxsave,
+    /// The state was saved with XSAVE64.
+    Xsave,
+}
+
+/// A per-processor buffer holding the guest's extended register state while an exit is handled.
+#[derive(Debug)]
+pub struct ExtendedState {
+    /// The 64-byte aligned save area.
+    area: NonNull<u8>,
+
+    /// The layout `area` was allocated with.
+    layout: Layout,
+
+    /// How the state in `area` was saved.
+    saved_with: SavedWith,
+}
+
+impl Default for ExtendedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtendedState {
+    /// Allocates a save area large enough for every state component the processor supports.
+    ///
+    /// The size is taken from CPUID.(EAX=0DH,ECX=0):ECX, so the area still fits if the guest enables
+    /// more components in XCR0 later on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if memory allocation fails.
+    pub fn new() -> Self {
+        let size = match is_xsave_supported() {
+            true => (x86::cpuid::cpuid!(0xd, 0).ecx as usize).max(FXSAVE_AREA_SIZE),
+            false => FXSAVE_AREA_SIZE,
+        };
+
+        let layout = Layout::from_size_align(size, XSAVE_AREA_ALIGNMENT).unwrap();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+
+        log::trace!("Extended state save area size: {:#x}", size);
+
+        Self {
+            area,
+            layout,
+            saved_with: SavedWith::Nothing,
+        }
+    }
+
+    /// Saves the extended register state of the current processor.
+    ///
+    /// Uses XSAVE with all components enabled in XCR0 if the host has CR4.OSXSAVE set, and FXSAVE otherwise.
+    pub fn save(&mut self) {
+        let area = self.area.as_ptr();
+
+        if cr4().contains(Cr4::CR4_ENABLE_OS_XSAVE) {
+            // The requested-feature bitmap in EDX:EAX is ANDed with XCR0.
+            unsafe {
+                asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack),
+                )
+            };
+            self.saved_with = SavedWith::Xsave;
+        } else {
+            unsafe { asm!("fxsave64 [{}]", in(reg) area, options(nostack)) };
+            self.saved_with = SavedWith::Fxsave;
+        }
+    }
+
+    /// Restores the extended register state saved by the last call to `save`.
+    ///
+    /// Components the guest enabled in XCR0 while the exit was handled are restored to their initial state.
+    pub fn restore(&mut self) {
+        let area = self.area.as_ptr();
+
+        match self.saved_with {
+            SavedWith::Nothing => {}
+            SavedWith::Fxsave => unsafe { asm!("fxrstor64 [{}]", in(reg) area, options(nostack)) },
+            SavedWith::Xsave => unsafe {
+                asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack),
+                )
+            },
+        }
+    }
+}
+
+impl Drop for ExtendedState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), self.layout) };
+    }
+}
+
+/// Checks whether the processor supports XSAVE (CPUID.01H:ECX.XSAVE[bit 26]).
+fn is_xsave_supported() -> bool {
+    x86::cpuid::cpuid!(0x1).ecx & (1 << 26) != 0
+}
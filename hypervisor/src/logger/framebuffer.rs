@@ -0,0 +1,276 @@
+//! Provides a log sink that renders text to a linear framebuffer.
+//!
+//! The framebuffer is the one set up by the UEFI Graphics Output Protocol (GOP), which stays mapped
+//! at the same physical address after the driver returns. This makes the log visible on machines
+//! without a serial port, such as most laptops, until the guest operating system takes over the
+//! display. Writing to the framebuffer afterwards draws over whatever the guest displays, so the
+//! sink is meant for debugging the boot process rather than for long-running sessions.
+//!
+//! Text is drawn with a built-in 5x8 bitmap font for printable ASCII. Instead of scrolling, which
+//! would copy the whole framebuffer for every line, the sink wraps around to the top and clears each
+//! line before writing it.
+
+use crate::logger::LogSink;
+
+/// The width of a character cell in pixels, including one column of spacing.
+const CELL_WIDTH: usize = 6;
+
+/// The height of a character cell in pixels, including two rows of spacing.
+const CELL_HEIGHT: usize = 10;
+
+/// The number of pixel columns of a glyph.
+const GLYPH_WIDTH: usize = 5;
+
+/// The color of the text, light gray in both the RGB and the BGR pixel format.
+const FOREGROUND: u32 = 0x00c0_c0c0;
+
+/// The color behind the text.
+const BACKGROUND: u32 = 0x0000_0000;
+
+/// The first character of `FONT`.
+const FONT_FIRST: u8 = b' ';
+
+/// The bitmap font for the printable ASCII characters from `' '` to `'~'`.
+///
+/// Each glyph is 8 rows from top to bottom, and bit 4 of a row is its leftmost pixel. The last row
+/// is only used by descenders.
+#[rustfmt::skip]
+const FONT: [[u8; 8]; 95] = [
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // ' '
+    [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100, 0b00000], // '!'
+    [0b01010, 0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // '"'
+    [0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010, 0b00000], // '#'
+    [0b00100, 0b01111, 0b10100, 0b01110, 0b00101, 0b11110, 0b00100, 0b00000], // '$'
+    [0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011, 0b00000], // '%'
+    [0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101, 0b00000], // '&'
+    [0b00100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // '\''
+    [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010, 0b00000], // '('
+    [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000, 0b00000], // ')'
+    [0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000, 0b00000], // '*'
+    [0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000, 0b00000], // '+'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000, 0b00000], // ','
+    [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000, 0b00000], // '-'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100, 0b00000], // '.'
+    [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000, 0b00000], // '/'
+    [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110, 0b00000], // '0'
+    [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110, 0b00000], // '1'
+    [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111, 0b00000], // '2'
+    [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110, 0b00000], // '3'
+    [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010, 0b00000], // '4'
+    [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110, 0b00000], // '5'
+    [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110, 0b00000], // '6'
+    [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00000], // '7'
+    [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110, 0b00000], // '8'
+    [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100, 0b00000], // '9'
+    [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000, 0b00000], // ':'
+    [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b00100, 0b01000, 0b00000], // ';'
+    [0b00010, 0b00100, 0b01000, 0b10000, 0b01000, 0b00100, 0b00010, 0b00000], // '<'
+    [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000], // '='
+    [0b01000, 0b00100, 0b00010, 0b00001, 0b00010, 0b00100, 0b01000, 0b00000], // '>'
+    [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100, 0b00000], // '?'
+    [0b01110, 0b10001, 0b00001, 0b01101, 0b10101, 0b10101, 0b01110, 0b00000], // '@'
+    [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001, 0b00000], // 'A'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110, 0b00000], // 'B'
+    [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110, 0b00000], // 'C'
+    [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100, 0b00000], // 'D'
+    [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111, 0b00000], // 'E'
+    [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000, 0b00000], // 'F'
+    [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111, 0b00000], // 'G'
+    [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001, 0b00000], // 'H'
+    [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110, 0b00000], // 'I'
+    [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100, 0b00000], // 'J'
+    [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001, 0b00000], // 'K'
+    [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111, 0b00000], // 'L'
+    [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001, 0b00000], // 'M'
+    [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001, 0b00000], // 'N'
+    [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110, 0b00000], // 'O'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000, 0b00000], // 'P'
+    [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101, 0b00000], // 'Q'
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001, 0b00000], // 'R'
+    [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110, 0b00000], // 'S'
+    [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000], // 'T'
+    [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110, 0b00000], // 'U'
+    [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00000], // 'V'
+    [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010, 0b00000], // 'W'
+    [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001, 0b00000], // 'X'
+    [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100, 0b00000], // 'Y'
+    [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111, 0b00000], // 'Z'
+    [0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110, 0b00000], // '['
+    [0b00000, 0b10000, 0b01000, 0b00100, 0b00010, 0b00001, 0b00000, 0b00000], // '\\'
+    [0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110, 0b00000], // ']'
+    [0b00100, 0b01010, 0b10001, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // '^'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111, 0b00000], // '_'
+    [0b01000, 0b00100, 0b00010, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // '`'
+    [0b00000, 0b00000, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111, 0b00000], // 'a'
+    [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b11110, 0b00000], // 'b'
+    [0b00000, 0b00000, 0b01110, 0b10000, 0b10000, 0b10001, 0b01110, 0b00000], // 'c'
+    [0b00001, 0b00001, 0b01101, 0b10011, 0b10001, 0b10001, 0b01111, 0b00000], // 'd'
+    [0b00000, 0b00000, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110, 0b00000], // 'e'
+    [0b00110, 0b01001, 0b01000, 0b11100, 0b01000, 0b01000, 0b01000, 0b00000], // 'f'
+    [0b00000, 0b00000, 0b01111, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110], // 'g'
+    [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001, 0b00000], // 'h'
+    [0b00100, 0b00000, 0b01100, 0b00100, 0b00100, 0b00100, 0b01110, 0b00000], // 'i'
+    [0b00010, 0b00000, 0b00110, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100], // 'j'
+    [0b10000, 0b10000, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b00000], // 'k'
+    [0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110, 0b00000], // 'l'
+    [0b00000, 0b00000, 0b11010, 0b10101, 0b10101, 0b10001, 0b10001, 0b00000], // 'm'
+    [0b00000, 0b00000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001, 0b00000], // 'n'
+    [0b00000, 0b00000, 0b01110, 0b10001, 0b10001, 0b10001, 0b01110, 0b00000], // 'o'
+    [0b00000, 0b00000, 0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000], // 'p'
+    [0b00000, 0b00000, 0b01111, 0b10001, 0b10001, 0b01111, 0b00001, 0b00001], // 'q'
+    [0b00000, 0b00000, 0b10110, 0b11001, 0b10000, 0b10000, 0b10000, 0b00000], // 'r'
+    [0b00000, 0b00000, 0b01110, 0b10000, 0b01110, 0b00001, 0b11110, 0b00000], // 's'
+    [0b01000, 0b01000, 0b11100, 0b01000, 0b01000, 0b01001, 0b00110, 0b00000], // 't'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b10011, 0b01101, 0b00000], // 'u'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00000], // 'v'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b10101, 0b10101, 0b01010, 0b00000], // 'w'
+    [0b00000, 0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b00000], // 'x'
+    [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110], // 'y'
+    [0b00000, 0b00000, 0b11111, 0b00010, 0b00100, 0b01000, 0b11111, 0b00000], // 'z'
+    [0b00010, 0b00100, 0b00100, 0b01000, 0b00100, 0b00100, 0b00010, 0b00000], // '{'
+    [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000], // '|'
+    [0b01000, 0b00100, 0b00100, 0b00010, 0b00100, 0b00100, 0b01000, 0b00000], // '}'
+    [0b00000, 0b00000, 0b01000, 0b10101, 0b00010, 0b00000, 0b00000, 0b00000], // '~'
+];
+
+/// A log sink that renders text to a 32-bit linear framebuffer.
+pub struct FramebufferSink {
+    /// The virtual address of the first pixel, which is identity mapped to its physical address.
+    base: *mut u32,
+
+    /// The number of visible pixels per scan line.
+    width: usize,
+
+    /// The number of scan lines.
+    height: usize,
+
+    /// The number of pixels from the start of one scan line to the next, at least `width`.
+    stride: usize,
+
+    /// The character column the next character is drawn at.
+    column: usize,
+
+    /// The text line the next character is drawn at.
+    line: usize,
+}
+
+// The framebuffer is only accessed through the logger, which serializes all writes.
+unsafe impl Send for FramebufferSink {}
+
+impl FramebufferSink {
+    /// Creates a sink that renders text to the given framebuffer and clears its first line.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The address of the framebuffer, e.g. from `GraphicsOutput::frame_buffer`.
+    /// * `width` - The number of visible pixels per scan line.
+    /// * `height` - The number of scan lines.
+    /// * `stride` - The number of pixels per scan line in memory, including padding.
+    ///
+    /// # Safety
+    ///
+    /// The framebuffer must use 32 bits per pixel with 8 bits per color, i.e. the RGB or BGR pixel
+    /// format, and `height * stride` pixels at `base` must stay mapped and unused by anything else
+    /// for as long as the sink is used.
+    pub unsafe fn new(base: *mut u8, width: usize, height: usize, stride: usize) -> Self {
+        let mut sink = Self {
+            base: base as *mut u32,
+            width,
+            height,
+            stride: stride.max(width),
+            column: 0,
+            line: 0,
+        };
+
+        sink.clear_line();
+        sink
+    }
+
+    /// Returns the number of characters that fit on a text line.
+    fn columns(&self) -> usize {
+        self.width / CELL_WIDTH
+    }
+
+    /// Returns the number of text lines that fit on the screen.
+    fn lines(&self) -> usize {
+        self.height / CELL_HEIGHT
+    }
+
+    /// Moves to the start of the next text line, wrapping around to the top, and clears it.
+    fn new_line(&mut self) {
+        self.column = 0;
+        self.line += 1;
+
+        if self.line >= self.lines() {
+            self.line = 0;
+        }
+
+        self.clear_line();
+    }
+
+    /// Fills the current text line with the background color.
+    fn clear_line(&mut self) {
+        let top = self.line * CELL_HEIGHT;
+
+        for y in top..(top + CELL_HEIGHT).min(self.height) {
+            for x in 0..self.width {
+                self.put_pixel(x, y, BACKGROUND);
+            }
+        }
+    }
+
+    /// Draws a character at the current position and advances it, wrapping long lines.
+    ///
+    /// # Arguments
+    ///
+    /// * `character` - The character to draw. Characters without a glyph are drawn as `'?'`.
+    fn draw_character(&mut self, character: u8) {
+        if self.column >= self.columns() {
+            self.new_line();
+        }
+
+        let glyph = match character {
+            b' '..=b'~' => &FONT[(character - FONT_FIRST) as usize],
+            _ => &FONT[(b'?' - FONT_FIRST) as usize],
+        };
+
+        let left = self.column * CELL_WIDTH;
+        let top = self.line * CELL_HEIGHT;
+
+        for (y, row) in glyph.iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                let lit = row & (1 << (GLYPH_WIDTH - 1 - x)) != 0;
+                self.put_pixel(left + x, top + y, if lit { FOREGROUND } else { BACKGROUND });
+            }
+        }
+
+        self.column += 1;
+    }
+
+    /// Writes a single pixel, ignoring coordinates outside of the visible area.
+    fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            // The framebuffer is device memory that is scanned out by the display controller, so
+            // writes must not be elided.
+            unsafe { self.base.add(y * self.stride + x).write_volatile(color) };
+        }
+    }
+}
+
+impl LogSink for FramebufferSink {
+    fn write_str(&mut self, string: &str) {
+        // Nothing fits on a framebuffer smaller than a single character.
+        if self.columns() == 0 || self.lines() == 0 {
+            return;
+        }
+
+        for byte in string.bytes() {
+            match byte {
+                b'\n' => self.new_line(),
+                b'\r' => self.column = 0,
+                b'\t' => self.draw_character(b' '),
+                _ => self.draw_character(byte),
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Provides the logger and the sinks it writes to.
+//!
+//! This module implements logging to a `LogSink`, which is a serial port by default. A framebuffer
+//! sink renders the log to the screen on machines without a serial port, and a no-op sink discards
+//! it. This is particularly useful for debugging hypervisor and kernel-level development where
+//! traditional logging mechanisms might not be available.
+//!
+//! Credits to Satoshi Tanda: https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/serial_logger.rs
+//!
+
+pub mod framebuffer;
+
+use {
+    crate::intel::support::{inb, outb},
+    alloc::boxed::Box,
+    core::{
+        fmt,
+        fmt::Write,
+        hint::spin_loop,
+        ptr::addr_of,
+        sync::atomic::{AtomicU32, Ordering},
+    },
+    spin::Mutex,
+};
+
+/// The global logger instance.
+static mut LOGGER: Option<Logger> = None;
+
+/// The value of `Logger::owner` while no processor is writing a message.
+const NO_OWNER: u32 = u32::MAX;
+
+/// How many times `prepare_fault_report` polls a sink locked by another processor before taking it
+/// over. Long enough for a message to be written to a serial port.
+const FAULT_REPORT_LOCK_SPINS: u32 = 1 << 24;
+
+/// A destination for log messages.
+///
+/// Sinks are called with the logger locked, so a message is written completely before the next one
+/// starts, even if several processors log at the same time. They are also called from VM-exit
+/// handlers, so they must not allocate or rely on UEFI boot services.
+pub trait LogSink: Send {
+    /// Writes a string slice to the sink.
+    ///
+    /// # Arguments
+    ///
+    /// - `string`: The string slice to write, which contains `\n` at the end of every log message.
+    fn write_str(&mut self, string: &str);
+}
+
+impl Write for dyn LogSink {
+    fn write_str(&mut self, string: &str) -> Result<(), fmt::Error> {
+        LogSink::write_str(self, string);
+        Ok(())
+    }
+}
+
+/// Enum representing available serial ports.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialPort {
+    /// COM1 serial port (0x3F8).
+    COM1 = 0x3F8,
+    /// COM2 serial port (0x2F8).
+    COM2 = 0x2F8,
+}
+
+/// Writes a string slice to the serial port.
+///
+/// Outputs a string to the serial port byte by byte. It waits for the transmitter holding
+/// register to be empty before sending each byte, ensuring that the entire message is
+/// transmitted sequentially.
+impl LogSink for SerialPort {
+    fn write_str(&mut self, string: &str) {
+        const UART_OFFSET_TRANSMITTER_HOLDING_BUFFER: u16 = 0;
+        const UART_OFFSET_LINE_STATUS: u16 = 5;
+
+        for byte in string.bytes() {
+            while (inb(*self as u16 + UART_OFFSET_LINE_STATUS) & 0x20) == 0 {}
+            outb(*self as u16 + UART_OFFSET_TRANSMITTER_HOLDING_BUFFER, byte);
+        }
+    }
+}
+
+/// A sink that discards all log messages.
+///
+/// Useful for release builds, where writing to a serial port that nothing listens on still costs
+/// time on every VM exit that logs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullSink;
+
+impl LogSink for NullSink {
+    fn write_str(&mut self, _string: &str) {}
+}
+
+/// Initializes the logger.
+///
+/// Sets up the logging framework to output through the given sink. This function configures the
+/// global logger to the `Logger` and sets the logging level.
+///
+/// # Arguments
+///
+/// - `sink`: The sink to write log messages to, e.g. `SerialPort::COM2`, a `FramebufferSink`, or `NullSink`.
+/// - `level`: The maximum log level filter. Messages with a level higher than this will not be logged.
+///
+pub fn init(sink: impl LogSink + 'static, level: log::LevelFilter) {
+    unsafe { LOGGER = Some(Logger::new(Box::new(sink))) };
+    let logger = unsafe { LOGGER.as_ref().unwrap() };
+
+    log::set_logger(logger)
+        .map(|()| log::set_max_level(level))
+        .unwrap();
+}
+
+/// Makes sure the fault report of the host exception handler can be logged.
+///
+/// The exception may have been raised while the current processor was writing a message, e.g. by a
+/// faulting sink, in which case the sink stays locked forever and logging the report would hang. The
+/// lock is then forcibly released. A sink locked by another processor is given some time to finish
+/// its message, and taken over if it does not, since that processor may have faulted as well. The
+/// report may then be interleaved with the message that was being written.
+pub(crate) fn prepare_fault_report() {
+    let Some(logger) = (unsafe { (*addr_of!(LOGGER)).as_ref() }) else {
+        return;
+    };
+
+    if logger.sink.is_locked() && logger.owner.load(Ordering::Acquire) == apic_id() {
+        unsafe { logger.sink.force_unlock() };
+        return;
+    }
+
+    for _ in 0..FAULT_REPORT_LOCK_SPINS {
+        if !logger.sink.is_locked() {
+            return;
+        }
+        spin_loop();
+    }
+
+    unsafe { logger.sink.force_unlock() };
+}
+
+/// A logger that outputs messages to a `LogSink`.
+///
+/// Encapsulates the functionality for logging messages to a sink. It holds a mutex-protected
+/// sink to ensure that log messages are written atomically without being interleaved with
+/// other output.
+///
+/// The logger can be used with the Rust `log` crate's macros (e.g., `info!`, `debug!`) to direct log output
+/// to the sink.
+struct Logger {
+    /// Mutex to protect access to the sink.
+    sink: Mutex<Box<dyn LogSink>>,
+
+    /// The APIC ID of the processor writing a message, or `NO_OWNER`. Lets `prepare_fault_report`
+    /// recognize a sink locked by the processor it runs on.
+    owner: AtomicU32,
+}
+
+impl Logger {
+    /// Creates a new instance of `Logger`.
+    ///
+    /// Initializes `Logger` with a sink protected by a `Mutex`. This ensures that access to the
+    /// sink is synchronized across different execution contexts, preventing data races and
+    /// ensuring thread safety.
+    ///
+    /// # Arguments
+    ///
+    /// - `sink`: The sink to write log messages to.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Logger` instance with a mutex-protected sink ready for logging.
+    fn new(sink: Box<dyn LogSink>) -> Self {
+        Self {
+            sink: Mutex::new(sink),
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    /// Acquires a lock on the sink for exclusive access.
+    ///
+    /// This method locks the mutex protecting the sink, ensuring that the current context has
+    /// exclusive access to the sink for writing log messages. The lock is released when the
+    /// returned `MutexGuard` is dropped at the end of its scope. The caller must reset `owner` to
+    /// `NO_OWNER` before dropping it.
+    ///
+    /// # Arguments
+    ///
+    /// - `vcpu_id`: The APIC ID of the current processor, recorded as the owner of the sink.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `MutexGuard` for the sink, providing exclusive access to it.
+    fn lock(&self, vcpu_id: u32) -> spin::MutexGuard<'_, Box<dyn LogSink>> {
+        let sink = self.sink.lock();
+        self.owner.store(vcpu_id, Ordering::Release);
+        sink
+    }
+}
+
+impl log::Log for Logger {
+    /// Determines if a log message should be logged.
+    ///
+    /// # Arguments
+    ///
+    /// - `metadata`: Metadata for the log message being checked.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if the message's level is less than or equal to `Level::Trace`, indicating
+    /// it should be logged.
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::Level::Trace
+    }
+
+    /// Logs a record.
+    ///
+    /// Writes the log message to the sink if its level is enabled.
+    ///
+    /// # Arguments
+    ///
+    /// - `record`: The log record to be output.
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            // Explicitly get the APIC ID (core number) before locking the sink
+            let vcpu_id = apic_id();
+
+            // Ensure we lock the mutex before writing to the sink
+            let mut sink = self.lock(vcpu_id);
+
+            // Format and print the log message with APIC ID, log level, and log message
+            let _ = writeln!(
+                sink.as_mut(),
+                "vcpu-{} {}: {}",
+                vcpu_id,
+                record.level(),
+                record.args()
+            );
+
+            self.owner.store(NO_OWNER, Ordering::Release);
+        }
+    }
+
+    /// Flushes buffered log messages.
+    ///
+    /// Currently, this is a no-op as messages are written directly to the sink without buffering.
+    fn flush(&self) {}
+}
+
+/// Gets an APIC ID.
+///
+/// # Returns
+///
+/// Returns the APIC ID of the current processor.
+pub(crate) fn apic_id() -> u32 {
+    // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
+    // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
+    x86::cpuid::cpuid!(0x1).ebx >> 24
+}
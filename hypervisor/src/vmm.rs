@@ -9,32 +9,25 @@ use {
         error::HypervisorError,
         intel::{
             capture::GuestRegisters,
+            events::EventInjection,
+            postmortem::{
+                dump_exit_trace, dump_last_exit_context, record_exit_context, register_exit_trace,
+            },
             shared::SharedData,
-            support::{vmread, vmwrite},
+            stack::HostStack,
             vm::Vm,
-            vmerror::VmxBasicExitReason,
             vmexit::{
-                cpuid::handle_cpuid,
-                ept::{handle_ept_misconfiguration, handle_ept_violation},
-                exception::{handle_exception, handle_undefined_opcode_exception},
-                halt::handle_halt,
-                init::handle_init_signal,
-                invd::handle_invd,
-                invept::handle_invept,
-                invvpid::handle_invvpid,
-                msr::{handle_msr_access, MsrAccessType},
-                rdtsc::handle_rdtsc,
-                sipi::handle_sipi_signal,
-                xsetbv::handle_xsetbv,
-                ExitType,
+                dispatch::dispatch_exit, nmi::queue_host_nmis, smi::log_smm_monitor_state, ExitType,
             },
             vmx::Vmx,
         },
     },
     log::*,
-    x86::vmx::vmcs::{guest, ro},
 };
 
+/// The number of failed VM exits per processor whose exit context and trace are dumped.
+const MAX_FAILED_EXIT_DUMPS: u32 = 8;
+
 /// Initiates the hypervisor, activating VMX and setting up the initial VM state.
 ///
 /// Validates CPU compatibility and VMX support, then proceeds to enable VMX operation.
@@ -44,12 +37,18 @@ use {
 ///
 /// - `guest_registers`: The initial state of the guest's general-purpose registers.
 /// - `shared_data`: Shared data between the hypervisor and the guest VM.
+/// - `host_stack`: The host stack this function runs on, used for VM-exit handling.
 ///
 /// # Panics
 ///
-/// Panics if the CPU is not supported, VMX cannot be enabled, VM or VMCS activation fails,
-/// or an unhandled VM exit reason is encountered.
-pub fn start_hypervisor(guest_registers: &GuestRegisters, shared_data: &mut SharedData) -> ! {
+/// Panics if the CPU is not supported, VMX cannot be enabled, VM or VMCS activation fails, a VM
+/// entry fails after the guest has run, or a VM exit reason without a handler in the dispatch table
+/// is encountered. A failed first VM entry is reported to the loader instead, see `abandon_launch`.
+pub fn start_hypervisor(
+    guest_registers: &GuestRegisters,
+    shared_data: &mut SharedData,
+    host_stack: &HostStack,
+) -> ! {
     debug!("Starting hypervisor");
 
     match check_supported_cpu() {
@@ -64,7 +63,9 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters, shared_data: &mut Shar
         Err(e) => panic!("Failed to enable VMX: {:?}", e),
     };
 
-    let mut vm = match Vm::new(&guest_registers, shared_data) {
+    log_smm_monitor_state();
+
+    let mut vm = match Vm::new(&guest_registers, shared_data, host_stack) {
         Ok(vm) => vm,
         Err(e) => panic!("Failed to create VM: {:?}", e),
     };
@@ -74,79 +75,104 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters, shared_data: &mut Shar
         Err(e) => panic!("Failed to activate VMCS: {:?}", e),
     }
 
+    register_exit_trace(&vm.exit_trace);
+
     info!("Launching the VM until a vmexit occurs...");
 
     loop {
-        if let Ok(basic_exit_reason) = vm.run() {
+        // Hand the guest back the FPU, SSE, and AVX registers it had before the last VM exit.
+        vm.restore_guest_extended_state();
+
+        let result = vm.run();
+        if let Ok(basic_exit_reason) = result {
+            // Save them before the exit handling code gets a chance to use them.
+            vm.save_guest_extended_state();
+
             trace!("Handling VM exit reason: {:?}", basic_exit_reason);
+            record_exit_context(&vm, basic_exit_reason);
+            vm.exit_trace
+                .record(basic_exit_reason, vm.guest_registers.rip);
             debug!(
                 "Register state before handling VM exit: {:#x?}",
                 vm.guest_registers
             );
 
-            let exit_type = match basic_exit_reason {
-                VmxBasicExitReason::ExceptionOrNmi => handle_exception(&mut vm),
-                VmxBasicExitReason::InitSignal => handle_init_signal(&mut vm.guest_registers),
-                VmxBasicExitReason::StartupIpi => handle_sipi_signal(&mut vm.guest_registers),
-                VmxBasicExitReason::Hlt => handle_halt(),
-                VmxBasicExitReason::Cpuid => handle_cpuid(&mut vm.guest_registers),
-
-                // Grouping multiple exit reasons that are handled by the same function
-                VmxBasicExitReason::Getsec
-                | VmxBasicExitReason::Vmcall
-                | VmxBasicExitReason::Vmclear
-                | VmxBasicExitReason::Vmlaunch
-                | VmxBasicExitReason::Vmptrld
-                | VmxBasicExitReason::Vmptrst
-                | VmxBasicExitReason::Vmresume
-                | VmxBasicExitReason::Vmxon
-                | VmxBasicExitReason::Vmxoff => handle_undefined_opcode_exception(),
-
-                VmxBasicExitReason::Rdmsr => {
-                    handle_msr_access(&mut vm.guest_registers, MsrAccessType::Read)
-                }
-                VmxBasicExitReason::Wrmsr => {
-                    handle_msr_access(&mut vm.guest_registers, MsrAccessType::Write)
-                }
-                VmxBasicExitReason::Invd => handle_invd(&mut vm.guest_registers),
-                VmxBasicExitReason::Rdtsc => handle_rdtsc(&mut vm.guest_registers),
-                VmxBasicExitReason::EptViolation => handle_ept_violation(&mut vm),
-                VmxBasicExitReason::EptMisconfiguration => handle_ept_misconfiguration(),
-                VmxBasicExitReason::Invept => handle_invept(),
-                VmxBasicExitReason::Invvpid => handle_invvpid(),
-                VmxBasicExitReason::Xsetbv => handle_xsetbv(&mut vm.guest_registers),
-                _ => panic!("Unhandled VM exit reason: {:?}", basic_exit_reason),
+            // Once the loader has returned, hide the hypervisor memory and drop its cached translations.
+            unsafe {
+                SharedData::hide_hypervisor_memory(
+                    vm.shared_data,
+                    &mut vm.memory_hidden,
+                    vm.guest_registers.rip,
+                )
             };
 
-            if exit_type == ExitType::IncrementRIP {
-                advance_guest_rip(&mut vm.guest_registers);
+            let watchdog = &unsafe { vm.shared_data.as_ref() }.watchdog;
+            watchdog.enter(vm.apic_id, basic_exit_reason, vm.guest_registers.rip);
+            let exit_type = dispatch_exit(&mut vm, basic_exit_reason);
+            watchdog.leave(vm.apic_id);
+
+            match exit_type {
+                ExitType::IncrementRIP => vm.advance_rip(),
+                // The handler gave up instead of panicking, which would hang this processor. Retrying
+                // the instruction would fail the same way, so the guest gets a #UD for it instead.
+                ExitType::ExitHypervisor => {
+                    if !EventInjection::is_event_pending() {
+                        EventInjection::vmentry_inject_ud();
+                    }
+
+                    // Log how the guest got here, but only for the first failures of this processor.
+                    vm.failed_exits = vm.failed_exits.saturating_add(1);
+                    if vm.failed_exits <= MAX_FAILED_EXIT_DUMPS {
+                        dump_last_exit_context();
+                        dump_exit_trace();
+                    }
+                }
+                ExitType::Continue => {}
             }
 
+            // NMIs that arrived while handling the exit were delivered through the host IDT.
+            queue_host_nmis(&mut vm);
+
             debug!(
                 "Register state after handling VM exit: {:#x?}",
                 vm.guest_registers
             );
-        } else {
-            panic!("Failed to run the VM");
+        } else if let Err(e) = result {
+            abandon_launch(&vm, e);
         }
     }
 }
 
-/// Advances the guest's instruction pointer after handling a VM exit.
+/// Reports a failed VM entry to the loader and continues the loader on the current processor
+/// without virtualizing it.
 ///
-/// Ensures the guest VM does not re-execute the instruction causing the VM exit
-/// by moving the instruction pointer to the next instruction.
+/// `start_hypervisor` runs on its own stack and cannot return to its caller, so the error is left
+/// in the launch failure slot of the processor, which `start_hypervisor_on_all_processors` checks
+/// once all processors have been started.
 ///
 /// # Arguments
 ///
-/// - `guest_registers`: A mutable reference to the guest's general-purpose registers.
-#[rustfmt::skip]
-fn advance_guest_rip(guest_registers: &mut GuestRegisters) {
-    trace!("Advancing guest RIP...");
-    let len = vmread(ro::VMEXIT_INSTRUCTION_LEN);
-    guest_registers.rip += len;
-    vmwrite(guest::RIP, guest_registers.rip);
-    trace!("Guest RIP advanced to: {:#x}", vmread(guest::RIP));
+/// - `vm`: The VM whose entry failed.
+/// - `error`: The error the VM entry failed with.
+///
+/// # Panics
+///
+/// Panics if the guest has already run or was launched with an initial guest state, since there is
+/// no loader to return to then.
+fn abandon_launch(vm: &Vm, error: HypervisorError) -> ! {
+    let shared_data = unsafe { vm.shared_data.as_ref() };
+
+    if vm.has_launched || shared_data.initial_guest_state.is_some() {
+        panic!("Failed to run the VM: {}", error);
+    }
+
+    error!(
+        "Failed to launch the VM, continuing without the hypervisor: {}",
+        error
+    );
+    shared_data.report_launch_failure(vm.apic_id, error);
+
+    unsafe { vm.resume_without_vmx() }
 }
 
 /// Checks if the CPU is supported for hypervisor operation.